@@ -1,3 +1,5 @@
 fn main() {
-    tauri_build::build()
+    // ir_core だけのファームウェア用ビルドではTauri(tauri-build)に依存しない
+    #[cfg(feature = "std")]
+    tauri_build::build();
 }
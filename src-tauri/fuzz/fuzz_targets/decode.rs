@@ -0,0 +1,55 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+//! 貼り付けられた文字列を解析 -> デコード -> エンコードしてもパニックしないことを確かめる
+//! `cargo fuzz run decode` で実行する
+#![no_main]
+use app_lib::infrared_remote::{
+    decode_phase1, encode_phase2, encode_phase3, MarkAndSpace, MarkAndSpaceMicros, Microseconds,
+};
+use app_lib::parsing::{from_array, from_infrared_code};
+use app_lib::protocol::ProtocolRegistry;
+use app_lib::time_base::estimate_time_base;
+use libfuzzer_sys::fuzz_target;
+
+fn pipeline(input: &[MarkAndSpaceMicros]) {
+    let registry = ProtocolRegistry::builtin();
+    let _ = estimate_time_base(input);
+    let _ = encode_phase3(input);
+    if let Ok(frames) = decode_phase1(input) {
+        let _ = registry.interpret_frames(&frames);
+        let decoded = registry.decode_frames(&frames);
+        for frame in decoded.iter() {
+            let _ = registry.fields(frame);
+        }
+        if let Ok(encoded) = registry.encode_frames(&decoded) {
+            let _ = encode_phase3(&encode_phase2(&encoded));
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    // 文字列として解析する
+    if let Ok(text) = std::str::from_utf8(data) {
+        if let Ok(xs) = from_infrared_code(text) {
+            pipeline(
+                &xs.iter()
+                    .map(|x| MarkAndSpaceMicros::from(*x))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        if let Ok(xs) = from_array(text) {
+            pipeline(&xs);
+        }
+    }
+    // 8バイトずつマークアンドスペースとして解析する
+    let xs = data
+        .chunks_exact(8)
+        .map(|x| MarkAndSpace {
+            mark: Microseconds(u32::from_le_bytes([x[0], x[1], x[2], x[3]])),
+            space: Some(Microseconds(u32::from_le_bytes([x[4], x[5], x[6], x[7]]))),
+        })
+        .collect::<Vec<MarkAndSpaceMicros>>();
+    pipeline(&xs);
+});
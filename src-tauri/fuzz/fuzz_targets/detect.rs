@@ -0,0 +1,32 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+//! 貼り付けられた文字列の書式を見分けて読み込み -> デコード -> エンコードしてもパニックしないことを確かめる
+//! `cargo fuzz run detect` で実行する
+#![no_main]
+use app_lib::formats::SignalFormatRegistry;
+use app_lib::infrared_remote::{decode_phase1, encode_phase2, encode_phase3};
+use app_lib::protocol::ProtocolRegistry;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let text = match std::str::from_utf8(data) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    let registry = SignalFormatRegistry::builtin();
+    let detected = match registry.detect(text) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    // 見分けた書式を指定して読み直しても同じになる
+    assert_eq!(registry.parse(&detected.format, text), Ok(detected.clone()));
+    let protocols = ProtocolRegistry::builtin_ref();
+    if let Ok(frames) = decode_phase1(&detected.signal) {
+        let decoded = protocols.decode_frames(&frames);
+        if let Ok(encoded) = protocols.encode_frames(&decoded) {
+            let _ = encode_phase3(&encode_phase2(&encoded));
+        }
+    }
+});
@@ -0,0 +1,35 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+//! IRP 記法を解析 -> 生成 -> 認識してもパニックせず、時間がかかりすぎないことを確かめる
+//! `cargo fuzz run irp` で実行する
+#![no_main]
+use app_lib::irp::parse_irp;
+use libfuzzer_sys::fuzz_target;
+use std::collections::BTreeMap;
+
+fuzz_target!(|data: &[u8]| {
+    // 先頭の1バイトは繰り返しの回数にする
+    let (repeat, text) = match data.split_first() {
+        Some((x, xs)) => (*x as u32 % 4, xs),
+        None => return,
+    };
+    let text = match std::str::from_utf8(text) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    if let Ok(irp) = parse_irp(text) {
+        // パラメータは範囲の下限と上限を試す
+        for upper in [false, true] {
+            let parameters = irp
+                .parameters
+                .iter()
+                .map(|x| (x.name.clone(), if upper { x.max } else { x.min }))
+                .collect::<BTreeMap<String, i64>>();
+            if let Ok(xs) = irp.render(&parameters, repeat) {
+                let _ = irp.recognise(&xs);
+            }
+        }
+    }
+});
@@ -0,0 +1,378 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+//! 1ビット1ビットで詰めたビット列
+use crate::ir_core::Bit;
+use crate::protocol::BitOrder;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::iter;
+use std::ops;
+
+#[derive(Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// ビット列
+/// i番目のビットは i/8 番目のバイトの下位から i%8 番目のビット(使わないビットは0)
+pub struct BitVec {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitVec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 長さnの0のビット列
+    pub fn zeros(n: usize) -> Self {
+        BitVec {
+            bytes: vec![0; (n + 7) / 8],
+            len: n,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// index番目のビット(範囲外ならNone)
+    pub fn get(&self, index: usize) -> Option<Bit> {
+        if index < self.len {
+            Some(Bit((self.bytes[index / 8] >> (index % 8)) & 1))
+        } else {
+            None
+        }
+    }
+
+    /// index番目のビットを変える
+    /// 範囲外ならパニックする
+    pub fn set(&mut self, index: usize, bit: Bit) {
+        assert!(
+            index < self.len,
+            "BitVec::set: index {} is out of range {}.",
+            index,
+            self.len
+        );
+        let mask = 1 << (index % 8);
+        if bit == Bit::ONE {
+            self.bytes[index / 8] |= mask;
+        } else {
+            self.bytes[index / 8] &= !mask;
+        }
+    }
+
+    /// 末尾に追加する
+    pub fn push(&mut self, bit: Bit) {
+        if self.len % 8 == 0 {
+            self.bytes.push(0);
+        }
+        self.len += 1;
+        self.set(self.len - 1, bit);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Bit> + '_ {
+        (0..self.len).map(move |i| Bit((self.bytes[i / 8] >> (i % 8)) & 1))
+    }
+
+    /// 範囲のビット列(範囲外ならNone)
+    pub fn slice(&self, range: ops::Range<usize>) -> Option<BitVec> {
+        if range.start <= range.end && range.end <= self.len {
+            Some(range.map(|i| self[i]).collect())
+        } else {
+            None
+        }
+    }
+
+    /// 前からn個と残りに分ける(nが長さを超えたら長さにする)
+    pub fn split_at(&self, n: usize) -> (BitVec, BitVec) {
+        let n = n.min(self.len);
+        (self.iter().take(n).collect(), self.iter().skip(n).collect())
+    }
+
+    /// startからbits個を整数にする(64ビットを超える、または範囲外ならNone)
+    pub fn value(&self, start: usize, bits: usize, bit_order: BitOrder) -> Option<u64> {
+        if 64 < bits || self.len < start.checked_add(bits)? {
+            return None;
+        }
+        let value = |acc: u64, (index, bit): (usize, Bit)| match bit_order {
+            BitOrder::LsbFirst => acc | ((bit.0 as u64) << index),
+            BitOrder::MsbFirst => (acc << 1) | bit.0 as u64,
+        };
+        Some(
+            self.iter()
+                .skip(start)
+                .take(bits)
+                .enumerate()
+                .fold(0, value),
+        )
+    }
+
+    /// startからの8ビット
+    pub fn u8_at(&self, start: usize, bit_order: BitOrder) -> Option<u8> {
+        self.value(start, 8, bit_order).map(|x| x as u8)
+    }
+
+    /// startからの16ビット
+    pub fn u16_at(&self, start: usize, bit_order: BitOrder) -> Option<u16> {
+        self.value(start, 16, bit_order).map(|x| x as u16)
+    }
+
+    /// startからの32ビット
+    pub fn u32_at(&self, start: usize, bit_order: BitOrder) -> Option<u32> {
+        self.value(start, 32, bit_order).map(|x| x as u32)
+    }
+
+    /// 整数の下位bits個を追加する
+    pub fn push_value(&mut self, value: u64, bits: usize, bit_order: BitOrder) {
+        for i in 0..bits {
+            let shift = match bit_order {
+                BitOrder::LsbFirst => i,
+                BitOrder::MsbFirst => bits - 1 - i,
+            };
+            // 64ビットを超える位置は0
+            let bit = u32::try_from(shift)
+                .ok()
+                .and_then(|x| value.checked_shr(x))
+                .unwrap_or(0);
+            self.push(Bit((bit & 1) as u8));
+        }
+    }
+
+    /// バイト列(各バイトは下位ビットが先)から
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        BitVec {
+            bytes: bytes.to_vec(),
+            len: bytes.len() * 8,
+        }
+    }
+
+    /// バイト列(各バイトは下位ビットが先、8ビットに満たない最後のバイトの上位は0)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    /// 16進数の文字列(バイトごと、空白は無視する)から
+    pub fn from_hex(input: &str) -> Result<Self, String> {
+        let digits = input
+            .chars()
+            .filter(|x| !x.is_whitespace())
+            .collect::<Vec<char>>();
+        // from_str_radix は先頭の + も受け付けるので、先に16進数の文字か調べる
+        if let Some(x) = digits.iter().find(|x| !x.is_ascii_hexdigit()) {
+            return Err(format!(
+                "BitVec: {} の {} は16進数ではありません。",
+                input, x
+            ));
+        }
+        if digits.len() % 2 != 0 {
+            return Err(format!("BitVec: {} の桁数が奇数です。", input));
+        }
+        let bytes = digits
+            .chunks(2)
+            .map(|xs| {
+                let s = xs.iter().collect::<String>();
+                u8::from_str_radix(&s, 16)
+                    .map_err(|_| format!("BitVec: {} は16進数ではありません。", s))
+            })
+            .collect::<Result<Vec<u8>, String>>()?;
+        Ok(Self::from_bytes(&bytes))
+    }
+
+    /// 16進数の文字列(バイトごと)
+    pub fn to_hex(&self) -> String {
+        self.bytes.iter().map(|x| format!("{:02X}", x)).collect()
+    }
+
+    /// 排他的論理和(長さが違えばNone)
+    pub fn xor(&self, other: &BitVec) -> Option<BitVec> {
+        if self.len != other.len {
+            return None;
+        }
+        Some(BitVec {
+            bytes: self
+                .bytes
+                .iter()
+                .zip(other.bytes.iter())
+                .map(|(a, b)| a ^ b)
+                .collect(),
+            len: self.len,
+        })
+    }
+
+    /// 指定範囲のビットを反転する
+    pub fn invert(&mut self, range: ops::Range<usize>) {
+        for i in range.take_while(|i| *i < self.len) {
+            self.bytes[i / 8] ^= 1 << (i % 8);
+        }
+    }
+
+    /// 1のビットの数
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
+    /// 違うビットの位置(長い方の余りも違うビットにする)
+    pub fn differences(&self, other: &BitVec) -> Vec<usize> {
+        (0..self.len.max(other.len))
+            .filter(|i| self.get(*i) != other.get(*i))
+            .collect()
+    }
+}
+
+impl ops::Index<usize> for BitVec {
+    type Output = Bit;
+    /// 範囲外ならパニックする
+    fn index(&self, index: usize) -> &Bit {
+        match self.get(index) {
+            Some(Bit(1)) => &Bit::ONE,
+            Some(_) => &Bit::ZERO,
+            None => panic!("BitVec: index {} is out of range {}.", index, self.len),
+        }
+    }
+}
+
+impl iter::FromIterator<Bit> for BitVec {
+    fn from_iter<T: IntoIterator<Item = Bit>>(iter: T) -> Self {
+        let mut bits = BitVec::new();
+        bits.extend(iter);
+        bits
+    }
+}
+
+impl iter::Extend<Bit> for BitVec {
+    fn extend<T: IntoIterator<Item = Bit>>(&mut self, iter: T) {
+        for bit in iter {
+            self.push(bit);
+        }
+    }
+}
+
+impl From<&[Bit]> for BitVec {
+    fn from(bits: &[Bit]) -> Self {
+        bits.iter().copied().collect()
+    }
+}
+
+impl From<Vec<Bit>> for BitVec {
+    fn from(bits: Vec<Bit>) -> Self {
+        bits.into_iter().collect()
+    }
+}
+
+impl fmt::Display for BitVec {
+    /// 8ビットごとに空白を入れて表示する
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, bit) in self.iter().enumerate() {
+            write!(f, "{}", bit.0)?;
+            if index & 7 == 7 {
+                write!(f, " ")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BitVec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BitVec({})", self)
+    }
+}
+
+impl Serialize for BitVec {
+    /// 0と1の配列(Vec<Bit> と同じ形)
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter().map(|x| x.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for BitVec {
+    /// 0と1の配列から(それ以外はエラー)
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let xs = Vec::<u8>::deserialize(deserializer)?;
+        xs.into_iter()
+            .map(|x| match x {
+                0 => Ok(Bit::ZERO),
+                1 => Ok(Bit::ONE),
+                _ => Err(de::Error::custom(format!(
+                    "Bit: {} は 0 か 1 でよろしく",
+                    x
+                ))),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod bitvec_tests {
+    use crate::bitvec::BitVec;
+    use crate::ir_core::Bit;
+    use crate::protocol::BitOrder;
+
+    #[test]
+    fn test_get_set() {
+        let mut bits = BitVec::zeros(10);
+        bits.set(9, Bit::ONE);
+        bits.push(Bit::ONE);
+        assert_eq!(bits.len(), 11);
+        assert_eq!(bits.get(9), Some(Bit::ONE));
+        assert_eq!(bits[8], Bit::ZERO);
+        assert_eq!(bits.get(11), None);
+        assert_eq!(bits.to_string(), "00000000 011");
+        assert_eq!(bits.slice(8..11).unwrap().to_string(), "011");
+        assert!(bits.slice(8..12).is_none());
+        // 使わないビットは0なので比べられる
+        bits.set(10, Bit::ZERO);
+        let mut expected = BitVec::zeros(11);
+        expected.set(9, Bit::ONE);
+        assert_eq!(bits, expected);
+    }
+
+    #[test]
+    fn test_values() {
+        // NECの 0x04 0xFB 0x08 0xF7
+        let bits = BitVec::from_hex("04FB08F7").unwrap();
+        assert_eq!(bits.len(), 32);
+        assert_eq!(bits.u8_at(0, BitOrder::LsbFirst), Some(0x04));
+        assert_eq!(bits.u8_at(0, BitOrder::MsbFirst), Some(0x20));
+        assert_eq!(bits.u16_at(16, BitOrder::LsbFirst), Some(0xF708));
+        assert_eq!(bits.u32_at(0, BitOrder::LsbFirst), Some(0xF708_FB04));
+        assert_eq!(bits.u32_at(8, BitOrder::LsbFirst), None);
+        assert_eq!(bits.to_hex(), "04FB08F7");
+        assert_eq!(bits.to_bytes(), vec!(0x04, 0xFB, 0x08, 0xF7));
+        let mut composed = BitVec::new();
+        composed.push_value(0x04, 8, BitOrder::LsbFirst);
+        composed.push_value(0xDF, 8, BitOrder::MsbFirst);
+        composed.push_value(0xF708, 16, BitOrder::LsbFirst);
+        assert_eq!(composed, bits);
+        assert!(BitVec::from_hex("4FB").is_err());
+        assert!(BitVec::from_hex("XY").is_err());
+        assert!(BitVec::from_hex("+F00").is_err());
+    }
+
+    #[test]
+    fn test_xor() {
+        let a = BitVec::from_hex("04FB").unwrap();
+        let b = BitVec::from_hex("0404").unwrap();
+        assert_eq!(a.xor(&b), Some(BitVec::from_hex("00FF").unwrap()));
+        assert_eq!(a.xor(&BitVec::zeros(3)), None);
+        assert_eq!(a.differences(&b), (8..16).collect::<Vec<usize>>());
+        let mut c = a.clone();
+        c.invert(8..16);
+        assert_eq!(c, b);
+        assert_eq!(a.count_ones(), 8);
+        assert!(b < a);
+    }
+
+    #[test]
+    fn test_serde() {
+        let bits = BitVec::from_hex("05").unwrap();
+        let json = serde_json::to_string(&bits).unwrap();
+        assert_eq!(json, "[1,0,1,0,0,0,0,0]");
+        assert_eq!(serde_json::from_str::<BitVec>(&json).unwrap(), bits);
+        assert!(serde_json::from_str::<BitVec>("[0,2]").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+use crate::infrared_remote::{MarkAndSpaceMicros, Microseconds};
+use crate::signal::Signal;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// ノイズ除去の設定
+pub struct CleanupOptions {
+    /// これより短いマーク(スペース)はノイズとみなす
+    pub minimum_pulse_width: Microseconds,
+    /// 先頭と最後のノイズを取り除くか
+    pub trim: bool,
+}
+
+impl Default for CleanupOptions {
+    /// 安い受光器で拾う外乱光のノイズは100us未満
+    fn default() -> Self {
+        CleanupOptions {
+            minimum_pulse_width: Microseconds(100),
+            trim: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// ノイズ除去で変更した内容
+/// 時刻は入力信号の先頭からの時刻
+pub enum CleanupEdit {
+    /// 短いマークを前後のスペースに併合した
+    MergedMark {
+        start: Microseconds,
+        duration: Microseconds,
+    },
+    /// 短いスペースを前後のマークに併合した
+    MergedSpace {
+        start: Microseconds,
+        duration: Microseconds,
+    },
+    /// 先頭のノイズを取り除いた
+    TrimmedLeading {
+        start: Microseconds,
+        duration: Microseconds,
+    },
+    /// 最後のノイズを取り除いた
+    TrimmedTrailing {
+        start: Microseconds,
+        duration: Microseconds,
+    },
+}
+
+impl fmt::Display for CleanupEdit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (what, start, duration) = match self {
+            CleanupEdit::MergedMark { start, duration } => ("merged mark", start, duration),
+            CleanupEdit::MergedSpace { start, duration } => ("merged space", start, duration),
+            CleanupEdit::TrimmedLeading { start, duration } => ("trimmed leading", start, duration),
+            CleanupEdit::TrimmedTrailing { start, duration } => {
+                ("trimmed trailing", start, duration)
+            }
+        };
+        write!(f, "{} {}us at {}us", what, duration.0, start.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// ノイズ除去の結果
+pub struct CleanupResult {
+    /// ノイズを除去した信号
+    pub output: Vec<MarkAndSpaceMicros>,
+    /// 変更した内容
+    pub edits: Vec<CleanupEdit>,
+}
+
+/// 時間列の1区間(開始時刻, 長さ, マークか)
+type Piece = (Microseconds, Microseconds, bool);
+
+/// 短い区間を前後の区間に併合する
+fn merge_glitches(
+    input: &[Piece],
+    minimum: Microseconds,
+    edits: &mut Vec<CleanupEdit>,
+) -> Vec<Piece> {
+    let mut output: Vec<Piece> = Vec::new();
+    let mut index = 0;
+    while index < input.len() {
+        let (start, duration, mark) = input[index];
+        match (output.last_mut(), input.get(index + 1)) {
+            // 前後があれば、前の区間に自分と次の区間を足す
+            (Some(previous), Some(next)) if duration < minimum => {
+                previous.1 = previous.1 + duration + next.1;
+                edits.push(if mark {
+                    CleanupEdit::MergedMark { start, duration }
+                } else {
+                    CleanupEdit::MergedSpace { start, duration }
+                });
+                index += 2;
+            }
+            _ => {
+                output.push((start, duration, mark));
+                index += 1;
+            }
+        }
+    }
+    output
+}
+
+/// 先頭と最後の短い区間を取り除く
+fn trim(input: &[Piece], minimum: Microseconds, edits: &mut Vec<CleanupEdit>) -> Vec<Piece> {
+    let mut output = input.to_vec();
+    // 先頭の短いマークは、その後ろのスペースと一緒に取り除く
+    while let Some((start, duration, true)) = output.first().copied() {
+        if minimum <= duration {
+            break;
+        }
+        edits.push(CleanupEdit::TrimmedLeading { start, duration });
+        output.remove(0);
+        if let Some((_, _, false)) = output.first() {
+            output.remove(0);
+        }
+    }
+    // 最後の短いマーク(スペース)を取り除く
+    while let Some((start, duration, _)) = output.last().copied() {
+        if minimum <= duration {
+            break;
+        }
+        edits.push(CleanupEdit::TrimmedTrailing { start, duration });
+        output.pop();
+    }
+    output
+}
+
+/// 入力マークアンドスペース列のノイズを取り除く
+/// フレームに分ける(decode_phase1)前に使う
+pub fn cleanup(input: &[MarkAndSpaceMicros], options: &CleanupOptions) -> CleanupResult {
+    let signal = Signal::from_mark_and_spaces(input);
+    let pieces = signal
+        .segments()
+        .map(|x| (x.start, x.duration, x.mark))
+        .collect::<Vec<Piece>>();
+    let mut edits = Vec::new();
+    let mut pieces = merge_glitches(&pieces, options.minimum_pulse_width, &mut edits);
+    if options.trim {
+        pieces = trim(&pieces, options.minimum_pulse_width, &mut edits);
+    }
+    let output = Signal::new(pieces.iter().map(|x| x.1).collect()).to_mark_and_spaces();
+    CleanupResult { output, edits }
+}
+
+#[cfg(test)]
+mod cleanup_tests {
+    use crate::cleanup::{cleanup, CleanupEdit, CleanupOptions};
+    use crate::infrared_remote::{decode_phase1, decode_phase2, MarkAndSpaceMicros, Microseconds};
+    use crate::protocol::ProtocolRegistry;
+
+    fn ms(mark: u32, space: u32) -> MarkAndSpaceMicros {
+        MarkAndSpaceMicros {
+            mark: Microseconds(mark),
+            space: Some(Microseconds(space)),
+        }
+    }
+
+    #[test]
+    fn test_merge_glitches() {
+        // リーダーのマークの途中に短いスペース、フレーム間隔の途中に短いマーク
+        let input = vec![
+            ms(4000, 40),
+            ms(4960, 4500),
+            ms(560, 20000),
+            ms(50, 20000),
+            ms(560, 560),
+        ];
+        let result = cleanup(&input, &CleanupOptions::default());
+        assert_eq!(
+            result.output,
+            vec!(ms(9000, 4500), ms(560, 40050), ms(560, 560))
+        );
+        assert_eq!(
+            result.edits,
+            vec!(
+                CleanupEdit::MergedSpace {
+                    start: Microseconds(4000),
+                    duration: Microseconds(40)
+                },
+                CleanupEdit::MergedMark {
+                    start: Microseconds(34060),
+                    duration: Microseconds(50)
+                },
+            )
+        );
+    }
+
+    #[test]
+    fn test_trim() {
+        let input = vec![ms(30, 50000), ms(9000, 4500), ms(560, 560), ms(560, 70)];
+        let result = cleanup(&input, &CleanupOptions::default());
+        assert_eq!(
+            result.output,
+            vec!(
+                ms(9000, 4500),
+                ms(560, 560),
+                MarkAndSpaceMicros::mark_only(Microseconds(560))
+            )
+        );
+        assert_eq!(result.edits.len(), 2);
+        // 取り除かなければそのまま
+        let options = CleanupOptions {
+            trim: false,
+            ..CleanupOptions::default()
+        };
+        assert_eq!(cleanup(&input, &options).output, input);
+    }
+
+    #[test]
+    fn test_decode_after_cleanup() {
+        let registry = ProtocolRegistry::builtin();
+        let values = [
+            ("custom_code".to_string(), 0xFF00),
+            ("data".to_string(), 0x12),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let frame = registry.compose("NEC", &values).unwrap();
+        let mut input = registry.encode(&frame).unwrap().0;
+        // 3ビット目のスペースの途中に外乱光
+        let space = input[3].space.unwrap().0;
+        input[3].space = Some(Microseconds(200));
+        input.insert(4, ms(40, space - 240));
+        let noisy = decode_phase1(&input).unwrap();
+        assert_ne!(decode_phase2(&noisy[0]), frame);
+        let cleaned = cleanup(&input, &CleanupOptions::default());
+        let frames = decode_phase1(&cleaned.output).unwrap();
+        assert_eq!(decode_phase2(&frames[0]), frame);
+        assert_eq!(cleaned.edits.len(), 1);
+    }
+}
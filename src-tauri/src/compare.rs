@@ -0,0 +1,308 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+use crate::bitvec::BitVec;
+use crate::fidelity::{round_trip_fidelity, PulseKind, StructuralDifference};
+use crate::infrared_remote::{
+    InfraredRemoteDemodulatedFrame, InfraredRemoteFrame, MarkAndSpaceMicros, Microseconds,
+};
+use crate::protocol::ProtocolRegistry;
+use crate::segmentation::segment_frames;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// 比べるときに無視するフィールド名(押すたびに変わるトグルビット)
+pub const TOGGLE_FIELD: &str = "toggle";
+
+/// 時間を比べるときの許容誤差の既定値
+pub const DEFAULT_COMPARE_TOLERANCE: Microseconds = Microseconds(200);
+
+/// 差分に書く時間の違いの最大行数
+const MAXIMUM_TIMING_LINES: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 2つの信号の同等性(上ほど強い)
+pub enum Equivalence {
+    /// 復調したフレームが同じ
+    SameFrames,
+    /// 時間が許容誤差内で同じ
+    SimilarTiming,
+    /// 同じコマンドで、トグルビットかリピート回数が違う
+    SameCommand,
+    /// 違う信号
+    Different,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 2つの信号の比較結果
+pub struct Comparison {
+    /// 同等性
+    pub equivalence: Equivalence,
+    /// 人が読める差分
+    pub differences: Vec<String>,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:?}", self.equivalence)?;
+        for x in self.differences.iter() {
+            writeln!(f, "  {}", x)?;
+        }
+        Ok(())
+    }
+}
+
+/// トグルビットを取り除いたフレーム
+fn without_toggle(
+    registry: &ProtocolRegistry,
+    input: &InfraredRemoteDemodulatedFrame,
+) -> InfraredRemoteDemodulatedFrame {
+    let definition = input.protocol().and_then(|name| registry.find(name));
+    match (definition, input.bits()) {
+        (Some(d), Some(bits)) if d.fields.iter().any(|x| x.name == TOGGLE_FIELD) => {
+            let mut rest = bits.clone();
+            let mut result = BitVec::new();
+            for field in d.fields.iter() {
+                let (x, tail) = rest.split_at(field.bits.unwrap_or(rest.len()));
+                if field.name != TOGGLE_FIELD {
+                    result.extend(x.iter());
+                }
+                rest = tail;
+            }
+            InfraredRemoteDemodulatedFrame::from_protocol(&d.name, result)
+        }
+        _ => input.clone(),
+    }
+}
+
+/// リピート信号と続けて送った同じフレームをまとめたコマンドの列
+fn commands(
+    registry: &ProtocolRegistry,
+    input: &[InfraredRemoteDemodulatedFrame],
+) -> Vec<InfraredRemoteDemodulatedFrame> {
+    let mut result = input
+        .iter()
+        .filter(|x| !matches!(x, InfraredRemoteDemodulatedFrame::Repeat(_)))
+        .map(|x| without_toggle(registry, x))
+        .collect::<Vec<InfraredRemoteDemodulatedFrame>>();
+    result.dedup();
+    result
+}
+
+/// 入力信号を分割して復調する
+fn decode(
+    registry: &ProtocolRegistry,
+    input: &[MarkAndSpaceMicros],
+) -> Result<Vec<InfraredRemoteDemodulatedFrame>, String> {
+    let frames = segment_frames(registry, input)?
+        .into_iter()
+        .map(|x| x.frame)
+        .collect::<Vec<InfraredRemoteFrame>>();
+    Ok(registry.decode_frames(&frames))
+}
+
+/// 2つの信号が同じボタンのものか比べる
+/// 復調したフレーム、時間、トグルビットとリピート回数を除いたコマンドの順に比べて、
+/// 最も強い同等性と差分を返す
+pub fn compare_signals(
+    registry: &ProtocolRegistry,
+    a: &[MarkAndSpaceMicros],
+    b: &[MarkAndSpaceMicros],
+    tolerance: Microseconds,
+) -> Result<Comparison, String> {
+    let decoded_a = decode(registry, a)?;
+    let decoded_b = decode(registry, b)?;
+    let report = round_trip_fidelity(registry, a, b)?;
+    let mut differences = Vec::new();
+
+    // 復調したフレームの違い
+    for index in 0..decoded_a.len().max(decoded_b.len()) {
+        match (decoded_a.get(index), decoded_b.get(index)) {
+            (Some(x), Some(y)) if x == y => (),
+            // 不明プロトコルは時間の違いで示す
+            (
+                Some(InfraredRemoteDemodulatedFrame::Unknown(_)),
+                Some(InfraredRemoteDemodulatedFrame::Unknown(_)),
+            ) => (),
+            (Some(x), Some(y)) => differences.push(format!("フレーム{}: {} ≠ {}", index, x, y)),
+            (Some(x), None) => differences.push(format!("フレーム{}: {} ≠ (なし)", index, x)),
+            (None, Some(y)) => differences.push(format!("フレーム{}: (なし) ≠ {}", index, y)),
+            (None, None) => (),
+        }
+    }
+    // 構造の違い
+    for x in report.differences.iter() {
+        differences.push(match x {
+            StructuralDifference::MissingFrame { frame } => {
+                format!("フレーム{}: 2つ目の信号にありません。", frame)
+            }
+            StructuralDifference::ExtraFrame { frame } => {
+                format!("フレーム{}: 1つ目の信号にありません。", frame)
+            }
+            StructuralDifference::MissingPulses { frame, count } => {
+                format!(
+                    "フレーム{}: 2つ目の信号のパルスが{}個少ない。",
+                    frame, count
+                )
+            }
+            StructuralDifference::ExtraPulses { frame, count } => {
+                format!("フレーム{}: 2つ目の信号のパルスが{}個多い。", frame, count)
+            }
+        });
+    }
+    // 時間の違い
+    let timing = report
+        .deltas
+        .iter()
+        .filter(|x| tolerance.0 as u64 <= x.delta.unsigned_abs())
+        .collect::<Vec<_>>();
+    for x in timing.iter().take(MAXIMUM_TIMING_LINES) {
+        let kind = match x.kind {
+            PulseKind::Mark => "マーク",
+            PulseKind::Space => "スペース",
+            PulseKind::Gap => "フレーム間隔",
+        };
+        differences.push(format!(
+            "フレーム{} #{} {}: {}us → {}us ({:+}us)",
+            x.frame, x.index, kind, x.original.0, x.encoded.0, x.delta
+        ));
+    }
+    if MAXIMUM_TIMING_LINES < timing.len() {
+        differences.push(format!(
+            "ほかに{}個の時間の違い",
+            timing.len() - MAXIMUM_TIMING_LINES
+        ));
+    }
+
+    let known = |xs: &[InfraredRemoteDemodulatedFrame]| {
+        xs.iter()
+            .all(|x| !matches!(x, InfraredRemoteDemodulatedFrame::Unknown(_)))
+    };
+    let commands_a = commands(registry, &decoded_a);
+    let commands_b = commands(registry, &decoded_b);
+    let equivalence = if decoded_a == decoded_b && known(&decoded_a) {
+        Equivalence::SameFrames
+    } else if report.is_within(tolerance) {
+        Equivalence::SimilarTiming
+    } else if commands_a == commands_b && known(&decoded_a) && known(&decoded_b) {
+        differences.push(format!(
+            "同じコマンドです。(フレーム数 {} と {})",
+            decoded_a.len(),
+            decoded_b.len()
+        ));
+        Equivalence::SameCommand
+    } else {
+        Equivalence::Different
+    };
+    Ok(Comparison {
+        equivalence,
+        differences,
+    })
+}
+
+#[cfg(test)]
+mod compare_tests {
+    use crate::compare::{compare_signals, Equivalence, DEFAULT_COMPARE_TOLERANCE};
+    use crate::infrared_remote::{
+        Bit, InfraredRemoteDemodulatedFrame, MarkAndSpaceMicros, Microseconds, TimedFrame,
+    };
+    use crate::protocol::ProtocolRegistry;
+
+    fn encode(
+        registry: &ProtocolRegistry,
+        input: &[InfraredRemoteDemodulatedFrame],
+    ) -> Vec<MarkAndSpaceMicros> {
+        let input = input
+            .iter()
+            .cloned()
+            .map(TimedFrame::from)
+            .collect::<Vec<TimedFrame>>();
+        registry
+            .encode_timed_frames(&input)
+            .unwrap()
+            .into_iter()
+            .flat_map(|x| x.0)
+            .collect()
+    }
+
+    fn nec(data: u8) -> InfraredRemoteDemodulatedFrame {
+        InfraredRemoteDemodulatedFrame::Nec(
+            [0x00, 0xFF, data, !data]
+                .iter()
+                .flat_map(|byte| (0..8).map(move |i| Bit((byte >> i) & 1)))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_same_frames() {
+        let registry = ProtocolRegistry::builtin();
+        let a = encode(&registry, &[nec(0x12)]);
+        // 別の学習リモコンで少しずれた時間
+        let b = a
+            .iter()
+            .map(|x| MarkAndSpaceMicros {
+                mark: x.mark + Microseconds(40),
+                space: x.space.map(|y| y - Microseconds(40)),
+            })
+            .collect::<Vec<MarkAndSpaceMicros>>();
+        let result = compare_signals(&registry, &a, &b, DEFAULT_COMPARE_TOLERANCE).unwrap();
+        assert_eq!(result.equivalence, Equivalence::SameFrames);
+        assert!(result.differences.is_empty());
+    }
+
+    #[test]
+    fn test_similar_timing() {
+        // 不明プロトコルは時間で比べる
+        let registry = ProtocolRegistry::builtin();
+        let ms = |mark: u32, space: u32| MarkAndSpaceMicros {
+            mark: Microseconds(mark),
+            space: Some(Microseconds(space)),
+        };
+        let a = vec![ms(3000, 3000), ms(500, 1500), ms(500, 500), ms(500, 40000)];
+        let b = vec![ms(3100, 2900), ms(450, 1550), ms(500, 500), ms(500, 30000)];
+        let result = compare_signals(&registry, &a, &b, DEFAULT_COMPARE_TOLERANCE).unwrap();
+        assert_eq!(result.equivalence, Equivalence::SimilarTiming);
+        assert_eq!(result.differences.len(), 1);
+        let c = vec![ms(3000, 3000), ms(500, 500), ms(500, 1500), ms(500, 40000)];
+        let result = compare_signals(&registry, &a, &c, DEFAULT_COMPARE_TOLERANCE).unwrap();
+        assert_eq!(result.equivalence, Equivalence::Different);
+    }
+
+    #[test]
+    fn test_same_command() {
+        let registry = ProtocolRegistry::builtin();
+        let repeat = InfraredRemoteDemodulatedFrame::Repeat("NEC".to_string());
+        let a = encode(&registry, &[nec(0x12), repeat.clone(), repeat]);
+        let b = encode(&registry, &[nec(0x12)]);
+        let result = compare_signals(&registry, &a, &b, DEFAULT_COMPARE_TOLERANCE).unwrap();
+        assert_eq!(result.equivalence, Equivalence::SameCommand);
+        assert!(result.to_string().contains("NEC Repeat ≠ (なし)"));
+        let c = encode(&registry, &[nec(0x13)]);
+        let result = compare_signals(&registry, &b, &c, DEFAULT_COMPARE_TOLERANCE).unwrap();
+        assert_eq!(result.equivalence, Equivalence::Different);
+    }
+
+    #[test]
+    fn test_toggle_bit() {
+        let mut registry = ProtocolRegistry::builtin();
+        registry
+            .load_json(
+                r#"{ "name": "TOGGLED", "time_base": 500, "tolerance": 200,
+                "leader": { "mark": 12, "space": 6 }, "modulation": "PulseDistance",
+                "bit_zero": { "mark": 1, "space": 1 }, "bit_one": { "mark": 1, "space": 3 },
+                "bit_order": "LsbFirst", "trailer": 1, "gap": 30000,
+                "fields": [ { "name": "toggle", "bits": 1 }, { "name": "command", "bits": 7 } ] }"#,
+            )
+            .unwrap();
+        let frame = |bits: [u8; 8]| InfraredRemoteDemodulatedFrame::Defined {
+            protocol: "TOGGLED".to_string(),
+            bits: bits.iter().map(|x| Bit(*x)).collect(),
+        };
+        let a = encode(&registry, &[frame([0, 1, 0, 1, 1, 0, 0, 0])]);
+        let b = encode(&registry, &[frame([1, 1, 0, 1, 1, 0, 0, 0])]);
+        let result = compare_signals(&registry, &a, &b, DEFAULT_COMPARE_TOLERANCE).unwrap();
+        assert_eq!(result.equivalence, Equivalence::SameCommand);
+    }
+}
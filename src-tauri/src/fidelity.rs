@@ -0,0 +1,231 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+use crate::infrared_remote::{MarkAndSpaceMicros, Microseconds};
+use crate::protocol::ProtocolRegistry;
+use crate::segmentation::segment_frames;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// パルスの種類
+pub enum PulseKind {
+    Mark,
+    Space,
+    /// フレーム最後のスペース(フレームの間隔)
+    Gap,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 1パルスの時間の差
+pub struct PulseDelta {
+    /// フレーム番号
+    pub frame: usize,
+    /// 元のフレーム内のマークアンドスペースの番号
+    pub index: usize,
+    /// パルスの種類
+    pub kind: PulseKind,
+    /// 元の時間
+    pub original: Microseconds,
+    /// 再エンコードした時間
+    pub encoded: Microseconds,
+    /// 差(再エンコード - 元)
+    pub delta: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 構造の違い
+pub enum StructuralDifference {
+    /// 再エンコードした信号にフレームが無い
+    MissingFrame { frame: usize },
+    /// 再エンコードした信号に余分なフレームがある
+    ExtraFrame { frame: usize },
+    /// 再エンコードしたフレームのマークアンドスペースが足りない
+    MissingPulses { frame: usize, count: usize },
+    /// 再エンコードしたフレームのマークアンドスペースが多い
+    ExtraPulses { frame: usize, count: usize },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// 元の信号と再エンコードした信号の比較結果
+pub struct FidelityReport {
+    /// 各パルスの時間の差
+    pub deltas: Vec<PulseDelta>,
+    /// マークとスペースの最大誤差(フレームの間隔を除く)
+    pub max_error: Microseconds,
+    /// マークとスペースの二乗平均平方根誤差(フレームの間隔を除く)
+    pub rms_error: f64,
+    /// フレームの間隔の最大誤差
+    pub max_gap_error: Microseconds,
+    /// 構造の違い
+    pub differences: Vec<StructuralDifference>,
+}
+
+impl FidelityReport {
+    /// 構造が同じで、マークとスペースの誤差がすべてtolerance以内か
+    pub fn is_within(&self, tolerance: Microseconds) -> bool {
+        self.differences.is_empty() && self.max_error <= tolerance
+    }
+}
+
+/// 時間の差
+fn delta(original: Microseconds, encoded: Microseconds) -> i64 {
+    encoded.0 as i64 - original.0 as i64
+}
+
+/// 元の信号と再エンコードした信号をフレームごと、パルスごとに並べて比べる
+pub fn round_trip_fidelity(
+    registry: &ProtocolRegistry,
+    original: &[MarkAndSpaceMicros],
+    encoded: &[MarkAndSpaceMicros],
+) -> Result<FidelityReport, String> {
+    let original = segment_frames(registry, original)?;
+    let encoded = segment_frames(registry, encoded)?;
+
+    let mut deltas = Vec::new();
+    let mut differences = Vec::new();
+    for (frame, (x, y)) in original.iter().zip(encoded.iter()).enumerate() {
+        // ビットは先頭から、最後のマークアンドスペース(フレームの間隔)は最後同士で比べる
+        let (xs, ys) = (&x.frame.0, &y.frame.0);
+        let n = xs.len().min(ys.len()).saturating_sub(1);
+        let pairs = xs
+            .iter()
+            .zip(ys.iter())
+            .take(n)
+            .enumerate()
+            .map(|(index, (a, b))| (index, PulseKind::Space, a, b))
+            .chain(
+                xs.last()
+                    .zip(ys.last())
+                    .map(|(a, b)| (xs.len() - 1, PulseKind::Gap, a, b)),
+            );
+        for (index, space_kind, a, b) in pairs {
+            for (kind, original, encoded) in [
+                (PulseKind::Mark, a.mark, b.mark),
+                (
+                    space_kind,
+                    a.space.unwrap_or_default(),
+                    b.space.unwrap_or_default(),
+                ),
+            ] {
+                deltas.push(PulseDelta {
+                    frame,
+                    index,
+                    kind,
+                    original,
+                    encoded,
+                    delta: delta(original, encoded),
+                });
+            }
+        }
+        if ys.len() < xs.len() {
+            differences.push(StructuralDifference::MissingPulses {
+                frame,
+                count: xs.len() - ys.len(),
+            });
+        } else if xs.len() < ys.len() {
+            differences.push(StructuralDifference::ExtraPulses {
+                frame,
+                count: ys.len() - xs.len(),
+            });
+        }
+    }
+    differences.extend(
+        (encoded.len()..original.len()).map(|frame| StructuralDifference::MissingFrame { frame }),
+    );
+    differences.extend(
+        (original.len()..encoded.len()).map(|frame| StructuralDifference::ExtraFrame { frame }),
+    );
+
+    let errors = deltas
+        .iter()
+        .filter(|x| x.kind != PulseKind::Gap)
+        .map(|x| x.delta.unsigned_abs())
+        .collect::<Vec<u64>>();
+    let max_error = errors.iter().copied().max().unwrap_or(0);
+    let rms_error = if errors.is_empty() {
+        0.0
+    } else {
+        let sum = errors.iter().map(|x| (*x as f64).powi(2)).sum::<f64>();
+        (sum / errors.len() as f64).sqrt()
+    };
+    let max_gap_error = deltas
+        .iter()
+        .filter(|x| x.kind == PulseKind::Gap)
+        .map(|x| x.delta.unsigned_abs())
+        .max()
+        .unwrap_or(0);
+    let micros = |x: u64| Microseconds(u32::try_from(x).unwrap_or(u32::MAX));
+    Ok(FidelityReport {
+        deltas,
+        max_error: micros(max_error),
+        rms_error,
+        max_gap_error: micros(max_gap_error),
+        differences,
+    })
+}
+
+#[cfg(test)]
+mod fidelity_tests {
+    use crate::fidelity::{round_trip_fidelity, PulseKind, StructuralDifference};
+    use crate::infrared_remote::{
+        decode_phase1, decode_phase2, encode_to_mark_and_spaces, InfraredRemoteDemodulatedFrame,
+        MarkAndSpaceMicros, Microseconds,
+    };
+    use crate::parsing::from_infrared_code;
+    use crate::protocol::ProtocolRegistry;
+
+    fn round_trip(ircode: &str) -> (Vec<MarkAndSpaceMicros>, Vec<MarkAndSpaceMicros>) {
+        let original = from_infrared_code(ircode)
+            .unwrap()
+            .iter()
+            .map(|x| MarkAndSpaceMicros::from(*x))
+            .collect::<Vec<MarkAndSpaceMicros>>();
+        let decoded = decode_phase1(&original)
+            .unwrap()
+            .iter()
+            .map(decode_phase2)
+            .collect::<Vec<InfraredRemoteDemodulatedFrame>>();
+        let encoded = encode_to_mark_and_spaces(&decoded).unwrap();
+        (original, encoded)
+    }
+
+    #[test]
+    fn test_round_trip_fidelity() {
+        let ircode = "5601A900180015001800140018001400190013001900140019001400170040001700150018003F0019003E0018003E0019003F0019003E00170040001800140019003E001800150018003F00180014001800140019003F0018001400170016001700150018003F001800140018003F0018003F001800140019003F0018003F0018003E0019004F03";
+        let (original, encoded) = round_trip(ircode);
+        let registry = ProtocolRegistry::builtin();
+        let report = round_trip_fidelity(&registry, &original, &encoded).unwrap();
+        assert!(report.differences.is_empty());
+        assert_eq!(report.deltas.len(), original.len() * 2);
+        assert!(report.is_within(Microseconds(150)));
+        assert!(0.0 < report.rms_error);
+        assert!(report.rms_error <= report.max_error.0 as f64);
+        // 最後のスペースはフレームの間隔
+        assert_eq!(report.deltas.last().unwrap().kind, PulseKind::Gap);
+    }
+
+    #[test]
+    fn test_structural_differences() {
+        let registry = ProtocolRegistry::builtin();
+        let ms = |mark: u32, space: u32| MarkAndSpaceMicros {
+            mark: Microseconds(mark),
+            space: Some(Microseconds(space)),
+        };
+        let frame = vec![ms(9000, 4500), ms(560, 560), ms(560, 1690), ms(560, 40000)];
+        let original = [frame.clone(), frame.clone()].concat();
+        // 2番目のフレームが無く、1番目のフレームのビットが多い
+        let encoded = [&frame[..3], &[ms(600, 560)], &frame[3..]].concat();
+        let report = round_trip_fidelity(&registry, &original, &encoded).unwrap();
+        assert_eq!(
+            report.differences,
+            vec!(
+                StructuralDifference::ExtraPulses { frame: 0, count: 1 },
+                StructuralDifference::MissingFrame { frame: 1 },
+            )
+        );
+        assert!(!report.is_within(Microseconds(1000)));
+        assert_eq!(report.max_error, Microseconds(0));
+        assert_eq!(report.max_gap_error, Microseconds(0));
+    }
+}
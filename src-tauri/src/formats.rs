@@ -0,0 +1,495 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+//! 貼り付けた赤外線リモコン信号の書式を見分けて読み込む
+use crate::infrared_remote::{MarkAndSpaceMicros, Microseconds};
+use crate::parsing::{from_array, from_infrared_code};
+use crate::signal::Signal;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Clone, Copy)]
+/// 赤外線リモコン信号の書式
+pub struct SignalFormat {
+    /// 書式の名前
+    pub name: &'static str,
+    /// 読み込む関数(読めなければ理由を返す)
+    pub parse: fn(&str) -> Result<Signal, String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 書式を見分けて読み込んだ信号
+pub struct DetectedSignal {
+    /// 書式の名前
+    pub format: String,
+    pub signal: Vec<MarkAndSpaceMicros>,
+    /// キャリア周波数(Hz) 書式に無ければ IR_CARRIER_FREQ
+    pub carrier_frequency: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// その書式で読めなかった理由
+pub struct FormatDiagnostic {
+    pub format: String,
+    pub reason: String,
+}
+
+impl fmt::Display for FormatDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.format, self.reason)
+    }
+}
+
+/// 書式の一覧
+/// 前から順番に試して、最初に読めた書式にする
+#[derive(Clone)]
+pub struct SignalFormatRegistry(Vec<SignalFormat>);
+
+impl SignalFormatRegistry {
+    /// 組み込みの書式
+    /// 0-9A-F だけで書けるものは、形の決まっているものから試す
+    pub fn builtin() -> Self {
+        SignalFormatRegistry(vec![
+            SignalFormat {
+                name: "json",
+                parse: parse_json,
+            },
+            SignalFormat {
+                name: "pronto",
+                parse: parse_pronto,
+            },
+            SignalFormat {
+                name: "sendir",
+                parse: parse_sendir,
+            },
+            SignalFormat {
+                name: "mode2",
+                parse: parse_mode2,
+            },
+            SignalFormat {
+                name: "hex-counter",
+                parse: parse_hex_counter,
+            },
+            SignalFormat {
+                name: "microseconds",
+                parse: parse_microseconds,
+            },
+            SignalFormat {
+                name: "broadlink",
+                parse: parse_broadlink,
+            },
+        ])
+    }
+
+    /// 書式の名前の一覧
+    pub fn names(&self) -> Vec<&'static str> {
+        self.0.iter().map(|x| x.name).collect()
+    }
+
+    /// 書式を追加する
+    /// 同じ名前の書式があれば置き換える
+    pub fn insert(&mut self, format: SignalFormat) {
+        match self.0.iter_mut().find(|x| x.name == format.name) {
+            Some(x) => *x = format,
+            None => self.0.push(format),
+        }
+    }
+
+    /// 書式を見分けて読み込む
+    /// どの書式でも読めなければ、書式ごとの理由を返す
+    pub fn detect(&self, input: &str) -> Result<DetectedSignal, Vec<FormatDiagnostic>> {
+        let mut diagnostics = Vec::new();
+        for format in self.0.iter() {
+            match parse_with(format, input) {
+                Ok(x) => return Ok(x),
+                Err(reason) => diagnostics.push(FormatDiagnostic {
+                    format: format.name.to_string(),
+                    reason,
+                }),
+            }
+        }
+        Err(diagnostics)
+    }
+
+    /// 書式を指定して読み込む
+    pub fn parse(&self, name: &str, input: &str) -> Result<DetectedSignal, String> {
+        let format = self
+            .0
+            .iter()
+            .find(|x| x.name == name)
+            .ok_or_else(|| format!("{} の書式はありません。", name))?;
+        parse_with(format, input).map_err(|e| format!("{}: {}", name, e))
+    }
+}
+
+fn parse_with(format: &SignalFormat, input: &str) -> Result<DetectedSignal, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("入力がありません。".to_string());
+    }
+    let signal = (format.parse)(input)?;
+    let mark_and_spaces = signal.to_mark_and_spaces();
+    if mark_and_spaces.is_empty() {
+        return Err("マークがありません。".to_string());
+    }
+    Ok(DetectedSignal {
+        format: format.name.to_string(),
+        signal: mark_and_spaces,
+        carrier_frequency: signal.carrier_frequency,
+    })
+}
+
+/// マークから始まる時間列(キャリア周波数つき)
+fn signal_from(durations: Vec<Microseconds>, carrier_frequency: Option<u32>) -> Signal {
+    let mut signal = Signal::new(durations);
+    if let Some(x) = carrier_frequency {
+        signal.carrier_frequency = x;
+    }
+    signal
+}
+
+/// 符号の無い整数
+fn unsigned(word: &str) -> Result<u32, String> {
+    word.parse::<u32>()
+        .map_err(|_| format!("{} は数字ではありません。", word))
+}
+
+/// 0-9A-F の4桁
+fn hex_word(word: &str) -> Result<u32, String> {
+    if word.len() == 4 {
+        u32::from_str_radix(word, 16).map_err(|_| format!("{} は16進数ではありません。", word))
+    } else {
+        Err(format!("{} は4桁の16進数ではありません。", word))
+    }
+}
+
+/// [{"mark": 9000, "space": 4500}, ...] か [9000, 4500, ...]
+fn parse_json(input: &str) -> Result<Signal, String> {
+    if !input.starts_with('[') {
+        return Err("[ で始まっていません。".to_string());
+    }
+    if let Ok(xs) = serde_json::from_str::<Vec<MarkAndSpaceMicros>>(input) {
+        return Ok(Signal::from_mark_and_spaces(&xs));
+    }
+    serde_json::from_str::<Vec<u32>>(input)
+        .map(|xs| signal_from(xs.into_iter().map(Microseconds).collect(), None))
+        .map_err(|e| format!("マークアンドスペースか数字の配列ではありません。({})", e))
+}
+
+/// Pronto Hex "0000 006D 0022 0002 0157 00AC ..."
+/// 周波数の語 x 0.241246us がキャリアの周期で、時間はその数
+/// 1回だけ送る部分と繰り返す部分を続ける
+fn parse_pronto(input: &str) -> Result<Signal, String> {
+    let words = input
+        .split_whitespace()
+        .map(hex_word)
+        .collect::<Result<Vec<u32>, String>>()?;
+    if words.len() < 4 {
+        return Err("4語より短いです。".to_string());
+    }
+    if words[0] != 0x0000 {
+        return Err(format!(
+            "{:04X} は変調した信号(0000)ではありません。",
+            words[0]
+        ));
+    }
+    let frequency = words[1];
+    if frequency == 0 {
+        return Err("周波数の語が0です。".to_string());
+    }
+    let pairs = (words[2] + words[3]) as usize;
+    if words.len() != 4 + 2 * pairs {
+        return Err(format!(
+            "{} 組のマークアンドスペースには {} 語必要ですが {} 語です。",
+            pairs,
+            4 + 2 * pairs,
+            words.len()
+        ));
+    }
+    let period = frequency as u64 * 241_246;
+    let durations = words[4..]
+        .iter()
+        .map(|x| {
+            let y = (*x as u64 * period + 500_000) / 1_000_000;
+            Microseconds(u32::try_from(y).unwrap_or(u32::MAX))
+        })
+        .collect();
+    let carrier_frequency = (1_000_000_000_000 + period / 2) / period;
+    Ok(signal_from(
+        durations,
+        u32::try_from(carrier_frequency).ok(),
+    ))
+}
+
+/// Global Caché "sendir,1:1,1,38000,1,1,342,171,21,21,..."
+/// 時間はキャリアの周期の数
+fn parse_sendir(input: &str) -> Result<Signal, String> {
+    let fields = input.split(',').map(|x| x.trim()).collect::<Vec<&str>>();
+    if !fields[0].eq_ignore_ascii_case("sendir") {
+        return Err("sendir で始まっていません。".to_string());
+    }
+    if fields.len() < 8 {
+        return Err("sendir,<モジュール:コネクタ>,<ID>,<周波数>,<回数>,<オフセット>,<時間>... の形ではありません。".to_string());
+    }
+    let frequency = unsigned(fields[3])?;
+    if !(15_000..=500_000).contains(&frequency) {
+        return Err(format!("周波数 {}Hz が範囲外です。", frequency));
+    }
+    let counts = fields[6..]
+        .iter()
+        .map(|x| unsigned(x))
+        .collect::<Result<Vec<u32>, String>>()?;
+    if counts.len() % 2 != 0 {
+        return Err("時間の数が奇数です。".to_string());
+    }
+    let durations = counts
+        .iter()
+        .map(|x| {
+            let y = (*x as u64 * 1_000_000 + frequency as u64 / 2) / frequency as u64;
+            Microseconds(u32::try_from(y).unwrap_or(u32::MAX))
+        })
+        .collect();
+    Ok(signal_from(durations, Some(frequency)))
+}
+
+/// LIRC の mode2 コマンドの出力 "pulse 9000" "space 4500" "timeout 30000"
+/// 最初のマークより前のスペースは無視して、続けて同じ種類が来たら足す
+fn parse_mode2(input: &str) -> Result<Signal, String> {
+    let mut durations: Vec<Microseconds> = Vec::new();
+    for line in input.lines().map(|x| x.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let mark = match words.next() {
+            Some("pulse") => true,
+            Some("space") | Some("timeout") => false,
+            _ => {
+                return Err(format!(
+                    "{} は pulse, space, timeout の行ではありません。",
+                    line
+                ))
+            }
+        };
+        let duration = match (words.next(), words.next()) {
+            (Some(x), None) => Microseconds(unsigned(x)?),
+            _ => return Err(format!("{} の時間が読めません。", line)),
+        };
+        // マークは偶数番目、スペースは奇数番目
+        match (durations.len() % 2 == 1, mark) {
+            (_, false) if durations.is_empty() => {}
+            (true, true) | (false, false) => {
+                if let Some(last) = durations.last_mut() {
+                    *last = *last + duration;
+                }
+            }
+            _ => durations.push(duration),
+        }
+    }
+    if durations.is_empty() {
+        return Err("pulse の行がありません。".to_string());
+    }
+    Ok(signal_from(durations, None))
+}
+
+/// マイクロ秒の配列 "{9000, 4500, 560}" "9000 4500 560" "+9000 -4500 +560"
+/// 符号をつけるならマーク(+)とスペース(-)を交互に書く
+fn parse_microseconds(input: &str) -> Result<Signal, String> {
+    let xs = from_array(input)?;
+    Ok(Signal::from_mark_and_spaces(&xs))
+}
+
+/// 赤外線リモコンコード(キャリア周波数カウンタの16進数)
+fn parse_hex_counter(input: &str) -> Result<Signal, String> {
+    if let Some(c) = input
+        .chars()
+        .find(|c| !c.is_ascii_hexdigit() && !c.is_whitespace())
+    {
+        return Err(format!("16進数ではない文字 {} があります。", c));
+    }
+    if let Some(word) = input.split_whitespace().find(|x| x.len() % 8 != 0) {
+        return Err(format!(
+            "{} が マーク4桁とスペース4桁の組になっていません。",
+            word
+        ));
+    }
+    let xs = from_infrared_code(input)?;
+    Ok(Signal::from_mark_and_spaces(
+        &xs.iter()
+            .map(|x| MarkAndSpaceMicros::from(*x))
+            .collect::<Vec<MarkAndSpaceMicros>>(),
+    ))
+}
+
+/// Base64
+fn decode_base64(input: &str) -> Result<Vec<u8>, String> {
+    let mut output = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in input
+        .trim_end_matches('=')
+        .chars()
+        .filter(|c| !c.is_whitespace())
+    {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' | '-' => 62,
+            '/' | '_' => 63,
+            _ => return Err(format!("Base64 ではない文字 {} があります。", c)),
+        };
+        buffer = (buffer << 6) | value;
+        bits += 6;
+        if 8 <= bits {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Ok(output)
+}
+
+/// Broadlink の学習コード(Base64)
+/// 0x26 (赤外線), 繰り返し回数, データの長さ(リトルエンディアン16ビット), データ
+/// データの時間は 8192/269 us 単位の1バイト、0x00 に続くときはビッグエンディアン16ビット
+/// 0x0d 0x05 で終わる
+fn parse_broadlink(input: &str) -> Result<Signal, String> {
+    let bytes = decode_base64(input)?;
+    if bytes.len() < 4 {
+        return Err("4バイトより短いです。".to_string());
+    }
+    if bytes[0] != 0x26 {
+        return Err(format!(
+            "先頭 {:02X} が赤外線の信号(26)ではありません。",
+            bytes[0]
+        ));
+    }
+    let length = bytes[2] as usize | (bytes[3] as usize) << 8;
+    let data = bytes
+        .get(4..4 + length)
+        .ok_or_else(|| format!("データの長さ {} に足りません。", length))?;
+    let mut durations = Vec::new();
+    let mut index = 0;
+    while index < data.len() {
+        let value = match data[index] {
+            0x0d if data.get(index + 1) == Some(&0x05) => break,
+            0x00 => {
+                let x = data
+                    .get(index + 1..index + 3)
+                    .ok_or_else(|| "データが途中で終わっています。".to_string())?;
+                index += 3;
+                (x[0] as u32) << 8 | x[1] as u32
+            }
+            x => {
+                index += 1;
+                x as u32
+            }
+        };
+        durations.push(Microseconds((value * 8192 + 134) / 269));
+    }
+    Ok(signal_from(durations, None))
+}
+
+#[cfg(test)]
+mod formats_tests {
+    use crate::formats::SignalFormatRegistry;
+    use crate::infrared_remote::{MarkAndSpaceMicros, Microseconds, IR_CARRIER_FREQ};
+
+    fn mark_and_spaces(xs: &[u32]) -> Vec<MarkAndSpaceMicros> {
+        xs.chunks(2)
+            .map(|x| match x {
+                [m, s] => MarkAndSpaceMicros {
+                    mark: Microseconds(*m),
+                    space: Some(Microseconds(*s)),
+                },
+                [m] => MarkAndSpaceMicros::mark_only(Microseconds(*m)),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    fn detect(input: &str) -> (String, Vec<MarkAndSpaceMicros>, u32) {
+        let x = SignalFormatRegistry::builtin().detect(input).unwrap();
+        (x.format, x.signal, x.carrier_frequency)
+    }
+
+    #[test]
+    fn test_detect() {
+        let expected = mark_and_spaces(&[9000, 4500, 560]);
+        for input in ["{9000, 4500, 560}", "9000 4500 560", "+9000 -4500 +560"] {
+            assert_eq!(
+                detect(input),
+                (
+                    "microseconds".to_string(),
+                    expected.clone(),
+                    IR_CARRIER_FREQ as u32
+                )
+            );
+        }
+        for input in [
+            r#"[{"mark": 9000, "space": 4500}, {"mark": 560}]"#,
+            r#"[{"mark": 9000, "space": 4500}, {"mark": 560, "space": null}]"#,
+            "[9000, 4500, 560]",
+        ] {
+            assert_eq!(detect(input).0, "json");
+            assert_eq!(detect(input).1, expected);
+        }
+        assert_eq!(
+            detect("space 16777215\npulse 9000\nspace 4500\npulse 300\npulse 260\ntimeout 30000"),
+            (
+                "mode2".to_string(),
+                mark_and_spaces(&[9000, 4500, 560, 30000]),
+                IR_CARRIER_FREQ as u32
+            )
+        );
+        assert_eq!(detect("5601AA0017001500").0, "hex-counter");
+    }
+
+    #[test]
+    fn test_carrier_formats() {
+        assert_eq!(
+            detect("0000 006D 0002 0001 0157 00AC 0015 0016 0015 05D6"),
+            (
+                "pronto".to_string(),
+                mark_and_spaces(&[9019, 4523, 552, 579, 552, 39286]),
+                38029
+            )
+        );
+        assert_eq!(
+            detect("sendir,1:1,1,38000,1,1,342,171,21,1521"),
+            (
+                "sendir".to_string(),
+                mark_and_spaces(&[9000, 4500, 553, 40026]),
+                38000
+            )
+        );
+        assert_eq!(
+            detect("JgALAAABKJQSEhI3Eg0FAA=="),
+            (
+                "broadlink".to_string(),
+                mark_and_spaces(&[9014, 4507, 548, 548, 548, 1675, 548]),
+                IR_CARRIER_FREQ as u32
+            )
+        );
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let registry = SignalFormatRegistry::builtin();
+        let diagnostics = registry.detect("hello, world").unwrap_err();
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|x| x.format.as_str())
+                .collect::<Vec<&str>>(),
+            registry.names()
+        );
+        assert!(diagnostics.iter().all(|x| !x.reason.is_empty()));
+        // 語数の合わない Pronto
+        let pronto = registry.parse("pronto", "0000 006D 0002 0000 0157 00AC");
+        assert!(pronto.unwrap_err().starts_with("pronto: "));
+        assert!(registry.parse("unknown", "").is_err());
+    }
+}
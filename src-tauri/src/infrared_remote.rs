@@ -1,141 +1,34 @@
+use crate::bitvec::BitVec;
+pub use crate::ir_core::{
+    Bit, MarkAndSpace, MarkAndSpaceMicros, Microseconds, THRESHOLD_FRAME_GAP,
+};
+use crate::protocol::{BitOrder, ProtocolRegistry};
+use crate::segmentation::segment_frames;
 use serde::{Deserialize, Serialize};
 use std::convert;
 use std::fmt;
 use std::iter;
 use std::ops;
-use std::ops::Range;
-
-/// 家製協プロトコルの定義
-mod protocol_aeha {
-    use crate::infrared_remote::{MarkAndSpace, MarkAndSpaceMicros, Microseconds};
-    /// 基準時間 350us ～ 500us typical 425. T = 440 μ秒(実測)
-    pub const TIME_BASE: Microseconds = Microseconds(440);
-
-    /// リーダーパルス
-    /// H-level width, 8 * T(425us) = typical 3400us
-    /// L-level width, 4 * T(425us) = typical 1700us
-    pub const LEADER: MarkAndSpaceMicros = MarkAndSpace {
-        mark: Microseconds(8 * TIME_BASE.0),
-        space: Microseconds(4 * TIME_BASE.0),
-    };
-
-    /// 0を意味する信号
-    /// H-level width, 1 * T(425us) = typical 425us
-    /// L-level width, 1 * T(425us) = typical 425us
-    pub const TYPICAL_BIT_ZERO: MarkAndSpaceMicros = MarkAndSpace {
-        mark: TIME_BASE,
-        space: TIME_BASE,
-    };
-
-    /// 1を意味する信号
-    /// H-level width, 1 * T(425us) = typical 425us
-    /// L-level width, 3 * T(425us) = typical 1275us
-    pub const TYPICAL_BIT_ONE: MarkAndSpaceMicros = MarkAndSpace {
-        mark: TIME_BASE,
-        space: Microseconds(3 * TIME_BASE.0),
-    };
-}
-
-/// NECプロトコルの定義
-mod protocol_nec {
-    use crate::infrared_remote::{MarkAndSpace, MarkAndSpaceMicros, Microseconds};
-    /// 基準時間 T = 562 μ秒
-    pub const TIME_BASE: Microseconds = Microseconds(562);
-
-    /// リーダーパルス
-    /// H-level width, 16 * T(562us) = typical 8992us
-    /// L-level width, 8 * T(562us) = typical 4496us
-    pub const LEADER: MarkAndSpaceMicros = MarkAndSpace {
-        mark: Microseconds(16 * TIME_BASE.0),
-        space: Microseconds(8 * TIME_BASE.0),
-    };
-
-    /// 0を意味する信号
-    /// H-level width, 1 * T(562us) = typical 562us
-    /// L-level width, 1 * T(562us) = typical 562us
-    pub const TYPICAL_BIT_ZERO: MarkAndSpaceMicros = MarkAndSpace {
-        mark: TIME_BASE,
-        space: TIME_BASE,
-    };
-
-    /// 1を意味する信号
-    /// H-level width, 1 * T(562us) = typical 562us
-    /// L-level width, 3 * T(562us) = typical 1686us
-    pub const TYPICAL_BIT_ONE: MarkAndSpaceMicros = MarkAndSpace {
-        mark: TIME_BASE,
-        space: Microseconds(3 * TIME_BASE.0),
-    };
-}
-
-/// SIRCプロトコルの定義
-mod protocol_sirc {
-    use crate::infrared_remote::{MarkAndSpace, MarkAndSpaceMicros, Microseconds};
-    /// 基準時間 T = 600 μ秒
-    pub const TIME_BASE: Microseconds = Microseconds(600);
-
-    /// リーダーパルス
-    /// H-level width, 4 * T(600us) = typical 2400us
-    /// L-level width, 1 * T(600us) = typical 600us
-    pub const LEADER: MarkAndSpaceMicros = MarkAndSpace {
-        mark: Microseconds(4 * TIME_BASE.0),
-        space: Microseconds(1 * TIME_BASE.0),
-    };
-
-    /// 0を意味する信号
-    /// H-level width, 1 * T(600us) = typical 600us
-    /// L-level width, 1 * T(600us) = typical 600us
-    pub const TYPICAL_BIT_ZERO: MarkAndSpaceMicros = MarkAndSpace {
-        mark: TIME_BASE,
-        space: TIME_BASE,
-    };
-
-    /// 1を意味する信号
-    /// H-level width, 2 * T(600us) = typical 1200us
-    /// L-level width, 1 * T(600us) = typical 600us
-    pub const TYPICAL_BIT_ONE: MarkAndSpaceMicros = MarkAndSpace {
-        mark: Microseconds(2 * TIME_BASE.0),
-        space: TIME_BASE,
-    };
-}
-
-/// ずれ時間の許容範囲はとりあえず 300us
-const TOLERANCE: Microseconds = Microseconds(300);
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-/// マイクロ秒型
-pub struct Microseconds(pub u32);
+/// 赤外線リモコン信号のキャリア周波数カウンタ型
+/// 送信形式では1つのマーク(スペース)を16ビットで表すので、
+/// それを超える時間は MarkAndSpaceIrCarrier::to_string_littel_endian_u16 で分割する
+pub struct IrCarrierCounter(pub u32);
 
-impl ops::Add for Microseconds {
-    type Output = Microseconds;
-    /// マイクロ秒型の加算演算子
-    fn add(self, other: Self) -> Self::Output {
-        Microseconds(self.0 + other.0)
-    }
-}
+/// 送信形式の1つのマーク(スペース)で表せる最大カウント
+/// (38kHzで約1.7秒)
+pub const IR_CARRIER_COUNTER_MAX: u32 = 0xfffe;
 
-impl ops::Sub for Microseconds {
-    type Output = Microseconds;
-    /// マイクロ秒型の減算演算子
-    fn sub(self, other: Self) -> Self::Output {
-        Microseconds(self.0 - other.0)
-    }
-}
-
-impl Default for Microseconds {
-    /// なにも指定されなかったら場合にとりあえず入れる値
-    fn default() -> Self {
-        Self(30000)
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-/// 赤外線リモコン信号のキャリア周波数カウンタ型
-pub struct IrCarrierCounter(pub u16);
+/// 送信形式で「次のマークアンドスペースに続く」ことを表す語
+/// 時間としては使わない
+pub const IR_CARRIER_COUNTER_CONTINUE: u32 = 0xffff;
 
 impl IrCarrierCounter {
     /// 16ビットリトルエンディアンで出力する
+    /// IR_CARRIER_COUNTER_MAX を超える値は IR_CARRIER_COUNTER_MAX にする
     /// ```
-    /// use infrared_remote::infrared_remote::IrCarrierCounter;
+    /// use app_lib::infrared_remote::IrCarrierCounter;
     /// assert_eq!(IrCarrierCounter(0x1234).to_string_littel_endian_u16(), "3412");
     /// assert_eq!(IrCarrierCounter(0xabcd).to_string_littel_endian_u16(), "CDAB");
     /// assert_eq!(IrCarrierCounter(0xf0a0).to_string_littel_endian_u16(), "A0F0");
@@ -144,8 +37,9 @@ impl IrCarrierCounter {
     /// assert_eq!(IrCarrierCounter(0x00ff).to_string_littel_endian_u16(), "FF00");
     /// ```
     pub fn to_string_littel_endian_u16(self) -> String {
-        let upper = (self.0 >> 8) & 0xff;
-        let lower = self.0 & 0xff;
+        let x = self.0.min(IR_CARRIER_COUNTER_MAX);
+        let upper = (x >> 8) & 0xff;
+        let lower = x & 0xff;
         format!("{lower:02X}{upper:02X}")
     }
 }
@@ -153,16 +47,18 @@ impl IrCarrierCounter {
 impl ops::Add for IrCarrierCounter {
     type Output = IrCarrierCounter;
     /// 赤外線リモコン信号のキャリア周波数カウンタ型の加算演算子
+    /// 桁あふれしたら最大値にする
     fn add(self, other: Self) -> Self::Output {
-        IrCarrierCounter(self.0 + other.0)
+        IrCarrierCounter(self.0.saturating_add(other.0))
     }
 }
 
 impl ops::Sub for IrCarrierCounter {
     type Output = IrCarrierCounter;
     /// 赤外線リモコン信号のキャリア周波数カウンタ型の減算演算子
+    /// 負になるなら0にする
     fn sub(self, other: Self) -> Self::Output {
-        IrCarrierCounter(self.0 - other.0)
+        IrCarrierCounter(self.0.saturating_sub(other.0))
     }
 }
 
@@ -172,13 +68,6 @@ impl fmt::Display for IrCarrierCounter {
     }
 }
 
-impl Default for IrCarrierCounter {
-    /// なにも指定されなかったら場合にとりあえず入れる値
-    fn default() -> Self {
-        Self(0x2c88)
-    }
-}
-
 /// 赤外線リモコン信号のキャリア周波数
 /// 38000 Hz = 38 kHz
 pub const IR_CARRIER_FREQ: u16 = 38000;
@@ -190,7 +79,7 @@ impl convert::From<IrCarrierCounter> for Microseconds {
         // 1000倍してミリ秒に
         // さらに1000倍してマイクロ秒にする
         let y = 1_000_000u64 * x.0 as u64 / IR_CARRIER_FREQ as u64;
-        Self(y as u32)
+        Self(u32::try_from(y).unwrap_or(u32::MAX))
     }
 }
 
@@ -200,7 +89,7 @@ impl convert::From<Microseconds> for IrCarrierCounter {
         // 1 秒が IR_CARRIER_FREQ カウントなので
         // 1マイクロ秒 が IrCarrirFreq/(1000*1000) カウント
         let y = x.0 as u64 * IR_CARRIER_FREQ as u64 / 1_000_000u64;
-        Self(y as u16)
+        Self(y as u32)
     }
 }
 
@@ -209,7 +98,7 @@ fn test_microseconds_to_ircarriercounter() {
     assert_eq!(
         MarkAndSpaceIrCarrier::from(MarkAndSpaceMicros {
             mark: Microseconds(9000),
-            space: Microseconds(4500),
+            space: Some(Microseconds(4500)),
         }),
         MarkAndSpaceIrCarrier {
             mark: IrCarrierCounter(0x0156),
@@ -255,7 +144,7 @@ fn test_ircarriercounter_to_microseconds() {
         }),
         MarkAndSpaceMicros {
             mark: Microseconds(9000),
-            space: Microseconds(4500),
+            space: Some(Microseconds(4500)),
         },
     );
     assert_eq!(
@@ -284,63 +173,125 @@ fn test_ircarriercounter_to_microseconds() {
     );
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-/// マークアンドスペース型
-pub struct MarkAndSpace<T> {
-    pub mark: T,
-    pub space: T,
-}
-
-impl<T> convert::From<MarkAndSpace<T>> for (T, T) {
-    /// マークアンドスペース型からタプル型へ変換する
-    fn from(x: MarkAndSpace<T>) -> Self {
-        (x.mark, x.space)
-    }
-}
-
-impl<T> convert::From<(T, T)> for MarkAndSpace<T> {
-    /// タプル型からマークアンドスペース型へ変換する
-    fn from((a, b): (T, T)) -> Self {
-        MarkAndSpace { mark: a, space: b }
-    }
-}
-
-impl<T: ops::Add<Output = T>> ops::Add for MarkAndSpace<T> {
-    type Output = MarkAndSpace<T>;
-    /// マークアンドスペースの加算演算子
-    fn add(self, other: Self) -> Self::Output {
-        Self::Output {
-            mark: self.mark + other.mark,
-            space: self.space + other.space,
-        }
-    }
+#[test]
+fn test_saturating_arithmetic() {
+    assert_eq!(Microseconds(300) - Microseconds(9000), Microseconds(0));
+    assert_eq!(
+        Microseconds(u32::MAX) + Microseconds(1),
+        Microseconds(u32::MAX)
+    );
+    assert_eq!(
+        IrCarrierCounter(u32::MAX) + IrCarrierCounter(1),
+        IrCarrierCounter(u32::MAX)
+    );
+    assert_eq!(
+        IrCarrierCounter(0) - IrCarrierCounter(1),
+        IrCarrierCounter(0)
+    );
+    // 16ビットに収まらない時間
+    assert_eq!(
+        IrCarrierCounter::from(Microseconds(2_000_000)),
+        IrCarrierCounter(76000)
+    );
 }
 
-impl<T: ops::Sub<Output = T>> ops::Sub for MarkAndSpace<T> {
-    type Output = MarkAndSpace<T>;
-    /// マークアンドスペースの減算演算子
-    fn sub(self, other: Self) -> Self::Output {
-        Self::Output {
-            mark: self.mark - other.mark,
-            space: self.space - other.space,
-        }
-    }
+#[test]
+fn test_long_duration() {
+    // 2秒(76000カウント)のスペース
+    let long_space = MarkAndSpaceIrCarrier {
+        mark: IrCarrierCounter(0x0156),
+        space: IrCarrierCounter(76000),
+    };
+    assert_eq!(long_space.to_string_littel_endian_u16(), "5601FEFFFFFFE228");
+    // 2秒のマーク
+    let long_mark = MarkAndSpaceIrCarrier {
+        mark: IrCarrierCounter(76000),
+        space: IrCarrierCounter(0x00AB),
+    };
+    assert_eq!(long_mark.to_string_littel_endian_u16(), "FEFFFFFFE228AB00");
+    //
+    let split = [
+        (IrCarrierCounter(0x0156), IrCarrierCounter(0xfffe)).into(),
+        (IrCarrierCounter(0xffff), IrCarrierCounter(0x28e2)).into(),
+        (IrCarrierCounter(0xfffe), IrCarrierCounter(0xffff)).into(),
+        (IrCarrierCounter(0x28e2), IrCarrierCounter(0x00AB)).into(),
+    ];
+    assert_eq!(
+        MarkAndSpaceIrCarrier::join(&split),
+        vec!(long_space, long_mark)
+    );
+    // 途中のスペース0やマーク0は続きではない
+    let zeros = [
+        (IrCarrierCounter(0x0156), IrCarrierCounter(0)).into(),
+        (IrCarrierCounter(0), IrCarrierCounter(0x00AB)).into(),
+    ];
+    assert_eq!(MarkAndSpaceIrCarrier::join(&zeros), zeros.to_vec());
 }
 
-/// マークアンドスペース(マイクロ秒ベース)
-pub type MarkAndSpaceMicros = MarkAndSpace<Microseconds>;
-
 /// マークアンドスペース(キャリア周波数カウンタ型ベース)
 pub type MarkAndSpaceIrCarrier = MarkAndSpace<IrCarrierCounter>;
 
 impl MarkAndSpaceIrCarrier {
     /// 16ビットリトルエンディアンで出力する
+    /// 16ビットに収まらない時間は複数のマークアンドスペースに分ける
+    /// * 長いマークは スペースを IR_CARRIER_COUNTER_CONTINUE にして、次のマークに続ける
+    /// * 長いスペースは マークを IR_CARRIER_COUNTER_CONTINUE にしたペアで、直前のスペースに続ける
     pub fn to_string_littel_endian_u16(&self) -> String {
-        format!(
-            "{}{}",
-            IrCarrierCounter::from(self.mark).to_string_littel_endian_u16(),
-            IrCarrierCounter::from(self.space).to_string_littel_endian_u16()
-        )
+        let word = |x: u32| match x {
+            IR_CARRIER_COUNTER_CONTINUE => "FFFF".to_string(),
+            x => IrCarrierCounter(x).to_string_littel_endian_u16(),
+        };
+        let pair = |mark: u32, space: u32| format!("{}{}", word(mark), word(space));
+        let mut output = String::new();
+        let mut mark = self.mark.0;
+        while IR_CARRIER_COUNTER_MAX < mark {
+            output.push_str(&pair(IR_CARRIER_COUNTER_MAX, IR_CARRIER_COUNTER_CONTINUE));
+            mark -= IR_CARRIER_COUNTER_MAX;
+        }
+        let mut space = self.space.0;
+        let first = space.min(IR_CARRIER_COUNTER_MAX);
+        output.push_str(&pair(mark, first));
+        space -= first;
+        while 0 < space {
+            let x = space.min(IR_CARRIER_COUNTER_MAX);
+            output.push_str(&pair(IR_CARRIER_COUNTER_CONTINUE, x));
+            space -= x;
+        }
+        output
+    }
+
+    /// 16ビットに分けたマークアンドスペースをつなぎ合わせる
+    /// (to_string_littel_endian_u16 の逆)
+    /// * スペースが IR_CARRIER_COUNTER_CONTINUE なら、次のマークアンドスペースはマークの続き
+    /// * マークが IR_CARRIER_COUNTER_CONTINUE なら、直前のスペースの続き
+    /// * 続ける相手が無い IR_CARRIER_COUNTER_CONTINUE は0にする
+    pub fn join(input: &[MarkAndSpaceIrCarrier]) -> Vec<MarkAndSpaceIrCarrier> {
+        let is_continue = |x: IrCarrierCounter| x.0 == IR_CARRIER_COUNTER_CONTINUE;
+        let mut output: Vec<MarkAndSpaceIrCarrier> = Vec::new();
+        for x in input {
+            match output.last_mut() {
+                Some(last) if is_continue(last.space) => {
+                    if !is_continue(x.mark) {
+                        last.mark = last.mark + x.mark;
+                    }
+                    last.space = x.space;
+                }
+                Some(last) if is_continue(x.mark) => {
+                    last.space = last.space + x.space;
+                }
+                _ if is_continue(x.mark) => output.push(MarkAndSpace {
+                    mark: IrCarrierCounter(0),
+                    space: x.space,
+                }),
+                _ => output.push(*x),
+            }
+        }
+        if let Some(last) = output.last_mut() {
+            if is_continue(last.space) {
+                last.space = IrCarrierCounter(0);
+            }
+        }
+        output
     }
 }
 
@@ -348,10 +299,14 @@ impl convert::From<MarkAndSpaceIrCarrier> for MarkAndSpaceMicros {
     /// マークアンドスペース(キャリア周波数カウンタ型ベース)から
     /// マークアンドスペース(マイクロ秒ベース)へ
     /// 変換する
+    /// 0カウントのスペースはスペースの無いマークにする
     fn from(carrir: MarkAndSpaceIrCarrier) -> Self {
         Self {
             mark: carrir.mark.into(),
-            space: carrir.space.into(),
+            space: match carrir.space {
+                IrCarrierCounter(0) => None,
+                x => Some(x.into()),
+            },
         }
     }
 }
@@ -360,29 +315,27 @@ impl convert::From<MarkAndSpaceMicros> for MarkAndSpaceIrCarrier {
     /// マークアンドスペース(マイクロ秒ベース)から
     /// マークアンドスペース(キャリア周波数カウンタ型ベース)へ
     /// 変換する
+    /// スペースの無いマークは0カウントのスペースにする
     fn from(micros: MarkAndSpaceMicros) -> Self {
         Self {
             mark: micros.mark.into(),
-            space: micros.space.into(),
+            space: micros.space.map_or(IrCarrierCounter(0), IrCarrierCounter::from),
         }
     }
 }
 
-/// 第1,2,3...フレームを区切る時間(8ms = 8000us)
-pub const THRESHOLD_FRAME_GAP: Microseconds = Microseconds(8000);
-
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 /// 赤外線リモコン信号フレーム
-pub struct InfraredRemoteFrame(Vec<MarkAndSpaceMicros>);
+pub struct InfraredRemoteFrame(pub(crate) Vec<MarkAndSpaceMicros>);
 
 impl InfraredRemoteFrame {
-    /// 先頭
-    pub fn head(&self) -> MarkAndSpaceMicros {
-        self.0[0]
+    /// 先頭(空のフレームならNone)
+    pub fn head(&self) -> Option<MarkAndSpaceMicros> {
+        self.0.first().copied()
     }
     /// 残り
     pub fn tail(&self) -> &[MarkAndSpaceMicros] {
-        &self.0[1..]
+        self.0.get(1..).unwrap_or(&[])
     }
 }
 
@@ -396,21 +349,40 @@ impl iter::FromIterator<MarkAndSpaceMicros> for InfraredRemoteFrame {
     }
 }
 
+#[test]
+fn test_mark_only_decode_phase1() {
+    let ms = |m: u32, s: u32| MarkAndSpaceMicros::from((Microseconds(m), Microseconds(s)));
+    let mark = |m: u32| MarkAndSpaceMicros::mark_only(Microseconds(m));
+    // 途中のスペースの無いマークは次のマークに続く
+    // 最後のスペースの無いマークはそのまま
+    let input = vec![ms(9000, 4500), mark(300), ms(260, 560), mark(560)];
+    let frames = decode_phase1(&input).unwrap();
+    assert_eq!(
+        frames,
+        vec!(InfraredRemoteFrame(vec!(
+            ms(9000, 4500),
+            ms(560, 560),
+            mark(560)
+        )))
+    );
+    assert_eq!(frames[0].0[2].trailing_space(), None);
+}
+
+#[test]
+fn test_empty_frame() {
+    let frame = InfraredRemoteFrame(vec![]);
+    assert_eq!(frame.head(), None);
+    assert!(frame.tail().is_empty());
+}
+
 /// デコード1段階目
 /// 入力マークアンドスペース列を各フレームに分ける
 pub fn decode_phase1(input: &[MarkAndSpaceMicros]) -> Result<Vec<InfraredRemoteFrame>, String> {
     if input.len() < 1 {
         return Err("decode_phase1: input is empty.".to_string());
     }
-
-    let threshold = THRESHOLD_FRAME_GAP;
-    let xs = input.split_inclusive(|ms| threshold <= ms.space);
-
-    let mut result = Vec::new();
-    for x in xs {
-        result.push(InfraredRemoteFrame(x.to_vec()));
-    }
-    Ok(result)
+    let frames = segment_frames(ProtocolRegistry::builtin_ref(), input)?;
+    Ok(frames.into_iter().map(|x| x.frame).collect())
 }
 
 #[test]
@@ -542,215 +514,180 @@ fn test5_decode_phase1() {
     );
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
-/// 1ビットを表す型
-pub struct Bit(u8);
-
 impl Bit {
-    //
-    pub fn new(init: u8) -> Self {
-        match init {
-            0 => Self(0),
-            1 => Self(1),
-            _ => panic!("0 か 1 でよろしく"),
-        }
+    /// 0か1以外はエラー
+    pub fn new(init: u8) -> Result<Self, String> {
+        Self::try_from(init)
     }
 }
 
-/// ビット型の配列を8ビットごとに空白を入れて表示する。
-fn show_bit_pattern(input: &[Bit]) -> String {
-    let mut s = String::new();
-    for (index, item) in input.iter().enumerate() {
-        s.push(match item {
-            Bit(0) => '0',
-            Bit(1) => '1',
-            _ => 'X',
-        });
-        if index & 7 == 7 {
-            s.push(' ');
+impl convert::TryFrom<u8> for Bit {
+    type Error = String;
+    /// 0か1以外はエラー
+    fn try_from(x: u8) -> Result<Self, Self::Error> {
+        match x {
+            0 => Ok(Self(0)),
+            1 => Ok(Self(1)),
+            _ => Err(format!("Bit: {} は 0 か 1 でよろしく", x)),
         }
     }
-    s
+}
+
+#[test]
+fn test_bit_new() {
+    assert_eq!(Bit::new(1), Ok(Bit(1)));
+    assert!(Bit::new(2).is_err());
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 /// 復調後の赤外線リモコン信号
 pub enum InfraredRemoteDemodulatedFrame {
-    Aeha(Vec<Bit>),
-    Nec(Vec<Bit>),
-    Sirc(Vec<Bit>),
+    Aeha(BitVec),
+    Nec(BitVec),
+    /// Samsung32プロトコル(アドレス8ビット x 2, コマンド8ビットと反転)
+    Samsung32(BitVec),
+    /// Samsung48プロトコル(アドレス8ビット x 2, コマンド8ビットと反転 x 2)
+    Samsung48(BitVec),
+    Sirc(BitVec),
+    /// 組み込み以外のプロトコル定義で復調した信号
+    Defined {
+        protocol: String,
+        bits: BitVec,
+    },
+    /// リピート信号
+    Repeat(String),
     Unknown(Vec<MarkAndSpaceMicros>),
 }
 
-impl fmt::Display for InfraredRemoteDemodulatedFrame {
-    //
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl InfraredRemoteDemodulatedFrame {
+    /// プロトコル名とビット列から組み立てる
+    pub fn from_protocol(protocol: &str, bits: BitVec) -> Self {
+        match protocol {
+            "AEHA" => InfraredRemoteDemodulatedFrame::Aeha(bits),
+            "NEC" => InfraredRemoteDemodulatedFrame::Nec(bits),
+            "SAMSUNG32" => InfraredRemoteDemodulatedFrame::Samsung32(bits),
+            "SAMSUNG48" => InfraredRemoteDemodulatedFrame::Samsung48(bits),
+            "SIRC" => InfraredRemoteDemodulatedFrame::Sirc(bits),
+            _ => InfraredRemoteDemodulatedFrame::Defined {
+                protocol: protocol.to_string(),
+                bits,
+            },
+        }
+    }
+
+    /// プロトコル名
+    pub fn protocol(&self) -> Option<&str> {
         match self {
-            InfraredRemoteDemodulatedFrame::Aeha(x) => write!(f, "AEHA {}", show_bit_pattern(x)),
-            InfraredRemoteDemodulatedFrame::Nec(x) => write!(f, "NEC {}", show_bit_pattern(x)),
-            InfraredRemoteDemodulatedFrame::Sirc(x) => write!(f, "SIRC {}", show_bit_pattern(x)),
-            InfraredRemoteDemodulatedFrame::Unknown(x) => write!(f, "Unknown {:?}", x),
+            InfraredRemoteDemodulatedFrame::Aeha(_) => Some("AEHA"),
+            InfraredRemoteDemodulatedFrame::Nec(_) => Some("NEC"),
+            InfraredRemoteDemodulatedFrame::Samsung32(_) => Some("SAMSUNG32"),
+            InfraredRemoteDemodulatedFrame::Samsung48(_) => Some("SAMSUNG48"),
+            InfraredRemoteDemodulatedFrame::Sirc(_) => Some("SIRC"),
+            InfraredRemoteDemodulatedFrame::Defined { protocol, .. } => Some(protocol),
+            InfraredRemoteDemodulatedFrame::Repeat(protocol) => Some(protocol),
+            InfraredRemoteDemodulatedFrame::Unknown(_) => None,
         }
     }
-}
 
-/// デコード2段階目
-/// 入力信号を復調して赤外線リモコン信号を取り出す
-pub fn decode_phase2(input: &InfraredRemoteFrame) -> InfraredRemoteDemodulatedFrame {
-    /// pulse distance modulation: NEC, AEHA
-    fn demodulate_pulse_distance_modulation(x: MarkAndSpaceMicros) -> Bit {
-        if x.mark + x.mark <= x.space {
-            // マーク時間の２倍以上スペース時間があれば
-            Bit(1)
-        } else {
-            Bit(0)
+    /// 復調したビット列
+    pub fn bits(&self) -> Option<&BitVec> {
+        match self {
+            InfraredRemoteDemodulatedFrame::Aeha(x) => Some(x),
+            InfraredRemoteDemodulatedFrame::Nec(x) => Some(x),
+            InfraredRemoteDemodulatedFrame::Samsung32(x) => Some(x),
+            InfraredRemoteDemodulatedFrame::Samsung48(x) => Some(x),
+            InfraredRemoteDemodulatedFrame::Sirc(x) => Some(x),
+            InfraredRemoteDemodulatedFrame::Defined { bits, .. } => Some(bits),
+            InfraredRemoteDemodulatedFrame::Repeat(_) => None,
+            InfraredRemoteDemodulatedFrame::Unknown(_) => None,
         }
     }
-    /// pulse width modulation: SIRC
-    fn demodulate_pulse_width_modulation(x: MarkAndSpaceMicros) -> Bit {
-        // upper lower tolerance 0.1ms = 100us
-        let tolerance = Microseconds(100);
-        let threshold = Microseconds(1200);
-        let upper = threshold + tolerance;
-        let lower = threshold - tolerance;
-        if lower <= x.mark && x.mark <= upper {
-            // マーク時間が閾値(1200us)付近なら
-            Bit(1)
-        } else {
-            Bit(0)
+
+    /// プロトコル名と8ビットごとの16進数で表示する "AEHA 02 20 E0 04 00"
+    /// 8ビットに満たない最後のバイトがあれば全体のビット数を "/12" のように付ける
+    /// ビット列の無いフレームは Display と同じ
+    pub fn to_hex_string(&self, bit_order: BitOrder) -> String {
+        match (self.protocol(), self.bits()) {
+            (Some(protocol), Some(bits)) => {
+                let mut text = protocol.to_string();
+                for start in (0..bits.len()).step_by(8) {
+                    let n = (bits.len() - start).min(8);
+                    let value = bits.value(start, n, bit_order).unwrap_or_default();
+                    text.push_str(&format!(" {:02X}", value));
+                }
+                if bits.len() % 8 != 0 {
+                    text.push_str(&format!(" /{}", bits.len()));
+                }
+                text
+            }
+            _ => self.to_string(),
         }
     }
+}
+
+impl fmt::Display for InfraredRemoteDemodulatedFrame {
     //
-    let aeha = (
-        Range {
-            start: protocol_aeha::LEADER.mark - TOLERANCE,
-            end: protocol_aeha::LEADER.mark + TOLERANCE,
-        },
-        Range {
-            start: protocol_aeha::LEADER.space - TOLERANCE,
-            end: protocol_aeha::LEADER.space + TOLERANCE,
-        },
-    );
-    //
-    let nec = (
-        Range {
-            start: protocol_nec::LEADER.mark - TOLERANCE,
-            end: protocol_nec::LEADER.mark + TOLERANCE,
-        },
-        Range {
-            start: protocol_nec::LEADER.space - TOLERANCE,
-            end: protocol_nec::LEADER.space + TOLERANCE,
-        },
-    );
-    //
-    let sirc = (
-        Range {
-            start: protocol_sirc::LEADER.mark - TOLERANCE,
-            end: protocol_sirc::LEADER.mark + TOLERANCE,
-        },
-        Range {
-            start: protocol_sirc::LEADER.space - TOLERANCE,
-            end: protocol_sirc::LEADER.space + TOLERANCE,
-        },
-    );
-    //
-    let leader_pulse = input.head();
-    let tail = &input.tail();
-    //
-    fn compare(
-        test: MarkAndSpaceMicros,
-        (mark, space): (Range<Microseconds>, Range<Microseconds>),
-    ) -> bool {
-        mark.contains(&test.mark) && space.contains(&test.space)
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InfraredRemoteDemodulatedFrame::Aeha(x) => write!(f, "AEHA {}", x),
+            InfraredRemoteDemodulatedFrame::Nec(x) => write!(f, "NEC {}", x),
+            InfraredRemoteDemodulatedFrame::Samsung32(x) => {
+                write!(f, "SAMSUNG32 {}", x)
+            }
+            InfraredRemoteDemodulatedFrame::Samsung48(x) => {
+                write!(f, "SAMSUNG48 {}", x)
+            }
+            InfraredRemoteDemodulatedFrame::Sirc(x) => write!(f, "SIRC {}", x),
+            InfraredRemoteDemodulatedFrame::Defined { protocol, bits } => {
+                write!(f, "{} {}", protocol, bits)
+            }
+            InfraredRemoteDemodulatedFrame::Repeat(protocol) => write!(f, "{} Repeat", protocol),
+            InfraredRemoteDemodulatedFrame::Unknown(x) => write!(f, "Unknown {:?}", x),
+        }
     }
-    //
-    if compare(leader_pulse, aeha) {
-        // PDM復調する
-        InfraredRemoteDemodulatedFrame::Aeha(
-            tail.iter()
-                .map(|&x| demodulate_pulse_distance_modulation(x))
-                .collect(),
-        )
-    } else if compare(leader_pulse, nec) {
-        // PDM復調する
-        InfraredRemoteDemodulatedFrame::Nec(
-            tail.iter()
-                .map(|&x| demodulate_pulse_distance_modulation(x))
-                .collect(),
-        )
-    } else if compare(leader_pulse, sirc) {
-        // PWM復調する
-        InfraredRemoteDemodulatedFrame::Sirc(
-            tail.iter()
-                .map(|&x| demodulate_pulse_width_modulation(x))
-                .collect(),
-        )
-    } else {
-        InfraredRemoteDemodulatedFrame::Unknown(input.0.clone())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// フレームの間隔の指定
+pub enum FrameSpacing {
+    /// フレームの終わりから次のフレームまでの間隔
+    Gap(Microseconds),
+    /// フレームの始まりから次のフレームまでの周期
+    Period(Microseconds),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// フレームの間隔つきの赤外線リモコン信号
+pub struct TimedFrame {
+    /// 赤外線リモコン信号
+    pub frame: InfraredRemoteDemodulatedFrame,
+    /// 実測または指定したフレームの間隔
+    /// Noneならプロトコルのフレーム周期(無ければフレーム間隔)にする
+    #[serde(default)]
+    pub spacing: Option<FrameSpacing>,
+}
+
+impl convert::From<InfraredRemoteDemodulatedFrame> for TimedFrame {
+    fn from(frame: InfraredRemoteDemodulatedFrame) -> Self {
+        TimedFrame {
+            frame,
+            spacing: None,
+        }
     }
 }
 
+/// デコード2段階目
+/// 入力信号を組み込みプロトコル定義で復調して赤外線リモコン信号を取り出す
+pub fn decode_phase2(input: &InfraredRemoteFrame) -> InfraredRemoteDemodulatedFrame {
+    ProtocolRegistry::builtin_ref().decode(input)
+}
+
 /// エンコード1段階目
-/// 赤外線リモコン信号から変調済みフレームを組み立てる
+/// 赤外線リモコン信号から組み込みプロトコル定義で変調済みフレームを組み立てる
 pub fn encode_phase1(
     input: &InfraredRemoteDemodulatedFrame,
 ) -> Result<InfraredRemoteFrame, String> {
-    /// 家製協プロトコルに従ってビット列を変調する
-    fn modulate_aeha(x: &Bit) -> Result<MarkAndSpaceMicros, String> {
-        match x {
-            Bit(0) => Ok(protocol_aeha::TYPICAL_BIT_ZERO),
-            Bit(1) => Ok(protocol_aeha::TYPICAL_BIT_ONE),
-            _ => Err(format!("encode_phase1: 異常値 {:?} です。", x)),
-        }
-    }
-    /// NECプロトコルに従ってビット列を変調する
-    fn modulate_nec(x: &Bit) -> Result<MarkAndSpaceMicros, String> {
-        match x {
-            Bit(0) => Ok(protocol_nec::TYPICAL_BIT_ZERO),
-            Bit(1) => Ok(protocol_nec::TYPICAL_BIT_ONE),
-            _ => Err(format!("encode_phase1: 異常値 {:?} です。", x)),
-        }
-    }
-    /// SIRCプロトコルに従ってビット列を変調する
-    fn modulate_sirc(x: &Bit) -> Result<MarkAndSpaceMicros, String> {
-        match x {
-            Bit(0) => Ok(protocol_sirc::TYPICAL_BIT_ZERO),
-            Bit(1) => Ok(protocol_sirc::TYPICAL_BIT_ONE),
-            _ => Err(format!("encode_phase1: 異常値 {:?} です。", x)),
-        }
-    }
-    match input {
-        InfraredRemoteDemodulatedFrame::Aeha(bitstream) => {
-            let leader = protocol_aeha::LEADER;
-            let trailer = bitstream
-                .iter()
-                .map(|bit| modulate_aeha(bit))
-                .collect::<Result<Vec<MarkAndSpaceMicros>, _>>()?;
-            // リーダーパルスを復元する
-            Ok(InfraredRemoteFrame([vec![leader], trailer].concat()))
-        }
-        InfraredRemoteDemodulatedFrame::Nec(bitstream) => {
-            let leader = protocol_nec::LEADER;
-            let trailer = bitstream
-                .iter()
-                .map(|bit| modulate_nec(bit))
-                .collect::<Result<Vec<MarkAndSpaceMicros>, _>>()?;
-            // リーダーパルスを復元する
-            Ok(InfraredRemoteFrame([vec![leader], trailer].concat()))
-        }
-        InfraredRemoteDemodulatedFrame::Sirc(bitstream) => {
-            let leader = protocol_sirc::LEADER;
-            let trailer = bitstream
-                .iter()
-                .map(|bit| modulate_sirc(bit))
-                .collect::<Result<Vec<MarkAndSpaceMicros>, _>>()?;
-            // リーダーパルスを復元する
-            Ok(InfraredRemoteFrame([vec![leader], trailer].concat()))
-        }
-        InfraredRemoteDemodulatedFrame::Unknown(_) => {
-            Err("encode_phase1: 不明プロトコルでは変調できません。".to_string())
-        }
-    }
+    ProtocolRegistry::builtin_ref().encode(input)
 }
 
 /// エンコード2段階目
@@ -758,15 +695,13 @@ pub fn encode_phase1(
 pub fn encode_phase2(input: &[InfraredRemoteFrame]) -> Vec<MarkAndSpaceMicros> {
     let mut frames: Vec<InfraredRemoteFrame> = Vec::new();
 
-    // 各フレームの最終スペース時間を THRESHOLD_FRAME_GAP に変換する。
+    // フレームの間隔はそのままにして、最終スペースの無いフレームだけ THRESHOLD_FRAME_GAP にする。
     for item in input {
         let mut x = item.clone();
         if let Some(last) = x.0.last_mut() {
-            // 最終フレーム
-            *last = MarkAndSpaceMicros {
-                mark: last.mark,
-                space: THRESHOLD_FRAME_GAP,
-            };
+            if last.trailing_space().is_none() {
+                last.space = Some(THRESHOLD_FRAME_GAP);
+            }
         }
         frames.push(x);
     }
@@ -774,13 +709,23 @@ pub fn encode_phase2(input: &[InfraredRemoteFrame]) -> Vec<MarkAndSpaceMicros> {
 }
 
 /// 赤外線リモコン信号からマークアンドスペースにする
+/// フレームの間隔はプロトコルのフレーム周期(無ければフレーム間隔)にする
 pub fn encode_to_mark_and_spaces(
     input: &[InfraredRemoteDemodulatedFrame],
 ) -> Result<Vec<MarkAndSpaceMicros>, String> {
-    let frames = input
+    let input = input
         .iter()
-        .map(|x| encode_phase1(x))
-        .collect::<Result<Vec<InfraredRemoteFrame>, String>>()?;
+        .cloned()
+        .map(TimedFrame::from)
+        .collect::<Vec<TimedFrame>>();
+    encode_timed_to_mark_and_spaces(&input)
+}
+
+/// フレームの間隔つきの赤外線リモコン信号からマークアンドスペースにする
+pub fn encode_timed_to_mark_and_spaces(
+    input: &[TimedFrame],
+) -> Result<Vec<MarkAndSpaceMicros>, String> {
+    let frames = ProtocolRegistry::builtin_ref().encode_timed_frames(input)?;
     Ok(encode_phase2(&frames))
 }
 
@@ -0,0 +1,764 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+//! 標準ライブラリもヒープも使わない、赤外線リモコン信号の基本型とNEC/AEHA/SIRCの復調・変調
+//! マイコンのファームウェアでも、"std" フィーチャー無しでこのモジュールだけ使える
+use core::ops;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// マイクロ秒型
+pub struct Microseconds(pub u32);
+
+impl ops::Add for Microseconds {
+    type Output = Microseconds;
+    /// マイクロ秒型の加算演算子
+    /// 桁あふれしたら最大値にする
+    fn add(self, other: Self) -> Self::Output {
+        Microseconds(self.0.saturating_add(other.0))
+    }
+}
+
+impl ops::Sub for Microseconds {
+    type Output = Microseconds;
+    /// マイクロ秒型の減算演算子
+    /// 負になるなら0にする
+    fn sub(self, other: Self) -> Self::Output {
+        Microseconds(self.0.saturating_sub(other.0))
+    }
+}
+
+/// 第1,2,3...フレームを区切る時間(8ms = 8000us)
+/// スペース時間の分布からフレーム間隔が求められないときに使う
+pub const THRESHOLD_FRAME_GAP: Microseconds = Microseconds(8000);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// マークアンドスペース型
+/// スペースの型Sは、スペースが無いことを表せる型にもできる
+pub struct MarkAndSpace<T, S = T> {
+    pub mark: T,
+    pub space: S,
+}
+
+impl<T> From<MarkAndSpace<T>> for (T, T) {
+    /// マークアンドスペース型からタプル型へ変換する
+    fn from(x: MarkAndSpace<T>) -> Self {
+        (x.mark, x.space)
+    }
+}
+
+impl<T> From<(T, T)> for MarkAndSpace<T> {
+    /// タプル型からマークアンドスペース型へ変換する
+    fn from((a, b): (T, T)) -> Self {
+        MarkAndSpace { mark: a, space: b }
+    }
+}
+
+impl<T: ops::Add<Output = T>> ops::Add for MarkAndSpace<T> {
+    type Output = MarkAndSpace<T>;
+    /// マークアンドスペースの加算演算子
+    fn add(self, other: Self) -> Self::Output {
+        Self::Output {
+            mark: self.mark + other.mark,
+            space: self.space + other.space,
+        }
+    }
+}
+
+impl<T: ops::Sub<Output = T>> ops::Sub for MarkAndSpace<T> {
+    type Output = MarkAndSpace<T>;
+    /// マークアンドスペースの減算演算子
+    fn sub(self, other: Self) -> Self::Output {
+        Self::Output {
+            mark: self.mark - other.mark,
+            space: self.space - other.space,
+        }
+    }
+}
+
+/// マークアンドスペース(マイクロ秒ベース)
+/// スペースが無ければ(信号の最後のマークの後ろ、または続きのあるマーク)None
+pub type MarkAndSpaceMicros = MarkAndSpace<Microseconds, Option<Microseconds>>;
+
+impl From<(Microseconds, Microseconds)> for MarkAndSpaceMicros {
+    /// タプル型からスペースのあるマークアンドスペース型へ変換する
+    fn from((mark, space): (Microseconds, Microseconds)) -> Self {
+        MarkAndSpace {
+            mark,
+            space: Some(space),
+        }
+    }
+}
+
+impl MarkAndSpaceMicros {
+    /// スペースの無いマーク(信号の最後)
+    pub fn mark_only(mark: Microseconds) -> Self {
+        Self { mark, space: None }
+    }
+
+    /// スペース(スペースが無ければNone)
+    pub fn trailing_space(&self) -> Option<Microseconds> {
+        self.space
+    }
+
+    /// マークとスペースを合わせた時間
+    pub fn duration(&self) -> Microseconds {
+        self.mark + self.space.unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// 1ビットを表す型
+pub struct Bit(pub(crate) u8);
+
+impl Bit {
+    pub const ZERO: Bit = Bit(0);
+    pub const ONE: Bit = Bit(1);
+
+    /// 0か1
+    pub fn value(self) -> u8 {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// 復調・変調の失敗
+pub enum CoreError {
+    /// バッファの容量が足りない
+    Capacity,
+    /// フレーム周期がフレームの長さより短い
+    FramePeriod,
+    /// リピート信号の無いプロトコル
+    NoRepeat,
+}
+
+#[derive(Clone, Copy, Debug)]
+/// 固定容量のバッファ
+pub struct FixedBuffer<T: Copy + Default, const N: usize> {
+    items: [T; N],
+    len: usize,
+}
+
+impl<T: Copy + Default, const N: usize> FixedBuffer<T, N> {
+    pub fn new() -> Self {
+        FixedBuffer {
+            items: [T::default(); N],
+            len: 0,
+        }
+    }
+
+    /// 容量
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// 末尾に追加する
+    pub fn push(&mut self, x: T) -> Result<(), CoreError> {
+        let item = self.items.get_mut(self.len).ok_or(CoreError::Capacity)?;
+        *item = x;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// 末尾を取り出す
+    pub fn pop(&mut self) -> Option<T> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.items[self.len])
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.items[..self.len]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.items[..self.len]
+    }
+}
+
+impl<T: Copy + Default, const N: usize> Default for FixedBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy + Default, const N: usize> ops::Deref for FixedBuffer<T, N> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T: Copy + Default + PartialEq, const N: usize> PartialEq for FixedBuffer<T, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<T: Copy + Default + Eq, const N: usize> Eq for FixedBuffer<T, N> {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// ビットの変調方式
+pub enum PulseCoding {
+    /// パルス間隔変調(スペースの長さで0と1を表す)
+    PulseDistance,
+    /// パルス幅変調(マークの長さで0と1を表す)
+    PulseWidth,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// プロトコルの時間の定義
+/// 時間は基準時間Tの倍数で書く(tolerance, gap, frame_periodはマイクロ秒)
+/// 組み込みのものは protocols/*.json と同じ値にする
+/// (ProtocolDefinition::core で JSON のプロトコル定義からも作れる)
+pub struct CoreProtocol<'a> {
+    /// プロトコル名
+    pub name: &'a str,
+    /// 基準時間T
+    pub time_base: Microseconds,
+    /// ずれ時間の許容範囲
+    pub tolerance: Microseconds,
+    /// リーダーパルス
+    pub leader: Option<MarkAndSpace<u32>>,
+    /// 変調方式
+    pub coding: PulseCoding,
+    /// 0を意味する信号
+    pub bit_zero: MarkAndSpace<u32>,
+    /// 1を意味する信号
+    pub bit_one: MarkAndSpace<u32>,
+    /// 決まったビット数(可変長ならNone)
+    pub bits: Option<usize>,
+    /// ストップビット(マーク時間)
+    pub trailer: Option<u32>,
+    /// フレーム最後のスペース時間
+    pub gap: Microseconds,
+    /// フレーム周期
+    pub frame_period: Option<Microseconds>,
+    /// リピート信号のリーダーパルス
+    pub repeat: Option<MarkAndSpace<u32>>,
+}
+
+/// NECプロトコル T = 562us
+pub const NEC: CoreProtocol<'static> = CoreProtocol {
+    name: "NEC",
+    time_base: Microseconds(562),
+    tolerance: Microseconds(300),
+    leader: Some(MarkAndSpace { mark: 16, space: 8 }),
+    coding: PulseCoding::PulseDistance,
+    bit_zero: MarkAndSpace { mark: 1, space: 1 },
+    bit_one: MarkAndSpace { mark: 1, space: 3 },
+    bits: Some(32),
+    trailer: Some(1),
+    gap: Microseconds(8000),
+    frame_period: Some(Microseconds(108_000)),
+    repeat: Some(MarkAndSpace { mark: 16, space: 4 }),
+};
+
+/// 家製協プロトコル T = 440us
+pub const AEHA: CoreProtocol<'static> = CoreProtocol {
+    name: "AEHA",
+    time_base: Microseconds(440),
+    tolerance: Microseconds(300),
+    leader: Some(MarkAndSpace { mark: 8, space: 4 }),
+    coding: PulseCoding::PulseDistance,
+    bit_zero: MarkAndSpace { mark: 1, space: 1 },
+    bit_one: MarkAndSpace { mark: 1, space: 3 },
+    bits: None,
+    trailer: Some(1),
+    gap: Microseconds(8000),
+    frame_period: None,
+    repeat: None,
+};
+
+/// SIRCプロトコル T = 600us
+pub const SIRC: CoreProtocol<'static> = CoreProtocol {
+    name: "SIRC",
+    time_base: Microseconds(600),
+    tolerance: Microseconds(300),
+    leader: Some(MarkAndSpace { mark: 4, space: 1 }),
+    coding: PulseCoding::PulseWidth,
+    bit_zero: MarkAndSpace { mark: 1, space: 1 },
+    bit_one: MarkAndSpace { mark: 2, space: 1 },
+    bits: None,
+    trailer: None,
+    gap: Microseconds(8000),
+    frame_period: Some(Microseconds(45000)),
+    repeat: None,
+};
+
+/// 組み込みのプロトコル
+pub const CORE_PROTOCOLS: [CoreProtocol<'static>; 3] = [NEC, AEHA, SIRC];
+
+/// 許容範囲内で一致するか
+fn within(actual: Microseconds, expected: Microseconds, tolerance: Microseconds) -> bool {
+    let diff = if actual < expected {
+        expected.0 - actual.0
+    } else {
+        actual.0 - expected.0
+    };
+    diff <= tolerance.0
+}
+
+impl<'a> CoreProtocol<'a> {
+    /// 基準時間Tの倍数からマイクロ秒にする
+    pub fn micros(&self, multiple: u32) -> Microseconds {
+        Microseconds(multiple.saturating_mul(self.time_base.0))
+    }
+
+    /// 基準時間Tの倍数のマークアンドスペースからマイクロ秒にする
+    pub fn mark_and_space(&self, x: MarkAndSpace<u32>) -> MarkAndSpaceMicros {
+        MarkAndSpace {
+            mark: self.micros(x.mark),
+            space: Some(self.micros(x.space)),
+        }
+    }
+
+    /// 許容範囲内で一致するか
+    pub fn compare(&self, test: MarkAndSpaceMicros, expected: MarkAndSpace<u32>) -> bool {
+        within(test.mark, self.micros(expected.mark), self.tolerance)
+            && within(
+                test.space.unwrap_or_default(),
+                self.micros(expected.space),
+                self.tolerance,
+            )
+    }
+
+    /// リピート信号のリーダーと一致するか
+    pub fn matches_repeat(&self, head: MarkAndSpaceMicros) -> bool {
+        self.repeat.map_or(false, |x| self.compare(head, x))
+    }
+
+    /// フレームの先頭がこのプロトコルのリーダー(リピート信号のリーダー)と一致するか
+    pub fn matches_leader(&self, head: MarkAndSpaceMicros) -> bool {
+        self.leader.map_or(false, |x| self.compare(head, x)) || self.matches_repeat(head)
+    }
+
+    /// index番目のマークに続くスペースspaceでフレームが終わるか
+    /// repeatはリピート信号のリーダーで始まるフレームならtrue
+    /// elapsedはフレームの先頭からそのマークの終わりまでの時間
+    pub fn is_frame_end(
+        &self,
+        repeat: bool,
+        index: usize,
+        elapsed: Microseconds,
+        space: Microseconds,
+    ) -> bool {
+        let longest = self.micros(self.bit_zero.space.max(self.bit_one.space)) + self.tolerance;
+        if space <= longest {
+            return false;
+        }
+        let trailer = self.trailer.map_or(0, |_| 1);
+        let expected_end = if repeat {
+            Some(trailer)
+        } else {
+            self.bits.map(|n| n + trailer)
+        };
+        let expected_gap = match self.frame_period {
+            Some(period) if elapsed < period => period - elapsed,
+            _ => self.gap,
+        };
+        expected_end == Some(index) || Microseconds(expected_gap.0 / 2) <= space
+    }
+
+    /// 1ビット復調する(0と1の中間を閾値にする)
+    pub fn demodulate_bit(&self, x: MarkAndSpaceMicros) -> Bit {
+        let (zero, one) = (self.bit_zero, self.bit_one);
+        let (test, threshold, one_is_longer) = match self.coding {
+            PulseCoding::PulseWidth => (
+                x.mark,
+                (self.micros(zero.mark).0 as u64 + self.micros(one.mark).0 as u64) / 2,
+                zero.mark < one.mark,
+            ),
+            PulseCoding::PulseDistance => (
+                x.space.unwrap_or_default(),
+                (self.micros(zero.space).0 as u64 + self.micros(one.space).0 as u64) / 2,
+                zero.space < one.space,
+            ),
+        };
+        if (threshold <= test.0 as u64) == one_is_longer {
+            Bit::ONE
+        } else {
+            Bit::ZERO
+        }
+    }
+
+    /// 最後のスペースをフレームの間隔にする
+    /// フレーム周期があれば周期の残り、無ければフレーム間隔
+    fn set_frame_gap(&self, output: &mut [MarkAndSpaceMicros]) -> Result<(), CoreError> {
+        let gap = match self.frame_period {
+            Some(period) => {
+                let duration = output
+                    .iter()
+                    .fold(Microseconds(0), |acc, x| acc + x.duration())
+                    - output.last().and_then(|x| x.space).unwrap_or_default();
+                if period <= duration {
+                    return Err(CoreError::FramePeriod);
+                }
+                period - duration
+            }
+            None => self.gap,
+        };
+        if let Some(last) = output.last_mut() {
+            last.space = Some(gap);
+        }
+        Ok(())
+    }
+
+    /// ストップビット(スペースはフレーム間隔)
+    fn trailer_micros(&self) -> Option<MarkAndSpaceMicros> {
+        self.trailer.map(|x| MarkAndSpace {
+            mark: self.micros(x),
+            space: Some(self.gap),
+        })
+    }
+
+    /// ビット列を変調したリーダー、ビット、ストップビット
+    /// (最後のスペースはフレームの間隔にしない)
+    pub fn modulated<'b, I>(&'b self, bits: I) -> impl Iterator<Item = MarkAndSpaceMicros> + 'b
+    where
+        I: IntoIterator<Item = Bit>,
+        I::IntoIter: 'b,
+    {
+        let leader = self.leader.map(|x| self.mark_and_space(x));
+        let body = bits.into_iter().map(move |bit| {
+            if bit == Bit::ONE {
+                self.mark_and_space(self.bit_one)
+            } else {
+                self.mark_and_space(self.bit_zero)
+            }
+        });
+        leader.into_iter().chain(body).chain(self.trailer_micros())
+    }
+
+    /// リピート信号のリーダーとストップビット
+    /// (最後のスペースはフレームの間隔にしない)
+    pub fn modulated_repeat(
+        &self,
+    ) -> Result<impl Iterator<Item = MarkAndSpaceMicros> + '_, CoreError> {
+        let repeat = self.repeat.ok_or(CoreError::NoRepeat)?;
+        Ok(core::iter::once(self.mark_and_space(repeat)).chain(self.trailer_micros()))
+    }
+
+    /// ビット列を変調する
+    pub fn modulate<const N: usize>(
+        &self,
+        bits: &[Bit],
+        output: &mut FixedBuffer<MarkAndSpaceMicros, N>,
+    ) -> Result<(), CoreError> {
+        output.clear();
+        for x in self.modulated(bits.iter().copied()) {
+            output.push(x)?;
+        }
+        self.set_frame_gap(output.as_mut_slice())
+    }
+
+    /// リピート信号を変調する
+    pub fn modulate_repeat<const N: usize>(
+        &self,
+        output: &mut FixedBuffer<MarkAndSpaceMicros, N>,
+    ) -> Result<(), CoreError> {
+        output.clear();
+        for x in self.modulated_repeat()? {
+            output.push(x)?;
+        }
+        self.set_frame_gap(output.as_mut_slice())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// 復調結果(gapは実測したフレーム後の間隔)
+pub enum CoreEvent<'a, const N: usize> {
+    /// 復調したフレーム
+    Frame {
+        protocol: &'a str,
+        bits: FixedBuffer<Bit, N>,
+        gap: Option<Microseconds>,
+    },
+    /// リピート信号
+    Repeat {
+        protocol: &'a str,
+        gap: Option<Microseconds>,
+    },
+    /// 復調できなかったフレーム(どのリーダーとも一致しない、またはビット数が容量を超えた)
+    Unknown { gap: Option<Microseconds> },
+}
+
+/// 分けている途中のフレーム
+#[derive(Clone, Copy, Debug)]
+struct Receiving<'a> {
+    /// リーダーが一致したプロトコル
+    protocol: Option<&'a CoreProtocol<'a>>,
+    /// リピート信号のリーダーだった
+    repeat: bool,
+    /// 次に受け取るマークアンドスペースの番号
+    index: usize,
+    /// フレームの先頭からの時間
+    elapsed: Microseconds,
+    /// ビット数が容量を超えた
+    overflow: bool,
+}
+
+/// マークアンドスペースを1つずつ受け取って復調する
+/// フレームの分け方と復調は segment_frames_with_threshold と ProtocolRegistry::decode と同じ
+/// Nは1フレームの最大ビット数
+pub struct CoreDecoder<'a, const N: usize> {
+    protocols: &'a [CoreProtocol<'a>],
+    threshold: Microseconds,
+    receiving: Option<Receiving<'a>>,
+    bits: FixedBuffer<Bit, N>,
+    /// 次のマークに続くスペースの無いマーク
+    joining: Option<MarkAndSpaceMicros>,
+}
+
+impl<'a, const N: usize> CoreDecoder<'a, N> {
+    /// THRESHOLD_FRAME_GAP 以上のスペースでもフレームを分ける
+    pub fn new(protocols: &'a [CoreProtocol<'a>]) -> Self {
+        CoreDecoder {
+            protocols,
+            threshold: THRESHOLD_FRAME_GAP,
+            receiving: None,
+            bits: FixedBuffer::new(),
+            joining: None,
+        }
+    }
+
+    /// マークアンドスペースを受け取る
+    /// フレームの終わりなら復調結果を返す
+    pub fn push(&mut self, x: MarkAndSpaceMicros) -> Option<CoreEvent<'a, N>> {
+        let x = match self.joining.take() {
+            Some(joining) => MarkAndSpace {
+                mark: joining.mark + x.mark,
+                space: x.space,
+            },
+            None => x,
+        };
+        if x.trailing_space().is_none() {
+            self.joining = Some(x);
+            return None;
+        }
+        self.accept(x, false)
+    }
+
+    /// 受信の終わり
+    /// 途中のフレームがあれば復調結果を返す
+    pub fn finish(&mut self) -> Option<CoreEvent<'a, N>> {
+        if let Some(x) = self.joining.take() {
+            return self.accept(x, true);
+        }
+        let receiving = self.receiving.take()?;
+        // 最後に受け取ったものはストップビットだった
+        if receiving.protocol.map_or(false, |p| p.trailer.is_some()) {
+            self.bits.pop();
+        }
+        Some(self.complete(receiving, None))
+    }
+
+    fn accept(&mut self, x: MarkAndSpaceMicros, finish: bool) -> Option<CoreEvent<'a, N>> {
+        let mut receiving = match self.receiving.take() {
+            Some(receiving) => receiving,
+            None => {
+                self.bits.clear();
+                let protocol = self.protocols.iter().find(|p| p.matches_leader(x));
+                Receiving {
+                    protocol,
+                    repeat: protocol.map_or(false, |p| p.matches_repeat(x)),
+                    index: 0,
+                    elapsed: Microseconds(0),
+                    overflow: false,
+                }
+            }
+        };
+        receiving.elapsed = receiving.elapsed + x.mark;
+        let end = finish
+            || match (x.trailing_space(), receiving.protocol) {
+                (Some(space), _) if self.threshold <= space => true,
+                (Some(space), Some(p)) => {
+                    p.is_frame_end(receiving.repeat, receiving.index, receiving.elapsed, space)
+                }
+                _ => false,
+            };
+        receiving.elapsed = receiving.elapsed + x.space.unwrap_or_default();
+        // リーダーとストップビット以外はビット
+        if let Some(p) = receiving.protocol {
+            let trailer = end && p.trailer.is_some();
+            if 0 < receiving.index && !trailer && self.bits.push(p.demodulate_bit(x)).is_err() {
+                receiving.overflow = true;
+            }
+        }
+        receiving.index += 1;
+        if end {
+            Some(self.complete(receiving, x.trailing_space()))
+        } else {
+            self.receiving = Some(receiving);
+            None
+        }
+    }
+
+    fn complete(
+        &mut self,
+        receiving: Receiving<'a>,
+        gap: Option<Microseconds>,
+    ) -> CoreEvent<'a, N> {
+        match receiving.protocol {
+            Some(p) if receiving.repeat && receiving.index <= 2 => CoreEvent::Repeat {
+                protocol: p.name,
+                gap,
+            },
+            Some(p) if !receiving.repeat && !receiving.overflow && !self.bits.is_empty() => {
+                CoreEvent::Frame {
+                    protocol: p.name,
+                    bits: self.bits,
+                    gap,
+                }
+            }
+            _ => CoreEvent::Unknown { gap },
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod ir_core_tests {
+    use crate::bitvec::BitVec;
+    use crate::infrared_remote::{InfraredRemoteDemodulatedFrame, TimedFrame};
+    use crate::ir_core::{
+        Bit, CoreDecoder, CoreError, CoreEvent, CoreProtocol, FixedBuffer, MarkAndSpace,
+        MarkAndSpaceMicros, Microseconds, CORE_PROTOCOLS, THRESHOLD_FRAME_GAP,
+    };
+    use crate::protocol::ProtocolRegistry;
+    use crate::segmentation::segment_frames_with_threshold;
+
+    fn bits(n: usize, seed: u32) -> Vec<Bit> {
+        (0..n)
+            .map(|i| Bit(((seed >> (i % 32)) & 1) as u8))
+            .collect()
+    }
+
+    #[test]
+    fn test_same_definitions() {
+        // protocols/*.json と同じ
+        let registry = ProtocolRegistry::builtin();
+        for p in CORE_PROTOCOLS.iter() {
+            assert_eq!(registry.find(p.name).unwrap().core(), *p);
+        }
+    }
+
+    #[test]
+    fn test_fixed_buffer() {
+        let mut buffer = FixedBuffer::<u8, 2>::new();
+        assert_eq!(buffer.push(1), Ok(()));
+        assert_eq!(buffer.push(2), Ok(()));
+        assert_eq!(buffer.push(3), Err(CoreError::Capacity));
+        assert_eq!(&buffer[..], &[1, 2]);
+        assert_eq!(buffer.pop(), Some(2));
+        assert_eq!(buffer.len(), 1);
+    }
+
+    /// 変調と復調が ProtocolRegistry と同じ
+    fn round_trip(p: &CoreProtocol, bits: &[Bit]) {
+        let registry = ProtocolRegistry::builtin();
+        let frame = InfraredRemoteDemodulatedFrame::from_protocol(p.name, BitVec::from(bits));
+        let expected = registry
+            .encode_timed_frames(&[TimedFrame::from(frame.clone())])
+            .unwrap()
+            .remove(0)
+            .0;
+        let mut modulated = FixedBuffer::<MarkAndSpaceMicros, 128>::new();
+        p.modulate(bits, &mut modulated).unwrap();
+        assert_eq!(&modulated[..], &expected[..]);
+
+        let mut input = [&modulated[..], &modulated[..]].concat();
+        // 最後はスペースの無いマーク
+        input.last_mut().unwrap().space = None;
+        let mut decoder = CoreDecoder::<64>::new(&CORE_PROTOCOLS);
+        let mut events = input
+            .iter()
+            .filter_map(|x| decoder.push(*x))
+            .collect::<Vec<_>>();
+        events.extend(decoder.finish());
+        let batch = segment_frames_with_threshold(&registry, &input, THRESHOLD_FRAME_GAP);
+        assert_eq!(events.len(), batch.len());
+        for (event, x) in events.iter().zip(batch.iter()) {
+            match event {
+                CoreEvent::Frame {
+                    protocol,
+                    bits: decoded,
+                    gap,
+                } => {
+                    let decoded = InfraredRemoteDemodulatedFrame::from_protocol(
+                        protocol,
+                        BitVec::from(&decoded[..]),
+                    );
+                    assert_eq!(decoded, registry.decode(&x.frame));
+                    assert_eq!(decoded, frame);
+                    assert_eq!(*gap, x.gap);
+                }
+                _ => panic!("{:?}", event),
+            }
+        }
+    }
+
+    #[test]
+    fn test_core_round_trip() {
+        let [nec, aeha, sirc] = CORE_PROTOCOLS;
+        round_trip(&nec, &bits(32, 0x12ED_00FF));
+        round_trip(&aeha, &bits(48, 0x8421_2002));
+        round_trip(&sirc, &bits(12, 0x0000_0A95));
+        // 容量が足りない
+        let mut small = FixedBuffer::<MarkAndSpaceMicros, 8>::new();
+        assert_eq!(
+            nec.modulate(&bits(32, 0), &mut small),
+            Err(CoreError::Capacity)
+        );
+    }
+
+    #[test]
+    fn test_core_repeat() {
+        let [nec, aeha, _] = CORE_PROTOCOLS;
+        let mut repeat = FixedBuffer::<MarkAndSpaceMicros, 4>::new();
+        nec.modulate_repeat(&mut repeat).unwrap();
+        assert_eq!(
+            repeat[0],
+            MarkAndSpace {
+                mark: Microseconds(8992),
+                space: Some(Microseconds(2248))
+            }
+        );
+        assert_eq!(aeha.modulate_repeat(&mut repeat), Err(CoreError::NoRepeat));
+        let mut decoder = CoreDecoder::<32>::new(&CORE_PROTOCOLS);
+        nec.modulate_repeat(&mut repeat).unwrap();
+        let events = repeat
+            .iter()
+            .filter_map(|x| decoder.push(*x))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec!(CoreEvent::Repeat {
+                protocol: "NEC",
+                gap: repeat.last().and_then(|x| x.space)
+            })
+        );
+        // どのリーダーとも一致しない
+        let unknown = [
+            MarkAndSpace::from((Microseconds(5000), Microseconds(5000))),
+            MarkAndSpace::from((Microseconds(500), Microseconds(40000))),
+        ];
+        let events = unknown
+            .iter()
+            .filter_map(|x| decoder.push(*x))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec!(CoreEvent::Unknown {
+                gap: Some(Microseconds(40000))
+            })
+        );
+    }
+}
@@ -0,0 +1,1570 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+use crate::infrared_remote::{MarkAndSpaceMicros, Microseconds};
+use crate::protocol::BitOrder;
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    character::complete::{char, digit1, hex_digit1, multispace0, one_of},
+    combinator::{all_consuming, map, map_res, opt, recognize},
+    multi::{many0, separated_list0, separated_list1},
+    sequence::{delimited, pair, preceded, tuple},
+    Finish, IResult,
+};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+
+/// 認識するときの時間の相対誤差(30%)
+const RELATIVE_TOLERANCE: f64 = 0.3;
+
+/// 認識するときの時間の最小誤差(100us)
+const MINIMUM_TOLERANCE: f64 = 100.0;
+
+/// 定義の展開の深さの上限
+const MAXIMUM_DEPTH: usize = 64;
+
+/// 生成する時間の個数の上限
+const MAXIMUM_DURATIONS: usize = 65536;
+
+/// 構文解析の入れ子の深さの上限
+const MAXIMUM_NESTING: usize = 32;
+
+/// 生成・認識で処理する要素の個数の上限
+const MAXIMUM_STEPS: usize = 4 * MAXIMUM_DURATIONS;
+
+/// 生成・認識で式を評価する回数の上限
+const MAXIMUM_EVALUATIONS: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 単項演算子
+pub enum UnaryOperator {
+    /// -x
+    Negate,
+    /// ~x
+    Complement,
+    /// !x
+    Not,
+    /// #x (1のビット数)
+    BitCount,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 二項演算子
+pub enum BinaryOperator {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Power,
+}
+
+/// 優先順位の低い順に並べた二項演算子
+/// 長い記号を先に書いておくこと
+const BINARY_OPERATORS: [&[(&str, BinaryOperator)]; 10] = [
+    &[("||", BinaryOperator::Or)],
+    &[("&&", BinaryOperator::And)],
+    &[("|", BinaryOperator::BitOr)],
+    &[("^", BinaryOperator::BitXor)],
+    &[("&", BinaryOperator::BitAnd)],
+    &[
+        ("==", BinaryOperator::Equal),
+        ("!=", BinaryOperator::NotEqual),
+    ],
+    &[
+        ("<=", BinaryOperator::LessEqual),
+        (">=", BinaryOperator::GreaterEqual),
+        ("<", BinaryOperator::Less),
+        (">", BinaryOperator::Greater),
+    ],
+    &[
+        ("<<", BinaryOperator::ShiftLeft),
+        (">>", BinaryOperator::ShiftRight),
+    ],
+    &[("+", BinaryOperator::Add), ("-", BinaryOperator::Subtract)],
+    &[
+        ("*", BinaryOperator::Multiply),
+        ("/", BinaryOperator::Divide),
+        ("%", BinaryOperator::Modulo),
+    ],
+];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// 式
+pub enum Expression {
+    Number(i64),
+    Name(String),
+    Unary(UnaryOperator, Box<Expression>),
+    Binary(BinaryOperator, Box<Expression>, Box<Expression>),
+    Conditional(Box<Expression>, Box<Expression>, Box<Expression>),
+    Bitfield(Box<Bitfield>),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// ビットフィールド ~D:-8:4
+pub struct Bitfield {
+    /// ビット反転
+    pub complement: bool,
+    /// 値
+    pub data: Expression,
+    /// ビットの並びを逆にする
+    pub reverse: bool,
+    /// ビット数
+    pub length: Expression,
+    /// 右シフト量
+    pub offset: Option<Expression>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 時間の単位
+pub enum DurationUnit {
+    /// 基準単位の倍数
+    Units,
+    /// マイクロ秒
+    Micros,
+    /// ミリ秒
+    Millis,
+    /// キャリア周期の倍数
+    Periods,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// 時間の値
+pub enum DurationValue {
+    Number(f64),
+    Name(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// 時間
+pub struct Duration {
+    pub value: DurationValue,
+    pub unit: DurationUnit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 繰り返し指定
+pub enum RepeatMarker {
+    /// 1回
+    Once,
+    /// 0回以上 *
+    Star,
+    /// 1回以上 +
+    Plus,
+    /// n回
+    Count(u32),
+    /// n回以上
+    CountPlus(u32),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// 信号列 (...)
+pub struct IrStream {
+    pub items: Vec<IrStreamItem>,
+    pub repeat: RepeatMarker,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// 信号列の要素
+pub enum IrStreamItem {
+    /// マーク
+    Flash(Duration),
+    /// スペース
+    Gap(Duration),
+    /// 区間の先頭からの時間を揃えるスペース ^108m
+    Extent(Duration),
+    /// ビットフィールド
+    Bitfield(Bitfield),
+    /// 入れ子の信号列
+    Stream(IrStream),
+    /// ビット表現を置き換えた信号列 <...>(...)
+    BitspecStream(Vec<Vec<IrStreamItem>>, IrStream),
+    /// 代入 T=1-T
+    Assignment(String, Expression),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// 全体の指定 {38.4k,564,lsb}
+pub struct GeneralSpec {
+    /// キャリア周波数(Hz)
+    pub frequency: u32,
+    /// 基準単位(マイクロ秒)
+    pub unit: f64,
+    /// ビットオーダー
+    pub bit_order: BitOrder,
+    /// デューティ比(%)
+    pub duty_cycle: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// パラメータの指定 D:0..255=0
+pub struct ParameterSpec {
+    pub name: String,
+    /// 前回の値を覚えておくパラメータ(トグルビットなど)
+    pub memory: bool,
+    pub min: i64,
+    pub max: i64,
+    pub default: Option<Expression>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// IRP記法のプロトコル
+pub struct Irp {
+    pub general: GeneralSpec,
+    pub bitspec: Vec<Vec<IrStreamItem>>,
+    pub stream: IrStream,
+    pub definitions: Vec<(String, Expression)>,
+    pub parameters: Vec<ParameterSpec>,
+}
+
+//
+// 構文解析
+//
+
+thread_local! {
+    /// 構文解析中の入れ子の深さ
+    static NESTING: Cell<usize> = Cell::new(0);
+    /// 生成・認識中に式を評価した回数
+    static EVALUATIONS: Cell<usize> = Cell::new(0);
+}
+
+/// 入れ子になる構文を解析する
+/// 深さが MAXIMUM_NESTING を超えたら、ほかの候補を試さずに失敗する
+fn nested<'a, O, F>(mut f: F) -> impl FnMut(&'a str) -> IResult<&'a str, O>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O>,
+{
+    move |input| {
+        let depth = NESTING.with(|x| x.get());
+        if MAXIMUM_NESTING <= depth {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::TooLarge,
+            )));
+        }
+        NESTING.with(|x| x.set(depth + 1));
+        let result = f(input);
+        NESTING.with(|x| x.set(depth));
+        result
+    }
+}
+
+/// 前後の空白を読み飛ばす
+fn ws<'a, O, F>(f: F) -> impl FnMut(&'a str) -> IResult<&'a str, O>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O>,
+{
+    delimited(multispace0, f, multispace0)
+}
+
+// 名前
+fn name(input: &str) -> IResult<&str, String> {
+    map(
+        recognize(pair(
+            take_while1(|c: char| c.is_ascii_alphabetic() || c == '_'),
+            opt(take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_')),
+        )),
+        |x: &str| x.to_string(),
+    )(input)
+}
+
+// 整数
+fn integer(input: &str) -> IResult<&str, i64> {
+    alt((
+        map_res(preceded(alt((tag("0x"), tag("0X"))), hex_digit1), |x| {
+            i64::from_str_radix(x, 16)
+        }),
+        map_res(
+            preceded(
+                alt((tag("0b"), tag("0B"))),
+                take_while1(|c: char| c == '0' || c == '1'),
+            ),
+            |x| i64::from_str_radix(x, 2),
+        ),
+        map_res(digit1, |x: &str| x.parse::<i64>()),
+    ))(input)
+}
+
+// 小数
+fn decimal(input: &str) -> IResult<&str, f64> {
+    map_res(
+        recognize(pair(digit1, opt(pair(char('.'), digit1)))),
+        |x: &str| x.parse::<f64>(),
+    )(input)
+}
+
+// 式の一番小さい単位
+fn primary(input: &str) -> IResult<&str, Expression> {
+    ws(alt((
+        map(integer, Expression::Number),
+        map(name, Expression::Name),
+        delimited(char('('), nested(expression), char(')')),
+    )))(input)
+}
+
+// ビットフィールドの値より後ろ :-8:4
+fn bitfield_suffix(input: &str) -> IResult<&str, (bool, Expression, Option<Expression>)> {
+    let (input, (_, reverse, length, offset)) = tuple((
+        char(':'),
+        map(opt(ws(char('-'))), |x| x.is_some()),
+        primary,
+        opt(preceded(char(':'), primary)),
+    ))(input)?;
+    Ok((input, (reverse, length, offset)))
+}
+
+// ビットフィールド
+fn bitfield(input: &str) -> IResult<&str, Bitfield> {
+    let (input, (complement, data, (reverse, length, offset))) = tuple((
+        map(opt(ws(char('~'))), |x| x.is_some()),
+        primary,
+        bitfield_suffix,
+    ))(input)?;
+    Ok((
+        input,
+        Bitfield {
+            complement,
+            data,
+            reverse,
+            length,
+            offset,
+        },
+    ))
+}
+
+// 単項式
+fn unary(input: &str, allow_bitfield: bool) -> IResult<&str, Expression> {
+    map(|i| unary_or_bitfield(i, allow_bitfield), |(x, _)| x)(input)
+}
+
+// 単項式(ビットフィールドそのものなら true も返す)
+// 同じ入力を何度も解析し直さないように、primary は1回だけ読む
+fn unary_or_bitfield(input: &str, allow_bitfield: bool) -> IResult<&str, (Expression, bool)> {
+    let mut operator = ws(alt((
+        map(char('-'), |_| UnaryOperator::Negate),
+        map(char('~'), |_| UnaryOperator::Complement),
+        map(char('!'), |_| UnaryOperator::Not),
+        map(char('#'), |_| UnaryOperator::BitCount),
+    )));
+    if let Ok((rest, op)) = operator(input) {
+        let (rest, (x, is_bitfield)) = nested(|i| unary_or_bitfield(i, allow_bitfield))(rest)?;
+        return Ok(match (op, x) {
+            // ~D:8 は反転したビットフィールド
+            (UnaryOperator::Complement, Expression::Bitfield(mut b))
+                if is_bitfield && !b.complement =>
+            {
+                b.complement = true;
+                (rest, (Expression::Bitfield(b), true))
+            }
+            (op, x) => (rest, (Expression::Unary(op, Box::new(x)), false)),
+        });
+    }
+    let (rest, base) = primary(input)?;
+    if allow_bitfield {
+        if let Ok((rest, (reverse, length, offset))) = bitfield_suffix(rest) {
+            let x = Bitfield {
+                complement: false,
+                data: base,
+                reverse,
+                length,
+                offset,
+            };
+            return Ok((rest, (Expression::Bitfield(Box::new(x)), true)));
+        }
+    }
+    // べき乗は右結合
+    match preceded(ws(tag("**")), nested(|i| unary(i, allow_bitfield)))(rest) {
+        Ok((rest, exponent)) => Ok((
+            rest,
+            (
+                Expression::Binary(BinaryOperator::Power, Box::new(base), Box::new(exponent)),
+                false,
+            ),
+        )),
+        Err(nom::Err::Failure(e)) => Err(nom::Err::Failure(e)),
+        Err(_) => Ok((rest, (base, false))),
+    }
+}
+
+// 二項演算子
+fn binary_operator(input: &str, level: usize) -> IResult<&str, BinaryOperator> {
+    let (rest, _) = multispace0(input)?;
+    for (symbol, op) in BINARY_OPERATORS[level].iter() {
+        if let Some(after) = rest.strip_prefix(symbol) {
+            // 同じ文字で始まる別の演算子と取り違えないようにする
+            let confused = match op {
+                BinaryOperator::BitOr => after.starts_with('|'),
+                BinaryOperator::BitAnd => after.starts_with('&'),
+                BinaryOperator::Less => after.starts_with('<'),
+                BinaryOperator::Greater => after.starts_with('>'),
+                BinaryOperator::Multiply => after.starts_with('*'),
+                _ => false,
+            };
+            if !confused {
+                return Ok((after, *op));
+            }
+        }
+    }
+    Err(nom::Err::Error(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::Tag,
+    )))
+}
+
+// 二項式(優先順位ごと)
+fn binary(input: &str, level: usize, allow_bitfield: bool) -> IResult<&str, Expression> {
+    if BINARY_OPERATORS.len() <= level {
+        return unary(input, allow_bitfield);
+    }
+    let (mut input, mut lhs) = binary(input, level + 1, allow_bitfield)?;
+    // 左結合で式の木が深くなるので、演算子の個数も入れ子の深さに数える
+    let mut depth = 0;
+    while let Ok((rest, op)) = binary_operator(input, level) {
+        depth += 1;
+        if MAXIMUM_NESTING < depth {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::TooLarge,
+            )));
+        }
+        let (rest, rhs) = binary(rest, level + 1, allow_bitfield)?;
+        lhs = Expression::Binary(op, Box::new(lhs), Box::new(rhs));
+        input = rest;
+    }
+    Ok((input, lhs))
+}
+
+// 式
+fn expression(input: &str) -> IResult<&str, Expression> {
+    let (input, condition) = binary(input, 0, true)?;
+    match tuple((
+        ws(char('?')),
+        |i| binary(i, 0, false),
+        ws(char(':')),
+        nested(expression),
+    ))(input)
+    {
+        Ok((rest, (_, then, _, otherwise))) => Ok((
+            rest,
+            Expression::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise)),
+        )),
+        Err(nom::Err::Failure(e)) => Err(nom::Err::Failure(e)),
+        Err(_) => Ok((input, condition)),
+    }
+}
+
+// 時間
+fn duration(input: &str) -> IResult<&str, Duration> {
+    let (input, value) = ws(alt((
+        map(decimal, DurationValue::Number),
+        map(name, DurationValue::Name),
+    )))(input)?;
+    let (input, unit) = map(opt(one_of("mup")), |x| match x {
+        Some('m') => DurationUnit::Millis,
+        Some('u') => DurationUnit::Micros,
+        Some('p') => DurationUnit::Periods,
+        _ => DurationUnit::Units,
+    })(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, Duration { value, unit }))
+}
+
+// 繰り返し指定
+fn repeat_marker(input: &str) -> IResult<&str, RepeatMarker> {
+    map(
+        opt(ws(alt((
+            map(char('*'), |_| RepeatMarker::Star),
+            map(char('+'), |_| RepeatMarker::Plus),
+            map(
+                pair(map_res(digit1, |x: &str| x.parse::<u32>()), opt(char('+'))),
+                |(n, plus)| match plus {
+                    Some(_) => RepeatMarker::CountPlus(n),
+                    None => RepeatMarker::Count(n),
+                },
+            ),
+        )))),
+        |x| x.unwrap_or(RepeatMarker::Once),
+    )(input)
+}
+
+// ビット表現 <1,-1|1,-3>
+fn bitspec(input: &str) -> IResult<&str, Vec<Vec<IrStreamItem>>> {
+    delimited(
+        ws(char('<')),
+        separated_list1(ws(char('|')), separated_list1(ws(char(',')), irstream_item)),
+        ws(char('>')),
+    )(input)
+}
+
+// 信号列 (...)
+fn irstream(input: &str) -> IResult<&str, IrStream> {
+    let (input, items) = delimited(
+        ws(char('(')),
+        separated_list0(ws(char(',')), irstream_item),
+        ws(char(')')),
+    )(input)?;
+    let (input, repeat) = repeat_marker(input)?;
+    Ok((input, IrStream { items, repeat }))
+}
+
+// 信号列の要素
+// 信号列とビット表現は入れ子にできるので深さを数える
+fn irstream_item(input: &str) -> IResult<&str, IrStreamItem> {
+    nested(ws(alt((
+        map(tuple((name, ws(char('=')), expression)), |(n, _, e)| {
+            IrStreamItem::Assignment(n, e)
+        }),
+        map(preceded(char('^'), duration), IrStreamItem::Extent),
+        map(pair(bitspec, irstream), |(b, s)| {
+            IrStreamItem::BitspecStream(b, s)
+        }),
+        map(bitfield, IrStreamItem::Bitfield),
+        map(irstream, IrStreamItem::Stream),
+        map(preceded(char('-'), duration), IrStreamItem::Gap),
+        map(duration, IrStreamItem::Flash),
+    ))))(input)
+}
+
+/// 全体の指定の要素
+enum GeneralSpecItem {
+    Frequency(f64),
+    Unit(f64, DurationUnit),
+    BitOrder(BitOrder),
+    DutyCycle(u32),
+}
+
+// 全体の指定 {38.4k,564,lsb}
+fn general_spec(input: &str) -> IResult<&str, GeneralSpec> {
+    let item = ws(alt((
+        map(tuple((decimal, ws(char('k')))), |(x, _)| {
+            GeneralSpecItem::Frequency(x * 1000.0)
+        }),
+        map(tuple((decimal, ws(char('%')))), |(x, _)| {
+            GeneralSpecItem::DutyCycle(x as u32)
+        }),
+        map(tag("msb"), |_| {
+            GeneralSpecItem::BitOrder(BitOrder::MsbFirst)
+        }),
+        map(tag("lsb"), |_| {
+            GeneralSpecItem::BitOrder(BitOrder::LsbFirst)
+        }),
+        map(pair(decimal, opt(one_of("up"))), |(x, unit)| match unit {
+            Some('p') => GeneralSpecItem::Unit(x, DurationUnit::Periods),
+            _ => GeneralSpecItem::Unit(x, DurationUnit::Micros),
+        }),
+    )));
+    let (input, items) = delimited(
+        ws(char('{')),
+        separated_list0(ws(char(',')), item),
+        ws(char('}')),
+    )(input)?;
+    let mut spec = GeneralSpec {
+        frequency: 38000,
+        unit: 1.0,
+        bit_order: BitOrder::LsbFirst,
+        duty_cycle: None,
+    };
+    let mut unit = (1.0, DurationUnit::Micros);
+    for x in items {
+        match x {
+            GeneralSpecItem::Frequency(f) => spec.frequency = f.round() as u32,
+            GeneralSpecItem::Unit(u, kind) => unit = (u, kind),
+            GeneralSpecItem::BitOrder(order) => spec.bit_order = order,
+            GeneralSpecItem::DutyCycle(d) => spec.duty_cycle = Some(d),
+        }
+    }
+    spec.unit = match unit {
+        (u, DurationUnit::Periods) if spec.frequency != 0 => u * 1e6 / spec.frequency as f64,
+        (u, _) => u,
+    };
+    Ok((input, spec))
+}
+
+// 定義 {C=D+S}
+fn definitions(input: &str) -> IResult<&str, Vec<(String, Expression)>> {
+    delimited(
+        ws(char('{')),
+        separated_list0(
+            ws(char(',')),
+            map(tuple((ws(name), char('='), expression)), |(n, _, e)| (n, e)),
+        ),
+        ws(char('}')),
+    )(input)
+}
+
+// パラメータの指定 [D:0..255,S:0..255=255-D]
+fn parameter_specs(input: &str) -> IResult<&str, Vec<ParameterSpec>> {
+    let spec = map(
+        tuple((
+            ws(name),
+            map(opt(char('@')), |x| x.is_some()),
+            ws(char(':')),
+            ws(integer),
+            tag(".."),
+            ws(integer),
+            opt(preceded(ws(char('=')), expression)),
+        )),
+        |(name, memory, _, min, _, max, default)| ParameterSpec {
+            name,
+            memory,
+            min,
+            max,
+            default,
+        },
+    );
+    delimited(
+        ws(char('[')),
+        separated_list0(ws(char(',')), spec),
+        ws(char(']')),
+    )(input)
+}
+
+/// IRP記法の文字列を解析する
+pub fn parse_irp(input: &str) -> Result<Irp, String> {
+    let parser = tuple((
+        general_spec,
+        bitspec,
+        irstream,
+        many0(definitions),
+        opt(parameter_specs),
+    ));
+    match all_consuming(parser)(input).finish() {
+        Ok((_, (general, bitspec, stream, definitions, parameters))) => Ok(Irp {
+            general,
+            bitspec,
+            stream,
+            definitions: definitions.concat(),
+            parameters: parameters.unwrap_or_default(),
+        }),
+        Err(e) if e.code == nom::error::ErrorKind::TooLarge => {
+            Err(format!("irp: nesting is deeper than {}.", MAXIMUM_NESTING))
+        }
+        Err(e) => Err(format!("irp: syntax error at \"{}\"", e.input)),
+    }
+}
+
+//
+// 評価
+//
+
+/// 値を決めたビット位置のマスク
+fn mask_of(length: i64) -> i64 {
+    if 63 <= length {
+        -1
+    } else if length <= 0 {
+        0
+    } else {
+        (1i64 << length) - 1
+    }
+}
+
+/// 下位lengthビットの並びを逆にする
+fn reverse_bits(value: i64, length: i64) -> i64 {
+    (0..length.clamp(0, 63)).fold(0, |acc, i| (acc << 1) | ((value >> i) & 1))
+}
+
+/// ビットフィールドの値
+fn bitfield_value(data: i64, complement: bool, reverse: bool, length: i64, offset: i64) -> i64 {
+    let x = if complement { !data } else { data };
+    let x = x.checked_shr(offset.clamp(0, 63) as u32).unwrap_or(0) & mask_of(length);
+    if reverse {
+        reverse_bits(x, length)
+    } else {
+        x
+    }
+}
+
+impl Irp {
+    /// 名前の値を求める
+    fn lookup(
+        &self,
+        values: &BTreeMap<String, i64>,
+        name: &str,
+        depth: usize,
+    ) -> Result<i64, String> {
+        if let Some(x) = values.get(name) {
+            return Ok(*x);
+        }
+        match self.definitions.iter().find(|(n, _)| n == name) {
+            Some((_, e)) => self.evaluate(values, e, depth + 1),
+            None => Err(format!("irp: {} is not assigned.", name)),
+        }
+    }
+
+    /// 式を評価する
+    fn evaluate(
+        &self,
+        values: &BTreeMap<String, i64>,
+        expression: &Expression,
+        depth: usize,
+    ) -> Result<i64, String> {
+        if MAXIMUM_DEPTH < depth {
+            return Err("irp: definitions are too deep.".to_string());
+        }
+        // 定義が互いに何度も参照し合うと評価の回数が指数的に増える
+        let count = EVALUATIONS.with(|x| x.get()) + 1;
+        if MAXIMUM_EVALUATIONS < count {
+            return Err("irp: too many evaluations.".to_string());
+        }
+        EVALUATIONS.with(|x| x.set(count));
+        let eval = |e: &Expression| self.evaluate(values, e, depth + 1);
+        let overflow = || "irp: arithmetic overflow.".to_string();
+        match expression {
+            Expression::Number(x) => Ok(*x),
+            Expression::Name(n) => self.lookup(values, n, depth),
+            Expression::Unary(op, x) => {
+                let x = eval(x)?;
+                match op {
+                    UnaryOperator::Negate => x.checked_neg().ok_or_else(overflow),
+                    UnaryOperator::Complement => Ok(!x),
+                    UnaryOperator::Not => Ok((x == 0) as i64),
+                    UnaryOperator::BitCount => Ok(x.count_ones() as i64),
+                }
+            }
+            Expression::Binary(op, a, b) => {
+                let a = eval(a)?;
+                let b = eval(b)?;
+                match op {
+                    BinaryOperator::Or => Ok((a != 0 || b != 0) as i64),
+                    BinaryOperator::And => Ok((a != 0 && b != 0) as i64),
+                    BinaryOperator::BitOr => Ok(a | b),
+                    BinaryOperator::BitXor => Ok(a ^ b),
+                    BinaryOperator::BitAnd => Ok(a & b),
+                    BinaryOperator::Equal => Ok((a == b) as i64),
+                    BinaryOperator::NotEqual => Ok((a != b) as i64),
+                    BinaryOperator::Less => Ok((a < b) as i64),
+                    BinaryOperator::LessEqual => Ok((a <= b) as i64),
+                    BinaryOperator::Greater => Ok((a > b) as i64),
+                    BinaryOperator::GreaterEqual => Ok((a >= b) as i64),
+                    BinaryOperator::ShiftLeft => {
+                        Ok(a.checked_shl(b.clamp(0, 63) as u32).unwrap_or(0))
+                    }
+                    BinaryOperator::ShiftRight => {
+                        Ok(a.checked_shr(b.clamp(0, 63) as u32).unwrap_or(0))
+                    }
+                    BinaryOperator::Add => a.checked_add(b).ok_or_else(overflow),
+                    BinaryOperator::Subtract => a.checked_sub(b).ok_or_else(overflow),
+                    BinaryOperator::Multiply => a.checked_mul(b).ok_or_else(overflow),
+                    BinaryOperator::Divide => a
+                        .checked_div(b)
+                        .ok_or_else(|| "irp: division by zero.".to_string()),
+                    BinaryOperator::Modulo => a
+                        .checked_rem(b)
+                        .ok_or_else(|| "irp: division by zero.".to_string()),
+                    BinaryOperator::Power => u32::try_from(b)
+                        .ok()
+                        .and_then(|b| a.checked_pow(b))
+                        .ok_or_else(overflow),
+                }
+            }
+            Expression::Conditional(c, a, b) => {
+                if eval(c)? != 0 {
+                    eval(a)
+                } else {
+                    eval(b)
+                }
+            }
+            Expression::Bitfield(x) => {
+                let offset = match &x.offset {
+                    Some(e) => eval(e)?,
+                    None => 0,
+                };
+                Ok(bitfield_value(
+                    eval(&x.data)?,
+                    x.complement,
+                    x.reverse,
+                    eval(&x.length)?,
+                    offset,
+                ))
+            }
+        }
+    }
+
+    /// 時間をマイクロ秒にする
+    fn micros(&self, values: &BTreeMap<String, i64>, x: &Duration) -> Result<f64, String> {
+        let value = match &x.value {
+            DurationValue::Number(n) => *n,
+            DurationValue::Name(n) => self.lookup(values, n, 0)? as f64,
+        };
+        let period = match self.general.frequency {
+            0 => 0.0,
+            f => 1e6 / f as f64,
+        };
+        Ok(match x.unit {
+            DurationUnit::Units => value * self.general.unit,
+            DurationUnit::Micros => value,
+            DurationUnit::Millis => value * 1000.0,
+            DurationUnit::Periods => value * period,
+        })
+    }
+
+    /// パラメータの既定値を埋めて範囲を調べる
+    fn complete_parameters(
+        &self,
+        values: &BTreeMap<String, i64>,
+    ) -> Result<BTreeMap<String, i64>, String> {
+        let mut result = values.clone();
+        for spec in self.parameters.iter() {
+            if !result.contains_key(&spec.name) {
+                let value = match &spec.default {
+                    Some(e) => self.evaluate(&result, e, 0)?,
+                    None => return Err(format!("irp: parameter {} is required.", spec.name)),
+                };
+                result.insert(spec.name.clone(), value);
+            }
+            let value = result[&spec.name];
+            if value < spec.min || spec.max < value {
+                return Err(format!(
+                    "irp: parameter {}={} is out of range {}..{}.",
+                    spec.name, value, spec.min, spec.max
+                ));
+            }
+        }
+        Ok(result)
+    }
+
+    /// 1シンボルあたりのビット数
+    fn bits_per_symbol(bitspec: &[Vec<IrStreamItem>]) -> Result<i64, String> {
+        match bitspec.len() {
+            2 => Ok(1),
+            4 => Ok(2),
+            8 => Ok(3),
+            16 => Ok(4),
+            n => Err(format!("irp: bitspec has {} items.", n)),
+        }
+    }
+
+    /// パラメータの値を与えて信号を生成する
+    /// * 指定の繰り返しはrepeat回、+指定の繰り返しは最低1回にする
+    pub fn render(
+        &self,
+        parameters: &BTreeMap<String, i64>,
+        repeat: u32,
+    ) -> Result<Vec<MarkAndSpaceMicros>, String> {
+        EVALUATIONS.with(|x| x.set(0));
+        let mut renderer = Renderer {
+            irp: self,
+            values: self.complete_parameters(parameters)?,
+            bitspecs: vec![&self.bitspec],
+            output: Vec::new(),
+            elapsed: 0.0,
+            repeat,
+            steps: 0,
+        };
+        renderer.stream(&self.stream)?;
+        Ok(to_mark_and_spaces(&renderer.output))
+    }
+
+    /// 入力信号がこのプロトコルか調べて、パラメータの値を取り出す
+    pub fn recognise(&self, input: &[MarkAndSpaceMicros]) -> Result<BTreeMap<String, i64>, String> {
+        EVALUATIONS.with(|x| x.set(0));
+        let durations = input
+            .iter()
+            .flat_map(|x| vec![x.mark.0 as f64, -(x.space.unwrap_or_default().0 as f64)])
+            .filter(|x| *x != 0.0)
+            .collect::<Vec<f64>>();
+        let mut matcher = Matcher {
+            irp: self,
+            input: &durations,
+            state: MatcherState::default(),
+            bitspecs: vec![&self.bitspec],
+            steps: 0,
+        };
+        if !matcher.stream(&self.stream)? {
+            return Err("irp: input does not match.".to_string());
+        }
+        // 最後に残ったスペースは無視する
+        let rest = &durations[matcher.state.position..];
+        if !rest.iter().all(|x| *x < 0.0) {
+            return Err("irp: input has extra signals.".to_string());
+        }
+        let mut values = matcher
+            .state
+            .values
+            .iter()
+            .map(|(k, (v, _))| (k.clone(), *v))
+            .collect::<BTreeMap<String, i64>>();
+        values = self.complete_parameters(&values)?;
+        for (field, read) in matcher.state.pending.iter() {
+            let offset = match &field.offset {
+                Some(e) => self.evaluate(&values, e, 0)?,
+                None => 0,
+            };
+            let length = self.evaluate(&values, &field.length, 0)?;
+            let expected = bitfield_value(
+                self.evaluate(&values, &field.data, 0)?,
+                field.complement,
+                field.reverse,
+                length,
+                offset,
+            );
+            if expected != *read {
+                return Err("irp: bitfield check failed.".to_string());
+            }
+        }
+        // パラメータ指定があれば、それだけを返す
+        if !self.parameters.is_empty() {
+            values.retain(|k, _| self.parameters.iter().any(|x| &x.name == k));
+        }
+        Ok(values)
+    }
+}
+
+/// 符号付きの時間列(正がマーク、負がスペース)をマークアンドスペースにする
+fn to_mark_and_spaces(input: &[f64]) -> Vec<MarkAndSpaceMicros> {
+    // 同じ符号は結合する
+    let mut merged: Vec<f64> = Vec::new();
+    for x in input.iter().filter(|x| **x != 0.0) {
+        match merged.last_mut() {
+            Some(last) if last.is_sign_positive() == x.is_sign_positive() => *last += x,
+            _ => merged.push(*x),
+        }
+    }
+    // 先頭のスペースは捨てる
+    let start = merged.iter().position(|x| *x > 0.0).unwrap_or(merged.len());
+    merged[start..]
+        .chunks(2)
+        .map(|xs| MarkAndSpaceMicros {
+            mark: Microseconds(xs[0].abs().round() as u32),
+            // 最後のマークにはスペースが無い
+            space: xs.get(1).map(|x| Microseconds(x.abs().round() as u32)),
+        })
+        .collect()
+}
+
+/// 信号を生成する
+struct Renderer<'a> {
+    irp: &'a Irp,
+    values: BTreeMap<String, i64>,
+    bitspecs: Vec<&'a [Vec<IrStreamItem>]>,
+    output: Vec<f64>,
+    elapsed: f64,
+    repeat: u32,
+    /// 処理した要素の個数
+    steps: usize,
+}
+
+impl<'a> Renderer<'a> {
+    fn push(&mut self, x: f64) -> Result<(), String> {
+        if MAXIMUM_DURATIONS <= self.output.len() {
+            return Err("irp: too many durations.".to_string());
+        }
+        self.output.push(x);
+        self.elapsed += x.abs();
+        Ok(())
+    }
+
+    fn stream(&mut self, x: &'a IrStream) -> Result<(), String> {
+        let count = match x.repeat {
+            RepeatMarker::Once => 1,
+            RepeatMarker::Star => self.repeat,
+            RepeatMarker::Plus => self.repeat.max(1),
+            RepeatMarker::Count(n) => n,
+            RepeatMarker::CountPlus(n) => self.repeat.max(n),
+        };
+        if MAXIMUM_DURATIONS < count as usize {
+            return Err("irp: too many repetitions.".to_string());
+        }
+        for _ in 0..count {
+            let saved = self.elapsed;
+            self.elapsed = 0.0;
+            for item in x.items.iter() {
+                self.item(item)?;
+            }
+            self.elapsed += saved;
+        }
+        Ok(())
+    }
+
+    fn item(&mut self, x: &'a IrStreamItem) -> Result<(), String> {
+        // 何も出力しない繰り返しの入れ子でも終わるようにする
+        self.steps += 1;
+        if MAXIMUM_STEPS < self.steps {
+            return Err("irp: too many steps.".to_string());
+        }
+        match x {
+            IrStreamItem::Flash(d) => {
+                let t = self.irp.micros(&self.values, d)?;
+                self.push(t)?;
+            }
+            IrStreamItem::Gap(d) => {
+                let t = self.irp.micros(&self.values, d)?;
+                self.push(-t)?;
+            }
+            IrStreamItem::Extent(d) => {
+                let t = self.irp.micros(&self.values, d)? - self.elapsed;
+                if t <= 0.0 {
+                    return Err("irp: extent is shorter than the signal.".to_string());
+                }
+                self.push(-t)?;
+                self.elapsed = 0.0;
+            }
+            IrStreamItem::Bitfield(b) => self.bitfield(b)?,
+            IrStreamItem::Stream(s) => self.stream(s)?,
+            IrStreamItem::BitspecStream(b, s) => {
+                self.bitspecs.push(b);
+                let result = self.stream(s);
+                self.bitspecs.pop();
+                result?;
+            }
+            IrStreamItem::Assignment(n, e) => {
+                let v = self.irp.evaluate(&self.values, e, 0)?;
+                self.values.insert(n.clone(), v);
+            }
+        }
+        Ok(())
+    }
+
+    fn bitfield(&mut self, x: &Bitfield) -> Result<(), String> {
+        let eval = |e: &Expression| self.irp.evaluate(&self.values, e, 0);
+        let length = eval(&x.length)?;
+        let offset = match &x.offset {
+            Some(e) => eval(e)?,
+            None => 0,
+        };
+        let value = bitfield_value(eval(&x.data)?, x.complement, x.reverse, length, offset);
+        let bitspec = *self
+            .bitspecs
+            .last()
+            .ok_or_else(|| "irp: no bitspec.".to_string())?;
+        let width = Irp::bits_per_symbol(bitspec)?;
+        if !(0..=63).contains(&length) || length % width != 0 {
+            return Err("irp: bitfield length does not fit bitspec.".to_string());
+        }
+        for i in 0..length / width {
+            let shift = match self.irp.general.bit_order {
+                BitOrder::LsbFirst => i * width,
+                BitOrder::MsbFirst => length - (i + 1) * width,
+            };
+            let symbol = (value >> shift) & mask_of(width);
+            for item in bitspec[symbol as usize].iter() {
+                match item {
+                    IrStreamItem::Flash(_) | IrStreamItem::Gap(_) | IrStreamItem::Extent(_) => {
+                        self.item(item)?
+                    }
+                    _ => return Err("irp: bitspec must consist of durations.".to_string()),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+/// 認識中の状態(やり直しのために丸ごと保存する)
+struct MatcherState {
+    /// 入力の位置
+    position: usize,
+    /// 入力の位置の時間を使った分
+    consumed: f64,
+    /// 区間の先頭からの時間
+    elapsed: f64,
+    /// 取り出したパラメータの値と決まったビット位置
+    values: BTreeMap<String, (i64, i64)>,
+    /// 最後に検査するビットフィールドと読み取った値
+    pending: Vec<(Bitfield, i64)>,
+}
+
+/// 入力信号を認識する
+struct Matcher<'a> {
+    irp: &'a Irp,
+    input: &'a [f64],
+    state: MatcherState,
+    bitspecs: Vec<&'a [Vec<IrStreamItem>]>,
+    /// 処理した要素の個数
+    steps: usize,
+}
+
+impl<'a> Matcher<'a> {
+    /// わかっているパラメータの値
+    fn known_values(&self) -> BTreeMap<String, i64> {
+        self.state
+            .values
+            .iter()
+            .map(|(k, (v, _))| (k.clone(), *v))
+            .collect()
+    }
+
+    /// 時間が一致するか
+    fn duration(&mut self, expected: f64) -> bool {
+        let current = match self.input.get(self.state.position) {
+            Some(x) => *x,
+            // 入力の最後のスペースは無くてもよい
+            None => return expected < 0.0,
+        };
+        if current.is_sign_positive() != expected.is_sign_positive() {
+            return false;
+        }
+        let rest = current.abs() - self.state.consumed;
+        let expected = expected.abs();
+        let tolerance = (expected * RELATIVE_TOLERANCE).max(MINIMUM_TOLERANCE);
+        if (rest - expected).abs() <= tolerance {
+            self.state.position += 1;
+            self.state.consumed = 0.0;
+            self.state.elapsed += rest;
+            true
+        } else if expected < rest {
+            // 同じ符号が続いて結合されている
+            self.state.consumed += expected;
+            self.state.elapsed += expected;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 区間の先頭からの時間を揃えるスペースが一致するか
+    fn extent(&mut self, total: f64) -> bool {
+        let expected = total - self.state.elapsed;
+        let current = match self.input.get(self.state.position) {
+            Some(x) => *x,
+            None => return true,
+        };
+        if current.is_sign_positive() {
+            return false;
+        }
+        let rest = current.abs() - self.state.consumed;
+        let tolerance = (expected * RELATIVE_TOLERANCE).max(MINIMUM_TOLERANCE);
+        // 入力の最後や、それより長いスペースなら一致とみなす
+        if expected - tolerance <= rest || self.state.position + 1 == self.input.len() {
+            self.state.position += 1;
+            self.state.consumed = 0.0;
+            self.state.elapsed = 0.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn stream(&mut self, x: &'a IrStream) -> Result<bool, String> {
+        let (minimum, unlimited) = match x.repeat {
+            RepeatMarker::Once => (1, false),
+            RepeatMarker::Star => (0, true),
+            RepeatMarker::Plus => (1, true),
+            RepeatMarker::Count(n) => (n, false),
+            RepeatMarker::CountPlus(n) => (n, true),
+        };
+        if MAXIMUM_DURATIONS < minimum as usize {
+            return Err("irp: too many repetitions.".to_string());
+        }
+        for _ in 0..minimum {
+            if !self.iteration(x)? {
+                return Ok(false);
+            }
+        }
+        while unlimited && self.state.position < self.input.len() {
+            let saved = self.state.clone();
+            if !self.iteration(x)? || self.state.position == saved.position {
+                self.state = saved;
+                break;
+            }
+        }
+        Ok(true)
+    }
+
+    fn iteration(&mut self, x: &'a IrStream) -> Result<bool, String> {
+        let saved = self.state.elapsed;
+        self.state.elapsed = 0.0;
+        for item in x.items.iter() {
+            if !self.item(item)? {
+                return Ok(false);
+            }
+        }
+        self.state.elapsed += saved;
+        Ok(true)
+    }
+
+    fn item(&mut self, x: &'a IrStreamItem) -> Result<bool, String> {
+        self.steps += 1;
+        if MAXIMUM_STEPS < self.steps {
+            return Err("irp: too many steps.".to_string());
+        }
+        let values = self.known_values();
+        match x {
+            IrStreamItem::Flash(d) => Ok(self.duration(self.irp.micros(&values, d)?)),
+            IrStreamItem::Gap(d) => Ok(self.duration(-self.irp.micros(&values, d)?)),
+            IrStreamItem::Extent(d) => Ok(self.extent(self.irp.micros(&values, d)?)),
+            IrStreamItem::Bitfield(b) => self.bitfield(b),
+            IrStreamItem::Stream(s) => self.stream(s),
+            IrStreamItem::BitspecStream(b, s) => {
+                self.bitspecs.push(b);
+                let result = self.stream(s);
+                self.bitspecs.pop();
+                result
+            }
+            IrStreamItem::Assignment(n, e) => {
+                // 入力から取り出した値は上書きしない
+                if !self.state.values.contains_key(n) {
+                    if let Ok(v) = self.irp.evaluate(&values, e, 0) {
+                        self.state.values.insert(n.clone(), (v, -1));
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    /// 1シンボル読み取る
+    fn symbol(&mut self, bitspec: &'a [Vec<IrStreamItem>]) -> Result<Option<i64>, String> {
+        let values = self.known_values();
+        let mut fallback = None;
+        for (index, alternative) in bitspec.iter().enumerate() {
+            let saved = self.state.clone();
+            let mut ok = true;
+            for item in alternative.iter() {
+                ok = match item {
+                    IrStreamItem::Flash(d) => self.duration(self.irp.micros(&values, d)?),
+                    IrStreamItem::Gap(d) => self.duration(-self.irp.micros(&values, d)?),
+                    IrStreamItem::Extent(d) => self.extent(self.irp.micros(&values, d)?),
+                    _ => return Err("irp: bitspec must consist of durations.".to_string()),
+                };
+                if !ok {
+                    break;
+                }
+            }
+            if ok && self.state.consumed == 0.0 {
+                return Ok(Some(index as i64));
+            }
+            // 途中まで使った時間は、次のシンボルが同じ符号で始まるか
+            // 後に続くスペースの時だけ候補にする
+            if ok && fallback.is_none() {
+                let sign = self.input[self.state.position].is_sign_positive();
+                let continued = bitspec.iter().any(|xs| match xs.first() {
+                    Some(IrStreamItem::Flash(_)) => sign,
+                    Some(IrStreamItem::Gap(_)) => !sign,
+                    _ => false,
+                });
+                if continued || !sign {
+                    fallback = Some((index as i64, self.state.clone()));
+                }
+            }
+            self.state = saved;
+        }
+        match fallback {
+            Some((index, state)) => {
+                self.state = state;
+                Ok(Some(index))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn bitfield(&mut self, x: &Bitfield) -> Result<bool, String> {
+        let values = self.known_values();
+        let length = self.irp.evaluate(&values, &x.length, 0)?;
+        let bitspec = *self
+            .bitspecs
+            .last()
+            .ok_or_else(|| "irp: no bitspec.".to_string())?;
+        let width = Irp::bits_per_symbol(bitspec)?;
+        if !(0..=63).contains(&length) || length % width != 0 {
+            return Err("irp: bitfield length does not fit bitspec.".to_string());
+        }
+        let mut read = 0i64;
+        for i in 0..length / width {
+            let symbol = match self.symbol(bitspec)? {
+                Some(s) => s,
+                None => return Ok(false),
+            };
+            let shift = match self.irp.general.bit_order {
+                BitOrder::LsbFirst => i * width,
+                BitOrder::MsbFirst => length - (i + 1) * width,
+            };
+            read |= symbol << shift;
+        }
+        let offset = match &x.offset {
+            Some(e) => self.irp.evaluate(&values, e, 0).ok(),
+            None => Some(0),
+        };
+        match (&x.data, offset) {
+            // まだ決まっていないパラメータならビットを当てはめる
+            (Expression::Name(n), Some(offset))
+                if !self.irp.definitions.iter().any(|(d, _)| d == n) =>
+            {
+                let bits = if x.reverse {
+                    reverse_bits(read, length)
+                } else {
+                    read
+                };
+                let bits = if x.complement {
+                    !bits & mask_of(length)
+                } else {
+                    bits
+                };
+                let mask = mask_of(length) << offset.clamp(0, 62);
+                let bits = bits << offset.clamp(0, 62);
+                let (value, known) = self.state.values.get(n).copied().unwrap_or((0, 0));
+                if (value ^ bits) & mask & known != 0 {
+                    return Ok(false);
+                }
+                self.state
+                    .values
+                    .insert(n.clone(), ((value & !mask) | bits, known | mask));
+            }
+            // 求められるなら検査して、求められないなら後回しにする
+            _ => match self.irp.evaluate(&values, &x.data, 0) {
+                Ok(data) if offset.is_some() => {
+                    let expected = bitfield_value(
+                        data,
+                        x.complement,
+                        x.reverse,
+                        length,
+                        offset.unwrap_or_default(),
+                    );
+                    if expected != read {
+                        return Ok(false);
+                    }
+                }
+                _ => self.state.pending.push((x.clone(), read)),
+            },
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod irp_tests {
+    use crate::infrared_remote::{decode_phase1, decode_phase2, InfraredRemoteDemodulatedFrame};
+    use crate::irp::*;
+
+    const NEC: &str = "{38.4k,564}<1,-1|1,-3>(16,-8,D:8,S:8,F:8,~F:8,1,^108m,(16,-4,1,^108m)*)[D:0..255,S:0..255=255-D,F:0..255]";
+    const RC5: &str =
+        "{36k,msb,889}<1,-1|-1,1>((1,~F:1:6,T:1,D:5,F:6,^114m)*,T=1-T)[D:0..31,F:0..127,T@:0..1=0]";
+
+    fn parameters(xs: &[(&str, i64)]) -> BTreeMap<String, i64> {
+        xs.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+    }
+
+    #[test]
+    fn test_hostile_irp() {
+        let too_long = parse_irp("{38k,500}<1,-1|1,-3>(D:64)[D:0..1]").unwrap();
+        assert!(too_long.render(&parameters(&[("D", 1)]), 0).is_err());
+        let negative = parse_irp("{38k,500}<1,-1|1,-3>(D:N){N=0-1}[D:0..1]").unwrap();
+        assert!(negative.render(&parameters(&[("D", 1)]), 0).is_err());
+        let too_many = parse_irp("{38k,500}<1,-1|1,-3>((1,-1)1000000000)").unwrap();
+        assert!(too_many.render(&parameters(&[]), 0).is_err());
+        // 何も出力しない繰り返しの入れ子
+        let endless = parse_irp("{38k,1}<1,-1|1,-3>(((T=1)65535)65535)65535").unwrap();
+        assert_eq!(
+            endless.render(&BTreeMap::new(), 0),
+            Err("irp: too many steps.".to_string())
+        );
+        assert!(endless.recognise(&[]).is_err());
+        // 互いに二度ずつ参照し合う定義
+        let definitions = (0..30)
+            .map(|i| format!("A{}=A{}+A{}", i, i + 1, i + 1))
+            .collect::<Vec<_>>()
+            .join(",");
+        let exponential = parse_irp(&format!(
+            "{{38k,1}}<1,-1|1,-3>(A0:8){{{},A30=0}}",
+            definitions
+        ))
+        .unwrap();
+        assert_eq!(
+            exponential.render(&BTreeMap::new(), 0),
+            Err("irp: too many evaluations.".to_string())
+        );
+        assert!(exponential.recognise(&[]).is_err());
+    }
+
+    #[test]
+    fn test_deeply_nested_irp() {
+        let streams =
+            |n: usize| format!("{{38k,1}}<1,-1|1,-3>({}1{})", "(".repeat(n), ")".repeat(n));
+        let parentheses = |n: usize| {
+            format!(
+                "{{38k,1}}<1,-1|1,-3>(D=({}1{}))",
+                "(".repeat(n),
+                ")".repeat(n)
+            )
+        };
+        let operators = |n: usize| format!("{{38k,1}}<1,-1|1,-3>(D={}1)", "-~".repeat(n));
+        let terms = |n: usize| format!("{{38k,1}}<1,-1|1,-3>(D=1{})", "+1".repeat(n));
+        // 入れ子が深くても解析し直さないので時間がかからない
+        assert!(parse_irp(&streams(24)).is_ok());
+        assert!(parse_irp(&parentheses(24)).is_ok());
+        assert!(parse_irp(&operators(12)).is_ok());
+        assert!(parse_irp(&terms(24)).is_ok());
+        // スタックが溢れる前に失敗する
+        for input in [
+            streams(200_000),
+            parentheses(200_000),
+            operators(100_000),
+            terms(200_000),
+        ] {
+            assert_eq!(
+                parse_irp(&input),
+                Err(format!("irp: nesting is deeper than {}.", MAXIMUM_NESTING))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        assert_eq!(
+            expression("1+2*3"),
+            Ok((
+                "",
+                Expression::Binary(
+                    BinaryOperator::Add,
+                    Box::new(Expression::Number(1)),
+                    Box::new(Expression::Binary(
+                        BinaryOperator::Multiply,
+                        Box::new(Expression::Number(2)),
+                        Box::new(Expression::Number(3)),
+                    )),
+                )
+            ))
+        );
+        let irp = parse_irp("{38k}<1,-1|1,-3>(1)").unwrap();
+        let eval = |s: &str| irp.evaluate(&parameters(&[("D", 5)]), &expression(s).unwrap().1, 0);
+        assert_eq!(eval("255-D"), Ok(250));
+        assert_eq!(eval("2**3**2"), Ok(512));
+        assert_eq!(eval("D>3?1:0"), Ok(1));
+        assert_eq!(eval("#D"), Ok(2));
+        assert_eq!(eval("D:2:1"), Ok(2));
+        assert_eq!(eval("1<<4|1"), Ok(17));
+        assert!(eval("1/0").is_err());
+    }
+
+    #[test]
+    fn test_parse_irp() {
+        let irp = parse_irp(NEC).unwrap();
+        assert_eq!(irp.general.frequency, 38400);
+        assert_eq!(irp.general.unit, 564.0);
+        assert_eq!(irp.general.bit_order, BitOrder::LsbFirst);
+        assert_eq!(irp.bitspec.len(), 2);
+        assert_eq!(irp.stream.items.len(), 9);
+        assert_eq!(irp.parameters.len(), 3);
+        assert_eq!(
+            irp.parameters[1].default,
+            Some(Expression::Binary(
+                BinaryOperator::Subtract,
+                Box::new(Expression::Number(255)),
+                Box::new(Expression::Name("D".to_string())),
+            ))
+        );
+        //
+        let irp = parse_irp(RC5).unwrap();
+        assert_eq!(irp.general.bit_order, BitOrder::MsbFirst);
+        assert!(irp.parameters[2].memory);
+        //
+        assert!(parse_irp("{38k}<1,-1|1,-3>(1").is_err());
+        assert!(parse_irp("").is_err());
+    }
+
+    #[test]
+    fn test_render_nec() {
+        let irp = parse_irp(NEC).unwrap();
+        let signal = irp
+            .render(&parameters(&[("D", 0x40), ("F", 0x12)]), 0)
+            .unwrap();
+        // リーダー + 32ビット + ストップビット
+        assert_eq!(signal.len(), 34);
+        assert_eq!(signal[0].mark, Microseconds(16 * 564));
+        assert_eq!(signal[0].space, Some(Microseconds(8 * 564)));
+        // 既存のデコーダーで読めること
+        let frames = decode_phase1(&signal).unwrap();
+        let decoded = frames.iter().map(decode_phase2).collect::<Vec<_>>();
+        match &decoded[0] {
+            InfraredRemoteDemodulatedFrame::Nec(bits) => assert_eq!(bits.len(), 32),
+            x => panic!("{:?}", x),
+        }
+        // 周期 108ms
+        let total: u32 = signal.iter().map(|x| x.duration().0).sum();
+        assert!((107_990..=108_010).contains(&total));
+        //
+        let repeated = irp
+            .render(&parameters(&[("D", 0x40), ("F", 0x12)]), 2)
+            .unwrap();
+        assert_eq!(repeated.len(), 34 + 2 * 2);
+        assert_eq!(
+            irp.render(&parameters(&[("D", 0x40)]), 0),
+            Err("irp: parameter F is required.".to_string())
+        );
+        assert!(irp.render(&parameters(&[("D", 256), ("F", 1)]), 0).is_err());
+    }
+
+    #[test]
+    fn test_recognise_nec() {
+        let irp = parse_irp(NEC).unwrap();
+        let input = parameters(&[("D", 0x40), ("S", 0xBF), ("F", 0x12)]);
+        let signal = irp.render(&input, 1).unwrap();
+        assert_eq!(irp.recognise(&signal), Ok(input));
+        // コマンドの反転ビットが壊れていたら一致しない
+        let mut broken = signal;
+        broken[32].space = Some(Microseconds(564));
+        assert!(irp.recognise(&broken).is_err());
+    }
+
+    #[test]
+    fn test_recognise_nec_capture() {
+        use crate::infrared_remote::MarkAndSpaceMicros;
+        use crate::parsing::from_infrared_code;
+        let ircode = "5601A900180015001800140018001400190013001900140019001400170040001700150018003F0019003E0018003E0019003F0019003E00170040001800140019003E001800150018003F00180014001800140019003F0018001400170016001700150018003F001800140018003F0018003F001800140019003F0018003F0018003E0019004F03";
+        let input = from_infrared_code(ircode)
+            .unwrap()
+            .iter()
+            .map(|x| MarkAndSpaceMicros::from(*x))
+            .collect::<Vec<MarkAndSpaceMicros>>();
+        let irp = parse_irp(NEC).unwrap();
+        assert_eq!(
+            irp.recognise(&input),
+            Ok(parameters(&[("D", 0x40), ("S", 0xBF), ("F", 0x12)]))
+        );
+    }
+
+    #[test]
+    fn test_render_and_recognise_rc5() {
+        let irp = parse_irp(RC5).unwrap();
+        let input = parameters(&[("D", 5), ("F", 35), ("T", 1)]);
+        let signal = irp.render(&input, 1).unwrap();
+        // バイフェーズ変調は同じ符号が結合されている
+        assert!(signal
+            .iter()
+            .any(|x| x.mark == Microseconds(1778) || x.space == Some(Microseconds(1778))));
+        assert_eq!(irp.recognise(&signal), Ok(input));
+    }
+
+    #[test]
+    fn test_render_and_recognise_rc6() {
+        let irp = parse_irp("{36k,444,msb}<-1,1|1,-1>((6,-2,1:1,0:3,<-2,2|2,-2>(T:1),D:8,F:8,^107m)*,T=1-T)[D:0..255,F:0..255,T@:0..1=0]").unwrap();
+        let input = parameters(&[("D", 0x12), ("F", 0x0C), ("T", 0)]);
+        let signal = irp.render(&input, 1).unwrap();
+        assert_eq!(signal[0].mark, Microseconds(6 * 444));
+        assert_eq!(irp.recognise(&signal), Ok(input));
+    }
+
+    #[test]
+    fn test_recognise_sirc_capture() {
+        use crate::infrared_remote::MarkAndSpaceMicros;
+        use crate::parsing::from_infrared_code;
+        let ircode = "5B0018002E001800180018002E001800170018002F00170018001700180017002F00180017001900170018001700180018004F03";
+        let input = from_infrared_code(ircode)
+            .unwrap()
+            .iter()
+            .map(|x| MarkAndSpaceMicros::from(*x))
+            .collect::<Vec<MarkAndSpaceMicros>>();
+        let irp = parse_irp("{40k,600}<1,-1|2,-1>(4,-1,F:7,D:5,^45m)* [D:0..31,F:0..127]").unwrap();
+        assert_eq!(
+            irp.recognise(&input),
+            Ok(parameters(&[("D", 1), ("F", 21)]))
+        );
+    }
+}
@@ -0,0 +1,41 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+//! 赤外線リモコン信号の解析と変換
+//! アプリケーション本体(main.rs)とファジングターゲットから使う
+//! "std" フィーチャー無しでは no_std で ir_core だけになる
+#![cfg_attr(not(feature = "std"), no_std)]
+#[cfg(feature = "std")]
+pub mod bitvec;
+#[cfg(feature = "std")]
+pub mod cleanup;
+#[cfg(feature = "std")]
+pub mod compare;
+#[cfg(feature = "std")]
+pub mod fidelity;
+#[cfg(feature = "std")]
+pub mod formats;
+#[cfg(feature = "std")]
+pub mod infrared_remote;
+pub mod ir_core;
+#[cfg(feature = "std")]
+pub mod irp;
+#[cfg(feature = "std")]
+pub mod lirc;
+#[cfg(feature = "std")]
+pub mod macros;
+#[cfg(feature = "std")]
+pub mod parsing;
+#[cfg(feature = "std")]
+pub mod protocol;
+#[cfg(feature = "std")]
+pub mod segmentation;
+#[cfg(feature = "std")]
+pub mod serial;
+#[cfg(feature = "std")]
+pub mod signal;
+#[cfg(feature = "std")]
+pub mod stream;
+#[cfg(feature = "std")]
+pub mod time_base;
@@ -0,0 +1,715 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+//! Linux LIRCキャラクタデバイス(/dev/lircN)での受信と送信
+use crate::infrared_remote::{
+    decode_phase1, decode_phase2, InfraredRemoteDemodulatedFrame, MarkAndSpaceMicros, Microseconds,
+    IR_CARRIER_FREQ, THRESHOLD_FRAME_GAP,
+};
+use crate::protocol::ProtocolRegistry;
+use crate::segmentation::segment_frames;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// mode2サンプルの種類のマスク
+const LIRC_MODE2_MASK: u32 = 0xFF00_0000;
+/// mode2サンプルの値のマスク
+const LIRC_VALUE_MASK: u32 = 0x00FF_FFFF;
+const LIRC_MODE2_SPACE: u32 = 0x0000_0000;
+const LIRC_MODE2_PULSE: u32 = 0x0100_0000;
+const LIRC_MODE2_FREQUENCY: u32 = 0x0200_0000;
+const LIRC_MODE2_TIMEOUT: u32 = 0x0300_0000;
+const LIRC_MODE2_OVERFLOW: u32 = 0x0400_0000;
+
+/// これ以上長いスペースで送信の終わりとみなす(リピート信号の間隔より長い)
+pub const TRANSMISSION_TIMEOUT: Microseconds = Microseconds(150_000);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// LIRC_MODE_MODE2 のサンプル
+pub enum Mode2Sample {
+    /// マーク(us)
+    Pulse(Microseconds),
+    /// スペース(us)
+    Space(Microseconds),
+    /// キャリア周波数(Hz)
+    Frequency(u32),
+    /// 受信タイムアウト(us)
+    Timeout(Microseconds),
+    /// 受信バッファがあふれた
+    Overflow,
+}
+
+impl Mode2Sample {
+    /// デバイスから読んだ32ビットの値から
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        let value = raw & LIRC_VALUE_MASK;
+        match raw & LIRC_MODE2_MASK {
+            LIRC_MODE2_SPACE => Some(Mode2Sample::Space(Microseconds(value))),
+            LIRC_MODE2_PULSE => Some(Mode2Sample::Pulse(Microseconds(value))),
+            LIRC_MODE2_FREQUENCY => Some(Mode2Sample::Frequency(value)),
+            LIRC_MODE2_TIMEOUT => Some(Mode2Sample::Timeout(Microseconds(value))),
+            LIRC_MODE2_OVERFLOW => Some(Mode2Sample::Overflow),
+            _ => None,
+        }
+    }
+
+    /// デバイスに書く32ビットの値にする
+    pub fn to_raw(&self) -> u32 {
+        match self {
+            Mode2Sample::Space(x) => LIRC_MODE2_SPACE | (x.0 & LIRC_VALUE_MASK),
+            Mode2Sample::Pulse(x) => LIRC_MODE2_PULSE | (x.0 & LIRC_VALUE_MASK),
+            Mode2Sample::Frequency(x) => LIRC_MODE2_FREQUENCY | (x & LIRC_VALUE_MASK),
+            Mode2Sample::Timeout(x) => LIRC_MODE2_TIMEOUT | (x.0 & LIRC_VALUE_MASK),
+            Mode2Sample::Overflow => LIRC_MODE2_OVERFLOW,
+        }
+    }
+}
+
+/// 受信を止めたか調べる間隔
+pub const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// 受信デバイス
+pub trait LircReceiver {
+    /// サンプルを1つ読む(デバイスが閉じられたらNone)
+    /// しばらくサンプルが来なければ io::ErrorKind::TimedOut で戻る
+    fn read_sample(&mut self) -> io::Result<Option<Mode2Sample>>;
+}
+
+/// mode2サンプルを読み出せるもの(デバイスファイル、パイプなど)からの受信
+pub struct Mode2Reader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> Mode2Reader<R> {
+    pub fn new(reader: R) -> Self {
+        Mode2Reader { reader }
+    }
+}
+
+impl<R: Read> LircReceiver for Mode2Reader<R> {
+    fn read_sample(&mut self) -> io::Result<Option<Mode2Sample>> {
+        loop {
+            let mut buffer = [0u8; 4];
+            match self.reader.read_exact(&mut buffer) {
+                Ok(()) => {
+                    // 知らない種類のサンプルは読み飛ばす
+                    if let Some(x) = Mode2Sample::from_raw(u32::from_ne_bytes(buffer)) {
+                        return Ok(Some(x));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// mode2サンプルを送信ごとのマークアンドスペース列にまとめる
+#[derive(Clone, Debug)]
+pub struct Mode2Assembler {
+    current: Vec<MarkAndSpaceMicros>,
+    timeout: Microseconds,
+}
+
+impl Default for Mode2Assembler {
+    fn default() -> Self {
+        Mode2Assembler::new(TRANSMISSION_TIMEOUT)
+    }
+}
+
+impl Mode2Assembler {
+    /// timeout以上のスペースで送信の終わりとみなす
+    pub fn new(timeout: Microseconds) -> Self {
+        Mode2Assembler {
+            current: Vec::new(),
+            timeout,
+        }
+    }
+
+    /// サンプルを1つ加える
+    /// 送信が終わればその信号を返す(最後のマークはスペース無し)
+    pub fn push(&mut self, sample: Mode2Sample) -> Option<Vec<MarkAndSpaceMicros>> {
+        match sample {
+            Mode2Sample::Pulse(x) => {
+                match self.current.last_mut() {
+                    // 続けて来たマークはつなげる
+                    Some(last) if last.trailing_space().is_none() => last.mark = last.mark + x,
+                    _ => self.current.push(MarkAndSpaceMicros::mark_only(x)),
+                }
+                None
+            }
+            Mode2Sample::Space(x) => {
+                // 先頭のスペースは捨てる
+                let last = self.current.last_mut()?;
+                let space = last.space.unwrap_or_default() + x;
+                last.space = Some(space);
+                if self.timeout <= space {
+                    self.finish()
+                } else {
+                    None
+                }
+            }
+            Mode2Sample::Timeout(_) => self.finish(),
+            Mode2Sample::Overflow => {
+                // 途中が欠けた信号は捨てる
+                self.current.clear();
+                None
+            }
+            Mode2Sample::Frequency(_) => None,
+        }
+    }
+
+    /// 受信中の信号を送信の終わりとして返す
+    pub fn finish(&mut self) -> Option<Vec<MarkAndSpaceMicros>> {
+        let mut result = std::mem::take(&mut self.current);
+        let last = result.last_mut()?;
+        last.space = None;
+        Some(result)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 受信した信号
+pub struct CapturedSignal {
+    /// 受信したマークアンドスペース列
+    pub signal: Vec<MarkAndSpaceMicros>,
+    /// 復調結果
+    pub frames: Vec<InfraredRemoteDemodulatedFrame>,
+}
+
+impl CapturedSignal {
+    /// 受信した信号を復調する
+    pub fn decode(signal: Vec<MarkAndSpaceMicros>) -> Result<Self, String> {
+        let frames = decode_phase1(&signal)?
+            .iter()
+            .map(decode_phase2)
+            .collect::<Vec<InfraredRemoteDemodulatedFrame>>();
+        Ok(CapturedSignal { signal, frames })
+    }
+}
+
+/// デバイスが閉じられるか、runningがfalseになるまで受信する
+/// 送信が終わるたびに復調してon_signalを呼ぶ
+/// runningは読み出しがタイムアウトするたびにも調べる
+pub fn capture<D: LircReceiver, F: FnMut(Result<CapturedSignal, String>)>(
+    device: &mut D,
+    running: &AtomicBool,
+    mut on_signal: F,
+) -> io::Result<()> {
+    let mut assembler = Mode2Assembler::default();
+    while running.load(Ordering::SeqCst) {
+        match device.read_sample() {
+            Ok(Some(sample)) => {
+                if let Some(signal) = assembler.push(sample) {
+                    on_signal(CapturedSignal::decode(signal));
+                }
+            }
+            Ok(None) => {
+                if let Some(signal) = assembler.finish() {
+                    on_signal(CapturedSignal::decode(signal));
+                }
+                break;
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod ioctl {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    /// _IOR('i', 0x00, __u32)
+    pub const LIRC_GET_FEATURES: u64 = 0x8004_6900;
+    /// _IOW('i', 0x12, __u32)
+    pub const LIRC_SET_REC_MODE: u64 = 0x4004_6912;
+    /// _IOW('i', 0x18, __u32)
+    pub const LIRC_SET_REC_TIMEOUT: u64 = 0x4004_6918;
+    /// _IOW('i', 0x11, __u32)
+    pub const LIRC_SET_SEND_MODE: u64 = 0x4004_6911;
+    /// _IOW('i', 0x13, __u32)
+    pub const LIRC_SET_SEND_CARRIER: u64 = 0x4004_6913;
+    /// _IOW('i', 0x15, __u32)
+    pub const LIRC_SET_SEND_DUTY_CYCLE: u64 = 0x4004_6915;
+    pub const LIRC_MODE_PULSE: u32 = 0x0000_0002;
+    pub const LIRC_MODE_MODE2: u32 = 0x0000_0004;
+    pub const LIRC_CAN_SEND_PULSE: u32 = 0x0000_0002;
+    pub const LIRC_CAN_SET_SEND_CARRIER: u32 = 0x0000_0100;
+    pub const LIRC_CAN_SET_SEND_DUTY_CYCLE: u32 = 0x0000_0200;
+    pub const LIRC_CAN_REC_MODE2: u32 = 0x0004_0000;
+
+    /// 値を読むioctl
+    pub fn read(file: &File, request: u64) -> io::Result<u32> {
+        let mut value: u32 = 0;
+        // 引数は__u32へのポインタ
+        let result = unsafe { libc::ioctl(file.as_raw_fd(), request as _, &mut value) };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// 値を書くioctl
+    pub fn write(file: &File, request: u64, value: u32) -> io::Result<()> {
+        let result = unsafe { libc::ioctl(file.as_raw_fd(), request as _, &value) };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// 読めるようになるまでtimeoutだけ待つデバイスファイル
+#[cfg(target_os = "linux")]
+pub struct PollingFile {
+    file: std::fs::File,
+    timeout: std::time::Duration,
+}
+
+#[cfg(target_os = "linux")]
+impl Read for PollingFile {
+    /// LIRCデバイスはサンプル単位で読めるので、サンプルの途中でタイムアウトすることはない
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        let mut fds = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let result = unsafe { libc::poll(&mut fds, 1, self.timeout.as_millis() as i32) };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else if result == 0 {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "lirc: poll timed out.",
+            ))
+        } else {
+            self.file.read(buf)
+        }
+    }
+}
+
+/// LIRCデバイスを LIRC_MODE_MODE2 で開く
+/// 読み出しは POLL_INTERVAL でタイムアウトする
+#[cfg(target_os = "linux")]
+pub fn open_receiver(path: &str) -> io::Result<Mode2Reader<PollingFile>> {
+    let file = std::fs::File::open(path)?;
+    let features = ioctl::read(&file, ioctl::LIRC_GET_FEATURES)?;
+    if features & ioctl::LIRC_CAN_REC_MODE2 == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} は mode2 で受信できません。", path),
+        ));
+    }
+    ioctl::write(&file, ioctl::LIRC_SET_REC_MODE, ioctl::LIRC_MODE_MODE2)?;
+    // 送信の終わりでタイムアウトのサンプルが来るようにする(対応していなければスペースで判断する)
+    let _ = ioctl::write(&file, ioctl::LIRC_SET_REC_TIMEOUT, TRANSMISSION_TIMEOUT.0);
+    Ok(Mode2Reader::new(PollingFile {
+        file,
+        timeout: POLL_INTERVAL,
+    }))
+}
+
+/// LIRCはLinuxだけ
+#[cfg(not(target_os = "linux"))]
+pub fn open_receiver(path: &str) -> io::Result<Mode2Reader<std::fs::File>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("{}: LIRC はLinuxだけで使えます。", path),
+    ))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 送信の設定
+pub struct TransmitOptions {
+    /// キャリア周波数(Hz)
+    pub carrier_frequency: u32,
+    /// デューティ比(%)
+    pub duty_cycle: u32,
+    /// 繰り返して送る回数(0なら1回だけ送る)
+    pub repeats: usize,
+}
+
+impl Default for TransmitOptions {
+    fn default() -> Self {
+        TransmitOptions {
+            carrier_frequency: IR_CARRIER_FREQ as u32,
+            duty_cycle: 33,
+            repeats: 0,
+        }
+    }
+}
+
+/// 送信デバイス
+pub trait LircTransmitter {
+    /// LIRC_SET_SEND_CARRIER
+    fn set_send_carrier(&mut self, frequency: u32) -> io::Result<()>;
+    /// LIRC_SET_SEND_DUTY_CYCLE
+    fn set_send_duty_cycle(&mut self, percent: u32) -> io::Result<()>;
+    /// マークで始まりマークで終わる、マークとスペースを交互に並べた時間(us)を送る
+    /// 合計は MAXIMUM_WRITE_DURATION より短い
+    /// 送り終わるまで戻らない
+    fn write_pulses(&mut self, pulses: &[u32]) -> io::Result<()>;
+    /// 次の送信まで待つ
+    fn wait(&mut self, duration: Microseconds);
+}
+
+/// 送ったものを記録するだけの送信デバイス
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecordingTransmitter {
+    /// 設定したキャリア周波数
+    pub carrier_frequency: Option<u32>,
+    /// 設定したデューティ比
+    pub duty_cycle: Option<u32>,
+    /// 送ったパルス
+    pub sent: Vec<Vec<u32>>,
+    /// 待った時間
+    pub waits: Vec<Microseconds>,
+}
+
+impl LircTransmitter for RecordingTransmitter {
+    fn set_send_carrier(&mut self, frequency: u32) -> io::Result<()> {
+        self.carrier_frequency = Some(frequency);
+        Ok(())
+    }
+    fn set_send_duty_cycle(&mut self, percent: u32) -> io::Result<()> {
+        self.duty_cycle = Some(percent);
+        Ok(())
+    }
+    fn write_pulses(&mut self, pulses: &[u32]) -> io::Result<()> {
+        self.sent.push(pulses.to_vec());
+        Ok(())
+    }
+    fn wait(&mut self, duration: Microseconds) {
+        self.waits.push(duration);
+    }
+}
+
+/// LIRCデバイスに一度に書ける長さ(カーネルはこれ以上長い送信を受け付けない)
+pub const MAXIMUM_WRITE_DURATION: Microseconds = Microseconds(500_000);
+
+/// 一度に書くパルスと、書いた後に待つ時間に分ける
+/// フレーム間隔で分けて、それでも MAXIMUM_WRITE_DURATION を超えるフレームはその前のスペースで分ける
+fn split_writes(input: &[MarkAndSpaceMicros]) -> io::Result<Vec<(Vec<u32>, Option<Microseconds>)>> {
+    let frames = segment_frames(ProtocolRegistry::builtin_ref(), input)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut writes = Vec::new();
+    for segmented in frames {
+        let frame = segmented.frame.0;
+        let mut pulses: Vec<u32> = Vec::new();
+        let mut elapsed = Microseconds(0);
+        for (index, x) in frame.iter().enumerate() {
+            if MAXIMUM_WRITE_DURATION <= x.mark {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "transmit: mark is too long.",
+                ));
+            }
+            if !pulses.is_empty() && MAXIMUM_WRITE_DURATION <= elapsed + x.mark {
+                // 直前のスペースは送らずに待つ
+                let space = pulses.pop().map(Microseconds);
+                writes.push((std::mem::take(&mut pulses), space));
+                elapsed = Microseconds(0);
+            }
+            pulses.push(x.mark.0);
+            elapsed = elapsed + x.mark;
+            if index + 1 < frame.len() {
+                let space = x.space.unwrap_or_default();
+                pulses.push(space.0);
+                elapsed = elapsed + space;
+            }
+        }
+        writes.push((pulses, segmented.gap));
+    }
+    Ok(writes)
+}
+
+/// マークアンドスペース列を送る
+/// フレームごとに書いて、フレーム間隔の分だけ待つ
+/// 最後のスペースは、繰り返すときに次の送信まで待つ時間にする(無ければ THRESHOLD_FRAME_GAP)
+pub fn transmit<T: LircTransmitter + ?Sized>(
+    device: &mut T,
+    input: &[MarkAndSpaceMicros],
+    options: &TransmitOptions,
+) -> io::Result<()> {
+    let writes = split_writes(input)?;
+    device.set_send_carrier(options.carrier_frequency)?;
+    device.set_send_duty_cycle(options.duty_cycle)?;
+    let mut gap = None;
+    for _ in 0..=options.repeats {
+        for (pulses, next) in writes.iter() {
+            if let Some(x) = gap {
+                device.wait(x);
+            }
+            device.write_pulses(pulses)?;
+            gap = Some(next.unwrap_or(THRESHOLD_FRAME_GAP));
+        }
+    }
+    Ok(())
+}
+
+/// LIRCデバイスでの送信
+#[cfg(target_os = "linux")]
+pub struct LircSender {
+    file: std::fs::File,
+    features: u32,
+}
+
+#[cfg(target_os = "linux")]
+impl LircTransmitter for LircSender {
+    /// 設定できないデバイスでは何もしない
+    fn set_send_carrier(&mut self, frequency: u32) -> io::Result<()> {
+        if self.features & ioctl::LIRC_CAN_SET_SEND_CARRIER == 0 {
+            return Ok(());
+        }
+        ioctl::write(&self.file, ioctl::LIRC_SET_SEND_CARRIER, frequency)
+    }
+    /// 設定できないデバイスでは何もしない
+    fn set_send_duty_cycle(&mut self, percent: u32) -> io::Result<()> {
+        if self.features & ioctl::LIRC_CAN_SET_SEND_DUTY_CYCLE == 0 {
+            return Ok(());
+        }
+        ioctl::write(&self.file, ioctl::LIRC_SET_SEND_DUTY_CYCLE, percent)
+    }
+    fn write_pulses(&mut self, pulses: &[u32]) -> io::Result<()> {
+        let bytes = pulses
+            .iter()
+            .flat_map(|x| x.to_ne_bytes())
+            .collect::<Vec<u8>>();
+        self.file.write_all(&bytes)
+    }
+    fn wait(&mut self, duration: Microseconds) {
+        std::thread::sleep(std::time::Duration::from_micros(duration.0 as u64));
+    }
+}
+
+/// LIRCデバイスを LIRC_MODE_PULSE で開く
+#[cfg(target_os = "linux")]
+pub fn open_transmitter(path: &str) -> io::Result<Box<dyn LircTransmitter + Send>> {
+    let file = std::fs::OpenOptions::new().write(true).open(path)?;
+    let features = ioctl::read(&file, ioctl::LIRC_GET_FEATURES)?;
+    if features & ioctl::LIRC_CAN_SEND_PULSE == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} は送信できません。", path),
+        ));
+    }
+    ioctl::write(&file, ioctl::LIRC_SET_SEND_MODE, ioctl::LIRC_MODE_PULSE)?;
+    Ok(Box::new(LircSender { file, features }))
+}
+
+/// LIRCはLinuxだけ
+#[cfg(not(target_os = "linux"))]
+pub fn open_transmitter(path: &str) -> io::Result<Box<dyn LircTransmitter + Send>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("{}: LIRC はLinuxだけで使えます。", path),
+    ))
+}
+
+#[cfg(test)]
+mod lirc_tests {
+    use crate::infrared_remote::{
+        encode_to_mark_and_spaces, Bit, InfraredRemoteDemodulatedFrame, MarkAndSpaceMicros,
+        Microseconds,
+    };
+    use crate::lirc::{
+        capture, transmit, LircReceiver, Mode2Assembler, Mode2Reader, Mode2Sample,
+        RecordingTransmitter, TransmitOptions, MAXIMUM_WRITE_DURATION, TRANSMISSION_TIMEOUT,
+    };
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// マークアンドスペース列をデバイスから読んだバイト列にする
+    fn to_bytes(input: &[MarkAndSpaceMicros]) -> Vec<u8> {
+        input
+            .iter()
+            .flat_map(|x| {
+                std::iter::once(Mode2Sample::Pulse(x.mark))
+                    .chain(x.trailing_space().map(Mode2Sample::Space))
+            })
+            .flat_map(|x| x.to_raw().to_ne_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_mode2_sample() {
+        assert_eq!(
+            Mode2Sample::from_raw(0x0100_0230),
+            Some(Mode2Sample::Pulse(Microseconds(0x230)))
+        );
+        assert_eq!(
+            Mode2Sample::from_raw(0x0300_0000 | 150_000),
+            Some(Mode2Sample::Timeout(TRANSMISSION_TIMEOUT))
+        );
+        assert_eq!(Mode2Sample::from_raw(0x0500_0000), None);
+        let x = Mode2Sample::Space(Microseconds(4500));
+        assert_eq!(Mode2Sample::from_raw(x.to_raw()), Some(x));
+    }
+
+    #[test]
+    fn test_assembler() {
+        let mut assembler = Mode2Assembler::default();
+        let samples = [
+            Mode2Sample::Space(Microseconds(1_000_000)),
+            Mode2Sample::Pulse(Microseconds(4000)),
+            Mode2Sample::Pulse(Microseconds(5000)),
+            Mode2Sample::Space(Microseconds(4500)),
+            Mode2Sample::Frequency(38000),
+            Mode2Sample::Pulse(Microseconds(560)),
+        ];
+        for x in samples {
+            assert_eq!(assembler.push(x), None);
+        }
+        assert_eq!(
+            assembler.push(Mode2Sample::Timeout(Microseconds(150_000))),
+            Some(vec!(
+                MarkAndSpaceMicros {
+                    mark: Microseconds(9000),
+                    space: Some(Microseconds(4500))
+                },
+                MarkAndSpaceMicros::mark_only(Microseconds(560))
+            ))
+        );
+        // あふれたら捨てる
+        assert_eq!(assembler.push(Mode2Sample::Pulse(Microseconds(560))), None);
+        assert_eq!(assembler.push(Mode2Sample::Overflow), None);
+        assert_eq!(assembler.finish(), None);
+    }
+
+    #[test]
+    fn test_capture_replay() {
+        let nec = InfraredRemoteDemodulatedFrame::Nec(
+            [0x00u8, 0xFF, 0x12, 0xED]
+                .iter()
+                .flat_map(|byte| (0..8).map(move |i| Bit((byte >> i) & 1)))
+                .collect(),
+        );
+        let signal = encode_to_mark_and_spaces(std::slice::from_ref(&nec)).unwrap();
+        // 長いスペースをはさんで2回押す
+        let mut bytes = to_bytes(&signal);
+        bytes.extend(
+            Mode2Sample::Space(Microseconds(500_000))
+                .to_raw()
+                .to_ne_bytes(),
+        );
+        bytes.extend(to_bytes(&signal));
+        let mut device = Mode2Reader::new(bytes.as_slice());
+        let mut captured = Vec::new();
+        capture(&mut device, &AtomicBool::new(true), |x| {
+            captured.push(x.unwrap())
+        })
+        .unwrap();
+        assert_eq!(captured.len(), 2);
+        for x in captured {
+            assert_eq!(x.frames, vec!(nec.clone()));
+            assert_eq!(x.signal.last().unwrap().trailing_space(), None);
+        }
+    }
+
+    /// 何も来ないままタイムアウトを繰り返す受信デバイス
+    struct IdleReceiver<'a> {
+        running: &'a AtomicBool,
+        polls: usize,
+    }
+
+    impl<'a> LircReceiver for IdleReceiver<'a> {
+        fn read_sample(&mut self) -> io::Result<Option<Mode2Sample>> {
+            self.polls += 1;
+            if 3 <= self.polls {
+                self.running.store(false, Ordering::SeqCst);
+            }
+            Err(io::Error::new(io::ErrorKind::TimedOut, "idle"))
+        }
+    }
+
+    #[test]
+    fn test_capture_stop() {
+        // 信号が来なくても止められる
+        let running = AtomicBool::new(true);
+        let mut device = IdleReceiver {
+            running: &running,
+            polls: 0,
+        };
+        let mut captured = 0;
+        capture(&mut device, &running, |_| captured += 1).unwrap();
+        assert_eq!(device.polls, 3);
+        assert_eq!(captured, 0);
+    }
+
+    #[test]
+    fn test_transmit() {
+        let ms = |mark: u32, space: u32| MarkAndSpaceMicros {
+            mark: Microseconds(mark),
+            space: Some(Microseconds(space)),
+        };
+        let input = vec![
+            ms(9000, 4500),
+            MarkAndSpaceMicros::mark_only(Microseconds(300)),
+            ms(260, 560),
+            ms(560, 40000),
+        ];
+        let options = TransmitOptions {
+            carrier_frequency: 40000,
+            duty_cycle: 50,
+            repeats: 2,
+        };
+        let mut device = RecordingTransmitter::default();
+        transmit(&mut device, &input, &options).unwrap();
+        assert_eq!(device.carrier_frequency, Some(40000));
+        assert_eq!(device.duty_cycle, Some(50));
+        // スペースの無いマークはつなげて、最後のスペースは送らずに待つ
+        let pulses = vec![9000, 4500, 560, 560, 560];
+        assert_eq!(device.sent, vec!(pulses.clone(), pulses.clone(), pulses));
+        assert_eq!(device.waits, vec!(Microseconds(40000), Microseconds(40000)));
+        assert!(transmit(&mut device, &[], &options).is_err());
+    }
+
+    #[test]
+    fn test_transmit_long() {
+        let nec = InfraredRemoteDemodulatedFrame::Nec(
+            [0x00u8, 0xFF, 0x12, 0xED]
+                .iter()
+                .flat_map(|byte| (0..8).map(move |i| Bit((byte >> i) & 1)))
+                .collect(),
+        );
+        let mut frame = encode_to_mark_and_spaces(std::slice::from_ref(&nec)).unwrap();
+        frame.last_mut().unwrap().space = Some(Microseconds(100_000));
+        // 100msの間隔で8回送ったあと、間隔の無い1秒の信号を送る
+        let mut input = frame.repeat(8);
+        input.extend(
+            std::iter::repeat(MarkAndSpaceMicros {
+                mark: Microseconds(300),
+                space: Some(Microseconds(700)),
+            })
+            .take(1000),
+        );
+        let mut device = RecordingTransmitter::default();
+        transmit(&mut device, &input, &TransmitOptions::default()).unwrap();
+        let total = |xs: &[u32]| xs.iter().map(|x| *x as u64).sum::<u64>();
+        for pulses in device.sent.iter() {
+            assert_eq!(pulses.len() % 2, 1);
+            assert!(total(pulses) < MAXIMUM_WRITE_DURATION.0 as u64);
+        }
+        assert!(10 <= device.sent.len());
+        assert_eq!(device.waits.len() + 1, device.sent.len());
+        assert!(device.waits[..7]
+            .iter()
+            .all(|x| *x == Microseconds(100_000)));
+        // 待った時間も合わせれば元の長さになる
+        let sent = device.sent.iter().map(|x| total(x)).sum::<u64>();
+        let waited = device.waits.iter().map(|x| x.0 as u64).sum::<u64>();
+        let expected = input.iter().map(|x| x.duration().0 as u64).sum::<u64>();
+        assert_eq!(sent + waited, expected - 700);
+    }
+}
@@ -0,0 +1,457 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+//! 名前をつけた赤外線リモコン信号を、待ち時間と繰り返しつきで順番に送るマクロ
+use crate::infrared_remote::{
+    encode_phase2, InfraredRemoteDemodulatedFrame, MarkAndSpaceMicros, Microseconds, TimedFrame,
+    IR_CARRIER_FREQ,
+};
+use crate::lirc::{transmit, LircTransmitter, TransmitOptions};
+use crate::protocol::ProtocolRegistry;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// マクロで送る赤外線リモコン信号
+pub enum MacroCode {
+    /// MacroBook に名前をつけて登録した信号
+    Named(String),
+    /// マクロの中に直接書いた信号
+    Inline(InfraredRemoteDemodulatedFrame),
+}
+
+/// Send で送る回数の上限
+pub const MAXIMUM_SEND_COUNT: u32 = 100;
+
+fn default_count() -> u32 {
+    1
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// マクロの手順
+pub enum MacroStep {
+    /// 信号をcount回送る(間隔はプロトコルのフレーム周期かフレーム間隔)
+    Send {
+        code: MacroCode,
+        #[serde(default = "default_count")]
+        count: u32,
+    },
+    /// 直前の信号の終わりから次の信号まで待つ(続けて書いたら足す)
+    /// フレーム周期かフレーム間隔より短ければ、そちらだけ待つ
+    Delay(Microseconds),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// マクロ
+pub struct Macro {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub steps: Vec<MacroStep>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// 名前をつけた赤外線リモコン信号とマクロの一覧
+/// JSONにしてバージョン管理する
+pub struct MacroBook {
+    #[serde(default)]
+    pub codes: BTreeMap<String, Vec<InfraredRemoteDemodulatedFrame>>,
+    #[serde(default)]
+    pub macros: Vec<Macro>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 送信計画の中の1つの信号
+pub struct PlannedSignal {
+    /// マクロの始まりから信号の始まりまでの時間
+    pub start: Microseconds,
+    /// キャリア周波数(Hz)
+    pub carrier_frequency: u32,
+    /// 最後はスペースの無いマーク
+    pub signal: Vec<MarkAndSpaceMicros>,
+    /// 信号の終わりから次の信号まで待つ時間
+    pub wait: Microseconds,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// マクロを組み立てた送信計画
+pub struct TransmissionPlan {
+    pub signals: Vec<PlannedSignal>,
+    /// 最後の信号の終わりまでの時間
+    pub duration: Microseconds,
+}
+
+/// マークアンドスペース列の長さ
+fn signal_duration(input: &[MarkAndSpaceMicros]) -> Microseconds {
+    input
+        .iter()
+        .fold(Microseconds(0), |acc, x| acc + x.duration())
+}
+
+impl MacroBook {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| e.to_string())
+    }
+
+    /// 名前でマクロを探す
+    pub fn find(&self, name: &str) -> Option<&Macro> {
+        self.macros.iter().find(|x| x.name == name)
+    }
+
+    /// マクロを追加する
+    /// 同じ名前のマクロがあれば置き換える
+    pub fn insert(&mut self, input: Macro) {
+        match self.macros.iter_mut().find(|x| x.name == input.name) {
+            Some(x) => *x = input,
+            None => self.macros.push(input),
+        }
+    }
+
+    /// マクロを消す
+    pub fn remove(&mut self, name: &str) -> Option<Macro> {
+        let index = self.macros.iter().position(|x| x.name == name)?;
+        Some(self.macros.remove(index))
+    }
+
+    /// 信号の名前を解決する
+    fn resolve<'a>(
+        &'a self,
+        code: &'a MacroCode,
+    ) -> Result<&'a [InfraredRemoteDemodulatedFrame], String> {
+        match code {
+            MacroCode::Named(name) => self
+                .codes
+                .get(name)
+                .map(|x| x.as_slice())
+                .ok_or_else(|| format!("{} の信号がありません。", name)),
+            MacroCode::Inline(frame) => Ok(std::slice::from_ref(frame)),
+        }
+    }
+
+    /// 1つの手順の信号を1回分のマークアンドスペースにする(最後のスペースはフレーム間隔)
+    fn encode_step(
+        &self,
+        registry: &ProtocolRegistry,
+        code: &MacroCode,
+        count: u32,
+    ) -> Result<Vec<MarkAndSpaceMicros>, String> {
+        let frames = self.resolve(code)?;
+        if frames.is_empty() {
+            return Err("信号にフレームがありません。".to_string());
+        }
+        if count == 0 {
+            return Err("送る回数が0です。".to_string());
+        }
+        if MAXIMUM_SEND_COUNT < count {
+            return Err(format!(
+                "送る回数 {} は {} 回までです。",
+                count, MAXIMUM_SEND_COUNT
+            ));
+        }
+        let timed = frames
+            .iter()
+            .cloned()
+            .map(TimedFrame::from)
+            .collect::<Vec<TimedFrame>>();
+        Ok(encode_phase2(&registry.encode_timed_frames(&timed)?))
+    }
+
+    /// マクロを検査する
+    /// すべての問題を1行ずつ返す
+    pub fn validate(&self, registry: &ProtocolRegistry, input: &Macro) -> Result<(), String> {
+        let mut errors = Vec::new();
+        if input.name.trim().is_empty() {
+            errors.push("マクロの名前がありません。".to_string());
+        }
+        if !input
+            .steps
+            .iter()
+            .any(|x| matches!(x, MacroStep::Send { .. }))
+        {
+            errors.push(format!("{}: 送る信号がありません。", input.name));
+        }
+        for (index, step) in input.steps.iter().enumerate() {
+            if let MacroStep::Send { code, count } = step {
+                if let Err(e) = self.encode_step(registry, code, *count) {
+                    errors.push(format!("{} {}番目: {}", input.name, index + 1, e));
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// マクロを送信計画にする
+    /// count回送る信号は1回ずつ別の信号にして、それぞれの後にフレーム間隔だけ待つ
+    pub fn compile(
+        &self,
+        registry: &ProtocolRegistry,
+        name: &str,
+    ) -> Result<TransmissionPlan, String> {
+        let input = self
+            .find(name)
+            .ok_or_else(|| format!("{} のマクロがありません。", name))?;
+        self.validate(registry, input)?;
+        let mut plan = TransmissionPlan::default();
+        // 最初の信号の前の待ち時間
+        let mut start = Microseconds(0);
+        // 直前の信号のフレーム周期かフレーム間隔の待ち時間
+        let mut gap = Microseconds(0);
+        // 直前から続けて書いた Delay の合計
+        let mut delay: Option<Microseconds> = None;
+        for step in input.steps.iter() {
+            match step {
+                MacroStep::Send { code, count } => {
+                    let mut signal = self.encode_step(registry, code, *count)?;
+                    let wait = signal
+                        .last_mut()
+                        .and_then(|x| x.space.take())
+                        .unwrap_or_default();
+                    gap = wait;
+                    let carrier_frequency = self.resolve(code)?[0]
+                        .protocol()
+                        .and_then(|x| registry.find(x))
+                        .map_or(IR_CARRIER_FREQ as u32, |x| x.carrier_frequency);
+                    for _ in 0..*count {
+                        if let Some(previous) = plan.signals.last() {
+                            start =
+                                previous.start + signal_duration(&previous.signal) + previous.wait;
+                        }
+                        plan.signals.push(PlannedSignal {
+                            start,
+                            carrier_frequency,
+                            signal: signal.clone(),
+                            wait,
+                        });
+                    }
+                }
+                MacroStep::Delay(duration) => {
+                    let total = delay.unwrap_or_default() + *duration;
+                    match plan.signals.last_mut() {
+                        Some(previous) => previous.wait = gap.max(total),
+                        None => start = start + *duration,
+                    }
+                    delay = Some(total);
+                    continue;
+                }
+            }
+            delay = None;
+        }
+        plan.duration = plan
+            .signals
+            .last()
+            .map_or(Microseconds(0), |x| x.start + signal_duration(&x.signal));
+        Ok(plan)
+    }
+}
+
+/// 送信計画の通りに送る
+/// 最後の信号の後は待たない
+pub fn run_plan<T: LircTransmitter + ?Sized>(
+    device: &mut T,
+    plan: &TransmissionPlan,
+    duty_cycle: u32,
+) -> io::Result<()> {
+    if let Some(first) = plan.signals.first() {
+        if first.start.0 > 0 {
+            device.wait(first.start);
+        }
+    }
+    for (index, x) in plan.signals.iter().enumerate() {
+        let options = TransmitOptions {
+            carrier_frequency: x.carrier_frequency,
+            duty_cycle,
+            repeats: 0,
+        };
+        transmit(device, &x.signal, &options)?;
+        if index + 1 < plan.signals.len() {
+            device.wait(x.wait);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod macros_tests {
+    use crate::bitvec::BitVec;
+    use crate::infrared_remote::{InfraredRemoteDemodulatedFrame, Microseconds};
+    use crate::lirc::RecordingTransmitter;
+    use crate::macros::{
+        run_plan, signal_duration, Macro, MacroBook, MacroCode, MacroStep, MAXIMUM_SEND_COUNT,
+    };
+    use crate::protocol::ProtocolRegistry;
+
+    fn nec(command: u8) -> InfraredRemoteDemodulatedFrame {
+        InfraredRemoteDemodulatedFrame::Nec(BitVec::from_bytes(&[0x04, 0xFB, command, !command]))
+    }
+
+    fn book() -> MacroBook {
+        let mut book = MacroBook::default();
+        book.codes.insert("power".to_string(), vec![nec(0x08)]);
+        book.codes.insert("volume_up".to_string(), vec![nec(0x02)]);
+        book.insert(Macro {
+            name: "movie".to_string(),
+            description: None,
+            steps: vec![
+                MacroStep::Send {
+                    code: MacroCode::Named("power".to_string()),
+                    count: 1,
+                },
+                MacroStep::Delay(Microseconds(5_000_000)),
+                MacroStep::Send {
+                    code: MacroCode::Inline(nec(0x40)),
+                    count: 1,
+                },
+                MacroStep::Send {
+                    code: MacroCode::Named("volume_up".to_string()),
+                    count: 10,
+                },
+            ],
+        });
+        book
+    }
+
+    #[test]
+    fn test_compile() {
+        let registry = ProtocolRegistry::builtin();
+        let plan = book().compile(&registry, "movie").unwrap();
+        // 10回送る信号は1回ずつ
+        assert_eq!(plan.signals.len(), 12);
+        assert_eq!(plan.signals[0].start, Microseconds(0));
+        assert_eq!(plan.signals[0].wait, Microseconds(5_000_000));
+        assert!(plan.signals[0]
+            .signal
+            .last()
+            .unwrap()
+            .trailing_space()
+            .is_none());
+        // NECのフレーム(マーク34個)をそれぞれフレーム周期の残りだけ待って10回
+        for x in plan.signals[2..].iter() {
+            assert_eq!(x.signal.len(), 34);
+            assert_eq!(x.signal.last().unwrap().trailing_space(), None);
+            assert_eq!(
+                x.start + signal_duration(&x.signal) + x.wait,
+                x.start + Microseconds(108_000)
+            );
+        }
+        for xs in plan.signals.windows(2) {
+            let end = xs[0]
+                .signal
+                .iter()
+                .fold(xs[0].start, |acc, x| acc + x.duration());
+            assert_eq!(xs[1].start, end + xs[0].wait);
+        }
+        let mut device = RecordingTransmitter::default();
+        run_plan(&mut device, &plan, 50).unwrap();
+        assert_eq!(device.sent.len(), 12);
+        assert_eq!(device.waits.len(), 11);
+        assert_eq!(device.waits[0], Microseconds(5_000_000));
+        assert_eq!(device.duty_cycle, Some(50));
+    }
+
+    #[test]
+    fn test_delay() {
+        let registry = ProtocolRegistry::builtin();
+        let mut book = book();
+        let power = || MacroStep::Send {
+            code: MacroCode::Named("power".to_string()),
+            count: 1,
+        };
+        book.insert(Macro {
+            name: "delays".to_string(),
+            description: None,
+            steps: vec![
+                power(),
+                MacroStep::Delay(Microseconds(0)),
+                power(),
+                MacroStep::Delay(Microseconds(60_000)),
+                MacroStep::Delay(Microseconds(60_000)),
+                power(),
+            ],
+        });
+        let plan = book.compile(&registry, "delays").unwrap();
+        // フレーム周期より短い Delay はフレーム周期だけ待つ
+        let x = &plan.signals[0];
+        assert_eq!(
+            x.start + signal_duration(&x.signal) + x.wait,
+            x.start + Microseconds(108_000)
+        );
+        // 続けて書いた Delay は足してから比べる
+        assert_eq!(plan.signals[1].wait, Microseconds(120_000));
+    }
+
+    #[test]
+    fn test_validate() {
+        let registry = ProtocolRegistry::builtin();
+        let mut book = book();
+        book.insert(Macro {
+            name: "broken".to_string(),
+            description: None,
+            steps: vec![
+                MacroStep::Send {
+                    code: MacroCode::Named("mute".to_string()),
+                    count: 1,
+                },
+                MacroStep::Send {
+                    code: MacroCode::Inline(nec(0x08)),
+                    count: 0,
+                },
+            ],
+        });
+        let errors = book.compile(&registry, "broken").unwrap_err();
+        assert_eq!(errors.lines().count(), 2, "{}", errors);
+        // 送る回数の上限を超えたら信号を作らずにエラー
+        book.insert(Macro {
+            name: "flood".to_string(),
+            description: None,
+            steps: vec![MacroStep::Send {
+                code: MacroCode::Named("power".to_string()),
+                count: 4_000_000_000,
+            }],
+        });
+        assert!(book.compile(&registry, "flood").is_err());
+        book.insert(Macro {
+            name: "maximum".to_string(),
+            description: None,
+            steps: vec![MacroStep::Send {
+                code: MacroCode::Named("power".to_string()),
+                count: MAXIMUM_SEND_COUNT,
+            }],
+        });
+        assert_eq!(
+            book.compile(&registry, "maximum").unwrap().signals.len(),
+            MAXIMUM_SEND_COUNT as usize
+        );
+        assert!(book.compile(&registry, "nothing").is_err());
+        assert!(book
+            .validate(&registry, book.find("movie").unwrap())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_json() {
+        let book = book();
+        let json = book.to_json().unwrap();
+        assert_eq!(MacroBook::from_json(&json), Ok(book));
+        // count は省略できる
+        let json = r#"{ "macros": [ { "name": "x", "steps": [
+            { "Send": { "code": { "Named": "power" } } }, { "Delay": 1000 } ] } ] }"#;
+        let book = MacroBook::from_json(json).unwrap();
+        assert_eq!(
+            book.macros[0].steps[0],
+            MacroStep::Send {
+                code: MacroCode::Named("power".to_string()),
+                count: 1,
+            }
+        );
+    }
+}
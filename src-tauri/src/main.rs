@@ -7,23 +7,71 @@
     windows_subsystem = "windows"
 )]
 
-#[allow(dead_code)]
-mod infrared_remote;
-#[allow(dead_code)]
-mod parsing;
-
-use crate::infrared_remote::*;
-use crate::parsing::*;
+use app_lib::cleanup::*;
+use app_lib::compare::*;
+use app_lib::fidelity::*;
+use app_lib::formats::*;
+use app_lib::infrared_remote::*;
+use app_lib::irp::parse_irp;
+use app_lib::lirc::*;
+use app_lib::macros::*;
+use app_lib::parsing::*;
+use app_lib::protocol::*;
+use app_lib::segmentation::*;
+use app_lib::serial::*;
+use app_lib::signal::*;
+use app_lib::time_base::*;
 use std;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 fn main() {
     tauri::Builder::default()
+        .manage(Mutex::new(ProtocolRegistry::builtin()))
+        .manage(Mutex::new(MacroBook::default()))
+        .manage(LircCapture(Mutex::new(None)))
         .invoke_handler(tauri::generate_handler![
             greet,
             parse_infrared_code,
+            detect_signal_format,
             decode,
+            decode_candidates,
+            frame_segments,
             encode2,
-            encode
+            encode,
+            decode_timed,
+            encode_timed2,
+            encode_timed,
+            fidelity_report,
+            compare_captures,
+            frame_fields,
+            compose_frame,
+            format_frames_hex,
+            parse_frames_hex,
+            encode_sequence,
+            signal_segments,
+            clean_up_signal,
+            analyze_time_base,
+            protocol_definitions,
+            load_protocol_definitions,
+            render_irp,
+            recognise_irp,
+            start_lirc_capture,
+            stop_lirc_capture,
+            carrier_frequency,
+            send_lirc,
+            macro_book,
+            load_macro_book,
+            export_macro_book,
+            save_macro_code,
+            save_macro,
+            delete_macro,
+            compile_macro,
+            run_macro,
+            serial_supported,
+            learn_serial,
+            send_serial
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -34,39 +82,466 @@ fn greet(name: &str) -> String {
     format!("Hello, {}!", name)
 }
 
+/// 書式を見分けて読み込んだ赤外線リモコン信号
+/// どの書式でも読めなければ書式ごとの理由を1行ずつ返す
 #[tauri::command]
 fn parse_infrared_code(ircode: &str) -> Result<Vec<MarkAndSpaceMicros>, String> {
-    fn convert(x: Vec<MarkAndSpaceIrCarrier>) -> Vec<MarkAndSpaceMicros> {
-        x.iter().map(|x| MarkAndSpaceMicros::from(*x)).collect()
+    detect_signal_format(ircode).map(|x| x.signal).map_err(|e| {
+        e.iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    })
+}
+
+/// 書式を見分けて赤外線リモコン信号を読み込む
+#[tauri::command]
+fn detect_signal_format(ircode: &str) -> Result<DetectedSignal, Vec<FormatDiagnostic>> {
+    SignalFormatRegistry::builtin().detect(ircode)
+}
+
+#[tauri::command]
+fn decode(
+    input: Vec<MarkAndSpaceMicros>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<Vec<InfraredRemoteDemodulatedFrame>, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    let frames = segment_frames(&registry, &input)?
+        .into_iter()
+        .map(|x| x.frame)
+        .collect::<Vec<InfraredRemoteFrame>>();
+    Ok(registry.decode_frames(&frames))
+}
+
+#[tauri::command]
+fn decode_candidates(
+    input: Vec<MarkAndSpaceMicros>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<Vec<Vec<Interpretation>>, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    let frames = segment_frames(&registry, &input)?
+        .into_iter()
+        .map(|x| x.frame)
+        .collect::<Vec<InfraredRemoteFrame>>();
+    Ok(registry.interpret_frames(&frames))
+}
+
+#[tauri::command]
+fn frame_segments(
+    input: Vec<MarkAndSpaceMicros>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<Vec<SegmentedFrame>, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    segment_frames(&registry, &input)
+}
+
+#[tauri::command]
+fn decode_timed(
+    input: Vec<MarkAndSpaceMicros>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<Vec<TimedFrame>, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    let frames = segment_frames(&registry, &input)?;
+    Ok(registry.decode_timed_frames(&frames))
+}
+
+/// spacingを指定すると、すべてのフレームの間隔をそれにする
+#[tauri::command]
+fn encode_timed2(
+    input: Vec<TimedFrame>,
+    spacing: Option<FrameSpacing>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<Vec<MarkAndSpaceMicros>, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    let input = input
+        .into_iter()
+        .map(|x| TimedFrame {
+            spacing: spacing.or(x.spacing),
+            ..x
+        })
+        .collect::<Vec<TimedFrame>>();
+    let frames = registry.encode_timed_frames(&input)?;
+    Ok(encode_phase2(&frames))
+}
+
+#[tauri::command]
+fn encode_timed(
+    input: Vec<TimedFrame>,
+    spacing: Option<FrameSpacing>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<String, String> {
+    encode_timed2(input, spacing, registry).map(|v| encode_phase3(&v))
+}
+
+/// encodedには元の信号をデコードして再エンコードした信号を渡す
+#[tauri::command]
+fn fidelity_report(
+    original: Vec<MarkAndSpaceMicros>,
+    encoded: Vec<MarkAndSpaceMicros>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<FidelityReport, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    round_trip_fidelity(&registry, &original, &encoded)
+}
+
+#[tauri::command]
+fn compare_captures(
+    a: Vec<MarkAndSpaceMicros>,
+    b: Vec<MarkAndSpaceMicros>,
+    tolerance: Option<Microseconds>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<Comparison, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    let tolerance = tolerance.unwrap_or(DEFAULT_COMPARE_TOLERANCE);
+    compare_signals(&registry, &a, &b, tolerance)
+}
+
+#[tauri::command]
+fn encode2(
+    input: Vec<InfraredRemoteDemodulatedFrame>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<Vec<MarkAndSpaceMicros>, String> {
+    encode_timed2(
+        input.into_iter().map(TimedFrame::from).collect(),
+        None,
+        registry,
+    )
+}
+
+#[tauri::command]
+fn encode(
+    input: Vec<InfraredRemoteDemodulatedFrame>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<String, String> {
+    encode2(input, registry).map(|v| encode_phase3(&v))
+}
+
+#[tauri::command]
+fn frame_fields(
+    input: InfraredRemoteDemodulatedFrame,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<Vec<FieldValue>, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    registry.fields(&input)
+}
+
+#[tauri::command]
+fn compose_frame(
+    protocol: &str,
+    fields: BTreeMap<String, u64>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<InfraredRemoteDemodulatedFrame, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    registry.compose(protocol, &fields)
+}
+
+/// 赤外線リモコン信号を1行に1フレームずつ16進数で表示する
+#[tauri::command]
+fn format_frames_hex(
+    input: Vec<InfraredRemoteDemodulatedFrame>,
+    order: Option<BitOrder>,
+) -> String {
+    let order = order.unwrap_or(BitOrder::LsbFirst);
+    input
+        .iter()
+        .map(|x| x.to_hex_string(order))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// 1行に1フレームずつ16進数で書いた赤外線リモコン信号を読み込む
+#[tauri::command]
+fn parse_frames_hex(
+    text: &str,
+    order: Option<BitOrder>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<Vec<InfraredRemoteDemodulatedFrame>, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    from_hex_frames(&registry, text, order.unwrap_or(BitOrder::LsbFirst))
+}
+
+/// 手で書いた赤外線リモコン信号の列を搬送波周波数とマークアンドスペースにする
+#[tauri::command]
+fn encode_sequence(
+    text: &str,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<EncodedSequence, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    compile_sequence(&registry, &parse_sequence(text)?)?.encode(&registry)
+}
+
+#[tauri::command]
+fn signal_segments(input: Vec<MarkAndSpaceMicros>) -> Vec<Segment> {
+    Signal::from_mark_and_spaces(&input).segments().collect()
+}
+
+#[tauri::command]
+fn clean_up_signal(
+    input: Vec<MarkAndSpaceMicros>,
+    options: Option<CleanupOptions>,
+) -> CleanupResult {
+    cleanup(&input, &options.unwrap_or_default())
+}
+
+#[tauri::command]
+fn analyze_time_base(input: Vec<MarkAndSpaceMicros>) -> Result<TimeBaseEstimation, String> {
+    estimate_time_base(&input)
+}
+
+#[tauri::command]
+fn protocol_definitions(
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<Vec<ProtocolDefinition>, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    Ok(registry.definitions().to_vec())
+}
+
+#[tauri::command]
+fn load_protocol_definitions(
+    json: &str,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<Vec<ProtocolDefinition>, String> {
+    let mut registry = registry.lock().map_err(|e| e.to_string())?;
+    registry.load_json(json)?;
+    Ok(registry.definitions().to_vec())
+}
+
+#[tauri::command]
+fn render_irp(
+    irp: &str,
+    parameters: BTreeMap<String, i64>,
+    repeat: u32,
+) -> Result<Vec<MarkAndSpaceMicros>, String> {
+    parse_irp(irp)?.render(&parameters, repeat)
+}
+
+#[tauri::command]
+fn recognise_irp(
+    irp: &str,
+    input: Vec<MarkAndSpaceMicros>,
+) -> Result<BTreeMap<String, i64>, String> {
+    parse_irp(irp)?.recognise(&input)
+}
+
+/// LIRCデバイスから受信中ならtrueのフラグと受信スレッド
+struct LircCapture(Mutex<Option<(Arc<AtomicBool>, std::thread::JoinHandle<()>)>>);
+
+/// 受信スレッドを止めて終わるのを待つ
+fn stop_capture_thread(
+    current: &mut Option<(Arc<AtomicBool>, std::thread::JoinHandle<()>)>,
+) -> Result<(), String> {
+    if let Some((running, handle)) = current.take() {
+        running.store(false, Ordering::SeqCst);
+        handle
+            .join()
+            .map_err(|_| "受信スレッドが異常終了しました。".to_string())?;
     }
+    Ok(())
+}
+
+/// 受信した信号は "lirc-capture" イベント、エラーは "lirc-capture-error" イベントで送る
+/// 受信中なら前の受信を止めてから始める
+#[tauri::command]
+fn start_lirc_capture(
+    path: String,
+    window: tauri::Window,
+    state: tauri::State<LircCapture>,
+) -> Result<(), String> {
+    let mut current = state.0.lock().map_err(|e| e.to_string())?;
+    stop_capture_thread(&mut current)?;
+    let mut device = open_receiver(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let running = Arc::new(AtomicBool::new(true));
+    let flag = running.clone();
+    let handle = std::thread::spawn(move || {
+        let result = capture(&mut device, &flag, |x| {
+            let _ = match x {
+                Ok(signal) => window.emit("lirc-capture", signal),
+                Err(e) => window.emit("lirc-capture-error", e),
+            };
+        });
+        if let Err(e) = result {
+            let _ = window.emit("lirc-capture-error", e.to_string());
+        }
+        flag.store(false, Ordering::SeqCst);
+    });
+    *current = Some((running, handle));
+    Ok(())
+}
+
+/// 受信を止めて、受信スレッドが終わるまで待つ
+#[tauri::command]
+fn stop_lirc_capture(state: tauri::State<LircCapture>) -> Result<(), String> {
+    let mut current = state.0.lock().map_err(|e| e.to_string())?;
+    stop_capture_thread(&mut current)
+}
 
-    let a = from_infrared_code(ircode).map(convert);
-    if a.is_err() {
-        from_array(ircode)
-    } else {
-        a
+/// 復調済みフレームのプロトコルのキャリア周波数(わからなければ IR_CARRIER_FREQ)
+#[tauri::command]
+fn carrier_frequency(
+    input: Vec<InfraredRemoteDemodulatedFrame>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<u32, String> {
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    Ok(input
+        .first()
+        .and_then(|x| x.protocol())
+        .and_then(|x| registry.find(x))
+        .map_or(IR_CARRIER_FREQ as u32, |x| x.carrier_frequency))
+}
+
+/// LIRCデバイスから送信する
+/// フレーム間隔と繰り返しを待つので別スレッドで動かす
+#[tauri::command]
+async fn send_lirc(
+    path: String,
+    input: Vec<MarkAndSpaceMicros>,
+    repeats: Option<usize>,
+    carrier_frequency: Option<u32>,
+    duty_cycle: Option<u32>,
+) -> Result<(), String> {
+    let default = TransmitOptions::default();
+    let options = TransmitOptions {
+        carrier_frequency: carrier_frequency.unwrap_or(default.carrier_frequency),
+        duty_cycle: duty_cycle.unwrap_or(default.duty_cycle),
+        repeats: repeats.unwrap_or(default.repeats),
+    };
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut device = open_transmitter(&path).map_err(|e| format!("{}: {}", path, e))?;
+        transmit(device.as_mut(), &input, &options).map_err(|e| format!("{}: {}", path, e))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// 登録した信号とマクロの一覧
+#[tauri::command]
+fn macro_book(book: tauri::State<Mutex<MacroBook>>) -> Result<MacroBook, String> {
+    let book = book.lock().map_err(|e| e.to_string())?;
+    Ok(book.clone())
+}
+
+/// JSONで書いた信号とマクロの一覧に置き換える
+#[tauri::command]
+fn load_macro_book(json: &str, book: tauri::State<Mutex<MacroBook>>) -> Result<(), String> {
+    let mut book = book.lock().map_err(|e| e.to_string())?;
+    *book = MacroBook::from_json(json)?;
+    Ok(())
+}
+
+/// 信号とマクロの一覧をJSONにする
+#[tauri::command]
+fn export_macro_book(book: tauri::State<Mutex<MacroBook>>) -> Result<String, String> {
+    let book = book.lock().map_err(|e| e.to_string())?;
+    book.to_json()
+}
+
+/// 名前をつけて信号を登録する
+#[tauri::command]
+fn save_macro_code(
+    name: String,
+    frames: Vec<InfraredRemoteDemodulatedFrame>,
+    book: tauri::State<Mutex<MacroBook>>,
+) -> Result<(), String> {
+    let mut book = book.lock().map_err(|e| e.to_string())?;
+    book.codes.insert(name, frames);
+    Ok(())
+}
+
+/// 検査してマクロを登録する
+#[tauri::command]
+fn save_macro(
+    input: Macro,
+    book: tauri::State<Mutex<MacroBook>>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<(), String> {
+    let mut book = book.lock().map_err(|e| e.to_string())?;
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    book.validate(&registry, &input)?;
+    book.insert(input);
+    Ok(())
+}
+
+/// マクロを消す
+#[tauri::command]
+fn delete_macro(name: &str, book: tauri::State<Mutex<MacroBook>>) -> Result<(), String> {
+    let mut book = book.lock().map_err(|e| e.to_string())?;
+    book.remove(name)
+        .map(|_| ())
+        .ok_or_else(|| format!("{} のマクロがありません。", name))
+}
+
+/// マクロの送信計画
+#[tauri::command]
+fn compile_macro(
+    name: &str,
+    book: tauri::State<Mutex<MacroBook>>,
+    registry: tauri::State<Mutex<ProtocolRegistry>>,
+) -> Result<TransmissionPlan, String> {
+    let book = book.lock().map_err(|e| e.to_string())?;
+    let registry = registry.lock().map_err(|e| e.to_string())?;
+    book.compile(&registry, name)
+}
+
+/// マクロをLIRCデバイスから送信する
+/// 送り終わるまで待つので別スレッドで動かす
+#[tauri::command]
+async fn run_macro(
+    name: String,
+    path: String,
+    book: tauri::State<'_, Mutex<MacroBook>>,
+    registry: tauri::State<'_, Mutex<ProtocolRegistry>>,
+) -> Result<(), String> {
+    let plan = compile_macro(&name, book, registry)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut device = open_transmitter(&path).map_err(|e| format!("{}: {}", path, e))?;
+        let duty_cycle = TransmitOptions::default().duty_cycle;
+        run_plan(device.as_mut(), &plan, duty_cycle).map_err(|e| format!("{}: {}", path, e))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// シリアルポートの学習リモコンの設定
+fn serial_options(baud_rate: Option<u32>) -> SerialOptions {
+    let default = SerialOptions::default();
+    SerialOptions {
+        baud_rate: baud_rate.unwrap_or(default.baud_rate),
+        ..default
     }
 }
 
+/// シリアルポートの学習リモコンが使えるか(今はLinuxだけ)
 #[tauri::command]
-fn decode(input: Vec<MarkAndSpaceMicros>) -> Result<Vec<InfraredRemoteDemodulatedFrame>, String> {
-    let frames = decode_phase1(&input)?;
-    Ok(frames
-        .iter()
-        .map(|frame| decode_phase2(frame))
-        .collect::<Vec<InfraredRemoteDemodulatedFrame>>())
+fn serial_supported() -> bool {
+    cfg!(target_os = "linux")
 }
 
+/// シリアルポートの学習リモコンで学習した赤外線リモコンコードを返す
+/// 学習し終わるまで待つので別スレッドで動かす
 #[tauri::command]
-fn encode2(input: Vec<InfraredRemoteDemodulatedFrame>) -> Result<Vec<MarkAndSpaceMicros>, String> {
-    let frames = input
-        .iter()
-        .map(|x| encode_phase1(x))
-        .collect::<Result<Vec<InfraredRemoteFrame>, String>>()?;
-    Ok(encode_phase2(&frames))
+async fn learn_serial(path: String, baud_rate: Option<u32>) -> Result<String, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut learner = open_learner(&path, serial_options(baud_rate))
+            .map_err(|e| format!("{}: {}", path, e))?;
+        learner.learn()
+    })
+    .await
+    .map_err(|e| e.to_string())?
 }
 
+/// シリアルポートの学習リモコンから送信する
+/// 送信し終わるまで待つので別スレッドで動かす
 #[tauri::command]
-fn encode(input: Vec<InfraredRemoteDemodulatedFrame>) -> Result<String, String> {
-    encode_infrared_remote_code(&input)
+async fn send_serial(
+    path: String,
+    input: Vec<MarkAndSpaceMicros>,
+    baud_rate: Option<u32>,
+) -> Result<(), String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut learner = open_learner(&path, serial_options(baud_rate))
+            .map_err(|e| format!("{}: {}", path, e))?;
+        send_mark_and_spaces(&mut learner, &input)
+    })
+    .await
+    .map_err(|e| e.to_string())?
 }
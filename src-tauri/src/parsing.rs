@@ -1,14 +1,20 @@
+use crate::bitvec::BitVec;
 use crate::infrared_remote::{
-    IrCarrierCounter, MarkAndSpaceIrCarrier, MarkAndSpaceMicros, Microseconds,
+    encode_phase2, FrameSpacing, InfraredRemoteDemodulatedFrame, IrCarrierCounter,
+    MarkAndSpaceIrCarrier, MarkAndSpaceMicros, Microseconds, TimedFrame,
 };
+use crate::protocol::{BitOrder, ProtocolRegistry};
 use nom::{
-    bytes::complete::{take_while, take_while_m_n},
-    character::complete::{char, multispace0, space0},
-    combinator::{map_res},
-    multi::{many1, separated_list1},
-    sequence::{delimited, preceded, terminated, tuple},
+    branch::alt,
+    bytes::complete::{tag_no_case, take_while, take_while1, take_while_m_n},
+    character::complete::{char, digit1, multispace0, multispace1, space0, space1},
+    combinator::{all_consuming, map, map_res, opt, recognize},
+    multi::{many0, many1, separated_list1},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
     Finish, IResult,
 };
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
 fn from_hex(input: &str) -> Result<u8, std::num::ParseIntError> {
@@ -30,7 +36,7 @@ fn four_digits_hexadecimal(input: &str) -> IResult<&str, IrCarrierCounter> {
     // 入力値は 下位8ビット -> 上位8ビット の順番なので普通の数字の書き方(高位が前, 下位が後)に入れ替える。
     Ok((
         input,
-        IrCarrierCounter(((higher as u16) << 8) | lower as u16),
+        IrCarrierCounter(((higher as u32) << 8) | lower as u32),
     ))
 }
 
@@ -50,9 +56,10 @@ fn take_mark_and_spaces_ircarrier(input: &str) -> IResult<&str, Vec<MarkAndSpace
 }
 
 // 入力文字列を解析してマークアンドスペースのベクタにする
+// 16ビットに収まらないので分けられた長いマーク(スペース)はつなぎ合わせる
 pub fn from_infrared_code(input: &str) -> Result<Vec<MarkAndSpaceIrCarrier>, String> {
     match take_mark_and_spaces_ircarrier(input).finish() {
-        Ok((_, res)) => Ok(res),
+        Ok((_, res)) => Ok(MarkAndSpaceIrCarrier::join(&res)),
         Err(e) => Err(e.to_string()),
     }
 }
@@ -106,6 +113,42 @@ fn test_from_infrared_code() {
     );
 }
 
+#[test]
+fn test_from_infrared_code_long_duration() {
+    use crate::infrared_remote::{encode_phase3, MarkAndSpace};
+    // 3秒のフレーム間隔
+    let input = vec![
+        MarkAndSpaceMicros {
+            mark: Microseconds(9000),
+            space: Some(Microseconds(3_000_000)),
+        },
+        MarkAndSpace {
+            mark: Microseconds(560),
+            space: Some(Microseconds(560)),
+        },
+    ];
+    let decoded = from_infrared_code(&encode_phase3(&input))
+        .unwrap()
+        .iter()
+        .map(|x| MarkAndSpaceMicros::from(*x))
+        .collect::<Vec<MarkAndSpaceMicros>>();
+    assert_eq!(decoded.len(), 2);
+    let space = decoded[0].space.unwrap();
+    assert!(Microseconds(2_999_950) < space);
+    assert!(space <= Microseconds(3_000_000));
+}
+
+#[test]
+fn test_from_infrared_code_mark_only() {
+    // 最後のスペースが0カウントならスペースの無いマーク
+    let decoded = from_infrared_code("5601AA00 17000000")
+        .unwrap()
+        .iter()
+        .map(|x| MarkAndSpaceMicros::from(*x))
+        .collect::<Vec<MarkAndSpaceMicros>>();
+    assert_eq!(decoded[1].trailing_space(), None);
+}
+
 // 数字
 fn take_microseconds(input: &str) -> IResult<&str, Microseconds> {
     map_res(take_while(|c: char| c.is_ascii_digit()), |x| {
@@ -113,17 +156,46 @@ fn take_microseconds(input: &str) -> IResult<&str, Microseconds> {
     })(input)
 }
 
+// 符号を付けてもよい数字
+fn take_signed_microseconds(input: &str) -> IResult<&str, (Option<char>, Microseconds)> {
+    pair(opt(alt((char('+'), char('-')))), take_microseconds)(input)
+}
+
+// 符号を付けるならマーク(+)とスペース(-)を交互に書く
+fn check_signs(xs: Vec<(Option<char>, Microseconds)>) -> Result<Vec<Microseconds>, String> {
+    let signed = xs.iter().any(|(sign, _)| sign.is_some());
+    xs.into_iter()
+        .enumerate()
+        .map(|(index, (sign, x))| match (signed, index % 2 == 0, sign) {
+            (false, _, _) | (true, true, None | Some('+')) | (true, false, Some('-')) => Ok(x),
+            (true, true, _) => Err(format!("{} 番目の {} はマーク(+)ではありません。", index + 1, x.0)),
+            (true, false, _) => Err(format!("{} 番目の {} はスペース(-)ではありません。", index + 1, x.0)),
+        })
+        .collect()
+}
+
 // 入力文字列を解析してマークアンドスペースのベクタにする
+// "{9000, 4500, 560}" "9000 4500 560" "+9000 -4500 +560"
 pub fn from_array(input: &str) -> Result<Vec<MarkAndSpaceMicros>, String> {
+    let input = input.trim();
+    let body = match (input.strip_prefix('{'), input.ends_with('}')) {
+        (Some(x), true) => &x[..x.len() - 1],
+        (None, false) => input,
+        _ => return Err("{ と } が対になっていません。".to_string()),
+    };
     let mut parse = {
-        delimited(
-            terminated(char('{'), space0),
-            separated_list1(delimited(space0, char(','), space0), take_microseconds),
-            preceded(space0, char('}')),
-        )
+        all_consuming(delimited(
+            multispace0,
+            separated_list1(
+                alt((recognize(delimited(multispace0, char(','), multispace0)), multispace1)),
+                take_signed_microseconds,
+            ),
+            multispace0,
+        ))
     };
-    match parse(input).finish() {
-        Ok((_, mss)) => {
+    let parsed = parse(body).finish().map_err(|e| e.to_string());
+    match parsed.and_then(|(_, xs)| check_signs(xs)) {
+        Ok(mss) => {
             // Microsecods配列を2つづつペアにする
             let pairs = itertools::unfold(mss.into_iter(), move |xs| match xs.len() {
                 0 => None,
@@ -138,16 +210,16 @@ pub fn from_array(input: &str) -> Result<Vec<MarkAndSpaceMicros>, String> {
                 }
             })
             .collect::<Vec<(Microseconds, Option<Microseconds>)>>();
-            // Spaceが無い時はとりあえずなんか入れておく
+            // 最後のMarkの後にSpaceが無い時はスペースの無いマークにする
             Ok(pairs
                 .iter()
-                .map(|(m, opt)| MarkAndSpaceMicros {
-                    mark: *m,
-                    space: opt.unwrap_or_default(),
+                .map(|(m, opt)| match opt {
+                    Some(s) => MarkAndSpaceMicros { mark: *m, space: Some(*s) },
+                    None => MarkAndSpaceMicros::mark_only(*m),
                 })
                 .collect())
         }
-        Err(e) => Err(e.to_string()),
+        Err(e) => Err(e),
     }
 }
 
@@ -155,10 +227,7 @@ pub fn from_array(input: &str) -> Result<Vec<MarkAndSpaceMicros>, String> {
 fn test1_from_array() {
     assert_eq!(
         from_array("{1}"),
-        Ok(vec!(MarkAndSpaceMicros {
-            mark: Microseconds(1),
-            space: Microseconds::default(),
-        }))
+        Ok(vec!(MarkAndSpaceMicros::mark_only(Microseconds(1))))
     );
 }
 
@@ -168,7 +237,7 @@ fn test2_from_array() {
         from_array("{1,2}"),
         Ok(vec!(MarkAndSpaceMicros {
             mark: Microseconds(1),
-            space: Microseconds(2)
+            space: Some(Microseconds(2))
         }))
     );
 }
@@ -179,7 +248,7 @@ fn test3_from_array() {
         from_array("{ 1, 2 }"),
         Ok(vec!(MarkAndSpaceMicros {
             mark: Microseconds(1),
-            space: Microseconds(2)
+            space: Some(Microseconds(2))
         }))
     );
 }
@@ -191,12 +260,9 @@ fn test4_from_array() {
         Ok(vec!(
             MarkAndSpaceMicros {
                 mark: Microseconds(1),
-                space: Microseconds(2)
+                space: Some(Microseconds(2))
             },
-            MarkAndSpaceMicros {
-                mark: Microseconds(3),
-                space: Microseconds::default(),
-            }
+            MarkAndSpaceMicros::mark_only(Microseconds(3))
         ))
     );
 }
@@ -208,12 +274,756 @@ fn test5_from_array() {
         Ok(vec!(
             MarkAndSpaceMicros {
                 mark: Microseconds(1),
-                space: Microseconds(2)
+                space: Some(Microseconds(2))
             },
             MarkAndSpaceMicros {
                 mark: Microseconds(3),
-                space: Microseconds(4)
+                space: Some(Microseconds(4))
             }
         ))
     );
 }
+
+#[test]
+fn test6_from_array() {
+    let expected = Ok(vec![
+        MarkAndSpaceMicros {
+            mark: Microseconds(9000),
+            space: Some(Microseconds(4500)),
+        },
+        MarkAndSpaceMicros::mark_only(Microseconds(560)),
+    ]);
+    for input in ["{9000, 4500, 560}", "9000 4500 560", "+9000 -4500 +560", "{9000,\n4500 560}"] {
+        assert_eq!(from_array(input), expected);
+    }
+    assert!(from_array("{9000, 4500, 560").is_err());
+    assert!(from_array("+9000 4500 +560").is_err());
+    assert!(from_array("-9000 -4500").is_err());
+    assert!(from_array("9000 45x00").is_err());
+}
+
+// 0x を付けてもよい2桁までの16進数(8ビット)
+// "addr" のように16進数で始まる単語は16進数にしない
+fn hexadecimal_octet(input: &str) -> IResult<&str, u8> {
+    map_res(
+        take_while1(|c: char| c.is_ascii_alphanumeric()),
+        |x: &str| {
+            let digits = x
+                .strip_prefix("0x")
+                .or_else(|| x.strip_prefix("0X"))
+                .unwrap_or(x);
+            if digits.len() <= 2 {
+                from_hex(digits).map_err(|e| e.to_string())
+            } else {
+                Err(format!("{} は8ビットを超えています。", x))
+            }
+        },
+    )(input)
+}
+
+// 全体のビット数 "/12"
+fn bit_length(input: &str) -> IResult<&str, usize> {
+    preceded(char('/'), map_res(digit1, usize::from_str))(input)
+}
+
+// プロトコル名
+fn protocol_name(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-')(input)
+}
+
+// プロトコル名とバイト列(リピート信号ならNone)
+type HexadecimalFrame<'a> = (&'a str, Option<(Vec<u8>, Option<usize>)>);
+
+fn hexadecimal_frame(input: &str) -> IResult<&str, HexadecimalFrame<'_>> {
+    tuple((
+        protocol_name,
+        preceded(
+            space1,
+            alt((
+                map(tag_no_case("repeat"), |_| None),
+                map(
+                    tuple((
+                        separated_list1(space1, hexadecimal_octet),
+                        opt(preceded(space1, bit_length)),
+                    )),
+                    Some,
+                ),
+            )),
+        ),
+    ))(input)
+}
+
+// 16進数で書いたフレーム "NEC 0x04 0xFB 0x08 0xF7" を赤外線リモコン信号にする
+// 各バイトはbit_orderの順番でビット列にする(InfraredRemoteDemodulatedFrame::to_hex_string の逆)
+// プロトコル名は大文字小文字を区別せずにregistryから探す
+pub fn from_hex_frame(
+    registry: &ProtocolRegistry,
+    input: &str,
+    bit_order: BitOrder,
+) -> Result<InfraredRemoteDemodulatedFrame, String> {
+    let (protocol, body) =
+        match all_consuming(delimited(space0, hexadecimal_frame, space0))(input).finish() {
+            Ok((_, x)) => x,
+            Err(e) => return Err(e.to_string()),
+        };
+    let definition = registry
+        .find_ignore_case(protocol)
+        .ok_or_else(|| format!("{} のプロトコル定義がありません。", protocol))?;
+    let (octets, length) = match body {
+        Some(x) => x,
+        None => return Ok(InfraredRemoteDemodulatedFrame::Repeat(definition.name.clone())),
+    };
+    let bits = octets_to_bits(&octets, length, bit_order)
+        .map_err(|e| format!("{}: {}", input.trim(), e))?;
+    Ok(InfraredRemoteDemodulatedFrame::from_protocol(
+        &definition.name, bits,
+    ))
+}
+
+// バイト列をビット列にする
+// lengthが全体のビット数(Noneならバイト数 x 8)
+fn octets_to_bits(
+    octets: &[u8],
+    length: Option<usize>,
+    bit_order: BitOrder,
+) -> Result<BitVec, String> {
+    let full = octets.len() * 8;
+    let length = length.unwrap_or(full);
+    if full < length || length + 8 <= full {
+        return Err(format!(
+            "ビット数 {} が {} バイトと合いません。",
+            length,
+            octets.len()
+        ));
+    }
+    let mut bits = BitVec::new();
+    for (index, octet) in octets.iter().enumerate() {
+        // 最後のバイトは余りのビット数
+        let n = (length - index * 8).min(8);
+        if (*octet as u64) >> n != 0 {
+            return Err(format!("{:02X} が {} ビットに収まりません。", octet, n));
+        }
+        bits.push_value(*octet as u64, n, bit_order);
+    }
+    Ok(bits)
+}
+
+// 1行に1フレームずつ16進数で書いたフレーム(空行は無視する)
+pub fn from_hex_frames(
+    registry: &ProtocolRegistry,
+    input: &str,
+    bit_order: BitOrder,
+) -> Result<Vec<InfraredRemoteDemodulatedFrame>, String> {
+    input
+        .lines()
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| from_hex_frame(registry, x, bit_order))
+        .collect()
+}
+
+#[test]
+fn test_from_hex_frame() {
+    let registry = ProtocolRegistry::builtin_ref();
+    let nec = from_hex_frame(registry, "NEC 0x04 0xFB 0x08 0xF7", BitOrder::LsbFirst).unwrap();
+    assert_eq!(
+        nec,
+        InfraredRemoteDemodulatedFrame::Nec(BitVec::from_bytes(&[0x04, 0xFB, 0x08, 0xF7]))
+    );
+    assert_eq!(nec.to_hex_string(BitOrder::LsbFirst), "NEC 04 FB 08 F7");
+    assert_eq!(nec.to_hex_string(BitOrder::MsbFirst), "NEC 20 DF 10 EF");
+    assert_eq!(
+        from_hex_frame(registry, "NEC 20 DF 10 EF", BitOrder::MsbFirst),
+        Ok(nec)
+    );
+    assert_eq!(
+        from_hex_frame(registry, " AEHA 02 20 E0 04 00 00 00 06 ", BitOrder::LsbFirst),
+        Ok(InfraredRemoteDemodulatedFrame::Aeha(BitVec::from_bytes(&[
+            0x02, 0x20, 0xE0, 0x04, 0x00, 0x00, 0x00, 0x06
+        ])))
+    );
+    assert_eq!(
+        from_hex_frame(registry, "NEC Repeat", BitOrder::LsbFirst),
+        Ok(InfraredRemoteDemodulatedFrame::Repeat("NEC".to_string()))
+    );
+    assert!(from_hex_frame(registry, "NEC", BitOrder::LsbFirst).is_err());
+    assert!(from_hex_frame(registry, "NEC 0x123", BitOrder::LsbFirst).is_err());
+}
+
+#[test]
+fn test_from_hex_frame_bit_length() {
+    let registry = ProtocolRegistry::builtin_ref();
+    // 12ビットのSIRC
+    for bit_order in [BitOrder::LsbFirst, BitOrder::MsbFirst] {
+        let mut bits = BitVec::new();
+        bits.push_value(0x95, 7, BitOrder::LsbFirst);
+        bits.push_value(0x01, 5, BitOrder::LsbFirst);
+        let sirc = InfraredRemoteDemodulatedFrame::Sirc(bits);
+        let text = sirc.to_hex_string(bit_order);
+        assert!(text.ends_with(" /12"));
+        assert_eq!(from_hex_frame(registry, &text, bit_order), Ok(sirc));
+    }
+    assert!(from_hex_frame(registry, "SIRC 95 1F /12", BitOrder::LsbFirst).is_err());
+    assert!(from_hex_frame(registry, "SIRC 95 01 /17", BitOrder::LsbFirst).is_err());
+    assert!(from_hex_frame(registry, "SIRC 95 01 /8", BitOrder::LsbFirst).is_err());
+}
+
+#[test]
+fn test_from_hex_frame_ignore_case() {
+    // プロトコル名は定義の名前にする
+    let registry = ProtocolRegistry::builtin_ref();
+    assert_eq!(
+        from_hex_frame(registry, "nec 04 FB 08 F7", BitOrder::LsbFirst),
+        Ok(InfraredRemoteDemodulatedFrame::Nec(BitVec::from_bytes(&[
+            0x04, 0xFB, 0x08, 0xF7
+        ])))
+    );
+    assert_eq!(
+        from_hex_frame(registry, "Samsung32 07 07 02 FD", BitOrder::LsbFirst)
+            .map(|x| x.protocol().map(str::to_string)),
+        Ok(Some("SAMSUNG32".to_string()))
+    );
+    assert_eq!(
+        from_hex_frame(registry, "nec repeat", BitOrder::LsbFirst),
+        Ok(InfraredRemoteDemodulatedFrame::Repeat("NEC".to_string()))
+    );
+    assert!(from_hex_frame(registry, "VENDOR 01 02", BitOrder::LsbFirst).is_err());
+}
+
+#[test]
+fn test_from_hex_frames() {
+    let registry = ProtocolRegistry::builtin_ref();
+    assert_eq!(
+        from_hex_frames(registry, "NEC 04 FB 08 F7\n\nNEC Repeat\n", BitOrder::LsbFirst)
+            .map(|x| x.len()),
+        Ok(2)
+    );
+}
+
+// 手で書く赤外線リモコン信号の列
+// 文は ; か改行で区切り、# から行末まではコメント
+//
+// carrier 38k                     搬送波周波数(Hz, k は x1000)
+// nec addr=0x04 cmd=0x08 repeat=2 フィールドの値で組み立てたフレーム(repeat はリピート信号の数)
+// aeha 02 20 E0 04 00 00 00 06    8ビットごとの16進数(下位ビットが先)で書いたフレーム
+// gap 40ms                        直前のフレームの終わりから次のフレームまでの間隔(us, ms, s)
+// period 108ms                    直前のフレームの始まりから次のフレームまでの周期
+// raw 9000 -4500 560 -560         マーク(正)とスペース(負)の時間(us)
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// 赤外線リモコン信号の列の文
+pub enum SequenceStatement {
+    /// 搬送波周波数(Hz)
+    Carrier(u32),
+    /// フレーム
+    Frame {
+        protocol: String,
+        /// 16進数で書いたバイト列と全体のビット数
+        octets: Option<(Vec<u8>, Option<usize>)>,
+        /// フィールドの値と repeat
+        parameters: Vec<(String, u64)>,
+    },
+    /// 直前のフレームの間隔
+    Spacing(FrameSpacing),
+    /// マークアンドスペース
+    Raw(Vec<MarkAndSpaceMicros>),
+}
+
+// 小数点のある数字
+fn decimal(input: &str) -> IResult<&str, f64> {
+    map_res(
+        recognize(pair(digit1, opt(pair(char('.'), digit1)))),
+        f64::from_str,
+    )(input)
+}
+
+// 符号のある整数
+fn signed_integer(input: &str) -> IResult<&str, i64> {
+    map_res(
+        recognize(pair(opt(alt((char('+'), char('-')))), digit1)),
+        i64::from_str,
+    )(input)
+}
+
+// 0x を付けたら16進数の整数
+fn unsigned_integer(input: &str) -> IResult<&str, u64> {
+    alt((
+        preceded(
+            tag_no_case("0x"),
+            map_res(take_while1(is_hex_digit), |x| u64::from_str_radix(x, 16)),
+        ),
+        map_res(digit1, u64::from_str),
+    ))(input)
+}
+
+// 単位つきの時間 "40ms"(単位が無ければus)
+fn duration(input: &str) -> IResult<&str, Microseconds> {
+    let (input, (value, unit)) = pair(
+        decimal,
+        opt(alt((
+            tag_no_case("us"),
+            tag_no_case("ms"),
+            tag_no_case("s"),
+        ))),
+    )(input)?;
+    let scale = match unit.map(|x| x.to_ascii_lowercase()).as_deref() {
+        Some("ms") => 1_000.0,
+        Some("s") => 1_000_000.0,
+        _ => 1.0,
+    };
+    Ok((
+        input,
+        Microseconds((value * scale).round().min(u32::MAX as f64) as u32),
+    ))
+}
+
+// 搬送波周波数 "38k"
+fn carrier_statement(input: &str) -> IResult<&str, SequenceStatement> {
+    let (input, (value, kilo)) = preceded(
+        pair(tag_no_case("carrier"), space1),
+        terminated(pair(decimal, opt(tag_no_case("k"))), opt(tag_no_case("hz"))),
+    )(input)?;
+    let scale = if kilo.is_some() { 1_000.0 } else { 1.0 };
+    Ok((
+        input,
+        SequenceStatement::Carrier((value * scale).round().min(u32::MAX as f64) as u32),
+    ))
+}
+
+// フレームの間隔か周期
+fn spacing_statement(input: &str) -> IResult<&str, SequenceStatement> {
+    alt((
+        map(preceded(pair(tag_no_case("gap"), space1), duration), |x| {
+            SequenceStatement::Spacing(FrameSpacing::Gap(x))
+        }),
+        map(
+            preceded(pair(tag_no_case("period"), space1), duration),
+            |x| SequenceStatement::Spacing(FrameSpacing::Period(x)),
+        ),
+    ))(input)
+}
+
+// マークとスペースの時間
+fn raw_statement(input: &str) -> IResult<&str, SequenceStatement> {
+    map_res(
+        preceded(tag_no_case("raw"), many1(preceded(space1, signed_integer))),
+        |xs| {
+            let mut output: Vec<MarkAndSpaceMicros> = Vec::new();
+            for x in xs {
+                let duration =
+                    Microseconds(u32::try_from(x.unsigned_abs()).map_err(|e| e.to_string())?);
+                match (output.last_mut(), 0 < x) {
+                    (Some(last), false) if last.trailing_space().is_none() => last.space = Some(duration),
+                    (None, true) => output.push(MarkAndSpaceMicros::mark_only(duration)),
+                    (Some(last), true) if last.trailing_space().is_some() => {
+                        output.push(MarkAndSpaceMicros::mark_only(duration))
+                    }
+                    _ => {
+                        return Err(
+                            "raw: マーク(正)とスペース(負)を交互に書いてください。".to_string()
+                        )
+                    }
+                }
+            }
+            Ok(SequenceStatement::Raw(output))
+        },
+    )(input)
+}
+
+// フィールドの値 "addr=0x04"
+fn parameter(input: &str) -> IResult<&str, (String, u64)> {
+    map(
+        separated_pair(
+            take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+            char('='),
+            unsigned_integer,
+        ),
+        |(name, value): (&str, u64)| (name.to_ascii_lowercase(), value),
+    )(input)
+}
+
+// フレーム
+fn frame_statement(input: &str) -> IResult<&str, SequenceStatement> {
+    map(
+        tuple((
+            protocol_name,
+            opt(preceded(
+                space1,
+                pair(
+                    separated_list1(space1, hexadecimal_octet),
+                    opt(preceded(space1, bit_length)),
+                ),
+            )),
+            many0(preceded(space1, parameter)),
+        )),
+        |(protocol, octets, parameters)| SequenceStatement::Frame {
+            protocol: protocol.to_string(),
+            octets,
+            parameters,
+        },
+    )(input)
+}
+
+fn sequence_statement(input: &str) -> IResult<&str, SequenceStatement> {
+    delimited(
+        space0,
+        alt((
+            carrier_statement,
+            spacing_statement,
+            raw_statement,
+            frame_statement,
+        )),
+        space0,
+    )(input)
+}
+
+// 赤外線リモコン信号の列を文に分ける
+pub fn parse_sequence(input: &str) -> Result<Vec<SequenceStatement>, String> {
+    let mut statements = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        // コメントを除く
+        let line = line.split('#').next().unwrap_or_default();
+        for text in line.split(';').filter(|x| !x.trim().is_empty()) {
+            match all_consuming(sequence_statement)(text).finish() {
+                Ok((_, x)) => statements.push(x),
+                Err(e) => return Err(format!("{}行目 {}: {}", number + 1, text.trim(), e)),
+            }
+        }
+    }
+    Ok(statements)
+}
+
+// repeat= で書けるリピートの数の上限
+pub const MAXIMUM_SEQUENCE_REPEAT: u64 = 100;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 文から組み立てた赤外線リモコン信号の列
+pub struct CompiledSequence {
+    /// 搬送波周波数(Hz) 指定が無ければNone
+    pub carrier_frequency: Option<u32>,
+    /// フレームの間隔つきの赤外線リモコン信号(raw は Unknown)
+    pub frames: Vec<TimedFrame>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 赤外線リモコン信号の列を変調した信号
+pub struct EncodedSequence {
+    /// 搬送波周波数(Hz) 指定が無ければNone
+    pub carrier_frequency: Option<u32>,
+    /// マークアンドスペース
+    pub signal: Vec<MarkAndSpaceMicros>,
+}
+
+impl CompiledSequence {
+    /// マークアンドスペースにする
+    pub fn to_mark_and_spaces(
+        &self,
+        registry: &ProtocolRegistry,
+    ) -> Result<Vec<MarkAndSpaceMicros>, String> {
+        let frames = registry.encode_timed_frames(&self.frames)?;
+        Ok(encode_phase2(&frames))
+    }
+
+    /// 搬送波周波数とマークアンドスペースにする
+    pub fn encode(&self, registry: &ProtocolRegistry) -> Result<EncodedSequence, String> {
+        Ok(EncodedSequence {
+            carrier_frequency: self.carrier_frequency,
+            signal: self.to_mark_and_spaces(registry)?,
+        })
+    }
+}
+
+// フィールド名の別名
+// addr は address (NECは custom_code で、8ビットなら上位8ビットを反転で埋める)、cmd は command か data
+fn field_values(
+    protocol: &str,
+    field_names: &[&str],
+    parameters: &[(String, u64)],
+) -> Result<BTreeMap<String, u64>, String> {
+    let has = |name: &str| field_names.contains(&name);
+    let mut values = BTreeMap::new();
+    for (name, value) in parameters {
+        let (name, value) = match name.as_str() {
+            "addr" if has("address") => ("address", *value),
+            "addr" if has("custom_code") && *value <= 0xFF => {
+                ("custom_code", *value | ((!*value & 0xFF) << 8))
+            }
+            "addr" if has("custom_code") => ("custom_code", *value),
+            "addr" if has("customer_code") => ("customer_code", *value),
+            "cmd" if has("command") => ("command", *value),
+            "cmd" if has("data") => ("data", *value),
+            name if has(name) => (name, *value),
+            name => {
+                return Err(format!(
+                    "{} に {} のフィールドはありません。",
+                    protocol, name
+                ))
+            }
+        };
+        values.insert(name.to_string(), value);
+    }
+    Ok(values)
+}
+
+// 1つのフレームの文を赤外線リモコン信号とリピートの数にする
+fn compile_frame(
+    registry: &ProtocolRegistry,
+    protocol: &str,
+    octets: &Option<(Vec<u8>, Option<usize>)>,
+    parameters: &[(String, u64)],
+) -> Result<(InfraredRemoteDemodulatedFrame, u64), String> {
+    // プロトコル名は大文字小文字を区別しない
+    let definition = registry
+        .find_ignore_case(protocol)
+        .ok_or_else(|| format!("{} のプロトコル定義がありません。", protocol))?;
+    let repeat = parameters
+        .iter()
+        .rev()
+        .find(|(name, _)| name == "repeat")
+        .map_or(0, |(_, value)| *value);
+    if MAXIMUM_SEQUENCE_REPEAT < repeat {
+        return Err(format!(
+            "{}: repeat={} は {} 回までです。",
+            protocol, repeat, MAXIMUM_SEQUENCE_REPEAT
+        ));
+    }
+    let parameters = parameters
+        .iter()
+        .filter(|(name, _)| name != "repeat")
+        .cloned()
+        .collect::<Vec<(String, u64)>>();
+    let frame = match (octets, parameters.is_empty()) {
+        (Some((octets, length)), true) => InfraredRemoteDemodulatedFrame::from_protocol(
+            &definition.name,
+            octets_to_bits(octets, *length, BitOrder::LsbFirst)?,
+        ),
+        (None, false) => {
+            let names = definition
+                .fields
+                .iter()
+                .map(|x| x.name.as_str())
+                .collect::<Vec<&str>>();
+            registry.compose(
+                &definition.name,
+                &field_values(&definition.name, &names, &parameters)?,
+            )?
+        }
+        (Some(_), false) => {
+            return Err(format!(
+                "{}: バイト列とフィールドの値は一緒に書けません。",
+                protocol
+            ))
+        }
+        (None, true) => return Err(format!("{}: フレームの中身がありません。", protocol)),
+    };
+    Ok((frame, repeat))
+}
+
+// 文を赤外線リモコン信号の列にする
+pub fn compile_sequence(
+    registry: &ProtocolRegistry,
+    statements: &[SequenceStatement],
+) -> Result<CompiledSequence, String> {
+    let mut carrier_frequency = None;
+    let mut frames: Vec<TimedFrame> = Vec::new();
+    // 直前のフレームの間隔を gap, period の文で書いた
+    let mut written = false;
+    for statement in statements {
+        match statement {
+            SequenceStatement::Carrier(x) => carrier_frequency = Some(*x),
+            SequenceStatement::Frame {
+                protocol,
+                octets,
+                parameters,
+            } => {
+                let (frame, repeat) = compile_frame(registry, protocol, octets, parameters)?;
+                let name = frame.protocol().unwrap_or_default().to_string();
+                // リピート信号の無いプロトコルは同じフレームを繰り返す
+                let repeated = match registry.find(&name) {
+                    Some(d) if d.repeat.is_some() || d.repeat_frame || d.repeat_without_leader => {
+                        InfraredRemoteDemodulatedFrame::Repeat(name)
+                    }
+                    _ => frame.clone(),
+                };
+                frames.push(TimedFrame::from(frame));
+                for _ in 0..repeat {
+                    frames.push(TimedFrame::from(repeated.clone()));
+                }
+                written = false;
+            }
+            SequenceStatement::Spacing(spacing) => {
+                let last = frames
+                    .last_mut()
+                    .ok_or_else(|| "gap, period の前にフレームがありません。".to_string())?;
+                // 続けて書いた間隔は足す
+                last.spacing = match (last.spacing, *spacing) {
+                    (Some(FrameSpacing::Gap(a)), FrameSpacing::Gap(b)) if written => {
+                        Some(FrameSpacing::Gap(a + b))
+                    }
+                    (_, x) => Some(x),
+                };
+                written = true;
+            }
+            // raw の最後のスペースはそのままフレームの間隔にする
+            SequenceStatement::Raw(xs) => {
+                frames.push(TimedFrame {
+                    frame: InfraredRemoteDemodulatedFrame::Unknown(xs.clone()),
+                    spacing: xs
+                        .last()
+                        .and_then(|x| x.trailing_space())
+                        .map(FrameSpacing::Gap),
+                });
+                written = false;
+            }
+        }
+    }
+    Ok(CompiledSequence {
+        carrier_frequency,
+        frames,
+    })
+}
+
+// 手で書いた赤外線リモコン信号の列をマークアンドスペースにする
+pub fn from_sequence(
+    registry: &ProtocolRegistry,
+    input: &str,
+) -> Result<(CompiledSequence, Vec<MarkAndSpaceMicros>), String> {
+    let sequence = compile_sequence(registry, &parse_sequence(input)?)?;
+    let mark_and_spaces = sequence.to_mark_and_spaces(registry)?;
+    Ok((sequence, mark_and_spaces))
+}
+
+#[test]
+fn test_parse_sequence() {
+    let statements = parse_sequence(
+        "carrier 38k; nec addr=0x04 cmd=0x08 repeat=2; gap 40ms # comment\n\
+         aeha 02 20 E0 04 00 00 00 06\n\
+         raw 9000 -4500 560",
+    )
+    .unwrap();
+    assert_eq!(
+        statements,
+        vec!(
+            SequenceStatement::Carrier(38000),
+            SequenceStatement::Frame {
+                protocol: "nec".to_string(),
+                octets: None,
+                parameters: vec!(
+                    ("addr".to_string(), 0x04),
+                    ("cmd".to_string(), 0x08),
+                    ("repeat".to_string(), 2)
+                ),
+            },
+            SequenceStatement::Spacing(FrameSpacing::Gap(Microseconds(40000))),
+            SequenceStatement::Frame {
+                protocol: "aeha".to_string(),
+                octets: Some((vec!(0x02, 0x20, 0xE0, 0x04, 0x00, 0x00, 0x00, 0x06), None)),
+                parameters: vec!(),
+            },
+            SequenceStatement::Raw(vec!(
+                MarkAndSpaceMicros {
+                    mark: Microseconds(9000),
+                    space: Some(Microseconds(4500))
+                },
+                MarkAndSpaceMicros::mark_only(Microseconds(560))
+            )),
+        )
+    );
+    assert!(parse_sequence("gap 40").is_ok());
+    assert!(parse_sequence("raw 9000 4500").is_err());
+    assert!(parse_sequence("nec addr=").is_err());
+    let e = parse_sequence("carrier 38k\ncarrier x").unwrap_err();
+    assert!(e.starts_with("2行目"), "{}", e);
+}
+
+#[test]
+fn test_compile_sequence() {
+    let registry = ProtocolRegistry::builtin();
+    let (sequence, mark_and_spaces) = from_sequence(
+        &registry,
+        "carrier 38k; nec addr=0x04 cmd=0x08 repeat=2; gap 40ms; raw 9000 -4500 560 -560",
+    )
+    .unwrap();
+    assert_eq!(sequence.carrier_frequency, Some(38000));
+    let nec = InfraredRemoteDemodulatedFrame::Nec(BitVec::from_bytes(&[0x04, 0xFB, 0x08, 0xF7]));
+    let repeat = InfraredRemoteDemodulatedFrame::Repeat("NEC".to_string());
+    assert_eq!(
+        sequence.frames,
+        vec!(
+            TimedFrame::from(nec.clone()),
+            TimedFrame::from(repeat.clone()),
+            TimedFrame {
+                frame: repeat.clone(),
+                spacing: Some(FrameSpacing::Gap(Microseconds(40000))),
+            },
+            TimedFrame {
+                frame: InfraredRemoteDemodulatedFrame::Unknown(vec!(
+                    MarkAndSpaceMicros {
+                        mark: Microseconds(9000),
+                        space: Some(Microseconds(4500))
+                    },
+                    MarkAndSpaceMicros {
+                        mark: Microseconds(560),
+                        space: Some(Microseconds(560))
+                    },
+                )),
+                spacing: Some(FrameSpacing::Gap(Microseconds(560))),
+            },
+        )
+    );
+    // 組み立てたフレームは復調できる
+    let frames = crate::segmentation::segment_frames(&registry, &mark_and_spaces)
+        .unwrap()
+        .into_iter()
+        .map(|x| x.frame)
+        .collect::<Vec<_>>();
+    let decoded = registry.decode_frames(&frames);
+    assert_eq!(decoded[..3], [nec, repeat.clone(), repeat]);
+    // raw の前は gap の間隔
+    let raw = mark_and_spaces.len() - 2;
+    assert_eq!(mark_and_spaces[raw - 1].space, Some(Microseconds(40000)));
+    assert_eq!(mark_and_spaces[raw].mark, Microseconds(9000));
+}
+
+#[test]
+fn test_compile_sequence_errors() {
+    let registry = ProtocolRegistry::builtin();
+    let compile = |x: &str| compile_sequence(&registry, &parse_sequence(x)?);
+    assert!(compile("gap 40ms; nec addr=1 cmd=2").is_err());
+    assert!(compile("unknown addr=1").is_err());
+    assert!(compile("nec addr=1 foo=2").is_err());
+    assert!(compile("nec 04 FB 08 F7 cmd=2").is_err());
+    // AEHA はリピート信号が無いので同じフレームを繰り返す
+    let sequence = compile("aeha 02 20 E0 04 repeat=1").unwrap();
+    assert_eq!(sequence.frames[0], sequence.frames[1]);
+    // リピートの数には上限がある
+    assert!(compile("nec addr=1 cmd=2 repeat=100").is_ok());
+    assert!(compile("nec addr=1 cmd=2 repeat=4294967296").is_err());
+    // gap は raw の最後のスペースを置き換える
+    let sequence = compile("raw 560 -560 560 -20000; gap 40ms; gap 10ms").unwrap();
+    assert_eq!(
+        sequence.frames[0].spacing,
+        Some(FrameSpacing::Gap(Microseconds(50000)))
+    );
+}
+
+#[test]
+fn test_sequence_round_trip() {
+    // 組み立てた信号を carrier と raw で書き直しても同じ信号になる
+    let registry = ProtocolRegistry::builtin();
+    let encode = |x: &str| {
+        compile_sequence(&registry, &parse_sequence(x)?)?.encode(&registry)
+    };
+    let first = encode("carrier 40k; sirc 95 01 /12 repeat=1; raw 9000 -4500 560").unwrap();
+    assert_eq!(first.carrier_frequency, Some(40000));
+    let raw = first
+        .signal
+        .iter()
+        .flat_map(|x| {
+            std::iter::once(x.mark.0 as i64).chain(x.trailing_space().map(|y| -(y.0 as i64)))
+        })
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>()
+        .join(" ");
+    let text = format!("carrier {}; raw {}", first.carrier_frequency.unwrap(), raw);
+    assert_eq!(encode(&text), Ok(first));
+}
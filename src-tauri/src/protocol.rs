@@ -0,0 +1,1364 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+use crate::bitvec::BitVec;
+#[cfg(test)]
+use crate::infrared_remote::Bit;
+use crate::infrared_remote::{
+    FrameSpacing, InfraredRemoteDemodulatedFrame, InfraredRemoteFrame, MarkAndSpace,
+    MarkAndSpaceMicros, Microseconds, TimedFrame, IR_CARRIER_FREQ, THRESHOLD_FRAME_GAP,
+};
+use crate::ir_core::{CoreProtocol, PulseCoding};
+use crate::segmentation::SegmentedFrame;
+use crate::time_base::ModulationType;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// 組み込みのプロトコル定義
+/// 先に書いてあるものから順番にデコードを試す
+const BUILTIN_DEFINITIONS: [&str; 9] = [
+    include_str!("../protocols/aeha.json"),
+    include_str!("../protocols/denon.json"),
+    include_str!("../protocols/jvc.json"),
+    include_str!("../protocols/mitsubishi.json"),
+    include_str!("../protocols/nec.json"),
+    include_str!("../protocols/samsung32.json"),
+    include_str!("../protocols/samsung48.json"),
+    include_str!("../protocols/sharp.json"),
+    include_str!("../protocols/sirc.json"),
+];
+
+/// 組み込みのプロトコル定義(初めて使うときに1回だけ読み込む)
+/// 組み込みの定義が読めないのはバグなので、黙って除かずにパニックする
+static BUILTIN: Lazy<ProtocolRegistry> = Lazy::new(|| {
+    ProtocolRegistry(
+        BUILTIN_DEFINITIONS
+            .iter()
+            .flat_map(|x| {
+                ProtocolDefinition::from_json(x)
+                    .unwrap_or_else(|e| panic!("組み込みのプロトコル定義が読めません: {}", e))
+            })
+            .collect(),
+    )
+});
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// ビットオーダー
+pub enum BitOrder {
+    /// 下位ビットが先
+    LsbFirst,
+    /// 上位ビットが先
+    MsbFirst,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// フィールドの検査方法
+pub enum FieldCheck {
+    /// 指定フィールドのビット反転と一致する
+    Inverse(String),
+    /// 指定フィールドを4ビットごとに排他的論理和したものと一致する
+    NibbleParity(String),
+    /// 指定フィールドと一致する
+    Equal(String),
+    /// 決まった値
+    Value(u64),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// フィールドの定義
+pub struct FieldDefinition {
+    /// フィールド名
+    pub name: String,
+    /// ビット数(nullなら残り全部)
+    pub bits: Option<usize>,
+    /// 検査方法
+    #[serde(default)]
+    pub check: Option<FieldCheck>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// フィールドの値
+pub struct FieldValue {
+    /// フィールド名
+    pub name: String,
+    /// ビット数
+    pub bits: usize,
+    /// 値(64ビットを超える場合はNone)
+    pub value: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// プロトコル定義
+/// 時間はすべて基準時間Tの倍数で書く(gap, frame_period, toleranceはマイクロ秒)
+pub struct ProtocolDefinition {
+    /// プロトコル名
+    pub name: String,
+    /// 説明
+    #[serde(default)]
+    pub description: Option<String>,
+    /// 基準時間T
+    pub time_base: Microseconds,
+    /// ずれ時間の許容範囲
+    pub tolerance: Microseconds,
+    /// キャリア周波数
+    #[serde(default = "default_carrier_frequency")]
+    pub carrier_frequency: u32,
+    /// リーダーパルス
+    #[serde(default)]
+    pub leader: Option<MarkAndSpace<u32>>,
+    /// 変調方式
+    pub modulation: ModulationType,
+    /// 0を意味する信号
+    pub bit_zero: MarkAndSpace<u32>,
+    /// 1を意味する信号
+    pub bit_one: MarkAndSpace<u32>,
+    /// ビットオーダー
+    pub bit_order: BitOrder,
+    /// フィールドの並び
+    #[serde(default)]
+    pub fields: Vec<FieldDefinition>,
+    /// ストップビット(マーク時間)
+    #[serde(default)]
+    pub trailer: Option<u32>,
+    /// フレーム最後のスペース時間
+    #[serde(default = "default_gap")]
+    pub gap: Microseconds,
+    /// フレーム周期
+    #[serde(default)]
+    pub frame_period: Option<Microseconds>,
+    /// リピート信号のリーダーパルス(リーダーとストップビットだけのフレーム)
+    #[serde(default)]
+    pub repeat: Option<MarkAndSpace<u32>>,
+    /// リピートはフレーム全体を再送する
+    #[serde(default)]
+    pub repeat_frame: bool,
+    /// リピートはリーダーを省いたフレームを再送する
+    #[serde(default)]
+    pub repeat_without_leader: bool,
+    /// 確認フレームで反転するフィールド(空なら確認フレームは無い)
+    /// 確認フレームはフレーム間隔gapをあけて続けて送る
+    #[serde(default)]
+    pub confirmation: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// 復調結果の候補
+pub struct Interpretation {
+    /// 復調した赤外線リモコン信号
+    pub frame: InfraredRemoteDemodulatedFrame,
+    /// 確からしさ(0.0 ～ 1.0)
+    pub confidence: f64,
+    /// リーダーパルスの一致度
+    pub leader_fit: f64,
+    /// ビットの時間の一致度
+    pub timing_fit: f64,
+    /// ビット数の一致度
+    pub bit_count_fit: f64,
+    /// フィールド検査の一致度(検査が無ければ0.5)
+    pub check_fit: f64,
+}
+
+/// 確からしさの重み(リーダー, ビットの時間, ビット数, フィールド検査)
+const CONFIDENCE_WEIGHTS: (f64, f64, f64, f64) = (0.35, 0.3, 0.2, 0.15);
+
+fn default_carrier_frequency() -> u32 {
+    IR_CARRIER_FREQ as u32
+}
+
+fn default_gap() -> Microseconds {
+    THRESHOLD_FRAME_GAP
+}
+
+impl ProtocolDefinition {
+    /// JSON形式のプロトコル定義を読み込む
+    pub fn from_json(input: &str) -> Result<Vec<Self>, String> {
+        let definitions = match serde_json::from_str::<Vec<ProtocolDefinition>>(input) {
+            Ok(xs) => xs,
+            Err(_) => vec![serde_json::from_str::<ProtocolDefinition>(input)
+                .map_err(|e| format!("protocol definition: {}", e))?],
+        };
+        for x in definitions.iter() {
+            x.validate()?;
+        }
+        Ok(definitions)
+    }
+
+    /// 定義の矛盾を調べる
+    fn validate(&self) -> Result<(), String> {
+        if self.time_base.0 == 0 {
+            return Err(format!("{}: time_base must not be zero.", self.name));
+        }
+        if self.bit_zero == self.bit_one {
+            return Err(format!("{}: bit_zero and bit_one are same.", self.name));
+        }
+        match self.modulation {
+            ModulationType::PulseDistance | ModulationType::PulseWidth => Ok(()),
+            x => Err(format!("{}: {:?} is not supported.", self.name, x)),
+        }
+    }
+
+    /// 時間の定義
+    /// 変調と復調は ir_core の組み込みのプロトコルと同じものを使う
+    pub fn core(&self) -> CoreProtocol<'_> {
+        CoreProtocol {
+            name: &self.name,
+            time_base: self.time_base,
+            tolerance: self.tolerance,
+            leader: self.leader,
+            coding: match self.modulation {
+                ModulationType::PulseWidth => PulseCoding::PulseWidth,
+                _ => PulseCoding::PulseDistance,
+            },
+            bit_zero: self.bit_zero,
+            bit_one: self.bit_one,
+            bits: self.total_bits(),
+            trailer: self.trailer,
+            gap: self.gap,
+            frame_period: self.frame_period,
+            repeat: self.repeat,
+        }
+    }
+
+    /// 決まったビット数(可変長ならNone)
+    pub fn total_bits(&self) -> Option<usize> {
+        self.fields.iter().map(|x| x.bits).sum()
+    }
+
+    /// リピート信号か
+    pub fn is_repeat(&self, input: &InfraredRemoteFrame) -> bool {
+        match (self.repeat, input.0.split_first()) {
+            (Some(repeat), Some((head, tail))) => {
+                tail.len() <= 1 && self.core().compare(*head, repeat)
+            }
+            _ => false,
+        }
+    }
+
+    /// フレームの先頭がこのプロトコルのリーダー(リピート信号のリーダー)と一致するか
+    pub fn matches_leader(&self, head: MarkAndSpaceMicros) -> bool {
+        self.core().matches_leader(head)
+    }
+
+    /// 先頭がheadのフレームが、index番目のマークに続くスペースspaceで終わるか
+    /// elapsedはフレームの先頭からそのマークの終わりまでの時間
+    pub fn is_frame_end(
+        &self,
+        head: MarkAndSpaceMicros,
+        index: usize,
+        elapsed: Microseconds,
+        space: Microseconds,
+    ) -> bool {
+        let core = self.core();
+        core.is_frame_end(core.matches_repeat(head), index, elapsed, space)
+    }
+
+    /// リーダーパルスとストップビットを取り除いたビット部分
+    /// リーダーパルスが一致しなければNone
+    fn body<'a>(&self, input: &'a InfraredRemoteFrame) -> Option<&'a [MarkAndSpaceMicros]> {
+        let body = match self.leader {
+            Some(leader) => {
+                let (head, tail) = input.0.split_first()?;
+                if !self.core().compare(*head, leader) {
+                    return None;
+                }
+                tail
+            }
+            None => &input.0,
+        };
+        // ストップビットを取り除く
+        let body = match self.trailer {
+            Some(_) => body.split_last().map(|(_, init)| init)?,
+            None => body,
+        };
+        if body.is_empty() {
+            None
+        } else {
+            Some(body)
+        }
+    }
+
+    /// 入力信号を復調する
+    /// リーダーパルスが一致しなければNone
+    pub fn demodulate(&self, input: &InfraredRemoteFrame) -> Option<BitVec> {
+        let core = self.core();
+        let bits = self
+            .body(input)?
+            .iter()
+            .map(|x| core.demodulate_bit(*x))
+            .collect::<BitVec>();
+        // リーダーが無いプロトコルはビット数で判断する
+        match (self.leader, self.total_bits()) {
+            (None, Some(n)) if n != bits.len() => None,
+            (None, None) => None,
+            _ => Some(bits),
+        }
+    }
+
+    /// 許容範囲に対してどれだけ一致しているか(0.0 ～ 1.0)
+    fn fit(&self, test: MarkAndSpaceMicros, expected: MarkAndSpace<u32>) -> f64 {
+        let core = self.core();
+        let diff = |a: Microseconds, b: u32| (a.0 as f64 - core.micros(b).0 as f64).abs();
+        let error = (diff(test.mark, expected.mark)
+            + diff(test.space.unwrap_or_default(), expected.space))
+            / (2.0 * self.tolerance.0.max(1) as f64);
+        (1.0 - error).clamp(0.0, 1.0)
+    }
+
+    /// ビット部分の時間がどれだけ0か1の信号に近いか(0.0 ～ 1.0)
+    fn timing_fit(&self, body: &[MarkAndSpaceMicros]) -> f64 {
+        let time_base = self.time_base.0 as f64;
+        let core = self.core();
+        let diff = |a: Microseconds, b: u32| (a.0 as f64 - core.micros(b).0 as f64).abs();
+        let errors = body.iter().enumerate().map(|(index, x)| {
+            // ストップビットが無ければ最後のスペースはフレーム間隔
+            let last = index + 1 == body.len() && self.trailer.is_none();
+            let error = |e: MarkAndSpace<u32>| {
+                if last {
+                    diff(x.mark, e.mark) / time_base
+                } else {
+                    (diff(x.mark, e.mark) + diff(x.space.unwrap_or_default(), e.space))
+                        / (2.0 * time_base)
+                }
+            };
+            error(self.bit_zero).min(error(self.bit_one))
+        });
+        let mean = errors.sum::<f64>() / body.len().max(1) as f64;
+        (1.0 - mean).clamp(0.0, 1.0)
+    }
+
+    /// 入力信号をこのプロトコルとして解釈する
+    /// リーダーパルスが一致しなければNone
+    pub fn interpret(&self, input: &InfraredRemoteFrame) -> Option<Interpretation> {
+        if let (Some(repeat), true) = (self.repeat, self.is_repeat(input)) {
+            let leader_fit = self.fit(input.0[0], repeat);
+            return Some(Interpretation {
+                frame: InfraredRemoteDemodulatedFrame::Repeat(self.name.clone()),
+                confidence: leader_fit,
+                leader_fit,
+                timing_fit: 1.0,
+                bit_count_fit: 1.0,
+                check_fit: 1.0,
+            });
+        }
+        let bits = match self.demodulate(input) {
+            Some(x) => x,
+            None => return self.interpret_repeat_without_leader(input),
+        };
+        let leader_fit = match (self.leader, input.0.first()) {
+            (Some(leader), Some(head)) => self.fit(*head, leader),
+            _ => 0.5,
+        };
+        let timing_fit = self.body(input).map_or(0.0, |x| self.timing_fit(x));
+        let bit_count_fit = match self.total_bits() {
+            Some(n) if n == bits.len() => 1.0,
+            Some(n) => (1.0 - (n as f64 - bits.len() as f64).abs() / n.max(1) as f64).max(0.0),
+            // 可変長ならオクテット単位か
+            None if bits.len() % 8 == 0 => 1.0,
+            None => 0.5,
+        };
+        let check_fit = if self.fields.iter().all(|x| x.check.is_none()) {
+            0.5
+        } else if self.check_fields(&bits).is_ok() {
+            1.0
+        } else {
+            0.0
+        };
+        let confidence = CONFIDENCE_WEIGHTS.0 * leader_fit
+            + CONFIDENCE_WEIGHTS.1 * timing_fit
+            + CONFIDENCE_WEIGHTS.2 * bit_count_fit
+            + CONFIDENCE_WEIGHTS.3 * check_fit;
+        Some(Interpretation {
+            frame: InfraredRemoteDemodulatedFrame::from_protocol(&self.name, bits),
+            confidence,
+            leader_fit,
+            timing_fit,
+            bit_count_fit,
+            check_fit,
+        })
+    }
+
+    /// リーダーを省いたリピート信号として解釈する
+    fn interpret_repeat_without_leader(
+        &self,
+        input: &InfraredRemoteFrame,
+    ) -> Option<Interpretation> {
+        if !self.repeat_without_leader || self.leader.is_none() {
+            return None;
+        }
+        let headless = ProtocolDefinition {
+            leader: None,
+            repeat: None,
+            repeat_without_leader: false,
+            ..self.clone()
+        };
+        headless.interpret(input).map(|x| Interpretation {
+            frame: InfraredRemoteDemodulatedFrame::Repeat(self.name.clone()),
+            ..x
+        })
+    }
+
+    /// 確認フレームのビット列(確認フレームが無ければNone)
+    pub fn confirmation_bits(&self, bits: &BitVec) -> Option<BitVec> {
+        if self.confirmation.is_empty() {
+            return None;
+        }
+        let mut result = bits.clone();
+        let mut offset = 0;
+        for field in self.fields.iter() {
+            let n = field.bits.unwrap_or(bits.len().saturating_sub(offset));
+            if self.confirmation.contains(&field.name) {
+                result.invert(offset..offset + n);
+            }
+            offset += n;
+        }
+        Some(result)
+    }
+
+    /// 入力信号がビット列に対する確認フレームか
+    pub fn is_confirmation(&self, bits: &BitVec, input: &InfraredRemoteFrame) -> bool {
+        match self.confirmation_bits(bits) {
+            Some(expected) => self.demodulate(input) == Some(expected),
+            None => false,
+        }
+    }
+
+    /// ビット列を変調する
+    pub fn modulate(&self, bits: &BitVec) -> Result<InfraredRemoteFrame, String> {
+        Ok(self.core().modulated(bits.iter()).collect())
+    }
+
+    /// リピート信号を変調する
+    pub fn modulate_repeat(&self) -> Result<InfraredRemoteFrame, String> {
+        let core = self.core();
+        let repeat = core
+            .modulated_repeat()
+            .map_err(|_| format!("encode_phase1: {} にリピート信号はありません。", self.name))?;
+        Ok(repeat.collect())
+    }
+
+    /// ビット列をフィールドに分ける
+    pub fn decode_fields(&self, bits: &BitVec) -> Result<Vec<FieldValue>, String> {
+        let mut offset = 0;
+        let mut result = Vec::new();
+        for field in self.fields.iter() {
+            let rest = bits.len() - offset;
+            let n = field.bits.unwrap_or(rest);
+            if rest < n {
+                return Err(format!(
+                    "{}: field {} needs {} bits, but only {} bits left.",
+                    self.name, field.name, n, rest
+                ));
+            }
+            result.push(FieldValue {
+                name: field.name.clone(),
+                bits: n,
+                value: bits.value(offset, n, self.bit_order),
+            });
+            offset += n;
+        }
+        Ok(result)
+    }
+
+    /// フィールドの検査をする
+    pub fn check_fields(&self, bits: &BitVec) -> Result<(), String> {
+        let values = self.decode_fields(bits)?;
+        let find = |name: &str| {
+            values
+                .iter()
+                .find(|x| x.name == name)
+                .ok_or_else(|| format!("{}: field {} is not found.", self.name, name))
+        };
+        for field in self.fields.iter() {
+            let this = find(&field.name)?;
+            let ok = match &field.check {
+                None => true,
+                Some(FieldCheck::Inverse(name)) => {
+                    let other = find(name)?;
+                    let mask = u64::MAX >> (64 - this.bits.clamp(1, 64));
+                    this.bits == other.bits
+                        && this.value.zip(other.value).map(|(a, b)| a == !b & mask) == Some(true)
+                }
+                Some(FieldCheck::NibbleParity(name)) => {
+                    let other = find(name)?;
+                    let parity = other.value.map(|v| {
+                        (0..(other.bits + 3) / 4).fold(0, |acc, i| acc ^ ((v >> (4 * i)) & 0xf))
+                    });
+                    parity.is_some() && this.value == parity
+                }
+                Some(FieldCheck::Equal(name)) => {
+                    let other = find(name)?;
+                    this.bits == other.bits && this.value.is_some() && this.value == other.value
+                }
+                Some(FieldCheck::Value(value)) => this.value == Some(*value),
+            };
+            if !ok {
+                return Err(format!("{}: field {} check failed.", self.name, field.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// フィールドの値からビット列を組み立てる
+    /// 検査用のフィールドは省略すると計算して埋める
+    pub fn compose_fields(&self, values: &BTreeMap<String, u64>) -> Result<BitVec, String> {
+        let mut composed: BTreeMap<&str, (usize, u64)> = BTreeMap::new();
+        // 検査用のフィールドは参照先を先に決める
+        let (checks, plains): (Vec<&FieldDefinition>, Vec<&FieldDefinition>) = self
+            .fields
+            .iter()
+            .partition(|x| x.check.is_some() && !values.contains_key(&x.name));
+        for field in plains.iter().chain(checks.iter()) {
+            let bits = field.bits.ok_or_else(|| {
+                format!("{}: field {} has variable length.", self.name, field.name)
+            })?;
+            let mask = u64::MAX >> (64 - bits.clamp(1, 64));
+            let referred = |name: &str| {
+                composed
+                    .get(name)
+                    .map(|x| x.1)
+                    .ok_or_else(|| format!("{}: field {} is not given.", self.name, name))
+            };
+            let value = match (values.get(&field.name), &field.check) {
+                (Some(x), _) => *x,
+                (None, Some(FieldCheck::Inverse(name))) => !referred(name)?,
+                (None, Some(FieldCheck::Equal(name))) => referred(name)?,
+                (None, Some(FieldCheck::Value(value))) => *value,
+                (None, Some(FieldCheck::NibbleParity(name))) => {
+                    let v = referred(name)?;
+                    (0..16).fold(0, |acc, i| acc ^ ((v >> (4 * i)) & 0xf))
+                }
+                (None, None) => {
+                    return Err(format!("{}: field {} is not given.", self.name, field.name))
+                }
+            };
+            if value & !mask != 0 && values.contains_key(&field.name) {
+                return Err(format!(
+                    "{}: field {} = {:#x} exceeds {} bits.",
+                    self.name, field.name, value, bits
+                ));
+            }
+            composed.insert(&field.name, (bits, value & mask));
+        }
+        let mut bits = BitVec::new();
+        for field in self.fields.iter() {
+            let (n, value) = composed[field.name.as_str()];
+            bits.push_value(value, n, self.bit_order);
+        }
+        self.check_fields(&bits)?;
+        Ok(bits)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// プロトコル定義の一覧
+pub struct ProtocolRegistry(Vec<ProtocolDefinition>);
+
+impl Default for ProtocolRegistry {
+    /// 組み込みのプロトコル定義
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl ProtocolRegistry {
+    /// 組み込みのプロトコル定義
+    /// 定義を追加しないなら builtin_ref を使う
+    pub fn builtin() -> Self {
+        BUILTIN.clone()
+    }
+
+    /// 組み込みのプロトコル定義(複製しない)
+    pub fn builtin_ref() -> &'static Self {
+        &BUILTIN
+    }
+
+    /// プロトコル定義の一覧
+    pub fn definitions(&self) -> &[ProtocolDefinition] {
+        &self.0
+    }
+
+    /// 名前でプロトコル定義を探す
+    pub fn find(&self, name: &str) -> Option<&ProtocolDefinition> {
+        self.0.iter().find(|x| x.name == name)
+    }
+
+    /// 名前でプロトコル定義を探す(大文字小文字を区別しない)
+    pub fn find_ignore_case(&self, name: &str) -> Option<&ProtocolDefinition> {
+        self.0.iter().find(|x| x.name.eq_ignore_ascii_case(name))
+    }
+
+    /// プロトコル定義を追加する
+    /// 同じ名前の定義があれば置き換える
+    pub fn insert(&mut self, definition: ProtocolDefinition) {
+        match self.0.iter_mut().find(|x| x.name == definition.name) {
+            Some(x) => *x = definition,
+            None => self.0.push(definition),
+        }
+    }
+
+    /// JSON形式のプロトコル定義を読み込んで追加する
+    pub fn load_json(&mut self, input: &str) -> Result<(), String> {
+        for x in ProtocolDefinition::from_json(input)? {
+            self.insert(x);
+        }
+        Ok(())
+    }
+
+    /// 全てのプロトコル定義で入力信号を解釈して、確からしい順に並べる
+    pub fn interpret(&self, input: &InfraredRemoteFrame) -> Vec<Interpretation> {
+        let mut result = self
+            .0
+            .iter()
+            .filter_map(|x| x.interpret(input))
+            .collect::<Vec<Interpretation>>();
+        // 同点なら定義の順番を保つ
+        result.sort_by(|a, b| {
+            b.confidence
+                .partial_cmp(&a.confidence)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        result
+    }
+
+    /// 入力信号を復調して赤外線リモコン信号を取り出す
+    /// 一番確からしい解釈を採用する
+    pub fn decode(&self, input: &InfraredRemoteFrame) -> InfraredRemoteDemodulatedFrame {
+        match self.interpret(input).into_iter().next() {
+            Some(x) => x.frame,
+            None => InfraredRemoteDemodulatedFrame::Unknown(input.0.clone()),
+        }
+    }
+
+    /// 確認フレームを取り除いたフレームの添字
+    fn without_confirmations(
+        &self,
+        input: &[InfraredRemoteFrame],
+        decoded: &[InfraredRemoteDemodulatedFrame],
+    ) -> Vec<usize> {
+        let mut result = Vec::new();
+        let mut index = 0;
+        while index < decoded.len() {
+            result.push(index);
+            let definition = decoded[index].protocol().and_then(|name| self.find(name));
+            let confirmed = match (definition, decoded[index].bits(), input.get(index + 1)) {
+                (Some(d), Some(bits), Some(next)) => d.is_confirmation(bits, next),
+                _ => false,
+            };
+            // 確認フレームは1つの論理的なコマンドにまとめる
+            index += if confirmed { 2 } else { 1 };
+        }
+        result
+    }
+
+    /// 連続したフレームを解釈して、確からしい順に並べる
+    /// 確認フレームは直前のフレームにまとめる
+    pub fn interpret_frames(&self, input: &[InfraredRemoteFrame]) -> Vec<Vec<Interpretation>> {
+        let candidates = input
+            .iter()
+            .map(|frame| self.interpret(frame))
+            .collect::<Vec<Vec<Interpretation>>>();
+        let decoded = input
+            .iter()
+            .map(|frame| self.decode(frame))
+            .collect::<Vec<InfraredRemoteDemodulatedFrame>>();
+        self.without_confirmations(input, &decoded)
+            .into_iter()
+            .map(|index| candidates[index].clone())
+            .collect()
+    }
+
+    /// 連続したフレームを復調する
+    /// 確認フレームは直前のフレームにまとめて、
+    /// フレーム全体を再送してリピートするプロトコルは、直前と同じフレームをリピート信号にする
+    pub fn decode_frames(
+        &self,
+        input: &[InfraredRemoteFrame],
+    ) -> Vec<InfraredRemoteDemodulatedFrame> {
+        self.decode_frames_with_index(input)
+            .into_iter()
+            .map(|(_, x)| x)
+            .collect()
+    }
+
+    /// decode_frames の結果を、それぞれの先頭のフレームの位置とともに返す
+    fn decode_frames_with_index(
+        &self,
+        input: &[InfraredRemoteFrame],
+    ) -> Vec<(usize, InfraredRemoteDemodulatedFrame)> {
+        let mut sequencer = FrameSequencer::new(self);
+        input
+            .iter()
+            .enumerate()
+            .filter_map(|(index, frame)| sequencer.push(frame).map(|x| (index, x)))
+            .collect()
+    }
+
+    /// 分割したフレームを復調して、実測したフレームの間隔とともに返す
+    /// 確認フレームをまとめたものは、確認フレームの後の間隔にする
+    pub fn decode_timed_frames(&self, input: &[SegmentedFrame]) -> Vec<TimedFrame> {
+        let frames = input
+            .iter()
+            .map(|x| x.frame.clone())
+            .collect::<Vec<InfraredRemoteFrame>>();
+        let decoded = self.decode_frames_with_index(&frames);
+        let ends = decoded
+            .iter()
+            .skip(1)
+            .map(|(index, _)| *index)
+            .chain(std::iter::once(input.len()))
+            .collect::<Vec<usize>>();
+        decoded
+            .into_iter()
+            .zip(ends)
+            .map(|((_, frame), end)| TimedFrame {
+                frame,
+                spacing: input[end - 1].gap.map(FrameSpacing::Gap),
+            })
+            .collect()
+    }
+
+    /// 1つの赤外線リモコン信号から変調済みフレームを組み立てる
+    /// previousはリピート信号で再送する直前のフレーム
+    fn encode_command<'a>(
+        &self,
+        x: &'a InfraredRemoteDemodulatedFrame,
+        previous: &mut Option<&'a InfraredRemoteDemodulatedFrame>,
+    ) -> Result<Vec<InfraredRemoteFrame>, String> {
+        let definition = x.protocol().and_then(|name| self.find(name));
+        let mut frames = Vec::new();
+        match (x, definition) {
+            (InfraredRemoteDemodulatedFrame::Repeat(name), Some(d))
+                if d.repeat_frame || d.repeat_without_leader =>
+            {
+                let mut frame = match previous {
+                    Some(p) if p.protocol() == Some(name) => self.encode(p)?,
+                    _ => {
+                        return Err(format!(
+                            "encode_phase1: {} のリピート信号の前にフレームがありません。",
+                            name
+                        ))
+                    }
+                };
+                if d.repeat_without_leader && d.leader.is_some() {
+                    frame.0.remove(0);
+                }
+                frames.push(frame);
+            }
+            (InfraredRemoteDemodulatedFrame::Repeat(_), _) => frames.push(self.encode(x)?),
+            // 不明プロトコルのフレームはマークアンドスペースのまま送る
+            (InfraredRemoteDemodulatedFrame::Unknown(xs), _) => {
+                frames.push(InfraredRemoteFrame(xs.clone()))
+            }
+            (_, definition) => {
+                *previous = Some(x);
+                frames.push(self.encode(x)?);
+                // 確認フレーム
+                let confirmation = match (definition, x.bits()) {
+                    (Some(d), Some(bits)) => d.confirmation_bits(bits).map(|y| (d, y)),
+                    _ => None,
+                };
+                if let Some((d, bits)) = confirmation {
+                    frames.push(d.modulate(&bits)?);
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    /// 連続した赤外線リモコン信号から変調済みフレームを組み立てる
+    /// 確認フレームのあるプロトコルは確認フレームを続けて、
+    /// フレームを再送してリピートするプロトコルのリピート信号は、直前のフレームを再送する
+    /// 不明プロトコルのフレームはそのまま
+    pub fn encode_frames(
+        &self,
+        input: &[InfraredRemoteDemodulatedFrame],
+    ) -> Result<Vec<InfraredRemoteFrame>, String> {
+        let mut previous: Option<&InfraredRemoteDemodulatedFrame> = None;
+        let mut frames = Vec::new();
+        for x in input {
+            frames.extend(self.encode_command(x, &mut previous)?);
+        }
+        Ok(frames)
+    }
+
+    /// フレームの間隔を求める
+    /// 指定が無ければプロトコルのフレーム周期、フレーム周期も無ければフレーム間隔にする
+    /// 指定もプロトコル定義も無ければ(不明プロトコル)None
+    fn frame_gap(
+        &self,
+        input: &TimedFrame,
+        frames: &[InfraredRemoteFrame],
+    ) -> Result<Option<Microseconds>, String> {
+        let definition = input.frame.protocol().and_then(|name| self.find(name));
+        let spacing = match (input.spacing, definition) {
+            (Some(x), _) => x,
+            (None, Some(d)) => d
+                .frame_period
+                .map_or(FrameSpacing::Gap(d.gap), FrameSpacing::Period),
+            (None, None) => return Ok(None),
+        };
+        match spacing {
+            FrameSpacing::Gap(gap) => Ok(Some(gap)),
+            FrameSpacing::Period(period) => {
+                // 最後のスペースを除いたフレームの長さ
+                let spaces = frames.iter().flat_map(|x| x.0.iter());
+                let duration = spaces.fold(Microseconds(0), |acc, x| acc + x.duration())
+                    - frames
+                        .last()
+                        .and_then(|x| x.0.last())
+                        .and_then(|x| x.space)
+                        .unwrap_or_default();
+                if period <= duration {
+                    Err(format!(
+                        "encode_phase1: フレーム周期 {}us がフレームの長さ {}us より短いです。",
+                        period.0, duration.0
+                    ))
+                } else {
+                    Ok(Some(period - duration))
+                }
+            }
+        }
+    }
+
+    /// フレームの間隔つきの赤外線リモコン信号から変調済みフレームを組み立てる
+    /// 各赤外線リモコン信号の最後のフレームの最終スペースをフレームの間隔にする
+    /// 不明プロトコルのフレームは、指定が無ければ実測した最終スペースをそのまま使う
+    /// (最終スペースも無ければ THRESHOLD_FRAME_GAP)
+    pub fn encode_timed_frames(
+        &self,
+        input: &[TimedFrame],
+    ) -> Result<Vec<InfraredRemoteFrame>, String> {
+        let mut previous: Option<&InfraredRemoteDemodulatedFrame> = None;
+        let mut frames = Vec::new();
+        for x in input {
+            let mut command = self.encode_command(&x.frame, &mut previous)?;
+            let gap = self.frame_gap(x, &command)?;
+            if let Some(last) = command.last_mut().and_then(|y| y.0.last_mut()) {
+                last.space = gap.or(last.space).or(Some(THRESHOLD_FRAME_GAP));
+            }
+            frames.extend(command);
+        }
+        Ok(frames)
+    }
+
+    /// 赤外線リモコン信号をフィールドに分ける
+    pub fn fields(
+        &self,
+        input: &InfraredRemoteDemodulatedFrame,
+    ) -> Result<Vec<FieldValue>, String> {
+        match (input.protocol(), input.bits()) {
+            (Some(name), Some(bits)) => self
+                .find(name)
+                .ok_or_else(|| format!("{} のプロトコル定義がありません。", name))?
+                .decode_fields(bits),
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// フィールドの値から赤外線リモコン信号を組み立てる
+    pub fn compose(
+        &self,
+        protocol: &str,
+        values: &BTreeMap<String, u64>,
+    ) -> Result<InfraredRemoteDemodulatedFrame, String> {
+        let definition = self
+            .find(protocol)
+            .ok_or_else(|| format!("{} のプロトコル定義がありません。", protocol))?;
+        let bits = definition.compose_fields(values)?;
+        Ok(InfraredRemoteDemodulatedFrame::from_protocol(
+            &definition.name,
+            bits,
+        ))
+    }
+
+    /// 赤外線リモコン信号から変調済みフレームを組み立てる
+    pub fn encode(
+        &self,
+        input: &InfraredRemoteDemodulatedFrame,
+    ) -> Result<InfraredRemoteFrame, String> {
+        let find = |name: &str| {
+            self.find(name)
+                .ok_or_else(|| format!("encode_phase1: {} のプロトコル定義がありません。", name))
+        };
+        match input {
+            InfraredRemoteDemodulatedFrame::Unknown(_) => {
+                Err("encode_phase1: 不明プロトコルでは変調できません。".to_string())
+            }
+            InfraredRemoteDemodulatedFrame::Repeat(name) => find(name)?.modulate_repeat(),
+            x => match (x.protocol(), x.bits()) {
+                (Some(name), Some(bits)) => find(name)?.modulate(bits),
+                _ => Err("encode_phase1: 変調できません。".to_string()),
+            },
+        }
+    }
+}
+
+/// フレームを1つずつ復調する
+/// 確認フレームは直前のフレームにまとめて、
+/// フレーム全体を再送してリピートするプロトコルは、直前と同じフレームをリピート信号にする
+/// 覚えておくのは直前のフレームだけ
+pub struct FrameSequencer<'a> {
+    registry: &'a ProtocolRegistry,
+    /// リピート信号と比べる直前のフレーム
+    previous: Option<InfraredRemoteDemodulatedFrame>,
+    /// 次のフレームが確認フレームかを調べる、直前のフレームのプロトコルとビット列
+    confirming: Option<(String, BitVec)>,
+}
+
+impl<'a> FrameSequencer<'a> {
+    pub fn new(registry: &'a ProtocolRegistry) -> Self {
+        FrameSequencer {
+            registry,
+            previous: None,
+            confirming: None,
+        }
+    }
+
+    /// フレームを復調する
+    /// 直前のフレームの確認フレームならNone
+    pub fn push(&mut self, frame: &InfraredRemoteFrame) -> Option<InfraredRemoteDemodulatedFrame> {
+        let confirmed = match self.confirming.take() {
+            Some((name, bits)) => self
+                .registry
+                .find(&name)
+                .map_or(false, |d| d.is_confirmation(&bits, frame)),
+            None => false,
+        };
+        if confirmed {
+            return None;
+        }
+        let decoded = self.registry.decode(frame);
+        let definition = decoded.protocol().and_then(|name| self.registry.find(name));
+        if let (Some(d), Some(bits)) = (definition, decoded.bits()) {
+            self.confirming = Some((d.name.clone(), bits.clone()));
+        }
+        let repeat_frame = definition.map_or(false, |x| x.repeat_frame);
+        if repeat_frame && self.previous.as_ref() == Some(&decoded) {
+            let repeat = decoded.protocol().unwrap().to_string();
+            Some(InfraredRemoteDemodulatedFrame::Repeat(repeat))
+        } else {
+            // リピート信号は直前のフレームを変えない
+            if decoded.bits().is_some() {
+                self.previous = Some(decoded.clone());
+            }
+            Some(decoded)
+        }
+    }
+}
+
+#[test]
+fn test_builtin_definitions() {
+    let registry = ProtocolRegistry::builtin();
+    assert_eq!(
+        registry
+            .definitions()
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect::<Vec<&str>>(),
+        vec!(
+            "AEHA",
+            "DENON",
+            "JVC",
+            "MITSUBISHI",
+            "NEC",
+            "SAMSUNG32",
+            "SAMSUNG48",
+            "SHARP",
+            "SIRC"
+        )
+    );
+}
+
+#[test]
+fn test_builtin_definitions_load() {
+    // 組み込みの定義はすべて読める
+    for x in BUILTIN_DEFINITIONS.iter() {
+        if let Err(e) = ProtocolDefinition::from_json(x) {
+            panic!("{}\n{}", e, x);
+        }
+    }
+    assert!(std::ptr::eq(
+        ProtocolRegistry::builtin_ref(),
+        ProtocolRegistry::builtin_ref()
+    ));
+}
+
+#[test]
+fn test_load_json() {
+    let mut registry = ProtocolRegistry::builtin();
+    let json = r#"{
+        "name": "VENDOR",
+        "time_base": 500,
+        "tolerance": 200,
+        "leader": { "mark": 6, "space": 6 },
+        "modulation": "PulseDistance",
+        "bit_zero": { "mark": 1, "space": 1 },
+        "bit_one": { "mark": 1, "space": 2 },
+        "bit_order": "MsbFirst",
+        "fields": [ { "name": "data", "bits": 4 } ],
+        "trailer": 1
+    }"#;
+    assert_eq!(registry.load_json(json), Ok(()));
+    let definition = registry.find("VENDOR").unwrap();
+    assert_eq!(definition.gap, THRESHOLD_FRAME_GAP);
+    assert_eq!(definition.total_bits(), Some(4));
+    //
+    let bits = [Bit(1), Bit(0), Bit(1), Bit(1)]
+        .iter()
+        .copied()
+        .collect::<BitVec>();
+    let frame = InfraredRemoteDemodulatedFrame::from_protocol("VENDOR", bits.clone());
+    let modulated = registry.encode(&frame).unwrap();
+    assert_eq!(
+        registry.decode(&modulated),
+        InfraredRemoteDemodulatedFrame::Defined {
+            protocol: "VENDOR".to_string(),
+            bits: bits.clone(),
+        }
+    );
+    assert_eq!(
+        definition.decode_fields(&bits),
+        Ok(vec!(FieldValue {
+            name: "data".to_string(),
+            bits: 4,
+            value: Some(0b1011),
+        }))
+    );
+}
+
+#[test]
+fn test_load_invalid_json() {
+    let mut registry = ProtocolRegistry::builtin();
+    assert!(registry.load_json("{}").is_err());
+    assert!(registry
+        .load_json(
+            r#"{ "name": "X", "time_base": 0, "tolerance": 1, "modulation": "PulseWidth",
+                 "bit_zero": { "mark": 1, "space": 1 }, "bit_one": { "mark": 2, "space": 1 },
+                 "bit_order": "LsbFirst" }"#
+        )
+        .is_err());
+}
+
+#[test]
+fn test_nec_repeat() {
+    let registry = ProtocolRegistry::builtin();
+    let repeat = InfraredRemoteDemodulatedFrame::Repeat("NEC".to_string());
+    let modulated = registry.encode(&repeat).unwrap();
+    assert_eq!(registry.decode(&modulated), repeat);
+}
+
+#[test]
+fn test_check_fields() {
+    let nec = ProtocolRegistry::builtin().find("NEC").unwrap().clone();
+    // custom code 0x00FF, data 0x12, inverted data 0xED
+    let bits = BitVec::from_bytes(&[0x00, 0xFF, 0x12, 0xED]);
+    assert_eq!(nec.check_fields(&bits), Ok(()));
+    let mut broken = bits;
+    broken.set(31, Bit(0));
+    assert!(nec.check_fields(&broken).is_err());
+}
+
+#[test]
+fn test_interpret() {
+    let mut registry = ProtocolRegistry::builtin();
+    // NECと同じ時間で16ビットのプロトコル
+    let json = r#"{
+        "name": "NEC16",
+        "time_base": 562,
+        "tolerance": 300,
+        "leader": { "mark": 16, "space": 8 },
+        "modulation": "PulseDistance",
+        "bit_zero": { "mark": 1, "space": 1 },
+        "bit_one": { "mark": 1, "space": 3 },
+        "bit_order": "LsbFirst",
+        "fields": [ { "name": "data", "bits": 16 } ],
+        "trailer": 1
+    }"#;
+    registry.load_json(json).unwrap();
+    let bits = BitVec::from_bytes(&[0x00, 0xFF, 0x12, 0xED]);
+    let frame = registry
+        .encode(&InfraredRemoteDemodulatedFrame::Nec(bits.clone()))
+        .unwrap();
+    let candidates = registry.interpret(&frame);
+    assert_eq!(
+        candidates
+            .iter()
+            .map(|x| x.frame.protocol().unwrap())
+            .collect::<Vec<&str>>(),
+        vec!("NEC", "NEC16")
+    );
+    assert!(candidates[1].confidence < candidates[0].confidence);
+    assert_eq!(
+        registry.decode(&frame),
+        InfraredRemoteDemodulatedFrame::Nec(bits.clone())
+    );
+    // 反転ビットが壊れていると確からしさが下がる
+    let mut broken = bits;
+    broken.set(31, Bit(0));
+    let broken_frame = registry
+        .encode(&InfraredRemoteDemodulatedFrame::Nec(broken))
+        .unwrap();
+    let broken_candidates = registry.interpret(&broken_frame);
+    assert_eq!(broken_candidates[0].check_fit, 0.0);
+    assert!(broken_candidates[0].confidence < candidates[0].confidence);
+}
+
+#[test]
+fn test_samsung() {
+    let registry = ProtocolRegistry::builtin();
+    let values = [("address".to_string(), 0x07), ("command".to_string(), 0x02)]
+        .iter()
+        .cloned()
+        .collect::<BTreeMap<String, u64>>();
+    let frame = registry.compose("SAMSUNG32", &values).unwrap();
+    assert_eq!(
+        registry.fields(&frame).unwrap(),
+        vec!(
+            FieldValue {
+                name: "address".to_string(),
+                bits: 8,
+                value: Some(0x07)
+            },
+            FieldValue {
+                name: "address_repeated".to_string(),
+                bits: 8,
+                value: Some(0x07)
+            },
+            FieldValue {
+                name: "command".to_string(),
+                bits: 8,
+                value: Some(0x02)
+            },
+            FieldValue {
+                name: "command_inverted".to_string(),
+                bits: 8,
+                value: Some(0xFD)
+            },
+        )
+    );
+    let modulated = registry.encode(&frame).unwrap();
+    // 4.5ms / 4.5ms のリーダー
+    assert_eq!(
+        modulated.0[0],
+        MarkAndSpace {
+            mark: Microseconds(4480),
+            space: Some(Microseconds(4480))
+        }
+    );
+    assert_eq!(registry.decode(&modulated), frame);
+    // 48ビット
+    let mut values = values;
+    values.insert("command2".to_string(), 0x40);
+    let frame48 = registry.compose("SAMSUNG48", &values).unwrap();
+    assert_eq!(frame48.bits().unwrap().len(), 48);
+    let modulated48 = registry.encode(&frame48).unwrap();
+    assert_eq!(registry.decode(&modulated48), frame48);
+    // リピートはフレーム全体を再送する
+    let repeat = InfraredRemoteDemodulatedFrame::Repeat("SAMSUNG32".to_string());
+    let frames = registry
+        .encode_frames(&[frame.clone(), repeat.clone(), repeat.clone()])
+        .unwrap();
+    assert_eq!(
+        frames,
+        vec!(modulated.clone(), modulated.clone(), modulated)
+    );
+    assert_eq!(
+        registry.decode_frames(&frames),
+        vec!(frame, repeat.clone(), repeat.clone())
+    );
+    assert!(registry.encode_frames(&[repeat]).is_err());
+}
+
+#[test]
+fn test_sharp_and_denon() {
+    let registry = ProtocolRegistry::builtin();
+    for (protocol, address, command) in [("SHARP", 0x01, 0x23), ("DENON", 0x02, 0xA5)] {
+        let values = [
+            ("address".to_string(), address),
+            ("command".to_string(), command),
+        ]
+        .iter()
+        .cloned()
+        .collect::<BTreeMap<String, u64>>();
+        let frame = registry.compose(protocol, &values).unwrap();
+        assert_eq!(frame.bits().unwrap().len(), 15);
+        // 通常フレームと確認フレームを送る
+        let frames = registry
+            .encode_frames(std::slice::from_ref(&frame))
+            .unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0.last().unwrap().space, Some(Microseconds(40000)));
+        assert_ne!(frames[0], frames[1]);
+        // 2つのフレームを1つのコマンドにまとめる
+        assert_eq!(registry.decode_frames(&frames), vec!(frame.clone()));
+        assert_eq!(registry.interpret_frames(&frames).len(), 1);
+        assert_eq!(registry.decode(&frames[0]), frame);
+    }
+}
+
+#[test]
+fn test_jvc_repeat() {
+    let registry = ProtocolRegistry::builtin();
+    let values = [("address".to_string(), 0x03), ("command".to_string(), 0x17)]
+        .iter()
+        .cloned()
+        .collect::<BTreeMap<String, u64>>();
+    let frame = registry.compose("JVC", &values).unwrap();
+    let repeat = InfraredRemoteDemodulatedFrame::Repeat("JVC".to_string());
+    let frames = registry
+        .encode_frames(&[frame.clone(), repeat.clone()])
+        .unwrap();
+    // リピートはリーダーが無い
+    assert_eq!(frames[1].0.len() + 1, frames[0].0.len());
+    assert_eq!(frames[1].0[..], frames[0].0[1..]);
+    assert_eq!(registry.decode_frames(&frames), vec!(frame, repeat));
+}
+
+#[test]
+fn test_mitsubishi() {
+    let registry = ProtocolRegistry::builtin();
+    let values = [("address".to_string(), 0x47), ("command".to_string(), 0x0C)]
+        .iter()
+        .cloned()
+        .collect::<BTreeMap<String, u64>>();
+    let frame = registry.compose("MITSUBISHI", &values).unwrap();
+    let frames = registry
+        .encode_frames(std::slice::from_ref(&frame))
+        .unwrap();
+    assert_eq!(frames.len(), 1);
+    let candidates = registry.interpret(&frames[0]);
+    assert_eq!(candidates[0].frame, frame);
+    // 同じビット数のJVCリピートより確からしい
+    assert!(candidates
+        .iter()
+        .skip(1)
+        .all(|x| x.confidence < candidates[0].confidence));
+}
+
+#[test]
+fn test_timed_frames() {
+    use crate::segmentation::segment_frames;
+    let registry = ProtocolRegistry::builtin();
+    let values = [
+        ("custom_code".to_string(), 0xFF00),
+        ("data".to_string(), 0x12),
+    ]
+    .iter()
+    .cloned()
+    .collect::<BTreeMap<String, u64>>();
+    let frame = registry.compose("NEC", &values).unwrap();
+    let repeat = InfraredRemoteDemodulatedFrame::Repeat("NEC".to_string());
+    let duration = |xs: &[InfraredRemoteFrame]| {
+        xs.iter()
+            .flat_map(|x| x.0.iter())
+            .fold(Microseconds(0), |acc, x| acc + x.duration())
+    };
+    // 指定が無ければプロトコルのフレーム周期にする
+    let frames = registry
+        .encode_timed_frames(&[TimedFrame::from(frame.clone())])
+        .unwrap();
+    assert_eq!(duration(&frames), Microseconds(108000));
+    // 間隔の指定
+    let timed = vec![
+        TimedFrame {
+            frame: frame.clone(),
+            spacing: Some(FrameSpacing::Gap(Microseconds(30000))),
+        },
+        TimedFrame {
+            frame: repeat.clone(),
+            spacing: Some(FrameSpacing::Period(Microseconds(100000))),
+        },
+    ];
+    let frames = registry.encode_timed_frames(&timed).unwrap();
+    assert_eq!(frames[0].0.last().unwrap().space, Some(Microseconds(30000)));
+    assert_eq!(duration(&frames[1..]), Microseconds(100000));
+    // 実測した間隔を保つ
+    let input = frames
+        .into_iter()
+        .flat_map(|x| x.0)
+        .collect::<Vec<MarkAndSpaceMicros>>();
+    let segmented = segment_frames(&registry, &input).unwrap();
+    let decoded = registry.decode_timed_frames(&segmented);
+    assert_eq!(decoded[0], timed[0]);
+    assert_eq!(decoded[1].frame, repeat);
+    assert_eq!(
+        registry.encode_timed_frames(&decoded).unwrap(),
+        registry.encode_timed_frames(&timed).unwrap()
+    );
+    // フレームより短い周期
+    let too_short = TimedFrame {
+        frame,
+        spacing: Some(FrameSpacing::Period(Microseconds(50000))),
+    };
+    assert!(registry.encode_timed_frames(&[too_short]).is_err());
+}
+
+#[test]
+fn test_timed_unknown_frames() {
+    let registry = ProtocolRegistry::builtin();
+    let unknown = |last: MarkAndSpaceMicros| {
+        InfraredRemoteDemodulatedFrame::Unknown(vec![
+            MarkAndSpaceMicros::from((Microseconds(3000), Microseconds(3000))),
+            last,
+        ])
+    };
+    let captured = unknown(MarkAndSpaceMicros::from((
+        Microseconds(500),
+        Microseconds(25000),
+    )));
+    let last_space = |x: TimedFrame| {
+        registry.encode_timed_frames(&[x]).unwrap()[0]
+            .0
+            .last()
+            .unwrap()
+            .space
+    };
+    // 指定が無ければ実測したスペースのまま
+    assert_eq!(
+        last_space(TimedFrame::from(captured.clone())),
+        Some(Microseconds(25000))
+    );
+    // 指定があれば指定の間隔
+    assert_eq!(
+        last_space(TimedFrame {
+            frame: captured,
+            spacing: Some(FrameSpacing::Gap(Microseconds(30000))),
+        }),
+        Some(Microseconds(30000))
+    );
+    // 最後のスペースが無ければ THRESHOLD_FRAME_GAP
+    assert_eq!(
+        last_space(TimedFrame::from(unknown(MarkAndSpaceMicros::mark_only(
+            Microseconds(500)
+        )))),
+        Some(THRESHOLD_FRAME_GAP)
+    );
+}
+
+#[test]
+fn test_timed_confirmation_frames() {
+    use crate::segmentation::segment_frames;
+    let registry = ProtocolRegistry::builtin();
+    let values = [("address".to_string(), 0x01), ("command".to_string(), 0x23)]
+        .iter()
+        .cloned()
+        .collect::<BTreeMap<String, u64>>();
+    let timed = TimedFrame {
+        frame: registry.compose("SHARP", &values).unwrap(),
+        spacing: Some(FrameSpacing::Gap(Microseconds(70000))),
+    };
+    let frames = registry
+        .encode_timed_frames(std::slice::from_ref(&timed))
+        .unwrap();
+    // 通常フレームと確認フレームの間はプロトコルのフレーム間隔
+    assert_eq!(frames[0].0.last().unwrap().space, Some(Microseconds(40000)));
+    assert_eq!(frames[1].0.last().unwrap().space, Some(Microseconds(70000)));
+    let input = frames
+        .into_iter()
+        .flat_map(|x| x.0)
+        .collect::<Vec<MarkAndSpaceMicros>>();
+    let segmented = segment_frames(&registry, &input).unwrap();
+    assert_eq!(registry.decode_timed_frames(&segmented), vec!(timed));
+}
@@ -0,0 +1,312 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+use crate::infrared_remote::{
+    InfraredRemoteFrame, MarkAndSpaceMicros, Microseconds, THRESHOLD_FRAME_GAP,
+};
+use crate::protocol::{ProtocolDefinition, ProtocolRegistry};
+use serde::{Deserialize, Serialize};
+
+/// スペース時間の分布から求める閾値の下限
+/// (どのプロトコルのリーダーのスペースよりも、THRESHOLD_FRAME_GAP よりも長い)
+/// これより短いフレーム間隔は THRESHOLD_FRAME_GAP かプロトコルの定義で分ける
+pub const MINIMUM_FRAME_GAP: Microseconds = Microseconds(10000);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 分割したフレーム
+pub struct SegmentedFrame {
+    /// フレーム(最後のスペースはフレーム間隔)
+    pub frame: InfraredRemoteFrame,
+    /// 実測したフレーム後の間隔(最後のマークにスペースが無ければNone)
+    pub gap: Option<Microseconds>,
+}
+
+/// スペースの無いマークは次のマークに続くので結合する
+pub fn join_continued_marks(input: &[MarkAndSpaceMicros]) -> Vec<MarkAndSpaceMicros> {
+    let mut joined: Vec<MarkAndSpaceMicros> = Vec::new();
+    for x in input {
+        match joined.last_mut() {
+            Some(last) if last.trailing_space().is_none() => {
+                last.mark = last.mark + x.mark;
+                last.space = x.space;
+            }
+            _ => joined.push(*x),
+        }
+    }
+    joined
+}
+
+/// スペース時間の分布からフレーム間隔の閾値を求める
+/// MINIMUM_FRAME_GAP 以上で、一つ短いスペースの1.5倍以上になる最初(一番短い)ところを閾値にする
+/// (それより長いスペースはすべてフレーム間隔)
+/// 見つからなければNone
+pub fn gap_threshold(input: &[MarkAndSpaceMicros]) -> Option<Microseconds> {
+    let mut spaces = input
+        .iter()
+        .filter_map(|x| x.trailing_space())
+        .collect::<Vec<Microseconds>>();
+    spaces.sort();
+    spaces.dedup();
+    gap_threshold_of_spaces(&spaces)
+}
+
+/// 昇順に並べて重複を除いたスペース時間から gap_threshold と同じ閾値を求める
+pub fn gap_threshold_of_spaces(spaces: &[Microseconds]) -> Option<Microseconds> {
+    spaces
+        .windows(2)
+        .find(|xs| MINIMUM_FRAME_GAP <= xs[1] && 1.5 * xs[0].0 as f64 <= xs[1].0 as f64)
+        .map(|xs| xs[1])
+}
+
+/// マークアンドスペースを1つずつ受け取ってフレームに分ける
+/// フレームの先頭のリーダーでプロトコルがわかれば、そのビット数とフレーム周期でフレームの終わりを決める
+/// わからなければthreshold以上のスペースで分ける
+pub struct FrameSplitter<'a> {
+    registry: &'a ProtocolRegistry,
+    threshold: Microseconds,
+    frame: Vec<MarkAndSpaceMicros>,
+    candidates: Vec<&'a ProtocolDefinition>,
+    elapsed: Microseconds,
+}
+
+impl<'a> FrameSplitter<'a> {
+    pub fn new(registry: &'a ProtocolRegistry, threshold: Microseconds) -> Self {
+        FrameSplitter {
+            registry,
+            threshold,
+            frame: Vec::new(),
+            candidates: Vec::new(),
+            elapsed: Microseconds(0),
+        }
+    }
+
+    /// 分けている途中のフレームのマークアンドスペースの数
+    pub fn len(&self) -> usize {
+        self.frame.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frame.is_empty()
+    }
+
+    /// フレームの終わりを決める閾値を変える
+    pub fn set_threshold(&mut self, threshold: Microseconds) {
+        self.threshold = threshold;
+    }
+
+    /// 次にxを渡したらフレームの終わりになるか
+    /// (スペースが長くなっても終わりでなくなることはない)
+    pub fn is_end(&self, x: MarkAndSpaceMicros) -> bool {
+        let space = match x.trailing_space() {
+            Some(space) => space,
+            None => return false,
+        };
+        if self.threshold <= space {
+            return true;
+        }
+        match self.frame.first() {
+            Some(head) => {
+                let elapsed = self.elapsed + x.mark;
+                self.candidates
+                    .iter()
+                    .any(|d| d.is_frame_end(*head, self.frame.len(), elapsed, space))
+            }
+            None => self
+                .registry
+                .definitions()
+                .iter()
+                .any(|d| d.matches_leader(x) && d.is_frame_end(x, 0, x.mark, space)),
+        }
+    }
+
+    /// スペースの無いマークは結合してから渡す
+    /// フレームの終わりならそのフレームを返す
+    pub fn push(&mut self, x: MarkAndSpaceMicros) -> Option<SegmentedFrame> {
+        let end = self.is_end(x);
+        if self.frame.is_empty() {
+            self.candidates = self
+                .registry
+                .definitions()
+                .iter()
+                .filter(|d| d.matches_leader(x))
+                .collect();
+            self.elapsed = Microseconds(0);
+        }
+        self.elapsed = self.elapsed + x.mark + x.space.unwrap_or_default();
+        self.frame.push(x);
+        if end {
+            Some(SegmentedFrame {
+                frame: InfraredRemoteFrame(std::mem::take(&mut self.frame)),
+                gap: x.space,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// 途中のフレームを終わりにして返す
+    pub fn finish(&mut self) -> Option<SegmentedFrame> {
+        let gap = self.frame.last()?.trailing_space();
+        Some(SegmentedFrame {
+            frame: InfraredRemoteFrame(std::mem::take(&mut self.frame)),
+            gap,
+        })
+    }
+}
+
+/// 入力マークアンドスペース列を、threshold以上のスペース(またはリーダーでわかったプロトコルのフレームの終わり)で各フレームに分ける
+pub fn segment_frames_with_threshold(
+    registry: &ProtocolRegistry,
+    input: &[MarkAndSpaceMicros],
+    threshold: Microseconds,
+) -> Vec<SegmentedFrame> {
+    let mut splitter = FrameSplitter::new(registry, threshold);
+    let mut result = join_continued_marks(input)
+        .into_iter()
+        .filter_map(|x| splitter.push(x))
+        .collect::<Vec<SegmentedFrame>>();
+    result.extend(splitter.finish());
+    result
+}
+
+/// 入力マークアンドスペース列を各フレームに分ける
+/// フレームの先頭のリーダーでプロトコルがわかれば、そのビット数とフレーム周期でフレームの終わりを決める
+/// わからなければスペース時間の分布から求めた閾値(求められなければ THRESHOLD_FRAME_GAP)で分ける
+pub fn segment_frames(
+    registry: &ProtocolRegistry,
+    input: &[MarkAndSpaceMicros],
+) -> Result<Vec<SegmentedFrame>, String> {
+    if input.is_empty() {
+        return Err("segment_frames: input is empty.".to_string());
+    }
+    let threshold = gap_threshold(&join_continued_marks(input)).unwrap_or(THRESHOLD_FRAME_GAP);
+    Ok(segment_frames_with_threshold(registry, input, threshold))
+}
+
+#[cfg(test)]
+mod segmentation_tests {
+    use crate::infrared_remote::{
+        Bit, InfraredRemoteDemodulatedFrame, MarkAndSpaceMicros, Microseconds,
+    };
+    use crate::protocol::ProtocolRegistry;
+    use crate::segmentation::{gap_threshold, segment_frames};
+
+    fn ms(mark: u32, space: u32) -> MarkAndSpaceMicros {
+        MarkAndSpaceMicros {
+            mark: Microseconds(mark),
+            space: Some(Microseconds(space)),
+        }
+    }
+
+    /// フレームを変調して最後のスペースをgapにする
+    fn modulate(
+        registry: &ProtocolRegistry,
+        frame: &InfraredRemoteDemodulatedFrame,
+        gap: u32,
+    ) -> Vec<MarkAndSpaceMicros> {
+        let mut xs = registry.encode(frame).unwrap().0;
+        if let Some(last) = xs.last_mut() {
+            last.space = Some(Microseconds(gap));
+        }
+        xs
+    }
+
+    #[test]
+    fn test_gap_threshold() {
+        // MINIMUM_FRAME_GAP 以上で、一つ短いスペースの1.5倍以上になる最初のところ
+        // (4.5ms から 9ms も1.5倍以上だが、9ms は MINIMUM_FRAME_GAP より短いのでフレームの途中)
+        let input = vec![
+            ms(560, 560),
+            ms(560, 1690),
+            ms(9000, 4500),
+            ms(560, 9000),
+            ms(560, 40000),
+        ];
+        assert_eq!(gap_threshold(&input), Some(Microseconds(40000)));
+        // フレーム間隔が無い
+        assert_eq!(gap_threshold(&[ms(9000, 4500), ms(560, 560)]), None);
+        // 20ms のリピートの間隔と 100ms の間隔があれば短いほう
+        let mut input = vec![ms(9000, 4500), ms(560, 560), ms(560, 1690)];
+        for _ in 0..3 {
+            input.push(ms(560, 20000));
+            input.push(ms(9000, 2250));
+        }
+        input.push(ms(560, 100000));
+        assert_eq!(gap_threshold(&input), Some(Microseconds(20000)));
+    }
+
+    #[test]
+    fn test_short_gap() {
+        // 8ms より短い間隔で続くNECフレームもリーダーとビット数で分ける
+        let registry = ProtocolRegistry::builtin();
+        let nec = InfraredRemoteDemodulatedFrame::Nec(
+            [0x00u8, 0xFF, 0x12, 0xED]
+                .iter()
+                .flat_map(|byte| (0..8).map(move |i| Bit((byte >> i) & 1)))
+                .collect(),
+        );
+        let mut input = modulate(&registry, &nec, 6000);
+        input.extend(modulate(&registry, &nec, 40000));
+        let frames = segment_frames(&registry, &input).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].gap, Some(Microseconds(6000)));
+        assert_eq!(frames[1].gap, Some(Microseconds(40000)));
+        assert_eq!(registry.decode(&frames[0].frame), nec);
+        assert_eq!(registry.decode(&frames[1].frame), nec);
+    }
+
+    #[test]
+    fn test_long_intra_frame_space() {
+        // 不明プロトコルの9msのスペースはフレームの途中、40msはフレーム間隔
+        let registry = ProtocolRegistry::builtin();
+        let input = vec![
+            ms(3000, 3000),
+            ms(500, 500),
+            ms(500, 9000),
+            ms(500, 1500),
+            ms(500, 40000),
+            ms(3000, 3000),
+            ms(500, 500),
+            ms(500, 9000),
+            ms(500, 1500),
+            MarkAndSpaceMicros::mark_only(Microseconds(500)),
+        ];
+        let frames = segment_frames(&registry, &input).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].frame.0.len(), 5);
+        assert_eq!(frames[0].gap, Some(Microseconds(40000)));
+        assert_eq!(frames[1].gap, None);
+    }
+
+    #[test]
+    fn test_aeha_sub_frames() {
+        // 10msと35msの間隔で続くエアコンのフレーム
+        let registry = ProtocolRegistry::builtin();
+        let aeha = |bytes: &[u8]| {
+            InfraredRemoteDemodulatedFrame::Aeha(
+                bytes
+                    .iter()
+                    .flat_map(|byte| (0..8).map(move |i| Bit((byte >> i) & 1)))
+                    .collect(),
+            )
+        };
+        let first = aeha(&[0x02, 0x20, 0xE0, 0x04, 0x00]);
+        let second = aeha(&[0x02, 0x20, 0xE0, 0x04, 0x00, 0x41, 0x30]);
+        let mut input = modulate(&registry, &first, 10000);
+        input.extend(modulate(&registry, &second, 35000));
+        input.extend(modulate(&registry, &first, 10000));
+        let frames = segment_frames(&registry, &input).unwrap();
+        assert_eq!(
+            frames
+                .iter()
+                .map(|x| (registry.decode(&x.frame), x.gap))
+                .collect::<Vec<_>>(),
+            vec!(
+                (first.clone(), Some(Microseconds(10000))),
+                (second, Some(Microseconds(35000))),
+                (first, Some(Microseconds(10000))),
+            )
+        );
+    }
+}
@@ -0,0 +1,483 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+//! シリアルポート(USB-シリアル)接続の学習リモコンでの受信と送信
+//!
+//! 赤外線リモコンコード(キャリア周期の数、16ビットリトルエンディアンの16進数)で
+//! やりとりする、行単位のテキストプロトコル
+//!
+//! | 送る               | 応答                                           |
+//! |--------------------|------------------------------------------------|
+//! | `I`                | `OK` (状態を初期化する)                        |
+//! | `L`                | `OK` 、赤外線を受信したら赤外線リモコンコード  |
+//! | `S,<赤外線リモコンコード>` | 送信し終わったら `OK`                  |
+//!
+//! 失敗したら `ERR <理由>` を返す
+use crate::infrared_remote::{encode_phase3, MarkAndSpaceMicros};
+use crate::parsing::from_infrared_code;
+use std::io;
+use std::time::Duration;
+
+/// 学習リモコンとの1行のやりとり
+pub trait SerialLine {
+    /// 1行書く(改行は付け足す)
+    fn write_line(&mut self, line: &str) -> io::Result<()>;
+    /// 1行読む(改行は取り除く、空行は読み飛ばす)
+    /// timeout以内に読めなかったら io::ErrorKind::TimedOut
+    fn read_line(&mut self, timeout: Duration) -> io::Result<String>;
+    /// 受信済みで読んでいないものを捨てる
+    fn discard_input(&mut self) -> io::Result<()>;
+}
+
+/// 学習リモコン
+pub trait IrLearner {
+    /// 学習モードにして赤外線リモコンコードを受け取る
+    fn learn(&mut self) -> Result<String, String>;
+    /// 赤外線リモコンコードを送信する
+    fn send(&mut self, ircode: &str) -> Result<(), String>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// 学習リモコンとのやりとりの設定
+pub struct SerialOptions {
+    /// ボーレート
+    pub baud_rate: u32,
+    /// コマンドの応答を待つ時間
+    pub response_timeout: Duration,
+    /// 学習モードで赤外線を受信するまで待つ時間
+    pub learn_timeout: Duration,
+    /// 応答が無い、またはエラーの時にやり直す回数
+    /// (送信は送ったかわからなくなるので `ERR` の時だけやり直す)
+    pub retries: usize,
+}
+
+impl Default for SerialOptions {
+    fn default() -> Self {
+        SerialOptions {
+            baud_rate: 115_200,
+            response_timeout: Duration::from_secs(1),
+            learn_timeout: Duration::from_secs(10),
+            retries: 2,
+        }
+    }
+}
+
+/// コマンドの失敗
+enum CommandError {
+    /// 学習リモコンが `ERR` を返した(コマンドは実行していない)
+    Refused(String),
+    /// 応答が無いか不正(コマンドを実行したかわからない)
+    Unknown(String),
+}
+
+impl From<CommandError> for String {
+    fn from(e: CommandError) -> Self {
+        match e {
+            CommandError::Refused(x) | CommandError::Unknown(x) => x,
+        }
+    }
+}
+
+/// 16ビットリトルエンディアンの16進数で赤外線リモコンコードをやりとりする学習リモコン
+pub struct HexCounterLearner<L: SerialLine> {
+    line: L,
+    options: SerialOptions,
+}
+
+impl<L: SerialLine> HexCounterLearner<L> {
+    pub fn new(line: L, options: SerialOptions) -> Self {
+        HexCounterLearner { line, options }
+    }
+
+    /// 中の SerialLine を返す
+    pub fn into_inner(self) -> L {
+        self.line
+    }
+
+    /// 1行読んで、`ERR` ならエラーにする
+    fn read_response(&mut self, timeout: Duration) -> Result<String, CommandError> {
+        let line = self.line.read_line(timeout).map_err(|e| match e.kind() {
+            io::ErrorKind::TimedOut => {
+                CommandError::Unknown("学習リモコンから応答がありません。".to_string())
+            }
+            _ => CommandError::Unknown(e.to_string()),
+        })?;
+        match line.strip_prefix("ERR") {
+            Some(reason) => Err(CommandError::Refused(format!(
+                "学習リモコンのエラー: {}",
+                reason.trim()
+            ))),
+            None => Ok(line),
+        }
+    }
+
+    /// コマンドを送って `OK` を待つ
+    fn command(&mut self, command: &str) -> Result<(), CommandError> {
+        self.line
+            .write_line(command)
+            .map_err(|e| CommandError::Unknown(e.to_string()))?;
+        match self.read_response(self.options.response_timeout)? {
+            x if x == "OK" => Ok(()),
+            x => Err(CommandError::Unknown(format!(
+                "学習リモコンの応答が不正です: {}",
+                x
+            ))),
+        }
+    }
+
+    /// 読み残しを捨てて、学習リモコンの状態を初期化する
+    fn recover(&mut self) -> Result<(), String> {
+        self.line.discard_input().map_err(|e| e.to_string())?;
+        Ok(self.command("I")?)
+    }
+
+    /// 失敗したら初期化してやり直す
+    fn with_retries<T, F>(&mut self, mut f: F) -> Result<T, String>
+    where
+        F: FnMut(&mut Self) -> Result<T, String>,
+    {
+        let mut result = f(self);
+        for _ in 0..self.options.retries {
+            if result.is_ok() {
+                break;
+            }
+            result = self.recover().and_then(|_| f(self));
+        }
+        result
+    }
+}
+
+impl<L: SerialLine> IrLearner for HexCounterLearner<L> {
+    /// 赤外線を受信できないまま learn_timeout が過ぎたら、学習モードを止めてエラーにする
+    fn learn(&mut self) -> Result<String, String> {
+        self.with_retries(|x| Ok(x.command("L")?))?;
+        let timeout = self.options.learn_timeout;
+        match self.read_response(timeout) {
+            Ok(ircode) => {
+                from_infrared_code(&ircode).map_err(|e| {
+                    let _ = self.recover();
+                    format!("学習リモコンから受け取ったコードが不正です: {}", e)
+                })?;
+                Ok(ircode)
+            }
+            Err(e) => {
+                let _ = self.recover();
+                Err(e.into())
+            }
+        }
+    }
+
+    /// `ERR` が返ったら送信していないので、初期化して送り直す
+    /// 応答が無ければ送信したかもしれないので、初期化だけして送り直さない
+    fn send(&mut self, ircode: &str) -> Result<(), String> {
+        from_infrared_code(ircode)?;
+        let command = format!("S,{}", ircode);
+        let mut retries = self.options.retries;
+        loop {
+            match self.command(&command) {
+                Ok(()) => return Ok(()),
+                Err(CommandError::Refused(_)) if 0 < retries => {
+                    retries -= 1;
+                    self.recover()?;
+                }
+                Err(CommandError::Refused(e)) => return Err(e),
+                Err(CommandError::Unknown(e)) => {
+                    let _ = self.recover();
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// マークアンドスペース列を encode_phase3 の形式で送信する
+pub fn send_mark_and_spaces<T: IrLearner + ?Sized>(
+    learner: &mut T,
+    input: &[MarkAndSpaceMicros],
+) -> Result<(), String> {
+    if input.is_empty() {
+        return Err("send_mark_and_spaces: input is empty.".to_string());
+    }
+    learner.send(&encode_phase3(input))
+}
+
+/// termios で設定したシリアルポート
+#[cfg(target_os = "linux")]
+pub struct TtyLine {
+    file: std::fs::File,
+    buffer: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl TtyLine {
+    /// 開いた端末をRAWモード、8N1、指定のボーレートにする
+    pub fn from_file(file: std::fs::File, baud_rate: u32) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+        let speed = match baud_rate {
+            9600 => libc::B9600,
+            19200 => libc::B19200,
+            38400 => libc::B38400,
+            57600 => libc::B57600,
+            115_200 => libc::B115200,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} bps には対応していません。", baud_rate),
+                ))
+            }
+        };
+        let fd = file.as_raw_fd();
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(fd, &mut termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+            if libc::cfsetspeed(&mut termios, speed) < 0
+                || libc::tcsetattr(fd, libc::TCSANOW, &termios) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(TtyLine {
+            file,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// バッファから1行取り出す
+    fn take_line(&mut self) -> Option<String> {
+        while let Some(position) = self.buffer.iter().position(|x| *x == b'\n') {
+            let line = self.buffer.drain(..=position).collect::<Vec<u8>>();
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            if !line.is_empty() {
+                return Some(line);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl SerialLine for TtyLine {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        use std::io::Write;
+        self.file.write_all(format!("{}\r\n", line).as_bytes())?;
+        self.file.flush()
+    }
+
+    fn read_line(&mut self, timeout: Duration) -> io::Result<String> {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            if let Some(line) = self.take_line() {
+                return Ok(line);
+            }
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            let mut fds = libc::pollfd {
+                fd: self.file.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let millis = remaining.as_millis().min(i32::MAX as u128) as i32;
+            let result = unsafe { libc::poll(&mut fds, 1, millis) };
+            if result < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            } else if result == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "read_line: timed out.",
+                ));
+            }
+            let mut buf = [0u8; 256];
+            match self.file.read(&mut buf)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => self.buffer.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    fn discard_input(&mut self) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        self.buffer.clear();
+        if unsafe { libc::tcflush(self.file.as_raw_fd(), libc::TCIFLUSH) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// シリアルポートの学習リモコンを開く
+#[cfg(target_os = "linux")]
+pub fn open_learner(path: &str, options: SerialOptions) -> io::Result<HexCounterLearner<TtyLine>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    let line = TtyLine::from_file(file, options.baud_rate)?;
+    Ok(HexCounterLearner::new(line, options))
+}
+
+/// 今はLinuxだけ
+#[cfg(not(target_os = "linux"))]
+pub fn open_learner(
+    path: &str,
+    _options: SerialOptions,
+) -> io::Result<HexCounterLearner<Box<dyn SerialLine + Send>>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!(
+            "{}: シリアルポートの学習リモコンはLinuxだけで使えます。",
+            path
+        ),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+impl<T: SerialLine + ?Sized> SerialLine for Box<T> {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        (**self).write_line(line)
+    }
+    fn read_line(&mut self, timeout: Duration) -> io::Result<String> {
+        (**self).read_line(timeout)
+    }
+    fn discard_input(&mut self) -> io::Result<()> {
+        (**self).discard_input()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod serial_tests {
+    use crate::infrared_remote::{encode_phase3, MarkAndSpaceMicros};
+    use crate::parsing::from_infrared_code;
+    use crate::serial::{
+        send_mark_and_spaces, HexCounterLearner, IrLearner, SerialOptions, TtyLine,
+    };
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::io::FromRawFd;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    const IRCODE: &str = "5601A900180015001800140018001400190013001900140019001400170040001700150018003F0019003E0018003E0019003F0019003E00170040001800140019003E001800150018003F00180014001800140019003F0018001400170016001700150018003F001800140018003F0018003F001800140019003F0018003F0018003E0019004F03";
+
+    /// 疑似端末の片方を学習リモコンとして動かし、もう片方をつないだ HexCounterLearner を返す
+    /// 学習リモコンは受け取った行を送り、respondの返した行を返す
+    fn pty_learner<F>(respond: F) -> (HexCounterLearner<TtyLine>, mpsc::Receiver<String>)
+    where
+        F: FnMut(&str) -> Vec<String> + Send + 'static,
+    {
+        let (mut master, mut slave) = (0, 0);
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(result, 0);
+        let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+        let options = SerialOptions {
+            response_timeout: Duration::from_millis(200),
+            learn_timeout: Duration::from_millis(500),
+            ..SerialOptions::default()
+        };
+        let line = TtyLine::from_file(slave, options.baud_rate).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let mut respond = respond;
+        thread::spawn(move || {
+            let mut writer = master.try_clone().unwrap();
+            let reader = BufReader::new(master);
+            // 相手が閉じたら読めなくなって終わる
+            for line in reader.lines() {
+                let line = match line {
+                    Ok(x) => x.trim().to_string(),
+                    Err(_) => break,
+                };
+                for response in respond(&line) {
+                    let _ = writer.write_all(format!("{}\r\n", response).as_bytes());
+                }
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        (HexCounterLearner::new(line, options), receiver)
+    }
+
+    #[test]
+    fn test_learn_and_send() {
+        let (mut learner, received) = pty_learner(|line| match line {
+            "L" => vec!["OK".to_string(), IRCODE.to_string()],
+            _ => vec!["OK".to_string()],
+        });
+        assert_eq!(learner.learn(), Ok(IRCODE.to_string()));
+        assert_eq!(received.recv().unwrap(), "L");
+
+        let input = from_infrared_code(IRCODE)
+            .unwrap()
+            .iter()
+            .map(|x| MarkAndSpaceMicros::from(*x))
+            .collect::<Vec<MarkAndSpaceMicros>>();
+        send_mark_and_spaces(&mut learner, &input).unwrap();
+        let sent = received.recv().unwrap();
+        assert_eq!(sent, format!("S,{}", encode_phase3(&input)));
+    }
+
+    #[test]
+    fn test_recovery() {
+        // 最初の送信はエラー、初期化したあとは成功する
+        let mut count = 0;
+        let (mut learner, received) = pty_learner(move |line| {
+            count += 1;
+            match (count, line) {
+                (1, _) => vec!["ERR busy".to_string()],
+                _ => vec!["OK".to_string()],
+            }
+        });
+        assert_eq!(learner.send(IRCODE), Ok(()));
+        let lines = received.iter().take(3).collect::<Vec<String>>();
+        assert_eq!(lines[1], "I");
+        assert_eq!(lines[0], lines[2]);
+    }
+
+    #[test]
+    fn test_timeout() {
+        // 学習モードにはなるが、赤外線を受信しない
+        let (mut learner, received) = pty_learner(|line| match line {
+            "L" | "I" => vec!["OK".to_string()],
+            _ => vec![],
+        });
+        assert!(learner.learn().is_err());
+        assert_eq!(received.recv().unwrap(), "L");
+        // 学習モードを止める
+        assert_eq!(received.recv().unwrap(), "I");
+
+        // 応答しない学習リモコン
+        let (mut learner, _received) = pty_learner(|_| vec![]);
+        assert!(learner.send(IRCODE).is_err());
+        // 送信の応答が無ければ初期化するが、送り直さない
+        let (mut learner, received) = pty_learner(|line| match line {
+            "I" => vec!["OK".to_string()],
+            _ => vec![],
+        });
+        assert!(learner.send(IRCODE).is_err());
+        assert_eq!(received.recv().unwrap(), format!("S,{}", IRCODE));
+        assert_eq!(received.recv().unwrap(), "I");
+        assert!(received.recv_timeout(Duration::from_millis(500)).is_err());
+        // 不正なコードは送らない
+        assert!(learner.send("XYZ").is_err());
+    }
+}
@@ -0,0 +1,365 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+use crate::infrared_remote::{
+    IrCarrierCounter, MarkAndSpaceIrCarrier, MarkAndSpaceMicros, Microseconds, IR_CARRIER_FREQ,
+};
+use crate::segmentation::join_continued_marks;
+use serde::{Deserialize, Serialize};
+use std::convert;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 赤外線リモコン信号の時間列
+/// マークとスペースを交互に並べる
+/// (長さ0の時間は、その前後が続いていることを表す)
+pub struct Signal {
+    /// 最初の時間がマークか(falseならスペースから始まる)
+    pub first_is_mark: bool,
+    /// マークとスペースを交互に並べた時間
+    pub durations: Vec<Microseconds>,
+    /// キャリア周波数(Hz)
+    pub carrier_frequency: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 時間列の1区間
+pub struct Segment {
+    /// 信号の先頭からの開始時刻
+    pub start: Microseconds,
+    /// 長さ
+    pub duration: Microseconds,
+    /// マークか(falseならスペース)
+    pub mark: bool,
+}
+
+/// キャリア周波数カウンタからマイクロ秒にする(四捨五入)
+fn counter_to_micros(x: IrCarrierCounter, carrier_frequency: u32) -> Microseconds {
+    let f = carrier_frequency.max(1) as u64;
+    let y = (2_000_000u64 * x.0 as u64 + f) / (2 * f);
+    Microseconds(u32::try_from(y).unwrap_or(u32::MAX))
+}
+
+/// マイクロ秒からキャリア周波数カウンタにする(四捨五入)
+fn micros_to_counter(x: Microseconds, carrier_frequency: u32) -> IrCarrierCounter {
+    let y = (2 * x.0 as u64 * carrier_frequency as u64 + 1_000_000) / 2_000_000;
+    IrCarrierCounter(u32::try_from(y).unwrap_or(u32::MAX))
+}
+
+impl Signal {
+    /// マークから始まる時間列
+    pub fn new(durations: Vec<Microseconds>) -> Self {
+        Signal {
+            first_is_mark: true,
+            durations,
+            carrier_frequency: IR_CARRIER_FREQ as u32,
+        }
+    }
+
+    /// マークアンドスペースから時間列にする
+    /// 最後のスペースの無いマークは、マークで終わる時間列になる
+    /// 途中のスペースの無いマークは、復調するときと同じく次のマークと結合する(join_continued_marks)
+    /// 長さ0のスペースはそのまま長さ0のスペースにする
+    /// 先頭の長さ0のマーク(to_mark_and_spaces で付けたもの)はスペースから始まる時間列にする
+    pub fn from_mark_and_spaces(input: &[MarkAndSpaceMicros]) -> Self {
+        let input = join_continued_marks(input);
+        let mut durations = input
+            .iter()
+            .flat_map(|x| vec![x.mark, x.space.unwrap_or_default()])
+            .collect::<Vec<Microseconds>>();
+        if input.last().map_or(false, |x| x.space.is_none()) {
+            durations.pop();
+        }
+        match input.first() {
+            Some(x) if x.mark == Microseconds(0) && x.space.is_some() => Signal {
+                first_is_mark: false,
+                durations: durations.split_off(1),
+                carrier_frequency: IR_CARRIER_FREQ as u32,
+            },
+            _ => Signal::new(durations),
+        }
+    }
+
+    /// キャリア周波数カウンタのマークアンドスペースから時間列にする
+    pub fn from_ir_carrier(input: &[MarkAndSpaceIrCarrier], carrier_frequency: u32) -> Self {
+        let mut signal = Signal::from_mark_and_spaces(
+            &input
+                .iter()
+                .map(|x| MarkAndSpaceMicros {
+                    mark: counter_to_micros(x.mark, carrier_frequency),
+                    space: match x.space {
+                        IrCarrierCounter(0) => None,
+                        y => Some(counter_to_micros(y, carrier_frequency)),
+                    },
+                })
+                .collect::<Vec<MarkAndSpaceMicros>>(),
+        );
+        signal.carrier_frequency = carrier_frequency;
+        signal
+    }
+
+    /// 先頭のスペース(マークから始まるならNone)
+    pub fn leading_space(&self) -> Option<Microseconds> {
+        if self.first_is_mark {
+            None
+        } else {
+            self.durations.first().copied()
+        }
+    }
+
+    /// マークアンドスペースにする
+    /// 先頭のスペースは長さ0のマークに続くスペースにする
+    pub fn to_mark_and_spaces(&self) -> Vec<MarkAndSpaceMicros> {
+        let leading = self.leading_space().map(|_| Microseconds(0));
+        leading
+            .iter()
+            .chain(self.durations.iter())
+            .copied()
+            .collect::<Vec<Microseconds>>()
+            .chunks(2)
+            .map(|xs| MarkAndSpaceMicros {
+                mark: xs[0],
+                space: xs.get(1).copied(),
+            })
+            .collect()
+    }
+
+    /// キャリア周波数カウンタのマークアンドスペースにする
+    pub fn to_ir_carrier(&self) -> Vec<MarkAndSpaceIrCarrier> {
+        self.to_mark_and_spaces()
+            .iter()
+            .map(|x| MarkAndSpaceIrCarrier {
+                mark: micros_to_counter(x.mark, self.carrier_frequency),
+                space: x.space.map_or(IrCarrierCounter(0), |y| {
+                    micros_to_counter(y, self.carrier_frequency)
+                }),
+            })
+            .collect()
+    }
+
+    /// 全体の長さ
+    pub fn duration(&self) -> Microseconds {
+        self.durations
+            .iter()
+            .fold(Microseconds(0), |acc, x| acc + *x)
+    }
+
+    /// 各区間を開始時刻とともに取り出す
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        let first_is_mark = self.first_is_mark;
+        self.durations
+            .iter()
+            .enumerate()
+            .scan(Microseconds(0), move |start, (index, duration)| {
+                let segment = Segment {
+                    start: *start,
+                    duration: *duration,
+                    mark: (index % 2 == 0) == first_is_mark,
+                };
+                *start = *start + *duration;
+                Some(segment)
+            })
+    }
+
+    /// 信号が変化する時刻と変化後の状態(trueならマーク)
+    /// マークで終わるなら、信号の終わりの時刻をスペースとして付け加える
+    pub fn edges(&self) -> impl Iterator<Item = (Microseconds, bool)> + '_ {
+        let end = self
+            .segments()
+            .filter(|x| x.duration != Microseconds(0))
+            .last()
+            .filter(|x| x.mark)
+            .map(|x| (x.start + x.duration, false));
+        self.segments()
+            .filter(|x| x.duration != Microseconds(0))
+            .scan(None, |previous, x| {
+                let changed = *previous != Some(x.mark);
+                *previous = Some(x.mark);
+                Some(if changed {
+                    Some((x.start, x.mark))
+                } else {
+                    None
+                })
+            })
+            .flatten()
+            .chain(end)
+    }
+
+    /// 開始時刻から終了時刻までを切り出す
+    /// 範囲の境目にかかる区間は範囲内だけの長さにする
+    pub fn slice(&self, start: Microseconds, end: Microseconds) -> Signal {
+        let mut first_is_mark = None;
+        let mut durations = Vec::new();
+        for x in self.segments() {
+            let from = x.start.max(start);
+            let to = (x.start + x.duration).min(end);
+            if to <= from {
+                continue;
+            }
+            if first_is_mark.is_none() {
+                first_is_mark = Some(x.mark);
+            }
+            durations.push(to - from);
+        }
+        Signal {
+            first_is_mark: first_is_mark.unwrap_or(true),
+            durations,
+            carrier_frequency: self.carrier_frequency,
+        }
+    }
+}
+
+impl convert::From<&[MarkAndSpaceMicros]> for Signal {
+    fn from(input: &[MarkAndSpaceMicros]) -> Self {
+        Signal::from_mark_and_spaces(input)
+    }
+}
+
+impl convert::From<&[MarkAndSpaceIrCarrier]> for Signal {
+    fn from(input: &[MarkAndSpaceIrCarrier]) -> Self {
+        Signal::from_ir_carrier(input, IR_CARRIER_FREQ as u32)
+    }
+}
+
+impl convert::From<&Signal> for Vec<MarkAndSpaceMicros> {
+    fn from(input: &Signal) -> Self {
+        input.to_mark_and_spaces()
+    }
+}
+
+impl convert::From<&Signal> for Vec<MarkAndSpaceIrCarrier> {
+    fn from(input: &Signal) -> Self {
+        input.to_ir_carrier()
+    }
+}
+
+#[cfg(test)]
+mod signal_tests {
+    use crate::infrared_remote::{
+        IrCarrierCounter, MarkAndSpaceIrCarrier, MarkAndSpaceMicros, Microseconds,
+    };
+    use crate::parsing::from_infrared_code;
+    use crate::segmentation::join_continued_marks;
+    use crate::signal::{Segment, Signal};
+
+    fn ms(mark: u32, space: u32) -> MarkAndSpaceMicros {
+        MarkAndSpaceMicros {
+            mark: Microseconds(mark),
+            space: Some(Microseconds(space)),
+        }
+    }
+
+    #[test]
+    fn test_mark_and_spaces_round_trip() {
+        let input = vec![
+            ms(9000, 4500),
+            ms(560, 0),
+            ms(560, 1690),
+            MarkAndSpaceMicros::mark_only(Microseconds(560)),
+        ];
+        let signal = Signal::from(input.as_slice());
+        assert_eq!(signal.durations.len(), 7);
+        assert_eq!(signal.to_mark_and_spaces(), input);
+    }
+
+    #[test]
+    fn test_continued_marks_round_trip() {
+        // 途中のスペースの無いマークは次のマークと結合する
+        let input = vec![
+            ms(9000, 4500),
+            MarkAndSpaceMicros::mark_only(Microseconds(300)),
+            ms(260, 1690),
+            MarkAndSpaceMicros::mark_only(Microseconds(560)),
+        ];
+        let signal = Signal::from(input.as_slice());
+        assert_eq!(signal.durations.len(), 5);
+        assert_eq!(signal.to_mark_and_spaces(), join_continued_marks(&input));
+        assert_eq!(Signal::from(signal.to_mark_and_spaces().as_slice()), signal);
+    }
+
+    #[test]
+    fn test_ir_carrier_round_trip() {
+        let ircode = "5601AB00 15001500 15004000 81004000 16000000";
+        let input = from_infrared_code(ircode).unwrap();
+        let signal = Signal::from(input.as_slice());
+        assert_eq!(signal.to_ir_carrier(), input);
+        assert_eq!(
+            Vec::<MarkAndSpaceIrCarrier>::from(&signal)[0],
+            MarkAndSpaceIrCarrier {
+                mark: IrCarrierCounter(0x0156),
+                space: IrCarrierCounter(0x00AB),
+            }
+        );
+    }
+
+    #[test]
+    fn test_leading_space() {
+        let signal = Signal {
+            first_is_mark: false,
+            durations: vec![Microseconds(1000), Microseconds(560), Microseconds(560)],
+            carrier_frequency: 38000,
+        };
+        assert_eq!(signal.leading_space(), Some(Microseconds(1000)));
+        // 先頭のスペースは長さ0のマークに続くスペースになる
+        let mark_and_spaces = signal.to_mark_and_spaces();
+        assert_eq!(mark_and_spaces, vec!(ms(0, 1000), ms(560, 560)));
+        assert_eq!(Signal::from_mark_and_spaces(&mark_and_spaces), signal);
+        assert_eq!(
+            signal.segments().next(),
+            Some(Segment {
+                start: Microseconds(0),
+                duration: Microseconds(1000),
+                mark: false
+            })
+        );
+    }
+
+    #[test]
+    fn test_edges() {
+        let signal = Signal::from_mark_and_spaces(&[ms(9000, 4500), ms(560, 0), ms(560, 560)]);
+        assert_eq!(
+            signal.edges().collect::<Vec<(Microseconds, bool)>>(),
+            vec!(
+                (Microseconds(0), true),
+                (Microseconds(9000), false),
+                (Microseconds(13500), true),
+                (Microseconds(14620), false),
+            )
+        );
+        // マークで終われば終わりの時刻を付け加える
+        let signal = Signal::from_mark_and_spaces(&[
+            ms(9000, 4500),
+            MarkAndSpaceMicros::mark_only(Microseconds(560)),
+        ]);
+        assert_eq!(
+            signal.edges().collect::<Vec<(Microseconds, bool)>>(),
+            vec!(
+                (Microseconds(0), true),
+                (Microseconds(9000), false),
+                (Microseconds(13500), true),
+                (Microseconds(14060), false),
+            )
+        );
+    }
+
+    #[test]
+    fn test_slice() {
+        let signal = Signal::from_mark_and_spaces(&[ms(9000, 4500), ms(560, 560)]);
+        let sliced = signal.slice(Microseconds(8000), Microseconds(14000));
+        assert!(sliced.first_is_mark);
+        assert_eq!(
+            sliced.durations,
+            vec!(Microseconds(1000), Microseconds(4500), Microseconds(500))
+        );
+        let sliced = signal.slice(Microseconds(10000), Microseconds(20000));
+        assert!(!sliced.first_is_mark);
+        assert_eq!(
+            sliced.durations,
+            vec!(Microseconds(3500), Microseconds(560), Microseconds(560))
+        );
+        assert!(signal
+            .slice(Microseconds(20000), Microseconds(30000))
+            .durations
+            .is_empty());
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+//! 受信し続けている信号の逐次復調
+use crate::infrared_remote::{
+    InfraredRemoteDemodulatedFrame, MarkAndSpaceMicros, Microseconds, THRESHOLD_FRAME_GAP,
+};
+use crate::protocol::{FrameSequencer, ProtocolRegistry};
+use crate::segmentation::{gap_threshold_of_spaces, FrameSplitter, SegmentedFrame};
+use serde::{Deserialize, Serialize};
+
+/// 1フレームのマークアンドスペースの最大数(これを超えたらフレームを区切る)
+pub const MAX_FRAME_LENGTH: usize = 1024;
+
+/// フレーム間隔の閾値を求めるのに使う直近のスペースの数
+pub const MAX_SPACES: usize = 512;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 逐次復調の結果
+pub enum StreamEvent {
+    /// 復調したフレームと、実測したフレーム後の間隔
+    Frame {
+        frame: InfraredRemoteDemodulatedFrame,
+        gap: Option<Microseconds>,
+    },
+    /// リピート信号
+    Repeat {
+        protocol: String,
+        gap: Option<Microseconds>,
+    },
+}
+
+impl StreamEvent {
+    /// decode_frames と同じ形の復調済みフレーム
+    pub fn frame(&self) -> InfraredRemoteDemodulatedFrame {
+        match self {
+            StreamEvent::Frame { frame, .. } => frame.clone(),
+            StreamEvent::Repeat { protocol, .. } => {
+                InfraredRemoteDemodulatedFrame::Repeat(protocol.clone())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 時刻つきの信号の変化
+pub struct Edge {
+    /// 変化した時刻(us)
+    pub timestamp: u64,
+    /// 変化後がマークならtrue、スペースならfalse
+    pub mark: bool,
+}
+
+/// 時間を1つずつ受け取って復調する
+/// フレームの分割は segment_frames 、復調は ProtocolRegistry::decode_frames と同じ
+/// (スペース時間の分布は直近の MAX_SPACES 個のスペースから求める)
+/// スペースがフレームの終わりの長さになったところでフレームを返す
+pub struct StreamingDecoder<'a> {
+    splitter: FrameSplitter<'a>,
+    sequencer: FrameSequencer<'a>,
+    /// 決まった閾値(Noneならスペース時間の分布から求める)
+    threshold: Option<Microseconds>,
+    /// 直近のスペース時間(MAX_SPACES 個で一巡するリングバッファ)
+    spaces: Vec<Microseconds>,
+    /// 次にスペース時間を書く位置
+    next_space: usize,
+    /// 長さの決まっていないマークアンドスペース
+    current: Option<MarkAndSpaceMicros>,
+    /// 直前の変化
+    last_edge: Option<Edge>,
+}
+
+impl<'a> StreamingDecoder<'a> {
+    /// segment_frames と同じく、スペース時間の分布から求めた閾値(求められなければ THRESHOLD_FRAME_GAP)でフレームを分ける
+    pub fn new(registry: &'a ProtocolRegistry) -> Self {
+        StreamingDecoder {
+            splitter: FrameSplitter::new(registry, THRESHOLD_FRAME_GAP),
+            sequencer: FrameSequencer::new(registry),
+            threshold: None,
+            spaces: Vec::with_capacity(MAX_SPACES),
+            next_space: 0,
+            current: None,
+            last_edge: None,
+        }
+    }
+
+    /// threshold以上のスペースでフレームを分ける
+    pub fn with_threshold(registry: &'a ProtocolRegistry, threshold: Microseconds) -> Self {
+        StreamingDecoder {
+            splitter: FrameSplitter::new(registry, threshold),
+            threshold: Some(threshold),
+            ..Self::new(registry)
+        }
+    }
+
+    /// マークかスペースの時間を1つ受け取る
+    /// 続けて同じ種類を受け取ったら足し合わせる
+    pub fn push(&mut self, mark: bool, duration: Microseconds) -> Vec<StreamEvent> {
+        match (self.current.as_mut(), mark) {
+            // 先頭のスペースは無視する
+            (None, false) => Vec::new(),
+            (None, true) => {
+                self.current = Some(MarkAndSpaceMicros::mark_only(duration));
+                Vec::new()
+            }
+            (Some(current), true) if current.space.is_none() => {
+                current.mark = current.mark + duration;
+                Vec::new()
+            }
+            (Some(current), false) => {
+                let space = current.space.unwrap_or_default() + duration;
+                current.space = Some(space);
+                let x = *current;
+                // フレームの終わりなら次のマークを待たずに返す
+                let threshold = self.threshold_with(space);
+                self.splitter.set_threshold(threshold);
+                if self.splitter.is_end(x) {
+                    self.complete()
+                } else {
+                    Vec::new()
+                }
+            }
+            (Some(_), true) => {
+                let events = self.complete();
+                self.current = Some(MarkAndSpaceMicros::mark_only(duration));
+                events
+            }
+        }
+    }
+
+    /// 時刻つきの信号の変化をまとめて受け取る
+    pub fn push_edges(&mut self, edges: &[Edge]) -> Vec<StreamEvent> {
+        let mut events = Vec::new();
+        for edge in edges {
+            if let Some(last) = self.last_edge {
+                let duration = edge.timestamp.saturating_sub(last.timestamp);
+                let duration = Microseconds(u32::try_from(duration).unwrap_or(u32::MAX));
+                events.extend(self.push(last.mark, duration));
+            }
+            self.last_edge = Some(*edge);
+        }
+        events
+    }
+
+    /// 受信の終わり(タイムアウトなど)
+    /// 最後のスペースの長さが決まったとみなして、残りのフレームを返す
+    /// スペース時間の分布は次の受信では初めから求める
+    pub fn finish(&mut self) -> Vec<StreamEvent> {
+        let mut events = self.complete();
+        if let Some(frame) = self.splitter.finish() {
+            events.extend(self.decode(frame));
+        }
+        self.spaces.clear();
+        self.next_space = 0;
+        self.last_edge = None;
+        events
+    }
+
+    /// 直近のスペースにspaceを加えたときの閾値
+    fn threshold_with(&self, space: Microseconds) -> Microseconds {
+        if let Some(threshold) = self.threshold {
+            return threshold;
+        }
+        let mut spaces = self.spaces.clone();
+        spaces.push(space);
+        spaces.sort();
+        spaces.dedup();
+        gap_threshold_of_spaces(&spaces).unwrap_or(THRESHOLD_FRAME_GAP)
+    }
+
+    /// スペース時間を記録する(MAX_SPACES 個を超えたら一番古いものに上書きする)
+    fn record_space(&mut self, space: Microseconds) {
+        if self.spaces.len() < MAX_SPACES {
+            self.spaces.push(space);
+        } else {
+            self.spaces[self.next_space] = space;
+        }
+        self.next_space = (self.next_space + 1) % MAX_SPACES;
+    }
+
+    /// 長さの決まったマークアンドスペースをフレームに分ける
+    fn complete(&mut self) -> Vec<StreamEvent> {
+        let mut events = Vec::new();
+        if let Some(x) = self.current.take() {
+            if let Some(space) = x.trailing_space() {
+                let threshold = self.threshold_with(space);
+                self.splitter.set_threshold(threshold);
+                self.record_space(space);
+            }
+            if let Some(frame) = self.splitter.push(x) {
+                events.extend(self.decode(frame));
+            } else if MAX_FRAME_LENGTH <= self.splitter.len() {
+                // フレームの終わりが見つからないまま長くなりすぎた
+                if let Some(frame) = self.splitter.finish() {
+                    events.extend(self.decode(frame));
+                }
+            }
+        }
+        events
+    }
+
+    fn decode(&mut self, input: SegmentedFrame) -> Option<StreamEvent> {
+        let gap = input.gap;
+        self.sequencer.push(&input.frame).map(|frame| match frame {
+            InfraredRemoteDemodulatedFrame::Repeat(protocol) => {
+                StreamEvent::Repeat { protocol, gap }
+            }
+            frame => StreamEvent::Frame { frame, gap },
+        })
+    }
+}
+
+#[cfg(test)]
+mod stream_tests {
+    use crate::infrared_remote::{
+        decode_phase1, Bit, InfraredRemoteDemodulatedFrame, MarkAndSpaceMicros, Microseconds,
+    };
+    use crate::protocol::ProtocolRegistry;
+    use crate::signal::Signal;
+    use crate::stream::{Edge, StreamEvent, StreamingDecoder, MAX_SPACES};
+    use std::collections::BTreeMap;
+
+    /// 40ms 間隔で3回送った SAMSUNG32 のフレーム
+    fn input(registry: &ProtocolRegistry) -> Vec<MarkAndSpaceMicros> {
+        let values = [("address".to_string(), 0x07), ("command".to_string(), 0x02)]
+            .iter()
+            .cloned()
+            .collect::<BTreeMap<String, u64>>();
+        let frame = registry.compose("SAMSUNG32", &values).unwrap();
+        let mut xs = registry.encode(&frame).unwrap().0;
+        if let Some(last) = xs.last_mut() {
+            last.space = Some(Microseconds(40000));
+        }
+        [xs.clone(), xs.clone(), xs].concat()
+    }
+
+    /// 受信し終わってからまとめて復調した結果
+    fn batch(
+        registry: &ProtocolRegistry,
+        input: &[MarkAndSpaceMicros],
+    ) -> Vec<InfraredRemoteDemodulatedFrame> {
+        registry.decode_frames(&decode_phase1(input).unwrap())
+    }
+
+    /// マークとスペースを1つずつ渡して逐次復調した結果
+    fn stream(
+        registry: &ProtocolRegistry,
+        input: &[MarkAndSpaceMicros],
+    ) -> Vec<InfraredRemoteDemodulatedFrame> {
+        let mut decoder = StreamingDecoder::new(registry);
+        let mut events = Vec::new();
+        for x in input {
+            events.extend(decoder.push(true, x.mark));
+            if let Some(space) = x.trailing_space() {
+                events.extend(decoder.push(false, space));
+            }
+        }
+        events.extend(decoder.finish());
+        events.iter().map(StreamEvent::frame).collect()
+    }
+
+    #[test]
+    fn test_streaming_decoder() {
+        let registry = ProtocolRegistry::builtin();
+        let input = input(&registry);
+        let mut decoder = StreamingDecoder::new(&registry);
+        let mut events = Vec::new();
+        for (index, x) in input.iter().enumerate() {
+            let mut xs = decoder.push(true, x.mark);
+            xs.extend(decoder.push(false, x.space.unwrap_or_default()));
+            // フレームはフレーム間隔のスペースを受け取ったところで返る
+            if !xs.is_empty() {
+                assert_eq!((index + 1) % (input.len() / 3), 0);
+            }
+            events.extend(xs);
+        }
+        events.extend(decoder.finish());
+        assert_eq!(events.len(), 3);
+        assert!(matches!(events[1], StreamEvent::Repeat { .. }));
+        assert_eq!(
+            events.iter().map(StreamEvent::frame).collect::<Vec<_>>(),
+            batch(&registry, &input)
+        );
+    }
+
+    #[test]
+    fn test_push_edges() {
+        let registry = ProtocolRegistry::builtin();
+        let input = input(&registry);
+        let mut timestamp = 1_000_000u64;
+        let mut edges = Vec::new();
+        for (index, duration) in Signal::from_mark_and_spaces(&input)
+            .durations
+            .iter()
+            .enumerate()
+        {
+            edges.push(Edge {
+                timestamp,
+                mark: index % 2 == 0,
+            });
+            timestamp += duration.0 as u64;
+        }
+        edges.push(Edge {
+            timestamp,
+            mark: true,
+        });
+        let mut decoder = StreamingDecoder::new(&registry);
+        // 途中で区切って渡しても同じ
+        let mut events = Vec::new();
+        for chunk in edges.chunks(7) {
+            events.extend(decoder.push_edges(chunk));
+        }
+        events.extend(decoder.finish());
+        assert_eq!(
+            events.iter().map(StreamEvent::frame).collect::<Vec<_>>(),
+            batch(&registry, &input)
+        );
+        assert_eq!(
+            events.last(),
+            Some(&StreamEvent::Repeat {
+                protocol: "SAMSUNG32".to_string(),
+                gap: Some(Microseconds(40000)),
+            })
+        );
+    }
+
+    #[test]
+    fn test_same_as_decode_phase1() {
+        let registry = ProtocolRegistry::builtin();
+        let ms = |mark: u32, space: u32| MarkAndSpaceMicros {
+            mark: Microseconds(mark),
+            space: Some(Microseconds(space)),
+        };
+        // 20ms の間隔で続けて、最後に 100ms 空けた不明プロトコルのフレーム
+        let mut unknown = Vec::new();
+        for gap in [20000, 20000, 100000, 20000] {
+            unknown.extend([ms(3000, 3000), ms(500, 500), ms(500, 1500), ms(500, gap)]);
+        }
+        unknown.push(MarkAndSpaceMicros::mark_only(Microseconds(500)));
+        // 8ms より短い間隔で続くNECのフレーム
+        let nec = registry
+            .encode(&InfraredRemoteDemodulatedFrame::Nec(
+                [0x00u8, 0xFF, 0x12, 0xED]
+                    .iter()
+                    .flat_map(|byte| (0..8).map(move |i| Bit((byte >> i) & 1)))
+                    .collect(),
+            ))
+            .unwrap()
+            .0;
+        let mut short_gap = nec.clone();
+        short_gap.last_mut().unwrap().space = Some(Microseconds(6000));
+        short_gap.extend(nec);
+        for input in [input(&registry), unknown, short_gap] {
+            let expected = batch(&registry, &input);
+            assert!(1 < expected.len());
+            assert_eq!(stream(&registry, &input), expected);
+        }
+    }
+
+    #[test]
+    fn test_bounded_spaces() {
+        let registry = ProtocolRegistry::builtin();
+        let mut decoder = StreamingDecoder::new(&registry);
+        // finish せずに揺らぎのあるスペースを受け取り続ける
+        for i in 0..(4 * MAX_SPACES as u32) {
+            decoder.push(true, Microseconds(560));
+            decoder.push(false, Microseconds(500 + i * 7 % 1500));
+        }
+        assert_eq!(decoder.spaces.len(), MAX_SPACES);
+        assert!(decoder.spaces.capacity() <= MAX_SPACES);
+    }
+}
@@ -0,0 +1,319 @@
+// Copyright (c) 2022 Akihiro Yamamoto.
+// Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
+// See LICENSE file in the project root for full license information.
+//
+use crate::infrared_remote::{MarkAndSpace, MarkAndSpaceMicros, Microseconds, THRESHOLD_FRAME_GAP};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// 同じクラスタとみなす時間幅の相対誤差(25%)
+const CLUSTER_RELATIVE_TOLERANCE: f64 = 0.25;
+
+/// 同じクラスタとみなす時間幅の最小誤差(100us)
+const CLUSTER_MINIMUM_TOLERANCE: f64 = 100.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 時間幅のクラスタ(ヒストグラムの1本)
+pub struct DurationCluster {
+    /// 平均時間
+    pub center: Microseconds,
+    /// 最小時間
+    pub min: Microseconds,
+    /// 最大時間
+    pub max: Microseconds,
+    /// 度数
+    pub count: usize,
+    /// 基準時間Tの何倍か
+    pub multiple: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 推定した変調方式
+pub enum ModulationType {
+    /// パルス間隔変調(NEC, AEHAなど)
+    PulseDistance,
+    /// パルス幅変調(SIRCなど)
+    PulseWidth,
+    /// バイフェーズ変調(RC5など)
+    BiPhase,
+    /// 不明
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// 基準時間(T)の推定結果
+pub struct TimeBaseEstimation {
+    /// 推定した基準時間T
+    pub time_base: Microseconds,
+    /// マーク時間のクラスタ
+    pub mark_clusters: Vec<DurationCluster>,
+    /// スペース時間のクラスタ
+    pub space_clusters: Vec<DurationCluster>,
+    /// 入力をTの整数倍に量子化したもの
+    pub quantized: Vec<MarkAndSpace<u32>>,
+    /// 推定した変調方式
+    pub modulation: ModulationType,
+}
+
+impl fmt::Display for TimeBaseEstimation {
+    /// "8T 4T | 1T 1T | 1T 3T" の形式で表示する
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self
+            .quantized
+            .iter()
+            .map(|x| format!("{}T {}T", x.mark, x.space))
+            .collect::<Vec<String>>()
+            .join(" | ");
+        write!(f, "T={}us {}", self.time_base.0, s)
+    }
+}
+
+/// 時間幅を並べ替えて近いもの同士をまとめる
+fn clustering(input: &[Microseconds]) -> Vec<Vec<Microseconds>> {
+    let mut sorted = input.to_vec();
+    sorted.sort();
+    let mut clusters: Vec<Vec<Microseconds>> = Vec::new();
+    for x in sorted {
+        match clusters.last_mut() {
+            Some(current) => {
+                let mean = mean_of(current);
+                let tolerance = (mean * CLUSTER_RELATIVE_TOLERANCE).max(CLUSTER_MINIMUM_TOLERANCE);
+                if x.0 as f64 - mean <= tolerance {
+                    current.push(x);
+                } else {
+                    clusters.push(vec![x]);
+                }
+            }
+            None => clusters.push(vec![x]),
+        }
+    }
+    clusters
+}
+
+/// 平均値
+fn mean_of(input: &[Microseconds]) -> f64 {
+    let sum: u64 = input.iter().map(|x| x.0 as u64).sum();
+    sum as f64 / input.len() as f64
+}
+
+/// 基準時間Tの何倍か(最低1倍、スペースが無ければ0倍)
+fn multiple_of(x: Option<Microseconds>, time_base: f64) -> u32 {
+    match x {
+        Some(x) => ((x.0 as f64 / time_base).round() as u32).max(1),
+        None => 0,
+    }
+}
+
+/// クラスタの集計をする
+fn summarize(clusters: &[Vec<Microseconds>], time_base: f64) -> Vec<DurationCluster> {
+    clusters
+        .iter()
+        .filter_map(|xs| {
+            let min = *xs.iter().min()?;
+            let max = *xs.iter().max()?;
+            let center = Microseconds(mean_of(xs).round() as u32);
+            Some(DurationCluster {
+                center,
+                min,
+                max,
+                count: xs.len(),
+                multiple: multiple_of(Some(center), time_base),
+            })
+        })
+        .collect()
+}
+
+/// クラスタの中心値から基準時間Tを求める
+/// 一番短いクラスタを仮のTとして各クラスタの倍数を決めて
+/// 度数で重み付けした最小二乗法でTを合わせ込む
+/// (1回しか出てこないクラスタは雑音かもしれないので避ける)
+fn fit_time_base(clusters: &[Vec<Microseconds>]) -> Option<f64> {
+    let shortest = clusters
+        .iter()
+        .find(|xs| 2 <= xs.len())
+        .or_else(|| clusters.first())
+        .map(|xs| mean_of(xs))?;
+    let (numerator, denominator) = clusters.iter().fold((0.0, 0.0), |(num, den), xs| {
+        let center = mean_of(xs);
+        let k = (center / shortest).round().max(1.0);
+        let n = xs.len() as f64;
+        (num + n * center * k, den + n * k * k)
+    });
+    if denominator > 0.0 {
+        Some(numerator / denominator)
+    } else {
+        None
+    }
+}
+
+/// 変調方式を推定する
+/// リーダーとフレーム間隔を除いたビット部分のマークとスペースの種類数で判断する
+fn guess_modulation(bits: &[MarkAndSpace<u32>]) -> ModulationType {
+    // 5%未満しか出てこない時間幅は雑音とみなす
+    let kinds = |f: fn(&MarkAndSpace<u32>) -> u32| {
+        let mut xs = bits.iter().map(f).collect::<Vec<u32>>();
+        xs.sort_unstable();
+        let mut result = xs.clone();
+        result.dedup();
+        result.retain(|k| 20 * xs.iter().filter(|x| *x == k).count() >= xs.len());
+        result
+    };
+    let marks = kinds(|x| x.mark);
+    let spaces = kinds(|x| x.space);
+    match (marks.as_slice(), spaces.as_slice()) {
+        ([m1, m2], [s1, s2])
+            if m1.checked_mul(2) == Some(*m2) && s1.checked_mul(2) == Some(*s2) =>
+        {
+            ModulationType::BiPhase
+        }
+        ([_], [_, _]) => ModulationType::PulseDistance,
+        ([_, _], [_]) => ModulationType::PulseWidth,
+        _ => ModulationType::Unknown,
+    }
+}
+
+/// 入力マークアンドスペース列の基準時間(T)を推定して
+/// Tの整数倍に量子化する
+pub fn estimate_time_base(input: &[MarkAndSpaceMicros]) -> Result<TimeBaseEstimation, String> {
+    if input.is_empty() {
+        return Err("estimate_time_base: input is empty.".to_string());
+    }
+    // フレーム間隔と最後のスペースが無いところは基準時間の推定に使わない
+    let marks = input.iter().map(|x| x.mark).collect::<Vec<Microseconds>>();
+    let spaces = input
+        .iter()
+        .filter_map(|x| x.trailing_space())
+        .filter(|x| *x < THRESHOLD_FRAME_GAP)
+        .collect::<Vec<Microseconds>>();
+    let mark_clusters = clustering(&marks);
+    let space_clusters = clustering(&spaces);
+    //
+    let mut all_clusters = clustering(&[marks, spaces].concat());
+    all_clusters.retain(|xs| !xs.is_empty());
+    let time_base = fit_time_base(&all_clusters)
+        .filter(|t| *t >= 1.0)
+        .ok_or_else(|| "estimate_time_base: could not estimate time base.".to_string())?;
+    //
+    let quantized = input
+        .iter()
+        .map(|x| MarkAndSpace {
+            mark: multiple_of(Some(x.mark), time_base),
+            space: multiple_of(x.space, time_base),
+        })
+        .collect::<Vec<MarkAndSpace<u32>>>();
+    // 各フレームの先頭(リーダー)と最後(フレーム間隔)を除いた部分をビットとみなす
+    let pairs = input
+        .iter()
+        .zip(quantized.iter())
+        .collect::<Vec<(&MarkAndSpaceMicros, &MarkAndSpace<u32>)>>();
+    let mut bits = Vec::new();
+    let is_gap = |x: &MarkAndSpaceMicros| x.space.map_or(false, |y| THRESHOLD_FRAME_GAP <= y);
+    for frame in pairs.split_inclusive(|(x, _)| is_gap(x)) {
+        let has_leader = frame
+            .first()
+            .map_or(false, |(_, q)| 4 <= q.mark || 4 <= q.space);
+        let has_gap = frame.last().map_or(false, |(x, _)| is_gap(x));
+        let start = if has_leader { 1 } else { 0 };
+        let end = if has_gap {
+            frame.len() - 1
+        } else {
+            frame.len()
+        };
+        if start < end {
+            bits.extend(frame[start..end].iter().map(|(_, q)| **q));
+        }
+    }
+    //
+    Ok(TimeBaseEstimation {
+        time_base: Microseconds(time_base.round() as u32),
+        mark_clusters: summarize(&mark_clusters, time_base),
+        space_clusters: summarize(&space_clusters, time_base),
+        quantized,
+        modulation: guess_modulation(&bits),
+    })
+}
+
+#[cfg(test)]
+mod time_base_tests {
+    use crate::infrared_remote::{MarkAndSpace, MarkAndSpaceMicros, Microseconds};
+    use crate::parsing::from_infrared_code;
+    use crate::time_base::{estimate_time_base, ModulationType};
+
+    fn mark_and_spaces(input: &str) -> Vec<MarkAndSpaceMicros> {
+        from_infrared_code(input)
+            .unwrap()
+            .iter()
+            .map(|x| MarkAndSpaceMicros::from(*x))
+            .collect()
+    }
+
+    #[test]
+    fn test1_estimate_time_base() {
+        assert_eq!(
+            estimate_time_base(&[]),
+            Err("estimate_time_base: input is empty.".to_string())
+        );
+    }
+
+    #[test]
+    fn test2_estimate_time_base() {
+        // NEC
+        let ircode= "5601A900180015001800140018001400190013001900140019001400170040001700150018003F0019003E0018003E0019003F0019003E00170040001800140019003E001800150018003F00180014001800140019003F0018001400170016001700150018003F001800140018003F0018003F001800140019003F0018003F0018003E0019004F03";
+        let estimated = estimate_time_base(&mark_and_spaces(ircode)).unwrap();
+        println!("{}", estimated);
+        assert!(
+            Microseconds(530) <= estimated.time_base && estimated.time_base <= Microseconds(600)
+        );
+        // 受信機によってマーク時間が長めになるのでリーダーは15T～16Tになる
+        assert!((15..=16).contains(&estimated.quantized[0].mark));
+        assert_eq!(estimated.quantized[0].space, 8);
+        assert_eq!(estimated.quantized[1], MarkAndSpace { mark: 1, space: 1 });
+        assert_eq!(estimated.quantized[7], MarkAndSpace { mark: 1, space: 3 });
+        assert_eq!(estimated.modulation, ModulationType::PulseDistance);
+    }
+
+    #[test]
+    fn test3_estimate_time_base() {
+        // AEHA
+        let ircode= "8600420014000F00130031001300100014000E0014000F001300100014000F001300100014000F00130010001300100013000F001300100013003100130010001300100013000F0014000F0013001000130010001300100013001000130010001300300014000F0013001000130010001300100013000F0014000F001300100014000F00140030001300100013003000140030001300310014002F0014000F0015000D0016003000130010001300300014003000130031001300300014000F001300310014004F03";
+        let estimated = estimate_time_base(&mark_and_spaces(ircode)).unwrap();
+        println!("{}", estimated);
+        assert_eq!(estimated.quantized[0], MarkAndSpace { mark: 8, space: 4 });
+        assert_eq!(estimated.modulation, ModulationType::PulseDistance);
+    }
+
+    #[test]
+    fn test4_estimate_time_base() {
+        // SIRC
+        let ircode= "5B0018002E001800180018002E001800170018002F00170018001700180017002F00180017001900170018001700180018004F03";
+        let estimated = estimate_time_base(&mark_and_spaces(ircode)).unwrap();
+        println!("{}", estimated);
+        assert_eq!(estimated.quantized[0], MarkAndSpace { mark: 4, space: 1 });
+        assert_eq!(estimated.modulation, ModulationType::PulseWidth);
+    }
+
+    #[test]
+    fn test5_estimate_time_base() {
+        // RC5のようなバイフェーズ変調
+        let input = [
+            (889, 889),
+            (1778, 889),
+            (889, 1778),
+            (889, 889),
+            (1778, 1778),
+            (889, 889),
+            (889, 889),
+            (1778, 889),
+            (889, 40000),
+        ]
+        .iter()
+        .map(|(m, s)| MarkAndSpace {
+            mark: Microseconds(*m),
+            space: Some(Microseconds(*s)),
+        })
+        .collect::<Vec<MarkAndSpaceMicros>>();
+        let estimated = estimate_time_base(&input).unwrap();
+        assert_eq!(estimated.time_base, Microseconds(889));
+        assert_eq!(estimated.modulation, ModulationType::BiPhase);
+    }
+}
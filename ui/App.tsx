@@ -2,12 +2,13 @@
 // Licensed under the MIT License <https://spdx.org/licenses/MIT.html>
 // See LICENSE file in the project root for full license information.
 //
-import { useState } from 'react';
+import { useState, useEffect } from 'react';
 import { Modal, Button, Card, Divider, Input, Alert, Typography, Space, Table, message } from 'antd';
 import 'antd/dist/antd.min.css';
 import { Line, Datum } from '@ant-design/charts';
 import { invoke } from '@tauri-apps/api/tauri'
-import { RxIrRemoteCode, TxIrRemoteCode, RxTxIrRemoteCode, MarkAndSpace, convert_to_RxIrRemoteCode, convert_to_TxIrRemoteCode } from './index';
+import { listen } from '@tauri-apps/api/event'
+import { RxIrRemoteCode, TxIrRemoteCode, RxTxIrRemoteCode, MarkAndSpace, Segment, CleanupResult, CapturedSignal, DetectedSignal, FormatDiagnostic, convert_to_RxIrRemoteCode, convert_to_TxIrRemoteCode } from './index';
 import IrBitStream from './IrBitStream';
 import './App.css';
 
@@ -18,41 +19,39 @@ const { Text, Title } = Typography;
 const InfraredRemoteSignal = (props: { rx_ircode: RxIrRemoteCode }): JSX.Element => {
   let mark_and_spaces: MarkAndSpace[] = props.rx_ircode;
 
+  const [segments, setSegments] = useState<Segment[]>([])
+
+  // 各区間の開始時刻はバックエンドで求める
+  useEffect(
+    () => {
+      invoke<Segment[]>("signal_segments", { input: mark_and_spaces })
+        .then(x => setSegments(x))
+        .catch(_ => setSegments([]))
+    }
+    , [mark_and_spaces])
+
   type DatumForList = { key: any, sn: number, t: number, kinds: string, duration: number }
-  const convert_for_list = (input: MarkAndSpace[]): DatumForList[] => {
-    var acc: number = 0;
-    var output: DatumForList[] = [];
-    input.forEach((item, index) => {
-      let sequence_number = 1 + 2 * index;
-      output.push({ key: sequence_number, sn: sequence_number, t: acc, kinds: "Mark", duration: item.mark });
-      acc += item.mark;
-      output.push({ key: sequence_number + 1, sn: sequence_number + 1, t: acc, kinds: "Space", duration: item.space });
-      acc += item.space;
-    })
-    return output
-  }
+  const convert_for_list = (input: Segment[]): DatumForList[] =>
+    input
+      // 長さ0のスペースはスペースが無い(信号の最後)
+      .filter(x => x.mark || x.duration > 0)
+      .map((x, index) => ({ key: index + 1, sn: index + 1, t: x.start, kinds: x.mark ? "Mark" : "Space", duration: x.duration }))
 
   //
   type DatumForGraph = { t: number, bit: number }
-  const conv_ir_control_signal = (input: MarkAndSpace[]): Array<DatumForGraph> => {
+  const conv_ir_control_signal = (input: Segment[]): Array<DatumForGraph> => {
     if (input.length < 1) {
       return []
     }
-    var output: DatumForGraph[] = [];
-    var sum = 0;
-    output.push({ t: sum, bit: 0 });
-    input.forEach((item, index) => {
-      sum += item.mark;
-      output.push({ t: sum, bit: 1 });
-      sum += item.space;
-      output.push({ t: sum, bit: 0 });
-    })
-    return output;
+    let last = input[input.length - 1]
+    return input
+      .map(x => ({ t: x.start, bit: x.mark ? 1 : 0 }))
+      .concat([{ t: last.start + last.duration, bit: 0 }])
   }
 
   //
   const config = {
-    data: conv_ir_control_signal(mark_and_spaces),
+    data: conv_ir_control_signal(segments),
     height: 200,
     xField: 't',
     yField: 'bit',
@@ -110,7 +109,7 @@ const InfraredRemoteSignal = (props: { rx_ircode: RxIrRemoteCode }): JSX.Element
       <Line {...config} />
       <Divider>マークアンドスペース</Divider>
       <Table
-        dataSource={convert_for_list(mark_and_spaces)}
+        dataSource={convert_for_list(segments)}
         columns={columns}
         scroll={{ y: 240 }}
       />
@@ -139,6 +138,72 @@ const App = (): JSX.Element => {
   const [state, setState] = useState<State>(initState)
   const [rx_tx_ircode, setRxTxIrCode] = useState<RxTxIrRemoteCode>({ RxIrRemoteCode: [] })
 
+  const [capturing, setCapturing] = useState<boolean>(false)
+
+  // シリアルポートの学習リモコンは今はLinuxだけ
+  const [serialSupported, setSerialSupported] = useState<boolean>(false)
+  useEffect(
+    () => {
+      invoke<boolean>("serial_supported")
+        .then(setSerialSupported)
+        .catch(_ => setSerialSupported(false))
+    }
+    , [])
+
+  // LIRCデバイスで受信した信号
+  useEffect(
+    () => {
+      const unlisten = Promise.all([
+        listen<CapturedSignal>("lirc-capture", event => {
+          setRxTxIrCode({ RxIrRemoteCode: event.payload.signal })
+          setState(state => ({ ...state, alert: { type: "success", message: "受信しました。" } }))
+        }),
+        listen<string>("lirc-capture-error", event => {
+          setCapturing(false)
+          setState(state => ({ ...state, alert: { type: 'error', message: event.payload } }))
+        }),
+      ])
+      return () => { unlisten.then(xs => xs.forEach(f => f())) }
+    }
+    , [])
+
+  const handleCapture = () => {
+    if (capturing) {
+      invoke("stop_lirc_capture")
+        .then(() => setCapturing(false))
+        .catch(err => setState(state => ({ ...state, alert: { type: 'error', message: err } })))
+    } else {
+      invoke("start_lirc_capture", { path: "/dev/lirc0" })
+        .then(() => {
+          setCapturing(true)
+          setState(state => ({ ...state, alert: { type: "info", message: "/dev/lirc0 で受信中。" } }))
+        })
+        .catch(err => setState(state => ({ ...state, alert: { type: 'error', message: err } })))
+    }
+  }
+
+  const handleSend = () => {
+    const send = ([input, carrierFrequency]: [RxIrRemoteCode, number | null]) =>
+      invoke("send_lirc", { path: "/dev/lirc0", input: input, carrierFrequency: carrierFrequency })
+    const signal: Promise<[RxIrRemoteCode, number | null]> = "TxIrRemoteCode" in rx_tx_ircode
+      ? Promise.all([
+        invoke<RxIrRemoteCode>("encode2", { input: rx_tx_ircode.TxIrRemoteCode }),
+        invoke<number>("carrier_frequency", { input: rx_tx_ircode.TxIrRemoteCode }),
+      ])
+      : Promise.resolve<[RxIrRemoteCode, number | null]>([rx_tx_ircode.RxIrRemoteCode, null])
+    signal
+      .then(send)
+      .then(() => setState(state => ({ ...state, alert: { type: "success", message: "/dev/lirc0 から送信しました。" } })))
+      .catch(err => setState(state => ({ ...state, alert: { type: 'error', message: err } })))
+  }
+
+  const handleLearn = () => {
+    setState(state => ({ ...state, alert: { type: "info", message: "/dev/ttyUSB0 で学習中。" } }))
+    invoke<string>("learn_serial", { path: "/dev/ttyUSB0" })
+      .then(handleParse)
+      .catch(err => setState(state => ({ ...state, alert: { type: 'error', message: err } })))
+  }
+
   const handleReset = () => {
     setState(initState)
     setRxTxIrCode({ RxIrRemoteCode: [] })
@@ -146,7 +211,7 @@ const App = (): JSX.Element => {
 
   const handleConvert = () => {
     if ("RxIrRemoteCode" in rx_tx_ircode) {
-      let new_text = "{" + rx_tx_ircode.RxIrRemoteCode.map(item => item.mark + "," + item.space) + "}"
+      let new_text = "{" + rx_tx_ircode.RxIrRemoteCode.map(item => item.space !== null ? item.mark + "," + item.space : item.mark) + "}"
       setState({ ...state, text: new_text })
       message.info('表現を変換しました。')
     } else if ("TxIrRemoteCode" in rx_tx_ircode) {
@@ -162,13 +227,38 @@ const App = (): JSX.Element => {
     }
   }
 
+  const handleCleanup = () => {
+    if ("RxIrRemoteCode" in rx_tx_ircode) {
+      invoke<CleanupResult>("clean_up_signal", { input: rx_tx_ircode.RxIrRemoteCode })
+        .then(x => {
+          setRxTxIrCode({ RxIrRemoteCode: x.output })
+          let edits = x.edits
+            .map(edit => Object.entries(edit).map(([kind, { start, duration }]) => kind + " " + duration + "μs (" + start + "μs)"))
+            .flat()
+          let msg = edits.length > 0 ? "ノイズを除去しました。：" + edits.join(", ") : "ノイズはありませんでした。"
+          setState(state => ({ ...state, alert: { type: "info", message: msg } }))
+        }).catch(err => setState(state => ({ ...state, alert: { type: 'error', message: err } })))
+    } else {
+      message.info('受信した信号だけがノイズ除去できます。')
+    }
+  }
+
   const handleParse = (text: string) => {
     setState({ ...state, text: text })
-    invoke<RxIrRemoteCode>("parse_infrared_code", { ircode: text })
-      .then((rx) => {
-        setRxTxIrCode({ RxIrRemoteCode: rx })
-        setState(state => ({ ...state, alert: { type: "success", message: "いいですね。" } }))
-      }).catch(err => setState(state => ({ ...state, alert: { type: 'error', message: err } })))
+    invoke<DetectedSignal>("detect_signal_format", { ircode: text })
+      .then((x) => {
+        setRxTxIrCode({ RxIrRemoteCode: x.signal })
+        setState(state => ({ ...state, alert: { type: "success", message: "いいですね。(" + x.format + ")" } }))
+      }).catch((diagnostics: FormatDiagnostic[]) =>
+        // "NEC 04 FB 08 F7" のような16進数で書いたフレーム
+        invoke<TxIrRemoteCode>("parse_frames_hex", { text: text })
+          .then((tx) => {
+            setRxTxIrCode({ TxIrRemoteCode: tx })
+            setState(state => ({ ...state, alert: { type: "success", message: "いいですね。" } }))
+          }).catch(_ => {
+            let msg = diagnostics.map(x => x.format + ": " + x.reason).join(" / ")
+            setState(state => ({ ...state, alert: { type: 'error', message: msg } }))
+          }))
   }
 
   return (
@@ -176,6 +266,10 @@ const App = (): JSX.Element => {
       <Card size="small" title={<Title level={4}>解析する赤外線リモコン信号</Title>}>
         <Button type="primary" style={{ margin: 3 }} onClick={handleReset}>Reset</Button>
         <Button type="default" style={{ margin: 3 }} onClick={handleConvert}>変換</Button>
+        <Button type="default" style={{ margin: 3 }} onClick={handleCleanup}>ノイズ除去</Button>
+        <Button type="default" style={{ margin: 3 }} onClick={handleCapture}>{capturing ? "受信停止" : "受信"}</Button>
+        <Button type="default" style={{ margin: 3 }} onClick={handleSend}>送信</Button>
+        <Button type="default" style={{ margin: 3 }} onClick={handleLearn} disabled={!serialSupported} title={serialSupported ? undefined : "シリアルポートの学習リモコンはLinuxだけで使えます。"}>学習</Button>
         <TextArea
           rows={6}
           placeholder="ここに解析対象の赤外線リモコンコードを入れる。"
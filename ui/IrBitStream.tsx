@@ -7,7 +7,7 @@ import './IrBitStream.css'
 import { Statistic, Empty, Alert, Card, Divider, Radio, Space, Typography, Descriptions } from 'antd'
 import 'antd/dist/antd.min.css'
 import { invoke } from '@tauri-apps/api/tauri'
-import { RxTxIrRemoteCode, RxIrRemoteCode, InfraredRemoteDemodulatedFrame } from './index'
+import { RxTxIrRemoteCode, RxIrRemoteCode, InfraredRemoteDemodulatedFrame, MarkAndSpace, DurationCluster, TimeBaseEstimation, Interpretation, FieldValue, BitOrder } from './index'
 
 const { Title, Text, Paragraph } = Typography
 
@@ -20,11 +20,107 @@ const to_octets = (bitstream: Uint8Array): Uint8Array[] => {
   return output
 }
 
+// プロトコル名
+const protocol_name = (frame: InfraredRemoteDemodulatedFrame): string => {
+  if ("Aeha" in frame) {
+    return "AEHA"
+  } else if ("Nec" in frame) {
+    return "NEC"
+  } else if ("Samsung32" in frame) {
+    return "SAMSUNG32"
+  } else if ("Samsung48" in frame) {
+    return "SAMSUNG48"
+  } else if ("Sirc" in frame) {
+    return "SIRC"
+  } else if ("Defined" in frame) {
+    return frame.Defined.protocol
+  } else if ("Repeat" in frame) {
+    return frame.Repeat + " REPEAT"
+  } else {
+    return "UNKNOWN"
+  }
+}
+
+// 確からしさを百分率で表示する
+const percent = (x: number): string => (100 * x).toFixed(0) + "%"
+
+// 不明プロトコルの基準時間(T)を推定して表示する
+const UnknownFrame = (props: { mark_and_spaces: MarkAndSpace[] }): JSX.Element => {
+  const [estimation, setEstimation] = useState<TimeBaseEstimation | null>(null)
+
+  useEffect(
+    () => {
+      invoke<TimeBaseEstimation>("analyze_time_base", { input: props.mark_and_spaces })
+        .then(x => setEstimation(x))
+        .catch(_ => setEstimation(null))
+    }
+    , [props.mark_and_spaces])
+
+  if (estimation === null) {
+    return <Empty image={Empty.PRESENTED_IMAGE_SIMPLE} />
+  }
+  let quantized = estimation.quantized.map(x => x.mark + "T " + x.space + "T").join(" | ")
+  let histogram = (kinds: string, xs: DurationCluster[]) =>
+    xs.map(x => kinds + " " + x.multiple + "T(" + x.center + "μs) x " + x.count).join(", ")
+  return (
+    <Descriptions layout="vertical" column={2} bordered>
+      <Descriptions.Item label="T">
+        <Statistic value={estimation.time_base} suffix="μs" />
+      </Descriptions.Item>
+      <Descriptions.Item label="Modulation">
+        <Statistic value={estimation.modulation} />
+      </Descriptions.Item>
+      <Descriptions.Item label="Histogram" span={2}>
+        <Text>{histogram("Mark", estimation.mark_clusters)}</Text><br />
+        <Text>{histogram("Space", estimation.space_clusters)}</Text>
+      </Descriptions.Item>
+      <Descriptions.Item label="Quantized" span={2}>
+        <Text>{quantized}</Text>
+      </Descriptions.Item>
+    </Descriptions>
+  )
+}
+
+// プロトコル定義のフィールドに分けて表示する
+const FrameFields = (props: { frame: InfraredRemoteDemodulatedFrame }): JSX.Element => {
+  const [fields, setFields] = useState<FieldValue[]>([])
+
+  useEffect(
+    () => {
+      invoke<FieldValue[]>("frame_fields", { input: props.frame })
+        .then(x => setFields(x))
+        .catch(_ => setFields([]))
+    }
+    , [props.frame])
+
+  let text = fields
+    .map(x => x.name + " = " + (x.value === null ? "-" : "0x" + x.value.toString(16).toUpperCase()))
+    .join(", ")
+  return <Text>{text}</Text>
+}
+
+// 8ビットごとの16進数で表示する
+const FrameHex = (props: { frame: InfraredRemoteDemodulatedFrame, msb_first: boolean }): JSX.Element => {
+  const [text, setText] = useState<string>("")
+
+  useEffect(
+    () => {
+      const order: BitOrder = props.msb_first ? "MsbFirst" : "LsbFirst"
+      invoke<string>("format_frames_hex", { input: [props.frame], order: order })
+        .then(x => setText(x))
+        .catch(_ => setText(""))
+    }
+    , [props.frame, props.msb_first])
+
+  return <Text copyable>{text}</Text>
+}
+
 //
 const InfraredRemoteFrame = (props: {
   msb_first: boolean,
   index: number,
-  frame: InfraredRemoteDemodulatedFrame
+  frame: InfraredRemoteDemodulatedFrame,
+  candidates: Interpretation[],
 }): JSX.Element => {
   var protocol = ""
   var bitstream = new Uint8Array()
@@ -35,11 +131,27 @@ const InfraredRemoteFrame = (props: {
   } else if ("Nec" in props.frame) {
     protocol = "NEC"
     bitstream = props.frame.Nec
+  } else if ("Samsung32" in props.frame) {
+    protocol = "SAMSUNG32"
+    bitstream = props.frame.Samsung32
+  } else if ("Samsung48" in props.frame) {
+    protocol = "SAMSUNG48"
+    bitstream = props.frame.Samsung48
   } else if ("Sirc" in props.frame) {
     protocol = "SIRC"
     bitstream = props.frame.Sirc
+  } else if ("Defined" in props.frame) {
+    protocol = props.frame.Defined.protocol
+    bitstream = props.frame.Defined.bits
+  } else if ("Repeat" in props.frame) {
+    protocol = props.frame.Repeat + " REPEAT"
   } else if ("Unknown" in props.frame) {
-    protocol = "UNKNOWN"
+    return (
+      <>
+        <Divider>{"Frame# " + (1 + props.index) + " UNKNOWN"}</Divider>
+        <UnknownFrame mark_and_spaces={props.frame.Unknown} />
+      </>
+    )
   } else {
     throw new Error('unimplemented')
   }
@@ -73,7 +185,32 @@ const InfraredRemoteFrame = (props: {
       <Descriptions.Item key={protocol} label="Protocol">
         <Statistic value={protocol} />
       </Descriptions.Item>
+      {(props.candidates.length) ?
+        <Descriptions.Item key="confidence" label="Confidence">
+          <Statistic value={percent(props.candidates[0].confidence)} />
+        </Descriptions.Item>
+        : null}
       {descriptions_item}
+      <Descriptions.Item key="hex" label="Hex" span={14}>
+        <FrameHex frame={props.frame} msb_first={props.msb_first} />
+      </Descriptions.Item>
+      <Descriptions.Item key="fields" label="Fields" span={14}>
+        <FrameFields frame={props.frame} />
+      </Descriptions.Item>
+      {(props.candidates.length > 1) ?
+        <Descriptions.Item key="alternatives" label="Alternatives" span={14}>
+          {props.candidates.slice(1).map((x, index) =>
+            <Text key={index}>
+              {protocol_name(x.frame) + " " + percent(x.confidence)
+                + " (leader " + percent(x.leader_fit)
+                + ", timing " + percent(x.timing_fit)
+                + ", bits " + percent(x.bit_count_fit)
+                + ", check " + percent(x.check_fit) + ")"}
+              <br />
+            </Text>
+          )}
+        </Descriptions.Item>
+        : null}
     </Descriptions>
   )
 }
@@ -85,6 +222,7 @@ type Props = {
 type State = {
   msb_first: number,
   ir_frames: InfraredRemoteDemodulatedFrame[],
+  candidates: Interpretation[][],
   alert: {
     type: 'success' | 'info' | 'warning' | 'error',
     message: string,
@@ -94,6 +232,7 @@ type State = {
 const initState: State = {
   msb_first: 0,
   ir_frames: [],
+  candidates: [],
   alert: {
     type: 'info',
     message: "",
@@ -105,11 +244,15 @@ const IrBitStream = (props: Props): JSX.Element => {
   const [state, setState] = useState<State>(initState)
 
   const decode = (ircode: RxIrRemoteCode) => {
-    invoke<InfraredRemoteDemodulatedFrame[]>("decode", { input: ircode })
-      .then((tx) => {
+    Promise.all([
+      invoke<InfraredRemoteDemodulatedFrame[]>("decode", { input: ircode }),
+      invoke<Interpretation[][]>("decode_candidates", { input: ircode }),
+    ])
+      .then(([tx, candidates]) => {
         setState({
           ...state,
           ir_frames: tx,
+          candidates: candidates,
           alert: { type: "success", message: "デコード成功" },
         })
       })
@@ -166,7 +309,12 @@ const IrBitStream = (props: Props): JSX.Element => {
           }
         </Paragraph>
         {state.ir_frames.map((item, index) =>
-          <InfraredRemoteFrame key={index} msb_first={state.msb_first === 1} index={index} frame={item} />
+          <InfraredRemoteFrame
+            key={index}
+            msb_first={state.msb_first === 1}
+            index={index}
+            frame={item}
+            candidates={state.candidates[index] || []} />
         )}
         <Alert message={state.alert.message} type={state.alert.type} showIcon />
       </Space>
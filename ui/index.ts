@@ -46,13 +46,182 @@ export const convert_to_TxIrRemoteCode = (input: RxTxIrRemoteCode): TxIrRemoteCo
 // バックエンドとの通信用
 //
 
+// spaceがnullならスペースが無い(信号の最後のマーク)
 export interface MarkAndSpace {
+	mark: number,
+	space: number | null,
+};
+
+// 基準時間Tの倍数のマークアンドスペース
+export interface MarkAndSpaceMultiple {
 	mark: number,
 	space: number,
 };
 
+// 時間列の1区間
+export interface Segment {
+	start: number,
+	duration: number,
+	mark: boolean,
+};
+
+// ノイズ除去の設定
+export interface CleanupOptions {
+	minimum_pulse_width: number,
+	trim: boolean,
+};
+
+// ノイズ除去で変更した内容
+export type CleanupEdit =
+	| { MergedMark: { start: number, duration: number } }
+	| { MergedSpace: { start: number, duration: number } }
+	| { TrimmedLeading: { start: number, duration: number } }
+	| { TrimmedTrailing: { start: number, duration: number } }
+
+export interface CleanupResult {
+	output: MarkAndSpace[],
+	edits: CleanupEdit[],
+};
+
 export type InfraredRemoteDemodulatedFrame =
 	| { Aeha: Uint8Array }
 	| { Nec: Uint8Array }
+	| { Samsung32: Uint8Array }
+	| { Samsung48: Uint8Array }
 	| { Sirc: Uint8Array }
+	| { Defined: { protocol: string, bits: Uint8Array } }
+	| { Repeat: string }
 	| { Unknown: MarkAndSpace[] }
+
+// 16進数で表示するときのビットオーダー
+export type BitOrder = "LsbFirst" | "MsbFirst"
+
+// フレームの間隔(Gap: フレームの終わりから, Period: フレームの始まりから)
+export type FrameSpacing =
+	| { Gap: number }
+	| { Period: number }
+
+// spacingがnullならプロトコルのフレーム周期(無ければフレーム間隔)
+export interface TimedFrame {
+	frame: InfraredRemoteDemodulatedFrame,
+	spacing: FrameSpacing | null,
+};
+
+export type PulseKind = "Mark" | "Space" | "Gap"
+
+export interface PulseDelta {
+	frame: number,
+	index: number,
+	kind: PulseKind,
+	original: number,
+	encoded: number,
+	delta: number,
+};
+
+export type StructuralDifference =
+	| { MissingFrame: { frame: number } }
+	| { ExtraFrame: { frame: number } }
+	| { MissingPulses: { frame: number, count: number } }
+	| { ExtraPulses: { frame: number, count: number } }
+
+// 元の信号と再エンコードした信号の比較結果
+export interface FidelityReport {
+	deltas: PulseDelta[],
+	max_error: number,
+	rms_error: number,
+	max_gap_error: number,
+	differences: StructuralDifference[],
+};
+
+// 2つの信号の同等性(上ほど強い)
+export type Equivalence = "SameFrames" | "SimilarTiming" | "SameCommand" | "Different"
+
+export interface Comparison {
+	equivalence: Equivalence,
+	differences: string[],
+};
+
+// LIRCデバイスで受信した信号
+export interface CapturedSignal {
+	signal: MarkAndSpace[],
+	frames: InfraredRemoteDemodulatedFrame[],
+};
+
+export type ModulationType = "PulseDistance" | "PulseWidth" | "BiPhase" | "Unknown"
+
+export interface DurationCluster {
+	center: number,
+	min: number,
+	max: number,
+	count: number,
+	multiple: number,
+};
+
+export interface TimeBaseEstimation {
+	time_base: number,
+	mark_clusters: DurationCluster[],
+	space_clusters: DurationCluster[],
+	quantized: MarkAndSpaceMultiple[],
+	modulation: ModulationType,
+};
+
+export interface Interpretation {
+	frame: InfraredRemoteDemodulatedFrame,
+	confidence: number,
+	leader_fit: number,
+	timing_fit: number,
+	bit_count_fit: number,
+	check_fit: number,
+};
+
+export interface FieldValue {
+	name: string,
+	bits: number,
+	value: number | null,
+};
+
+// マクロで送る信号(Named: 登録した信号の名前, Inline: 直接書いた信号)
+export type MacroCode =
+	| { Named: string }
+	| { Inline: InfraredRemoteDemodulatedFrame }
+
+// マクロの手順(Delay はμs)
+export type MacroStep =
+	| { Send: { code: MacroCode, count: number } }
+	| { Delay: number }
+
+export interface Macro {
+	name: string,
+	description: string | null,
+	steps: MacroStep[],
+};
+
+export interface MacroBook {
+	codes: { [name: string]: InfraredRemoteDemodulatedFrame[] },
+	macros: Macro[],
+};
+
+export interface PlannedSignal {
+	start: number,
+	carrier_frequency: number,
+	signal: MarkAndSpace[],
+	wait: number,
+};
+
+export interface TransmissionPlan {
+	signals: PlannedSignal[],
+	duration: number,
+};
+
+// 書式を見分けて読み込んだ信号
+export interface DetectedSignal {
+	format: string,
+	signal: MarkAndSpace[],
+	carrier_frequency: number,
+};
+
+// その書式で読めなかった理由
+export interface FormatDiagnostic {
+	format: string,
+	reason: string,
+};